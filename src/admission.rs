@@ -0,0 +1,110 @@
+use axum::http::StatusCode;
+use metrics::{counter, gauge};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+use crate::system_monitor::Readings;
+use crate::types::{AppState, LoadShedding};
+
+// how often a queued submission looks at the readings again, they are
+// refreshed every 5 seconds
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type AdmissionError = (StatusCode, String);
+
+// turns new work away while the host can't run it properly, so it fails up
+// front with a reason instead of midway through a run. work already admitted
+// carries on
+pub async fn check(state: &AppState) -> Result<(), AdmissionError> {
+    let Some(readings) = state.system.readings() else {
+        return Ok(());
+    };
+    if let Some(min_free) = state.config.min_free_disk_bytes
+        && let Some(free) = readings.sandbox_disk_free_bytes
+        && free < min_free
+    {
+        counter!("admission_rejections_total", "reason" => "disk").increment(1);
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "server is low on disk space: {} bytes free, {} required",
+                free, min_free
+            ),
+        ));
+    }
+
+    let Some(shedding) = &state.config.load_shedding else {
+        return Ok(());
+    };
+    let Some(mut overload) = overloaded(shedding, &readings) else {
+        return Ok(());
+    };
+    // a busy host slows every run down, which judges see as time limits
+    // exceeded, so submissions wait for it to calm down or are turned away
+    let deadline = Instant::now() + Duration::from_secs(shedding.queue_timeout_secs);
+    gauge!("admission_queued").increment(1.0);
+    while Instant::now() < deadline {
+        time::sleep(QUEUE_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())))
+            .await;
+        match state
+            .system
+            .readings()
+            .and_then(|readings| overloaded(shedding, &readings))
+        {
+            Some(still) => overload = still,
+            None => {
+                gauge!("admission_queued").decrement(1.0);
+                return Ok(());
+            }
+        }
+    }
+    gauge!("admission_queued").decrement(1.0);
+    counter!("admission_rejections_total", "reason" => overload.0).increment(1);
+    Err((StatusCode::SERVICE_UNAVAILABLE, overload.1))
+}
+
+// whether new work would be held back right now, for runners deciding to
+// claim a queued request
+pub fn host_busy(state: &AppState) -> bool {
+    let Some(readings) = state.system.readings() else {
+        return false;
+    };
+    let low_on_disk = state
+        .config
+        .min_free_disk_bytes
+        .zip(readings.sandbox_disk_free_bytes)
+        .is_some_and(|(min_free, free)| free < min_free);
+    let overloaded = state
+        .config
+        .load_shedding
+        .as_ref()
+        .is_some_and(|shedding| overloaded(shedding, &readings).is_some());
+    low_on_disk || overloaded
+}
+
+// the reason label and message when a threshold is crossed
+fn overloaded(shedding: &LoadShedding, readings: &Readings) -> Option<(&'static str, String)> {
+    if let Some(max) = shedding.max_cpu_percent
+        && readings.cpu_usage_percent > max as f32
+    {
+        return Some((
+            "cpu",
+            format!(
+                "server is overloaded: cpu usage is {:.0}%, the limit is {}%",
+                readings.cpu_usage_percent, max
+            ),
+        ));
+    }
+    if let Some(max) = shedding.max_load_percent
+        && readings.load_percent > max as f64
+    {
+        return Some((
+            "load",
+            format!(
+                "server is overloaded: load is {:.0}% per cpu, the limit is {}%",
+                readings.load_percent, max
+            ),
+        ));
+    }
+    None
+}
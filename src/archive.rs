@@ -0,0 +1,598 @@
+use flate2::read::{DeflateDecoder, GzDecoder};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+// the end record is 22 bytes plus a comment of up to 64 KiB
+const END_RECORD_SIZE: usize = 22;
+const MAX_COMMENT_SIZE: usize = u16::MAX as usize;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+pub struct ArchiveEntry {
+    pub name: String, // relative path, never absolute or containing ".."
+    pub data: Vec<u8>,
+    pub executable: bool, // any execute bit set in the archived mode
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "truncated zip archive".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "truncated zip archive".to_string())
+}
+
+// reads the regular files of a zip archive. `max_size` caps the total
+// uncompressed size so a small archive can't expand without bound. zip64 and
+// encrypted entries are not supported
+pub fn read_zip(data: &[u8], max_size: u64) -> Result<Vec<ArchiveEntry>, String> {
+    let search_start = data
+        .len()
+        .saturating_sub(END_RECORD_SIZE + MAX_COMMENT_SIZE);
+    let end = (search_start..=data.len().saturating_sub(END_RECORD_SIZE))
+        .rev()
+        .find(|&offset| u32_at(data, offset) == Ok(END_OF_CENTRAL_DIRECTORY))
+        .ok_or("not a zip archive")?;
+
+    let count = u16_at(data, end + 10)?;
+    let mut offset = u32_at(data, end + 16)? as usize;
+    let mut remaining = max_size;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if u32_at(data, offset)? != CENTRAL_DIRECTORY_HEADER {
+            return Err("corrupt zip central directory".to_string());
+        }
+        let flags = u16_at(data, offset + 8)?;
+        let method = u16_at(data, offset + 10)?;
+        let crc = u32_at(data, offset + 16)?;
+        let compressed_size = u32_at(data, offset + 20)? as usize;
+        let size = u32_at(data, offset + 24)? as u64;
+        let name_length = u16_at(data, offset + 28)? as usize;
+        let extra_length = u16_at(data, offset + 30)? as usize;
+        let comment_length = u16_at(data, offset + 32)? as usize;
+        let external_attributes = u32_at(data, offset + 38)?;
+        let header_offset = u32_at(data, offset + 42)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or("truncated zip archive")?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| "zip entry name is not utf-8")?;
+        offset += 46 + name_length + extra_length + comment_length;
+
+        if name.ends_with('/') {
+            continue;
+        }
+        if !is_relative(&name) {
+            return Err(format!("zip entry {} escapes the archive", name));
+        }
+        if flags & 1 != 0 {
+            return Err(format!("zip entry {} is encrypted", name));
+        }
+        if size > remaining {
+            return Err(format!(
+                "zip archive expands to more than {} bytes",
+                max_size
+            ));
+        }
+        remaining -= size;
+
+        // the local header repeats the name but may have a different extra field
+        if u32_at(data, header_offset)? != LOCAL_FILE_HEADER {
+            return Err(format!("corrupt zip entry {}", name));
+        }
+        let start = header_offset
+            + 30
+            + u16_at(data, header_offset + 26)? as usize
+            + u16_at(data, header_offset + 28)? as usize;
+        let raw = data
+            .get(start..start + compressed_size)
+            .ok_or("truncated zip archive")?;
+
+        let contents = match method {
+            METHOD_STORED => raw.to_vec(),
+            METHOD_DEFLATE => {
+                let mut contents = Vec::with_capacity(size as usize);
+                // the declared size is untrusted, read at most one byte past it
+                DeflateDecoder::new(raw)
+                    .take(size + 1)
+                    .read_to_end(&mut contents)
+                    .map_err(|e| format!("failed to inflate zip entry {}: {}", name, e))?;
+                contents
+            }
+            _ => {
+                return Err(format!(
+                    "zip entry {} uses unsupported compression method {}",
+                    name, method
+                ));
+            }
+        };
+        if contents.len() as u64 != size || crc32fast::hash(&contents) != crc {
+            return Err(format!("zip entry {} is corrupt", name));
+        }
+        entries.push(ArchiveEntry {
+            name,
+            data: contents,
+            // archives made on unix keep the mode in the high bits
+            executable: (external_attributes >> 16) & 0o111 != 0,
+        });
+    }
+    Ok(entries)
+}
+
+pub fn is_relative(name: &str) -> bool {
+    Path::new(name)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+const USTAR_MAGIC: &[u8; 6] = b"ustar\0";
+const GNU_MAGIC: &[u8; 8] = b"ustar  \0";
+
+// name of the pseudo entry holding the next entry's long name
+const GNU_LONG_NAME: &str = "././@LongLink";
+
+// pax and gnu long name headers are a few paths, not file contents
+const MAX_TAR_HEADER_SIZE: u64 = 1024 * 1024;
+
+// symlinks followed while resolving one path inside an unpacked tree
+const MAX_SYMLINK_HOPS: usize = 40;
+
+// whiteouts in oci layers delete what lower layers put at a path
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+// one tar entry, its data follows in the reader padded to whole blocks
+struct TarEntry {
+    name: String, // relative, "./" and leading "/" stripped
+    link: String,
+    kind: u8,
+    size: u64,
+    mode: u32,
+}
+
+// reads the next entry header, folding gnu long names and pax paths into it.
+// none at the end of the archive
+fn next_tar_entry(reader: &mut impl Read) -> Result<Option<TarEntry>, String> {
+    let mut long_name: Option<String> = None;
+    let mut long_link: Option<String> = None;
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    loop {
+        if !read_block(reader, &mut header)? || header.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        let size = tar_number(&header[124..136])?;
+        let kind = header[156];
+        match kind {
+            // gnu long names and pax headers describe the next entry
+            b'L' | b'K' | b'x' => {
+                let data = read_entry(reader, size, MAX_TAR_HEADER_SIZE)?;
+                let (path, linkpath) = match kind {
+                    b'L' => (Some(tar_string(&data)), None),
+                    b'K' => (None, Some(tar_string(&data))),
+                    _ => pax_paths(&data),
+                };
+                long_name = path.or(long_name);
+                long_link = linkpath.or(long_link);
+                continue;
+            }
+            b'g' => {
+                skip_entry(reader, size)?;
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = long_name.take().unwrap_or_else(|| tar_name(&header));
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        if !name.is_empty() && name != "." && !is_relative(name) {
+            return Err(format!("tar entry {} escapes the archive", name));
+        }
+        return Ok(Some(TarEntry {
+            name: name.to_string(),
+            link: long_link
+                .take()
+                .unwrap_or_else(|| tar_string(&header[157..257])),
+            kind,
+            size,
+            mode: tar_number(&header[100..108])? as u32,
+        }));
+    }
+}
+
+// reads the regular files of a tar archive, like read_zip. links and special
+// files are skipped
+pub fn read_tar(mut reader: impl Read, max_size: u64) -> Result<Vec<ArchiveEntry>, String> {
+    let mut remaining = max_size;
+    let mut entries = Vec::new();
+    while let Some(entry) = next_tar_entry(&mut reader)? {
+        if !matches!(entry.kind, b'0' | 0 | b'7') || entry.name.is_empty() {
+            skip_entry(&mut reader, entry.size)?;
+            continue;
+        }
+        if entry.size > remaining {
+            return Err(format!(
+                "tar archive expands to more than {} bytes",
+                max_size
+            ));
+        }
+        remaining -= entry.size;
+        let data = read_entry(&mut reader, entry.size, entry.size)?;
+        entries.push(ArchiveEntry {
+            name: entry.name,
+            data,
+            executable: entry.mode & 0o111 != 0,
+        });
+    }
+    Ok(entries)
+}
+
+// packs the regular files and directories under `dir` into a gnu tar
+// archive. symlinks are left out, read on the host they could point anywhere
+pub fn write_tar(dir: &Path, max_size: u64) -> Result<Vec<u8>, String> {
+    let mut archive = Vec::new();
+    let mut remaining = max_size;
+    append_tar_dir(&mut archive, dir, "", &mut remaining, max_size)?;
+    archive.extend_from_slice(&[0; TAR_BLOCK_SIZE * 2]);
+    Ok(archive)
+}
+
+fn append_tar_dir(
+    archive: &mut Vec<u8>,
+    dir: &Path,
+    prefix: &str,
+    remaining: &mut u64,
+    max_size: u64,
+) -> Result<(), String> {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| format!("failed to read {}: {}", dir.display(), e))?
+        .flatten()
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = fs::symlink_metadata(entry.path())
+            .map_err(|e| format!("failed to read {}: {}", name, e))?;
+        let mode = metadata.permissions().mode() & 0o777;
+        if metadata.is_dir() {
+            let name = format!("{}/", name);
+            append_tar_header(archive, &name, b'5', mode, 0);
+            append_tar_dir(archive, &entry.path(), &name, remaining, max_size)?;
+        } else if metadata.is_file() {
+            if metadata.len() > *remaining {
+                return Err(format!("directory is larger than {} bytes", max_size));
+            }
+            *remaining -= metadata.len();
+            let data =
+                fs::read(entry.path()).map_err(|e| format!("failed to read {}: {}", name, e))?;
+            append_tar_header(archive, &name, b'0', mode, data.len() as u64);
+            append_tar_data(archive, &data);
+        }
+    }
+    Ok(())
+}
+
+fn append_tar_header(archive: &mut Vec<u8>, name: &str, kind: u8, mode: u32, size: u64) {
+    if name.len() >= 100 {
+        let mut long_name = name.as_bytes().to_vec();
+        long_name.push(0);
+        append_tar_header(archive, GNU_LONG_NAME, b'L', 0, long_name.len() as u64);
+        append_tar_data(archive, &long_name);
+    }
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    let short_name = &name.as_bytes()[..name.len().min(99)];
+    header[..short_name.len()].copy_from_slice(short_name);
+    header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+    header[108..116].copy_from_slice(b"0000000\0"); // uid
+    header[116..124].copy_from_slice(b"0000000\0"); // gid
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0"); // mtime
+    header[156] = kind;
+    header[257..265].copy_from_slice(GNU_MAGIC);
+    // the checksum is taken with its own field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    archive.extend_from_slice(&header);
+}
+
+fn append_tar_data(archive: &mut Vec<u8>, data: &[u8]) {
+    archive.extend_from_slice(data);
+    let padding = (TAR_BLOCK_SIZE - data.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+    archive.resize(archive.len() + padding, 0);
+}
+
+// zip, gzip compressed tar or plain tar, told apart by their magic bytes
+pub fn read_archive(data: &[u8], max_size: u64) -> Result<Vec<ArchiveEntry>, String> {
+    if data.starts_with(b"PK") {
+        read_zip(data, max_size)
+    } else if data.starts_with(&[0x1f, 0x8b]) {
+        read_tar(GzDecoder::new(data), max_size)
+    } else if data.get(257..262) == Some(b"ustar") {
+        read_tar(data, max_size)
+    } else if data.len() >= TAR_BLOCK_SIZE && data[..TAR_BLOCK_SIZE].iter().all(|b| *b == 0) {
+        // a tar of an empty directory is only the end blocks
+        Ok(Vec::new())
+    } else {
+        Err("unrecognized archive format, expected zip, tar.gz or tar".to_string())
+    }
+}
+
+// unpacks one tar layer of an image over `root`, applying whiteouts. paths,
+// including symlinks met on the way, never resolve outside `root`. ownership,
+// timestamps, setuid bits and device nodes are dropped. returns the bytes
+// written, failing once they would exceed `max_size`
+pub fn unpack_tar_layer(mut reader: impl Read, root: &Path, max_size: u64) -> Result<u64, String> {
+    let mut written = 0;
+    // what this layer put in place, which its own opaque whiteouts keep
+    let mut unpacked = HashSet::new();
+    while let Some(TarEntry {
+        name,
+        link,
+        kind,
+        size,
+        mode,
+    }) = next_tar_entry(&mut reader)?
+    {
+        if name.is_empty() || name == "." {
+            skip_entry(&mut reader, size)?;
+            continue;
+        }
+        let (parent, base) = match name.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, base)) => (parent, base),
+            None => ("", name.trim_end_matches('/')),
+        };
+        // layers needn't have entries for every directory
+        let parent = resolve_in_root(root, parent)?;
+        fs::create_dir_all(&parent)
+            .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+
+        if base == OPAQUE_WHITEOUT {
+            if let Ok(entries) = fs::read_dir(&parent) {
+                for entry in entries.flatten() {
+                    if !unpacked.contains(&entry.path()) {
+                        remove_path(&entry.path())?;
+                    }
+                }
+            }
+            skip_entry(&mut reader, size)?;
+            continue;
+        }
+        if let Some(hidden) = base.strip_prefix(WHITEOUT_PREFIX) {
+            if hidden.is_empty() || hidden == "." || hidden == ".." {
+                return Err(format!("invalid whiteout {}", name));
+            }
+            remove_path(&parent.join(hidden))?;
+            skip_entry(&mut reader, size)?;
+            continue;
+        }
+
+        let target = parent.join(base);
+        unpacked.insert(target.clone());
+        match kind {
+            b'0' | 0 | b'7' => {
+                written += size;
+                if written > max_size {
+                    return Err(format!("image is larger than {} bytes", max_size));
+                }
+                remove_path(&target)?;
+                let mut file = fs::File::create(&target)
+                    .map_err(|e| format!("failed to create {}: {}", name, e))?;
+                let copied = io::copy(&mut (&mut reader).take(size), &mut file)
+                    .map_err(|e| format!("failed to unpack {}: {}", name, e))?;
+                if copied != size {
+                    return Err("truncated tar layer".to_string());
+                }
+                skip_padding(&mut reader, size)?;
+                set_mode(&target, mode & 0o777)?;
+            }
+            b'5' => {
+                // a symlink to a directory is replaced, never followed
+                let is_dir = fs::symlink_metadata(&target).is_ok_and(|m| m.is_dir());
+                if !is_dir {
+                    remove_path(&target)?;
+                    fs::create_dir_all(&target)
+                        .map_err(|e| format!("failed to create {}: {}", name, e))?;
+                }
+                // the owner keeps write access so later layers and eviction can change it
+                set_mode(&target, (mode & 0o777) | 0o700)?;
+                skip_entry(&mut reader, size)?;
+            }
+            b'2' => {
+                // targets are resolved inside the sandbox, where the tree is /
+                remove_path(&target)?;
+                std::os::unix::fs::symlink(&link, &target)
+                    .map_err(|e| format!("failed to create {}: {}", name, e))?;
+                skip_entry(&mut reader, size)?;
+            }
+            b'1' => {
+                let source = resolve_in_root(root, link.trim_start_matches('/'))?;
+                remove_path(&target)?;
+                fs::hard_link(&source, &target)
+                    .map_err(|e| format!("failed to link {} to {}: {}", name, link, e))?;
+                skip_entry(&mut reader, size)?;
+            }
+            // devices and fifos have no place in a sandbox rootfs
+            _ => skip_entry(&mut reader, size)?,
+        }
+    }
+    Ok(written)
+}
+
+// joins `relative` onto `root` following symlinks as if `root` were /, so
+// neither ".." nor absolute link targets can reach outside it
+fn resolve_in_root(root: &Path, relative: &str) -> Result<PathBuf, String> {
+    let mut resolved = root.to_path_buf();
+    let mut pending: Vec<String> = relative
+        .split('/')
+        .rev()
+        .filter(|part| !part.is_empty())
+        .map(String::from)
+        .collect();
+    let mut hops = 0;
+    while let Some(part) = pending.pop() {
+        match part.as_str() {
+            "." => continue,
+            ".." => {
+                if resolved != root {
+                    resolved.pop();
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let next = resolved.join(&part);
+        match fs::read_link(&next) {
+            Ok(link) => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(format!("too many symlinks resolving {}", relative));
+                }
+                let link = link.to_string_lossy().to_string();
+                if link.starts_with('/') {
+                    resolved = root.to_path_buf();
+                }
+                pending.extend(
+                    link.split('/')
+                        .rev()
+                        .filter(|part| !part.is_empty())
+                        .map(String::from),
+                );
+            }
+            Err(_) => resolved = next,
+        }
+    }
+    Ok(resolved)
+}
+
+fn remove_path(path: &Path) -> Result<(), String> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    result.map_err(|e| format!("failed to remove {}: {}", path.display(), e))
+}
+
+fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| format!("failed to set mode of {}: {}", path.display(), e))
+}
+
+// false at the end of the stream, some producers leave out the end blocks
+fn read_block(reader: &mut impl Read, block: &mut [u8; TAR_BLOCK_SIZE]) -> Result<bool, String> {
+    let mut filled = 0;
+    while filled < TAR_BLOCK_SIZE {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err("truncated tar layer".to_string()),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("failed to read tar layer: {}", e)),
+        }
+    }
+    Ok(true)
+}
+
+fn read_entry(reader: &mut impl Read, size: u64, max_size: u64) -> Result<Vec<u8>, String> {
+    if size > max_size {
+        return Err("tar header entry is too large".to_string());
+    }
+    let mut data = vec![0; size as usize];
+    reader
+        .read_exact(&mut data)
+        .map_err(|_| "truncated tar layer".to_string())?;
+    skip_padding(reader, size)?;
+    Ok(data)
+}
+
+fn skip_entry(reader: &mut impl Read, size: u64) -> Result<(), String> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())
+        .map_err(|e| format!("failed to read tar layer: {}", e))?;
+    if skipped != size {
+        return Err("truncated tar layer".to_string());
+    }
+    skip_padding(reader, size)
+}
+
+fn skip_padding(reader: &mut impl Read, size: u64) -> Result<(), String> {
+    let padding = (TAR_BLOCK_SIZE as u64 - size % TAR_BLOCK_SIZE as u64) % TAR_BLOCK_SIZE as u64;
+    let mut block = [0u8; TAR_BLOCK_SIZE];
+    reader
+        .read_exact(&mut block[..padding as usize])
+        .map_err(|_| "truncated tar layer".to_string())
+}
+
+// ustar splits long paths into a prefix and a name, gnu tar keeps other
+// fields where the prefix would be
+fn tar_name(header: &[u8; TAR_BLOCK_SIZE]) -> String {
+    let name = tar_string(&header[0..100]);
+    let prefix = if header[257..263] == *USTAR_MAGIC {
+        tar_string(&header[345..500])
+    } else {
+        String::new()
+    };
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+// octal, or big-endian base-256 when the high bit of the first byte is set
+fn tar_number(field: &[u8]) -> Result<u64, String> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |n, b| (n << 8) | u64::from(*b)));
+    }
+    let digits = tar_string(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| "corrupt tar header".to_string())
+}
+
+// pax records are "<length> <key>=<value>\n"
+fn pax_paths(data: &[u8]) -> (Option<String>, Option<String>) {
+    let mut path = None;
+    let mut linkpath = None;
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let Some(length) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|length| *length > space && *length <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..length]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            match key {
+                "path" => path = Some(value.to_string()),
+                "linkpath" => linkpath = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        rest = &rest[length..];
+    }
+    (path, linkpath)
+}
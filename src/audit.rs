@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::types::SyscallViolation;
+
+// audit record type emitted by the kernel for seccomp actions that log
+const AUDIT_SECCOMP: &str = "type=1326";
+
+// collects seccomp audit records the kernel writes to /dev/kmsg for processes
+// inside a sandbox's pid namespace. best effort: records are attributed by the
+// pid namespace of the offending process, so one that exits before its record
+// is read is missed
+pub struct SyscallAudit {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<BTreeMap<String, u64>>>,
+}
+
+// opens the kernel log at its end, call before spawning so no record is missed
+pub fn open_kmsg() -> Result<fs::File, String> {
+    let mut kmsg = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")
+        .map_err(|e| format!("failed to open /dev/kmsg: {}", e))?;
+    kmsg.seek(SeekFrom::End(0))
+        .map_err(|e| format!("failed to seek /dev/kmsg: {}", e))?;
+    Ok(kmsg)
+}
+
+impl SyscallAudit {
+    // watches kmsg for records of processes created by the container process `pid`
+    pub fn start(mut kmsg: fs::File, pid: u32) -> Result<Self, String> {
+        let namespace = fs::read_link(format!("/proc/{}/ns/pid_for_children", pid))
+            .map_err(|e| format!("failed to resolve sandbox pid namespace: {}", e))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = std::thread::spawn(move || {
+            let mut violations = BTreeMap::new();
+            // each read returns exactly one record
+            let mut record = vec![0u8; 8192];
+            loop {
+                match kmsg.read(&mut record) {
+                    Ok(n) => {
+                        let line = String::from_utf8_lossy(&record[..n]);
+                        if let Some((pid, syscall)) = parse_record(&line) {
+                            let same_namespace = fs::read_link(format!("/proc/{}/ns/pid", pid))
+                                .map(|ns| ns == namespace)
+                                .unwrap_or(false);
+                            if same_namespace {
+                                *violations.entry(syscall_name(syscall)).or_insert(0) += 1;
+                            }
+                        }
+                    }
+                    // records overwritten before we read them
+                    Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        if stopped.load(Ordering::Acquire) {
+                            break;
+                        }
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => {
+                        tracing::warn!("failed to read /dev/kmsg: {}", e);
+                        break;
+                    }
+                }
+            }
+            violations
+        });
+
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    // drains the remaining records and returns the violations seen
+    pub fn finish(mut self) -> Vec<SyscallViolation> {
+        self.stop.store(true, Ordering::Release);
+        let violations = self
+            .handle
+            .take()
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+        violations
+            .into_iter()
+            .map(|(syscall, count)| SyscallViolation { syscall, count })
+            .collect()
+    }
+}
+
+// an execution that ends without finish, by panicking, still stops the reader
+impl Drop for SyscallAudit {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+// extracts pid and syscall number from a seccomp audit record
+fn parse_record(line: &str) -> Option<(u32, i64)> {
+    if !line.contains(AUDIT_SECCOMP) {
+        return None;
+    }
+
+    let mut pid = None;
+    let mut syscall = None;
+    for field in line.split_whitespace() {
+        if let Some(value) = field.strip_prefix("pid=") {
+            pid = value.parse().ok();
+        } else if let Some(value) = field.strip_prefix("syscall=") {
+            syscall = value.parse().ok();
+        }
+    }
+
+    Some((pid?, syscall?))
+}
+
+fn syscall_name(nr: i64) -> String {
+    let name = match nr {
+        libc::SYS_mount => "mount",
+        libc::SYS_umount2 => "umount2",
+        libc::SYS_reboot => "reboot",
+        libc::SYS_socket => "socket",
+        libc::SYS_bind => "bind",
+        libc::SYS_connect => "connect",
+        libc::SYS_listen => "listen",
+        libc::SYS_sendto => "sendto",
+        libc::SYS_recvfrom => "recvfrom",
+        _ => return format!("syscall_{}", nr),
+    };
+    name.to_string()
+}
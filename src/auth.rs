@@ -0,0 +1,313 @@
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::{HeaderMap, Request, StatusCode, header::AUTHORIZATION, request::Parts};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::client_ip::Peer;
+use crate::types::{AppConfig, AppState, JwtConfig};
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+const MAX_JWKS_SIZE: usize = 1024 * 1024;
+
+// what a caller may do, checked per route. api keys hold every scope but
+// admin, tokens hold the ones in their scope (or scp) claim and client
+// certificates the ones configured for their common name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Execute,    // run code: /execute, /judge, /jobs, /cron and reading problems
+    FilesWrite, // import and delete problems
+    Admin,      // the /admin routes
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Execute => "execute",
+            Scope::FilesWrite => "files:write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+type AuthError = (StatusCode, String);
+
+// what a request can authenticate with
+pub struct Credentials {
+    pub headers: HeaderMap,
+    pub client_certificate: Option<String>, // common name of the verified client certificate
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Credentials {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let client_certificate = parts
+            .extensions
+            .get::<ConnectInfo<Peer>>()
+            .and_then(|ConnectInfo(peer)| peer.client_certificate.clone());
+        Ok(Self {
+            headers: parts.headers.clone(),
+            client_certificate,
+        })
+    }
+}
+
+// resolves the tenant a request belongs to and checks it may use `scope`.
+// the service is open (None) when no tenants, jwt or tls clients are
+// configured
+pub fn authenticate(
+    state: &AppState,
+    credentials: &Credentials,
+    scope: Scope,
+) -> Result<Option<String>, AuthError> {
+    let headers = &credentials.headers;
+    // a certificate identifies the machine on the other end, whatever the
+    // request itself carries
+    if let Some(common_name) = &credentials.client_certificate
+        && let Some(tls) = &state.config.tls
+        && !tls.clients.is_empty()
+    {
+        let client = tls.clients.get(common_name).ok_or_else(|| {
+            (
+                StatusCode::FORBIDDEN,
+                format!("client certificate {} is not authorized", common_name),
+            )
+        })?;
+        if !client
+            .scopes
+            .iter()
+            .any(|granted| granted == scope.as_str())
+        {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!(
+                    "client certificate {} lacks the {} scope",
+                    common_name,
+                    scope.as_str()
+                ),
+            ));
+        }
+        return Ok(client.tenant.clone());
+    }
+    if let Some(jwt) = &state.jwt
+        && let Some(token) = bearer(headers)
+    {
+        return jwt.authenticate(&state.config, token, scope);
+    }
+    if state.config.tenants.is_empty() && state.jwt.is_none() && !has_tls_clients(state) {
+        return Ok(None);
+    }
+    if scope == Scope::Admin {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "api keys can't use admin routes".to_string(),
+        ));
+    }
+
+    let key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or((
+            StatusCode::UNAUTHORIZED,
+            "missing api key or token".to_string(),
+        ))?;
+    state
+        .config
+        .tenants
+        .iter()
+        .find(|(_, tenant)| secrets_match(key, &tenant.api_key))
+        .map(|(name, _)| Some(name.clone()))
+        .ok_or((StatusCode::UNAUTHORIZED, "invalid api key".to_string()))
+}
+
+pub fn has_tls_clients(state: &AppState) -> bool {
+    state
+        .config
+        .tls
+        .as_ref()
+        .is_some_and(|tls| !tls.clients.is_empty())
+}
+
+// digests are compared so the time taken says nothing about the secret
+pub fn secrets_match(given: &str, secret: &str) -> bool {
+    Sha256::digest(given.as_bytes()) == Sha256::digest(secret.as_bytes())
+}
+
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// checks tokens signed with the shared secret (HS*) or a key of the identity
+// provider's key set (RS*, PS*, ES*, EdDSA), refreshed in the background
+pub struct JwtVerifier {
+    config: JwtConfig,
+    secret: Option<DecodingKey>,
+    keys: RwLock<Vec<(Option<String>, DecodingKey)>>, // key id -> key
+}
+
+impl JwtVerifier {
+    // fetches the key set once before serving, failing to is only logged so
+    // a provider outage doesn't keep the service down
+    pub async fn start(config: &JwtConfig) -> Arc<Self> {
+        let verifier = Arc::new(Self {
+            config: config.clone(),
+            secret: config
+                .secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            keys: RwLock::new(Vec::new()),
+        });
+        if let Some(url) = &config.jwks_url {
+            verifier.refresh(url).await;
+            let interval = Duration::from_secs(config.jwks_refresh_secs.max(1));
+            let (verifier, url) = (verifier.clone(), url.clone());
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    verifier.refresh(&url).await;
+                }
+            });
+        }
+        verifier
+    }
+
+    async fn refresh(&self, url: &str) {
+        match fetch_jwks(url).await {
+            Ok(keys) => {
+                tracing::debug!(keys = keys.len(), "refreshed jwt signing keys");
+                *self.keys.write().unwrap() = keys;
+            }
+            Err(e) => tracing::warn!("failed to fetch jwt signing keys: {}", e),
+        }
+    }
+
+    fn authenticate(
+        &self,
+        config: &AppConfig,
+        token: &str,
+        scope: Scope,
+    ) -> Result<Option<String>, AuthError> {
+        let invalid = |message: String| (StatusCode::UNAUTHORIZED, message);
+        let header = decode_header(token).map_err(|e| invalid(format!("invalid token: {}", e)))?;
+
+        // the algorithm is the token's, but the key has to be of its family,
+        // so a public key can never be used as an hmac secret
+        let mut validation = Validation::new(header.alg);
+        validation.validate_aud = self.config.audience.is_some();
+        if let Some(audience) = &self.config.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = self
+                    .secret
+                    .as_ref()
+                    .ok_or_else(|| invalid("hmac signed tokens are not accepted".to_string()))?;
+                decode::<Map<String, Value>>(token, secret, &validation)
+            }
+            _ => {
+                let keys = self.keys.read().unwrap();
+                let key = keys
+                    .iter()
+                    .find(|(id, _)| header.kid.is_none() || *id == header.kid)
+                    .map(|(_, key)| key)
+                    .ok_or_else(|| invalid("token signed with an unknown key".to_string()))?;
+                decode::<Map<String, Value>>(token, key, &validation)
+            }
+        }
+        .map_err(|e| invalid(format!("invalid token: {}", e)))?
+        .claims;
+
+        if !scopes(&claims).any(|granted| granted == scope.as_str()) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("token lacks the {} scope", scope.as_str()),
+            ));
+        }
+        // tokens without a tenant run without quotas, like an open service,
+        // which is only one while no tenants are configured
+        match claims.get(&self.config.tenant_claim) {
+            None if config.tenants.is_empty() => Ok(None),
+            None => Err((
+                StatusCode::FORBIDDEN,
+                format!("token lacks the {} claim", self.config.tenant_claim),
+            )),
+            Some(Value::String(tenant)) if config.tenants.contains_key(tenant) => {
+                Ok(Some(tenant.clone()))
+            }
+            Some(tenant) => Err((StatusCode::FORBIDDEN, format!("unknown tenant {}", tenant))),
+        }
+    }
+}
+
+// "scope" is a space separated string (rfc 8693), "scp" is used as a list too
+fn scopes(claims: &Map<String, Value>) -> impl Iterator<Item = &str> {
+    ["scope", "scp"]
+        .into_iter()
+        .filter_map(|claim| claims.get(claim))
+        .flat_map(|value| match value {
+            Value::String(scopes) => scopes.split_whitespace().collect(),
+            Value::Array(scopes) => scopes.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        })
+}
+
+// keys the provider publishes that jsonwebtoken can't use are skipped, and
+// symmetric ones are never trusted, the key set is public
+async fn fetch_jwks(url: &str) -> Result<Vec<(Option<String>, DecodingKey)>, String> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .map_err(|e| format!("failed to load root certificates: {}", e))?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_, Empty<Bytes>> = Client::builder(TokioExecutor::new()).build(connector);
+    let request = Request::get(url)
+        .body(Empty::new())
+        .map_err(|e| format!("invalid jwks url {}: {}", url, e))?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|e| format!("failed to reach {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", url, response.status()));
+    }
+    let body = Limited::new(response.into_body(), MAX_JWKS_SIZE)
+        .collect()
+        .await
+        .map_err(|e| format!("failed to read {}: {}", url, e))?
+        .to_bytes();
+
+    let set: Value =
+        serde_json::from_slice(&body).map_err(|e| format!("invalid key set: {}", e))?;
+    let keys = set
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or("key set without keys")?
+        .iter()
+        .filter_map(|key| serde_json::from_value::<Jwk>(key.clone()).ok())
+        .filter(|jwk| !matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)))
+        .filter_map(|jwk| {
+            let key = DecodingKey::from_jwk(&jwk).ok()?;
+            Some((jwk.common.key_id, key))
+        })
+        .collect();
+    Ok(keys)
+}
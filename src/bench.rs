@@ -0,0 +1,203 @@
+use axum::body::Bytes;
+use axum::http::{Request, header::CONTENT_TYPE};
+use clap::Args;
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use hyper_util::rt::TokioExecutor;
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::cli;
+use crate::handlers::run::execute_code_inner;
+use crate::types::{AppState, ExecutionRequest, RunEvent};
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Instance to send requests to, e.g. http://127.0.0.1:3000. Without it
+    /// requests run in this process
+    #[arg(long)]
+    pub url: Option<String>,
+    /// API key the requests carry in x-api-key
+    #[arg(long)]
+    pub api_key: Option<String>,
+    /// /execute request body to send, - reads stdin. A run of /bin/true by default
+    #[arg(long, value_name = "PATH")]
+    pub request: Option<String>,
+    /// Requests to send in total
+    #[arg(long, default_value_t = 100)]
+    pub requests: u32,
+    /// Requests in flight at once
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: u32,
+}
+
+enum Target {
+    Remote {
+        client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+        url: String,
+        api_key: Option<String>,
+        body: Bytes,
+    },
+    Local {
+        state: AppState,
+        request: ExecutionRequest,
+    },
+}
+
+impl Target {
+    // whether every outcome of one request was a result
+    async fn send(&self) -> bool {
+        match self {
+            Target::Remote {
+                client,
+                url,
+                api_key,
+                body,
+            } => {
+                let mut request = Request::post(url).header(CONTENT_TYPE, "application/json");
+                if let Some(key) = api_key {
+                    request = request.header("x-api-key", key);
+                }
+                let Ok(request) = request.body(Full::new(body.clone())) else {
+                    return false;
+                };
+                let Ok(response) = client.request(request).await else {
+                    return false;
+                };
+                if !response.status().is_success() {
+                    return false;
+                }
+                // the stream ends after the done event
+                match response.into_body().collect().await {
+                    Ok(body) => !String::from_utf8_lossy(&body.to_bytes())
+                        .lines()
+                        .any(|line| line == "event: error"),
+                    Err(_) => false,
+                }
+            }
+            Target::Local { state, request } => {
+                let (tx, mut rx) = mpsc::channel(100);
+                let run = tokio::spawn(execute_code_inner(
+                    state.clone(),
+                    request.clone(),
+                    tx,
+                    None,
+                    None,
+                ));
+                let mut ok = true;
+                while let Some(event) = rx.recv().await {
+                    if let RunEvent::Outcome(data) = event {
+                        ok &= data.is_ok();
+                    }
+                }
+                run.await.is_ok() && ok
+            }
+        }
+    }
+}
+
+// benchmarks the instance at args.url, which needs no configuration of its own
+pub async fn remote(url: &str, args: &BenchArgs) -> i32 {
+    let body = match &args.request {
+        Some(path) => match cli::read_body(path) {
+            Ok(body) => Bytes::from(body),
+            Err(e) => {
+                eprintln!("pentagon: {}", e);
+                return 2;
+            }
+        },
+        None => Bytes::from(synthetic_request().to_string()),
+    };
+    let connector = match HttpsConnectorBuilder::new().with_native_roots() {
+        Ok(builder) => builder.https_or_http().enable_http1().build(),
+        Err(e) => {
+            eprintln!("pentagon: failed to load root certificates: {}", e);
+            return 2;
+        }
+    };
+    let target = Target::Remote {
+        client: Client::builder(TokioExecutor::new()).build(connector),
+        url: format!("{}/execute", url.trim_end_matches('/')),
+        api_key: args.api_key.clone(),
+        body,
+    };
+    run(target, args).await
+}
+
+// benchmarks the worker of this process, like `run` does for one request
+pub async fn local(state: AppState, args: &BenchArgs) -> i32 {
+    let request = match &args.request {
+        Some(path) => cli::read_request(&state, path),
+        None => serde_json::from_value(synthetic_request()).map_err(|e| e.to_string()),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("pentagon: {}", e);
+            return 2;
+        }
+    };
+    run(Target::Local { state, request }, args).await
+}
+
+fn synthetic_request() -> serde_json::Value {
+    json!({ "files": [], "executions": [{ "program": "/bin/true" }] })
+}
+
+// sends args.requests requests, args.concurrency at a time, and prints the
+// throughput and latency percentiles. fails when a request did
+async fn run(target: Target, args: &BenchArgs) -> i32 {
+    let target = Arc::new(target);
+    let next = Arc::new(AtomicU32::new(0));
+    let total = args.requests;
+    let start = Instant::now();
+    let clients: Vec<_> = (0..args.concurrency.clamp(1, total.max(1)))
+        .map(|_| {
+            let target = target.clone();
+            let next = next.clone();
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                while next.fetch_add(1, Ordering::Relaxed) < total {
+                    let sent = Instant::now();
+                    let ok = target.send().await;
+                    samples.push((sent.elapsed(), ok));
+                }
+                samples
+            })
+        })
+        .collect();
+    let mut samples = Vec::new();
+    for client in clients {
+        samples.extend(client.await.unwrap_or_default());
+    }
+    let elapsed = start.elapsed();
+
+    let failed = samples.iter().filter(|(_, ok)| !ok).count();
+    let mut latencies: Vec<Duration> = samples.iter().map(|(latency, _)| *latency).collect();
+    latencies.sort();
+    let percentile = |p: f64| {
+        let rank = ((p * latencies.len() as f64).ceil() as usize).max(1);
+        latencies
+            .get(rank - 1)
+            .map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+    };
+    println!("requests:    {} ({} failed)", samples.len(), failed);
+    println!("concurrency: {}", args.concurrency);
+    println!("duration:    {:.2} s", elapsed.as_secs_f64());
+    println!(
+        "throughput:  {:.2} requests/s",
+        samples.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency:     p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+    i32::from(failed > 0)
+}
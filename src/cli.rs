@@ -0,0 +1,109 @@
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use std::fs;
+use std::io::{self, Read};
+use tokio::sync::mpsc;
+
+use crate::bench::BenchArgs;
+use crate::handlers::run::{event_data, execute_code_inner};
+use crate::templates;
+use crate::types::{AppState, ExecutionRequest, RunEvent};
+
+// flags take precedence over APP_ variables, which take precedence over the
+// settings file
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Runs untrusted programs in sandboxes over an HTTP API"
+)]
+pub struct Cli {
+    /// Settings file, with or without its extension
+    #[arg(long, global = true, value_name = "PATH", default_value = "Settings")]
+    pub config: String,
+    /// Port to listen on, overrides `port`
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Address to listen on, overrides `host`
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub bind: Option<String>,
+    /// error, warn, info, debug or trace, overrides `log_level`
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Serve the API in the configured mode (the default)
+    Server,
+    /// Serve the API and run requests queued by coordinators, overrides `mode`
+    Runner,
+    /// Run one /execute request body and print its outcomes as JSON lines
+    Run {
+        /// File holding the request, - reads stdin
+        #[arg(default_value = "-")]
+        request: String,
+    },
+    /// Send concurrent requests to an instance, or this process, and report
+    /// throughput and latency
+    Bench(BenchArgs),
+}
+
+impl Cli {
+    pub fn mode(&self) -> Option<&'static str> {
+        match self.command {
+            Some(Command::Runner) => Some("runner"),
+            _ => None,
+        }
+    }
+}
+
+// runs the request in this process, whatever the configured mode, and
+// returns the exit status: 0 when every outcome was a result
+pub async fn run(state: AppState, path: &str) -> i32 {
+    let request = match read_request(&state, path) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("pentagon: {}", e);
+            return 2;
+        }
+    };
+
+    let (tx, mut rx) = mpsc::channel(100);
+    let run = tokio::spawn(execute_code_inner(state, request, tx, None, None));
+    let mut failed = false;
+    while let Some(event) = rx.recv().await {
+        // shaped like the messages of a websocket batch
+        let line = match event {
+            RunEvent::Progress(progress) => json!({ "progress": progress }).to_string(),
+            RunEvent::Outcome(data) => {
+                failed |= data.is_err();
+                event_data(&data)
+            }
+        };
+        println!("{}", line);
+    }
+    let _ = run.await;
+    i32::from(failed)
+}
+
+// a request file, - being stdin
+pub fn read_body(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut body = Vec::new();
+        io::stdin()
+            .read_to_end(&mut body)
+            .map_err(|e| format!("can't read stdin: {}", e))?;
+        Ok(body)
+    } else {
+        fs::read(path).map_err(|e| format!("can't read {}: {}", path, e))
+    }
+}
+
+// a request file with its language and template applied, as /execute does
+pub fn read_request(state: &AppState, path: &str) -> Result<ExecutionRequest, String> {
+    let body = read_body(path)?;
+    let request = serde_json::from_slice(&body).map_err(|e| format!("invalid request: {}", e))?;
+    templates::expand(&state.config, request)
+}
@@ -0,0 +1,140 @@
+use axum::{
+    extract::{ConnectInfo, Request, State, connect_info::Connected},
+    http::{HeaderMap, header::FORWARDED},
+    middleware::Next,
+    response::Response,
+    serve::IncomingStream,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpListener;
+
+use crate::types::{AppConfig, AppState};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// the other end of a connection, and on tls listeners with client_ca set the
+// common name of its verified client certificate
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub client_certificate: Option<String>,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self {
+            addr: *stream.remote_addr(),
+            client_certificate: None,
+        }
+    }
+}
+
+// the address a request came from, past any trusted proxies. set on every
+// request as an extension
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+// proxies whose forwarding headers are believed, from trusted_proxies
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &AppConfig) -> Result<Self, String> {
+        let nets = config
+            .trusted_proxies
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("{:?} is not an address or CIDR range", entry))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { nets })
+    }
+
+    fn trusts(&self, addr: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(&addr))
+    }
+
+    // walks the forwarding chain from the nearest hop back, the first address
+    // that isn't a trusted proxy is the client. only trusted proxies get to
+    // add hops, anything further left could have been sent by the client
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.trusts(client) {
+            return client;
+        }
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            // "unknown" and obfuscated identifiers end the chain at the proxy
+            // that reported them
+            let Some(addr) = hop else {
+                break;
+            };
+            client = addr;
+            if !self.trusts(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// the for= addresses of the standard Forwarded header, or X-Forwarded-For when
+// there is none, in the order the hops were added. headers may repeat
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| parse_node(value))
+                })?
+            })
+            .collect();
+    }
+    values(X_FORWARDED_FOR)
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+// 192.0.2.1, "192.0.2.1:4711", "[2001:db8::1]" or "[2001:db8::1]:4711"
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(addr) = node.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?
+        .strip_suffix(']')?
+        .parse::<IpAddr>()
+        .ok()
+}
+
+pub async fn client_ip(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = state
+        .trusted_proxies
+        .resolve(peer.addr.ip(), request.headers());
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// open websocket connections per client, each holds a worker and its sandbox
+// directory for as long as it stays open
+pub struct ConnectionLimiter {
+    max_per_client: usize,
+    clients: Mutex<HashMap<String, usize>>,
+}
+
+// frees the client's slot when the connection ends
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    client: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(count) = clients.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                clients.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_client: usize) -> Self {
+        Self {
+            max_per_client,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // none when the client already has as many connections as it may
+    pub fn acquire(self: &Arc<Self>, client: String) -> Option<ConnectionSlot> {
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.entry(client.clone()).or_default();
+        if *count >= self.max_per_client {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            client,
+        })
+    }
+}
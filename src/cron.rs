@@ -0,0 +1,274 @@
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+
+use crate::jobs::{self, Job, JobStatus};
+use crate::redis_client;
+use crate::types::{AppState, ExecutionRequest};
+use crate::utils::gen_random_id;
+
+// how far ahead a schedule is searched for its next run, "0 0 29 2 1" can
+// take years to come around
+const MAX_LOOKAHEAD_MINUTES: u64 = 60 * 24 * 366 * 8;
+
+const MINUTES_PER_DAY: u64 = 60 * 24;
+
+// a standard five field cron expression (minute hour day-of-month month
+// day-of-week), evaluated in UTC. each field is a bitmask of allowed values
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,     // day-of-month was `*`
+    any_weekday: bool, // day-of-week was `*`
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "cron expression must have 5 fields, got {}",
+                fields.len()
+            ));
+        };
+
+        // 7 is another name for sunday
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    // the first matching minute strictly after `time` (unix seconds), none if
+    // the schedule never fires
+    pub fn next_after(&self, time: u64) -> Option<u64> {
+        let mut minute = time / 60 + 1;
+        let limit = minute + MAX_LOOKAHEAD_MINUTES;
+        while minute < limit {
+            let day = minute / MINUTES_PER_DAY;
+            if !self.matches_day(day) {
+                minute = (day + 1) * MINUTES_PER_DAY;
+                continue;
+            }
+            if self.hours & (1 << (minute / 60 % 24)) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) != 0 {
+                return Some(minute * 60);
+            }
+            minute += 1;
+        }
+        None
+    }
+
+    // like cron, a day matches either day field when both are restricted
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = month_and_day(day);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        // 1970-01-01 was a thursday
+        let weekday = (day + 4) % 7;
+        let day_matches = self.days & (1 << day_of_month) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        if self.any_day || self.any_weekday {
+            day_matches && weekday_matches
+        } else {
+            day_matches || weekday_matches
+        }
+    }
+}
+
+// accepts `*`, `n`, `a-b` and a `/step` on any of them, comma separated
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field {}", field);
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u64>().map_err(|_| invalid())?)),
+            None => (part, None),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse().map_err(|_| invalid())?,
+                end.parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let start = range.parse().map_err(|_| invalid())?;
+            // `5/15` means every 15 starting at 5
+            (start, if step.is_some() { max } else { start })
+        };
+        if start < min || end > max || start > end || step == Some(0) {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+// civil month and day of the month for days since the unix epoch
+fn month_and_day(days: u64) -> (u64, u64) {
+    // shifted so the year starts in march and leap days fall at its end
+    let days = days + 719_468;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (month, day)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CronJob {
+    pub id: String,
+    pub tenant: Option<String>,
+    pub schedule: String,
+    pub request: ExecutionRequest, // submitted as a new job on every run
+    pub next_run: u64,             // unix seconds
+    pub last_job: Option<String>,  // id of the job started by the latest run
+}
+
+fn cron_key(id: &str) -> String {
+    redis_client::key(&format!("cron:{}", id))
+}
+
+// ids of recurring jobs, scored by the unix time of their next run
+fn schedule_key() -> String {
+    redis_client::key("cron:scheduled")
+}
+
+pub async fn save(connection: &mut MultiplexedConnection, cron: &CronJob) -> Result<(), String> {
+    let record = serde_json::to_string(cron).unwrap();
+    redis::pipe()
+        .set(cron_key(&cron.id), record)
+        .ignore()
+        .zadd(schedule_key(), &cron.id, cron.next_run)
+        .ignore()
+        .query_async::<()>(connection)
+        .await
+        .map_err(|e| format!("failed to save recurring job: {}", e))
+}
+
+pub async fn get(
+    connection: &mut MultiplexedConnection,
+    id: &str,
+) -> Result<Option<CronJob>, String> {
+    let stored: Option<String> = connection
+        .get(cron_key(id))
+        .await
+        .map_err(|e| format!("failed to read recurring job: {}", e))?;
+    stored
+        .map(|stored| {
+            serde_json::from_str(&stored).map_err(|e| format!("corrupt recurring job: {}", e))
+        })
+        .transpose()
+}
+
+pub async fn list(connection: &mut MultiplexedConnection) -> Result<Vec<CronJob>, String> {
+    let ids: Vec<String> = connection
+        .zrange(schedule_key(), 0, -1)
+        .await
+        .map_err(|e| format!("failed to list recurring jobs: {}", e))?;
+    let mut crons = Vec::new();
+    for id in ids {
+        if let Some(cron) = get(connection, &id).await? {
+            crons.push(cron);
+        }
+    }
+    Ok(crons)
+}
+
+pub async fn delete(connection: &mut MultiplexedConnection, id: &str) -> Result<(), String> {
+    redis::pipe()
+        .del(cron_key(id))
+        .ignore()
+        .zrem(schedule_key(), id)
+        .ignore()
+        .query_async::<()>(connection)
+        .await
+        .map_err(|e| format!("failed to delete recurring job: {}", e))
+}
+
+// submits a job for every recurring job that is due. runs missed while no
+// instance was up are not made up, the next run is always in the future
+pub async fn enqueue_due(state: &AppState) -> Result<(), String> {
+    let mut connection = state.redis()?;
+    let now = jobs::unix_now();
+    let due: Vec<String> = connection
+        .zrangebyscore(schedule_key(), "-inf", now)
+        .await
+        .map_err(|e| format!("failed to read recurring schedule: {}", e))?;
+
+    for id in due {
+        // the same claim as for jobs: only the instance that removes the id
+        // submits the run, and it puts the id back with the next run time
+        let claimed: i64 = connection
+            .zrem(schedule_key(), &id)
+            .await
+            .map_err(|e| format!("failed to claim recurring job: {}", e))?;
+        if claimed == 0 {
+            continue;
+        }
+        let Some(mut cron) = get(&mut connection, &id).await? else {
+            continue;
+        };
+
+        let job = Job {
+            id: gen_random_id(16),
+            tenant: cron.tenant.clone(),
+            run_at: now,
+            status: JobStatus::Scheduled,
+            request: Some(cron.request.clone()),
+            results: Vec::new(),
+            webhook: None,
+        };
+        jobs::schedule(&mut connection, &job).await?;
+        tracing::info!(id, job = job.id, "recurring job submitted");
+
+        cron.last_job = Some(job.id);
+        let next_run = Schedule::parse(&cron.schedule)
+            .ok()
+            .and_then(|schedule| schedule.next_after(now));
+        match next_run {
+            Some(next_run) => {
+                cron.next_run = next_run;
+                save(&mut connection, &cron).await?;
+            }
+            None => {
+                tracing::warn!(id, "recurring job has no further runs, removing it");
+                delete(&mut connection, &id).await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+
+use crate::jobs::{self, Job, JobStatus};
+use crate::redis_client;
+use crate::types::ExecutionRequest;
+use crate::utils::gen_random_id;
+
+// newest letters kept, older ones are dropped as new ones come in
+const MAX_LETTERS: isize = 1000;
+// serialized bytes of a request kept with its letter, larger ones are
+// dropped so the letters stay within MAX_LETTERS times this
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+// a request that kept failing on the host after infrastructure_retries,
+// kept so an operator can see why and run it again
+#[derive(Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>, // the job it ran for, none for requests a coordinator queued
+    pub tenant: Option<String>,
+    pub failed_at: u64,      // unix seconds
+    pub errors: Vec<String>, // of every attempt, oldest first
+    pub request_size: usize, // serialized bytes of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<ExecutionRequest>, // none above MAX_REQUEST_SIZE
+}
+
+impl DeadLetter {
+    pub fn new(
+        job: Option<String>,
+        tenant: Option<String>,
+        request: ExecutionRequest,
+        errors: Vec<String>,
+    ) -> Self {
+        let request_size = serde_json::to_vec(&request).map_or(0, |bytes| bytes.len());
+        Self {
+            id: gen_random_id(16),
+            job,
+            tenant,
+            failed_at: jobs::unix_now(),
+            errors,
+            request_size,
+            request: (request_size <= MAX_REQUEST_SIZE).then_some(request),
+        }
+    }
+}
+
+// ids of the kept letters, scored by failed_at
+fn index_key() -> String {
+    redis_client::key("dead:letters")
+}
+
+fn letter_key(id: &str) -> String {
+    redis_client::key(&format!("dead:letter:{}", id))
+}
+
+// keeps `letter` for `ttl` seconds, dropping the oldest ones past MAX_LETTERS
+pub async fn record(connection: &mut MultiplexedConnection, letter: &DeadLetter, ttl: u64) {
+    let recorded = redis::pipe()
+        .atomic()
+        .set_ex(
+            letter_key(&letter.id),
+            serde_json::to_string(letter).unwrap(),
+            ttl,
+        )
+        .ignore()
+        .zadd(index_key(), &letter.id, letter.failed_at)
+        .ignore()
+        // their letters expired on their own
+        .zrembyscore(index_key(), "-inf", letter.failed_at.saturating_sub(ttl))
+        .ignore()
+        .expire(index_key(), ttl as i64)
+        .ignore()
+        .query_async::<()>(connection)
+        .await;
+    match recorded {
+        Ok(()) => tracing::warn!(id = letter.id, job = letter.job, "dead-lettered request"),
+        Err(e) => tracing::error!("failed to dead-letter request: {}", e),
+    }
+
+    let dropped: Vec<String> = connection
+        .zrange(index_key(), 0, -MAX_LETTERS - 1)
+        .await
+        .unwrap_or_default();
+    if !dropped.is_empty() {
+        let keys: Vec<String> = dropped.iter().map(|id| letter_key(id)).collect();
+        let trimmed = redis::pipe()
+            .del(keys)
+            .ignore()
+            .zrem(index_key(), dropped)
+            .ignore()
+            .query_async::<()>(connection)
+            .await;
+        if let Err(e) = trimmed {
+            tracing::error!("failed to drop old dead letters: {}", e);
+        }
+    }
+}
+
+// newest first
+pub async fn list(connection: &mut MultiplexedConnection) -> Result<Vec<DeadLetter>, String> {
+    let ids: Vec<String> = connection
+        .zrevrange(index_key(), 0, -1)
+        .await
+        .map_err(|e| format!("failed to read dead letters: {}", e))?;
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<String> = ids.iter().map(|id| letter_key(id)).collect();
+    let letters: Vec<Option<String>> =
+        redis::cmd("MGET")
+            .arg(keys)
+            .query_async(connection)
+            .await
+            .map_err(|e| format!("failed to read dead letters: {}", e))?;
+    Ok(letters
+        .iter()
+        .flatten()
+        .filter_map(|letter| serde_json::from_str(letter).ok())
+        .collect())
+}
+
+pub enum Requeue {
+    Missing,
+    Dropped, // its request was too large to keep, the letter stays
+    Scheduled(Box<Job>),
+}
+
+// runs letter `id` again as a job: the one it ran for while that is still
+// kept, a new one otherwise. the letter is removed, missing when it is
+// unknown or another request took it first
+pub async fn requeue(
+    connection: &mut MultiplexedConnection,
+    id: &str,
+    ttl: u64,
+) -> Result<Requeue, String> {
+    let stored: Option<String> = connection
+        .get(letter_key(id))
+        .await
+        .map_err(|e| format!("failed to read dead letter: {}", e))?;
+    let Some(letter) = stored.and_then(|stored| serde_json::from_str::<DeadLetter>(&stored).ok())
+    else {
+        return Ok(Requeue::Missing);
+    };
+    let Some(request) = letter.request.clone() else {
+        return Ok(Requeue::Dropped);
+    };
+    let (taken, _): (i64, i64) = redis::pipe()
+        .del(letter_key(id))
+        .zrem(index_key(), id)
+        .query_async(connection)
+        .await
+        .map_err(|e| format!("failed to take dead letter: {}", e))?;
+    if taken == 0 {
+        return Ok(Requeue::Missing);
+    }
+
+    let existing = match &letter.job {
+        Some(job) => jobs::get(connection, job).await?,
+        None => None,
+    };
+    let job = Job {
+        id: existing
+            .as_ref()
+            .map_or_else(|| gen_random_id(16), |job| job.id.clone()),
+        tenant: letter.tenant.clone(),
+        run_at: jobs::unix_now(),
+        status: JobStatus::Scheduled,
+        request: Some(request),
+        results: Vec::new(),
+        webhook: existing.and_then(|job| job.webhook),
+    };
+    if let Err(e) = jobs::schedule(connection, &job).await {
+        // put back, so it isn't lost with the job
+        record(connection, &letter, ttl).await;
+        return Err(e);
+    }
+    Ok(Requeue::Scheduled(Box::new(job)))
+}
@@ -0,0 +1,220 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::process::Command;
+
+use crate::types::{EncryptionConfig, EncryptionKey};
+
+// starts every encrypted blob, anything else is read as plaintext
+pub const MAGIC: &[u8] = b"PGENC\x00\x01\x00";
+
+// plaintext bytes per record, each record is sealed on its own so blobs can
+// be written and read in pieces
+pub const RECORD_SIZE: usize = 4 * 1024 * 1024;
+
+pub const TAG_LEN: usize = 16;
+
+// what a sealed record adds to its plaintext
+pub const SEALED_RECORD_SIZE: usize = RECORD_SIZE + TAG_LEN;
+
+const KEY_LEN: usize = 32;
+
+// nonce and sealed data key in a header
+const WRAPPED_KEY_LEN: usize = NONCE_LEN + KEY_LEN + TAG_LEN;
+
+// the largest header: magic, key id length, key id and wrapped data key
+pub const MAX_HEADER_LEN: usize = MAGIC.len() + 1 + u8::MAX as usize + WRAPPED_KEY_LEN;
+
+// envelope encryption of stored files: every blob gets a random data key,
+// sealed with a master key and kept in the blob's header. a blob is
+//
+//   magic | key id length | key id | nonce | sealed data key | records
+//
+// and its records are the plaintext in RECORD_SIZE pieces sealed with the
+// data key, the record index as nonce. the last record is shorter than
+// RECORD_SIZE, empty if need be, and sealed as last so truncation is noticed
+pub struct Encryption {
+    key_id: String,                     // master key new blobs are sealed with
+    keys: HashMap<String, LessSafeKey>, // every master key blobs may be sealed with
+    rng: SystemRandom,
+}
+
+impl Encryption {
+    // loads the master keys, running the commands of those that come from a
+    // key management service
+    pub fn new(config: &EncryptionConfig) -> Result<Self, String> {
+        if !config.keys.contains_key(&config.key_id) {
+            return Err(format!(
+                "encryption key {} is not configured",
+                config.key_id
+            ));
+        }
+        let mut keys = HashMap::new();
+        for (id, key) in &config.keys {
+            if id.is_empty() || id.len() > u8::MAX as usize {
+                return Err(format!("encryption key id {:?} is empty or too long", id));
+            }
+            let bytes = load_key(key).map_err(|e| format!("encryption key {}: {}", id, e))?;
+            let key = UnboundKey::new(&AES_256_GCM, &bytes)
+                .map_err(|_| format!("encryption key {} must be {} bytes", id, KEY_LEN))?;
+            keys.insert(id.clone(), LessSafeKey::new(key));
+        }
+        Ok(Self {
+            key_id: config.key_id.clone(),
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    // a header for a new blob and the data key its records are sealed with
+    pub fn new_blob(&self) -> Result<(Vec<u8>, LessSafeKey), String> {
+        let mut data_key = [0u8; KEY_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut data_key)
+            .and_then(|()| self.rng.fill(&mut nonce))
+            .map_err(|_| "failed to generate a data key".to_string())?;
+
+        let mut wrapped = data_key.to_vec();
+        self.keys[&self.key_id]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.key_id.as_bytes()),
+                &mut wrapped,
+            )
+            .map_err(|_| "failed to seal the data key".to_string())?;
+
+        let mut header = MAGIC.to_vec();
+        header.push(self.key_id.len() as u8);
+        header.extend_from_slice(self.key_id.as_bytes());
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&wrapped);
+        let key = UnboundKey::new(&AES_256_GCM, &data_key).unwrap();
+        Ok((header, LessSafeKey::new(key)))
+    }
+
+    // the header length and data key of a blob starting with `prefix`, at
+    // least MAX_HEADER_LEN bytes of it unless the blob is shorter
+    pub fn open_blob(&self, prefix: &[u8]) -> Result<(usize, LessSafeKey), String> {
+        let truncated = || "encrypted file has a truncated header".to_string();
+        let rest = prefix.strip_prefix(MAGIC).ok_or_else(truncated)?;
+        let (&id_len, rest) = rest.split_first().ok_or_else(truncated)?;
+        let id_len = id_len as usize;
+        if rest.len() < id_len + WRAPPED_KEY_LEN {
+            return Err(truncated());
+        }
+        let key_id = String::from_utf8_lossy(&rest[..id_len]);
+        let master = self
+            .keys
+            .get(key_id.as_ref())
+            .ok_or_else(|| format!("file is encrypted with unknown key {}", key_id))?;
+        let nonce = Nonce::try_assume_unique_for_key(&rest[id_len..id_len + NONCE_LEN]).unwrap();
+        let mut wrapped = rest[id_len + NONCE_LEN..id_len + WRAPPED_KEY_LEN].to_vec();
+        let data_key = master
+            .open_in_place(nonce, Aad::from(key_id.as_bytes()), &mut wrapped)
+            .map_err(|_| format!("failed to unseal the data key with key {}", key_id))?;
+        let key = UnboundKey::new(&AES_256_GCM, data_key).unwrap();
+        Ok((
+            MAGIC.len() + 1 + id_len + WRAPPED_KEY_LEN,
+            LessSafeKey::new(key),
+        ))
+    }
+
+    // the whole blob for `data`
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let (mut blob, key) = self.new_blob()?;
+        let mut records = data.chunks(RECORD_SIZE).peekable();
+        let mut index = 0;
+        while let Some(record) = records.next() {
+            let last = records.peek().is_none() && record.len() < RECORD_SIZE;
+            blob.extend_from_slice(&seal_record(&key, index, last, record.to_vec())?);
+            index += 1;
+        }
+        // a multiple of RECORD_SIZE still needs a last record
+        if data.len().is_multiple_of(RECORD_SIZE) {
+            blob.extend_from_slice(&seal_record(&key, index, true, Vec::new())?);
+        }
+        Ok(blob)
+    }
+
+    // the plaintext of a whole blob
+    pub fn decrypt(&self, blob: &[u8]) -> Result<Vec<u8>, String> {
+        let (header_len, key) = self.open_blob(blob)?;
+        let mut data = Vec::with_capacity(blob.len());
+        let mut records = blob[header_len..].chunks(SEALED_RECORD_SIZE);
+        for index in 0.. {
+            let Some(record) = records.next() else {
+                return Err("encrypted file is truncated".to_string());
+            };
+            let last = record.len() < SEALED_RECORD_SIZE;
+            data.extend_from_slice(&open_record(&key, index, last, record.to_vec())?);
+            if last {
+                break;
+            }
+        }
+        Ok(data)
+    }
+}
+
+pub fn is_encrypted(blob: &[u8]) -> bool {
+    blob.starts_with(MAGIC)
+}
+
+// the record index is the nonce, unique since every blob has its own key
+fn record_nonce(index: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&index.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+pub fn seal_record(
+    key: &LessSafeKey,
+    index: u64,
+    last: bool,
+    mut record: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    key.seal_in_place_append_tag(record_nonce(index), Aad::from([last as u8]), &mut record)
+        .map_err(|_| "failed to encrypt file".to_string())?;
+    Ok(record)
+}
+
+pub fn open_record(
+    key: &LessSafeKey,
+    index: u64,
+    last: bool,
+    mut record: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let len = key
+        .open_in_place(record_nonce(index), Aad::from([last as u8]), &mut record)
+        .map_err(|_| "encrypted file is corrupted or was tampered with".to_string())?
+        .len();
+    record.truncate(len);
+    Ok(record)
+}
+
+// the raw bytes of a master key, given base64 encoded or printed that way
+// by a command, like a kms decrypt of a wrapped key
+fn load_key(key: &EncryptionKey) -> Result<Vec<u8>, String> {
+    let encoded = match (&key.key, &key.command) {
+        (Some(key), None) => key.clone(),
+        (None, Some(command)) => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .map_err(|e| format!("failed to run key command: {}", e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "key command failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+        _ => return Err("needs exactly one of key and command".to_string()),
+    };
+    STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| format!("invalid base64: {}", e))
+}
@@ -0,0 +1,176 @@
+use metrics::{counter, gauge};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use crate::types::AppConfig;
+use crate::utils::gen_random_id;
+
+// remote files kept on local disk under the sha1 of their content, which
+// redis computes for us, so a key rewritten with new content is never served
+// stale. evicted least recently used first once over `budget` bytes
+pub struct FileCache {
+    dir: Option<PathBuf>, // none when the cache is disabled
+    budget: u64,
+    max_file_size: u64,
+    files: Mutex<HashMap<String, CachedFile>>,
+}
+
+struct CachedFile {
+    size: u64,
+    last_used: Instant,
+}
+
+impl FileCache {
+    // picks up files cached by earlier runs, ordered by when they were last
+    // used. anything else in the directory is an insert that was cut short
+    pub fn new(config: &AppConfig) -> Self {
+        let dir = config.file_cache_dir.as_ref().map(PathBuf::from);
+        let mut files = HashMap::new();
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir).expect("Failed to create file cache directory");
+            let now = SystemTime::now();
+            for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if !is_sha1_hex(&name) || !metadata.is_file() {
+                    tracing::info!("removing partial cached file {}", entry.path().display());
+                    let _ = fs::remove_file(entry.path());
+                    continue;
+                }
+                let age = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .unwrap_or_default();
+                let file = CachedFile {
+                    size: metadata.len(),
+                    last_used: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                };
+                files.insert(name, file);
+            }
+        }
+        let cache = Self {
+            dir,
+            budget: config.file_cache_budget,
+            max_file_size: config.file_cache_max_file_size,
+            files: Mutex::new(files),
+        };
+        cache.evict();
+        cache
+    }
+
+    // files above this size are neither hashed nor cached, none when disabled
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.dir.as_ref().map(|_| self.max_file_size)
+    }
+
+    // copies the cached file with `digest` to `target`, false on a miss
+    pub fn copy_to(&self, digest: &str, size: u64, target: &Path) -> Result<bool, String> {
+        let Some(path) = self.lookup(digest, size) else {
+            counter!("file_cache_requests_total", "outcome" => "miss").increment(1);
+            return Ok(false);
+        };
+        // the sandbox may write to its copy, so cached files are never linked
+        match fs::copy(&path, target) {
+            Ok(_) => {
+                counter!("file_cache_requests_total", "outcome" => "hit").increment(1);
+                Ok(true)
+            }
+            // evicted meanwhile
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                counter!("file_cache_requests_total", "outcome" => "miss").increment(1);
+                Ok(false)
+            }
+            Err(e) => Err(format!("failed to copy cached file {}: {}", digest, e)),
+        }
+    }
+
+    fn lookup(&self, digest: &str, size: u64) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let mut files = self.files.lock().unwrap();
+        let file = files.get_mut(digest).filter(|file| file.size == size)?;
+        file.last_used = Instant::now();
+        let path = dir.join(digest);
+        // the file's mtime carries the lru order across restarts
+        if let Ok(file) = fs::File::open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(path)
+    }
+
+    // adds the downloaded file at `path` under `digest`, once its content is
+    // checked to match. failing to cache never fails the download
+    pub fn insert(&self, digest: &str, path: &Path) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if !is_sha1_hex(digest) {
+            return;
+        }
+        let partial = dir.join(format!("{}.{}", digest, gen_random_id(8)));
+        let result = (|| {
+            // the key may have changed between hashing it and downloading it
+            let mut file = fs::File::open(path)?;
+            let mut hasher = Sha1::new();
+            let size = io::copy(&mut file, &mut hasher)?;
+            if hex::encode(hasher.finalize()) != digest {
+                return Ok(None);
+            }
+            fs::copy(path, &partial)?;
+            fs::rename(&partial, dir.join(digest))?;
+            Ok::<_, io::Error>(Some(size))
+        })();
+        match result {
+            Ok(Some(size)) => {
+                self.files.lock().unwrap().insert(
+                    digest.to_string(),
+                    CachedFile {
+                        size,
+                        last_used: Instant::now(),
+                    },
+                );
+                self.evict();
+            }
+            Ok(None) => tracing::debug!(digest, "remote file changed while downloading"),
+            Err(e) => {
+                tracing::warn!(digest, "failed to cache file: {}", e);
+                let _ = fs::remove_file(&partial);
+            }
+        }
+    }
+
+    // drops files, least recently used first, until the cache fits
+    fn evict(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let mut files = self.files.lock().unwrap();
+        let mut total: u64 = files.values().map(|file| file.size).sum();
+        while total > self.budget {
+            let Some(digest) = files
+                .iter()
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(digest, _)| digest.clone())
+            else {
+                break;
+            };
+            let file = files.remove(&digest).unwrap();
+            total -= file.size;
+            if let Err(e) = fs::remove_file(dir.join(&digest)) {
+                tracing::error!("failed to evict cached file {}: {}", digest, e);
+            }
+        }
+        gauge!("file_cache_bytes").set(total as f64);
+    }
+}
+
+fn is_sha1_hex(name: &str) -> bool {
+    name.len() == 40 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
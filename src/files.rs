@@ -1,9 +1,193 @@
-use crate::types::FilePath;
+use crate::encryption::{self, Encryption};
+use crate::redis_client;
+use crate::types::{AppConfig, AppState, FilePath};
 use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+// remote files expire after three days
+const REMOTE_FILE_TTL: u64 = 60 * 60 * 24 * 3;
+
+// size of each APPEND or GETRANGE when streaming a file into or out of Redis
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// files growing past this are stored in parts of this size under keys of
+// their own, with a manifest of them under the file's key. redis strings
+// can't exceed 512 MiB, and large values block redis while they are copied
+const PART_SIZE: u64 = 32 * 1024 * 1024;
+
+// starts the manifest of a file stored in parts, followed by its json
+const MANIFEST_MAGIC: &[u8] = b"PGPARTS\x00";
+
+fn part_key(id: &str, index: u64) -> String {
+    format!("{}:part:{}", id, index)
+}
+
+fn owner_key(id: &str) -> String {
+    format!("{}:owner", id)
+}
+
+fn digest_key(id: &str) -> String {
+    format!("{}:digest", id)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    size: u64, // of the whole file
+    parts: u64,
+}
+
+// the size and sha1 of a file's content, stored next to it when it is saved
+// so digest is a plain GET. encrypted files get no sha1, see digest
+#[derive(Serialize, Deserialize)]
+struct StoredDigest {
+    size: u64,
+    sha1: Option<String>,
+}
+
+// hashes what a file being saved is read from
+struct HashingReader<R> {
+    inner: R,
+    sha1: Sha1,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sha1.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+// where a file's bytes are
+enum Layout {
+    Single { size: u64 },
+    Parts(Manifest),
+}
+
+impl Layout {
+    fn size(&self) -> u64 {
+        match self {
+            Layout::Single { size } => *size,
+            Layout::Parts(manifest) => manifest.size,
+        }
+    }
+}
+
+// writes a file as it comes, moving it into parts once it outgrows PART_SIZE
+struct Appender {
+    id: String,
+    written: u64,
+    parts: bool,
+}
+
+impl Appender {
+    // the file's key has to be reset with the remote file ttl beforehand
+    fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            written: 0,
+            parts: false,
+        }
+    }
+
+    async fn append(
+        &mut self,
+        connection: &mut MultiplexedConnection,
+        mut data: &[u8],
+    ) -> Result<(), String> {
+        let failed = |e: redis::RedisError| format!("Failed to append to remote file: {}", e);
+        while !data.is_empty() {
+            if !self.parts && self.written + data.len() as u64 <= PART_SIZE {
+                let _: () = connection.append(&self.id, data).await.map_err(failed)?;
+                self.written += data.len() as u64;
+                return Ok(());
+            }
+            if !self.parts {
+                // what was written so far becomes the first part, keeping its ttl
+                if self.written > 0 {
+                    let _: () = connection
+                        .rename(&self.id, part_key(&self.id, 0))
+                        .await
+                        .map_err(failed)?;
+                }
+                self.parts = true;
+            }
+            let offset = self.written % PART_SIZE;
+            let len = ((PART_SIZE - offset) as usize).min(data.len());
+            let (piece, rest) = data.split_at(len);
+            let key = part_key(&self.id, self.written / PART_SIZE);
+            // a part left by an earlier file under the same id is replaced
+            let _: () = if offset == 0 {
+                connection.set_ex(key, piece, REMOTE_FILE_TTL).await
+            } else {
+                connection.append(key, piece).await
+            }
+            .map_err(failed)?;
+            self.written += len as u64;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    async fn finish(self, connection: &mut MultiplexedConnection) -> Result<u64, String> {
+        if self.parts {
+            let mut manifest = MANIFEST_MAGIC.to_vec();
+            serde_json::to_writer(
+                &mut manifest,
+                &Manifest {
+                    size: self.written,
+                    parts: self.written.div_ceil(PART_SIZE),
+                },
+            )
+            .unwrap();
+            let _: () = connection
+                .set_ex(&self.id, manifest, REMOTE_FILE_TTL)
+                .await
+                .map_err(|e| format!("Failed to save remote file: {}", e))?;
+        }
+        Ok(self.written)
+    }
+}
+
+#[derive(Clone)]
 pub struct RedisFileManager {
     connection: MultiplexedConnection,
+    encryption: Option<Arc<Encryption>>, // remote files are written encrypted when set
+}
+
+// keeps remote files in this process instead of redis, shared by its clones.
+// they aren't encrypted, expire like redis ones and are held to a total size
+#[derive(Clone)]
+pub struct MemoryFileManager {
+    store: Arc<Mutex<MemoryStore>>,
+    max_size: u64,
+}
+
+#[derive(Default)]
+struct MemoryStore {
+    files: HashMap<String, MemoryFile>,
+    size: u64, // of every file's content
+}
+
+struct MemoryFile {
+    content: Vec<u8>,
+    owner: Option<String>, // tenant of a returned file, "" for none
+    expires: Instant,
+}
+
+// where a worker keeps remote files
+#[derive(Clone)]
+pub enum FileManager {
+    Redis(RedisFileManager),
+    Memory(MemoryFileManager),
 }
 
 pub trait FileManagerTrait {
@@ -19,6 +203,22 @@ pub trait FileManagerTrait {
         file: FilePath,
         base_path: Option<String>,
     ) -> Result<Vec<u8>, String>;
+
+    // writes everything from `reader` without holding it in memory at once,
+    // returning the number of bytes stored
+    async fn save_file_chunked(
+        &mut self,
+        file_path: FilePath,
+        reader: impl Read + Send,
+    ) -> Result<u64, String>;
+
+    // the other way around, copies a file into `writer` one chunk at a time,
+    // returning the number of bytes copied
+    async fn get_file_chunked(
+        &mut self,
+        file: FilePath,
+        writer: impl Write + Send,
+    ) -> Result<u64, String>;
 }
 
 impl FileManagerTrait for RedisFileManager {
@@ -30,34 +230,36 @@ impl FileManagerTrait for RedisFileManager {
     ) -> Result<(), String> {
         match file_path {
             FilePath::Remote { id } => {
-                let _: () = self
-                    .connection
-                    .set_ex(id, content, 60 * 60 * 24 * 3)
+                let id = self.key(&id)?;
+                let digest = StoredDigest {
+                    size: content.len() as u64,
+                    sha1: self
+                        .encryption
+                        .is_none()
+                        .then(|| hex::encode(Sha1::digest(&content))),
+                };
+                let content = match &self.encryption {
+                    Some(encryption) => encryption.encrypt(&content)?,
+                    None => content,
+                };
+                let large = content.len() as u64 > PART_SIZE;
+                let _: () = redis::pipe()
+                    .atomic()
+                    .set_ex(&id, if large { &[][..] } else { &content }, REMOTE_FILE_TTL)
+                    .del(digest_key(&id))
+                    .query_async(&mut self.connection)
                     .await
                     .map_err(|e| format!("Failed to save remote file: {}", e))?;
-                Ok(())
+                if large {
+                    let mut appender = Appender::new(&id);
+                    appender.append(&mut self.connection, &content).await?;
+                    appender.finish(&mut self.connection).await?;
+                }
+                self.save_digest(&id, &digest).await
             }
 
             FilePath::Local { name, executable } => {
-                let full_path = if let Some(base) = base_path {
-                    format!("{}/{}", base, name)
-                } else {
-                    name
-                };
-                fs::write(full_path.clone(), content)
-                    .map_err(|e| format!("Failed to write local file: {}", e))?;
-
-                if executable {
-                    let metadata = fs::metadata(&full_path)
-                        .map_err(|e| format!("Failed to get file metadata: {}", e))?;
-                    let mut permissions = metadata.permissions();
-
-                    use std::os::unix::fs::PermissionsExt;
-                    permissions.set_mode(0o755);
-                    fs::set_permissions(&full_path, permissions)
-                        .map_err(|e| format!("Failed to set executable permission: {}", e))?;
-                }
-                Ok(())
+                save_local(name, executable, base_path, content)
             }
 
             _ => Err("Unsupported file path type for saving".to_string()),
@@ -73,33 +275,705 @@ impl FileManagerTrait for RedisFileManager {
             FilePath::Local {
                 name,
                 executable: _,
-            } => {
-                let full_path = if let Some(base) = base_path {
-                    format!("{}/{}", base, name)
-                } else {
-                    name
-                };
-                let data =
-                    fs::read(full_path).map_err(|e| format!("Failed to read local file: {}", e))?;
-                Ok(data)
-            }
+            } => get_local(name, base_path),
 
             FilePath::Remote { id } => {
-                let data: Vec<u8> = self
+                let id = self.key(&id)?;
+                let mut data: Vec<u8> = self
                     .connection
-                    .get(id)
+                    .get(&id)
                     .await
                     .map_err(|e| format!("Failed to get remote file: {}", e))?;
-                Ok(data)
+                if let Some(manifest) = data.strip_prefix(MANIFEST_MAGIC) {
+                    let manifest = parse_manifest(&id, manifest)?;
+                    data = self
+                        .read_range(&id, &Layout::Parts(manifest), 0, u64::MAX)
+                        .await?;
+                }
+                if !encryption::is_encrypted(&data) {
+                    return Ok(data);
+                }
+                self.encryption()?.decrypt(&data)
             }
 
             _ => Err("Unsupported file path type".to_string()),
         }
     }
+
+    async fn save_file_chunked(
+        &mut self,
+        file_path: FilePath,
+        reader: impl Read + Send,
+    ) -> Result<u64, String> {
+        let FilePath::Remote { id } = file_path else {
+            return Err("Unsupported file path type for chunked saving".to_string());
+        };
+        let id = self.key(&id)?;
+
+        // reset the key first so a previous value never leaks into the result,
+        // APPEND keeps the expiry set here
+        let _: () = redis::pipe()
+            .atomic()
+            .set_ex(&id, Vec::<u8>::new(), REMOTE_FILE_TTL)
+            .del(digest_key(&id))
+            .query_async(&mut self.connection)
+            .await
+            .map_err(|e| format!("Failed to save remote file: {}", e))?;
+
+        let mut appender = Appender::new(&id);
+        let mut reader = HashingReader {
+            inner: reader,
+            sha1: Sha1::new(),
+            size: 0,
+        };
+        if let Some(encryption) = self.encryption.clone() {
+            let written = self
+                .save_encrypted(&mut appender, &encryption, &mut reader)
+                .await?;
+            appender.finish(&mut self.connection).await?;
+            let digest = StoredDigest {
+                size: written,
+                sha1: None,
+            };
+            self.save_digest(&id, &digest).await?;
+            return Ok(written);
+        }
+
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = reader
+                .read(&mut buffer)
+                .map_err(|e| format!("Failed to read file for remote save: {}", e))?;
+            if n == 0 {
+                break;
+            }
+            appender.append(&mut self.connection, &buffer[..n]).await?;
+        }
+
+        let written = appender.finish(&mut self.connection).await?;
+        let digest = StoredDigest {
+            size: reader.size,
+            sha1: Some(hex::encode(reader.sha1.finalize())),
+        };
+        self.save_digest(&id, &digest).await?;
+        Ok(written)
+    }
+
+    async fn get_file_chunked(
+        &mut self,
+        file: FilePath,
+        mut writer: impl Write + Send,
+    ) -> Result<u64, String> {
+        let FilePath::Remote { id } = file else {
+            return Err("Unsupported file path type for chunked reading".to_string());
+        };
+        let id = self.key(&id)?;
+
+        let layout = self.layout(&id).await?;
+        let size = layout.size();
+        let header = self
+            .read_range(&id, &layout, 0, encryption::MAX_HEADER_LEN as u64)
+            .await?;
+        if encryption::is_encrypted(&header) {
+            return self.get_encrypted(&id, &layout, &header, writer).await;
+        }
+
+        let mut read: u64 = 0;
+        while read < size {
+            let end = (read + CHUNK_SIZE as u64).min(size);
+            let chunk = self.read_range(&id, &layout, read, end).await?;
+            // the file was replaced by a shorter one meanwhile
+            if chunk.is_empty() {
+                break;
+            }
+            writer
+                .write_all(&chunk)
+                .map_err(|e| format!("Failed to write remote file: {}", e))?;
+            read += chunk.len() as u64;
+        }
+        writer
+            .flush()
+            .map_err(|e| format!("Failed to write remote file: {}", e))?;
+
+        Ok(read)
+    }
+}
+
+impl FileManagerTrait for MemoryFileManager {
+    async fn save_file(
+        &mut self,
+        file_path: FilePath,
+        base_path: Option<String>,
+        content: Vec<u8>,
+    ) -> Result<(), String> {
+        match file_path {
+            FilePath::Remote { id } => self.insert(id, content),
+            FilePath::Local { name, executable } => {
+                save_local(name, executable, base_path, content)
+            }
+            _ => Err("Unsupported file path type for saving".to_string()),
+        }
+    }
+
+    async fn get_file(
+        &mut self,
+        file: FilePath,
+        base_path: Option<String>,
+    ) -> Result<Vec<u8>, String> {
+        match file {
+            FilePath::Local {
+                name,
+                executable: _,
+            } => get_local(name, base_path),
+            FilePath::Remote { id } => self
+                .live(&id, |file| file.content.clone())
+                .ok_or_else(|| format!("Failed to get remote file: {} does not exist", id)),
+            _ => Err("Unsupported file path type".to_string()),
+        }
+    }
+
+    async fn save_file_chunked(
+        &mut self,
+        file_path: FilePath,
+        mut reader: impl Read + Send,
+    ) -> Result<u64, String> {
+        let FilePath::Remote { id } = file_path else {
+            return Err("Unsupported file path type for chunked saving".to_string());
+        };
+        let mut content = Vec::new();
+        reader
+            .read_to_end(&mut content)
+            .map_err(|e| format!("Failed to read file for remote save: {}", e))?;
+        let size = content.len() as u64;
+        self.insert(id, content)?;
+        Ok(size)
+    }
+
+    async fn get_file_chunked(
+        &mut self,
+        file: FilePath,
+        mut writer: impl Write + Send,
+    ) -> Result<u64, String> {
+        let FilePath::Remote { .. } = &file else {
+            return Err("Unsupported file path type for chunked reading".to_string());
+        };
+        let content = self.get_file(file, None).await?;
+        writer
+            .write_all(&content)
+            .and_then(|_| writer.flush())
+            .map_err(|e| format!("Failed to write remote file: {}", e))?;
+        Ok(content.len() as u64)
+    }
+}
+
+impl MemoryFileManager {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            store: Arc::default(),
+            max_size: config.memory_file_store_size,
+        }
+    }
+
+    // replaces file `id`, dropping expired files first. the new one is
+    // refused when the others leave no room for it
+    fn insert(&self, id: String, content: Vec<u8>) -> Result<(), String> {
+        let mut store = self.store.lock().unwrap();
+        let now = Instant::now();
+        let mut freed = 0;
+        store.files.retain(|name, file| {
+            let keep = file.expires > now && *name != id;
+            if !keep {
+                freed += file.content.len() as u64;
+            }
+            keep
+        });
+        store.size -= freed;
+        let size = content.len() as u64;
+        if store.size + size > self.max_size {
+            return Err(format!(
+                "Failed to save remote file {}: the memory file store is full",
+                id
+            ));
+        }
+        store.size += size;
+        let file = MemoryFile {
+            content,
+            owner: None,
+            expires: now + Duration::from_secs(REMOTE_FILE_TTL),
+        };
+        store.files.insert(id, file);
+        Ok(())
+    }
+
+    // `read` of file `id` unless it is missing or expired
+    fn live<T>(&self, id: &str, read: impl FnOnce(&MemoryFile) -> T) -> Option<T> {
+        let store = self.store.lock().unwrap();
+        let file = store.files.get(id)?;
+        (file.expires > Instant::now()).then(|| read(file))
+    }
+
+    pub fn set_owner(&self, id: &str, tenant: Option<&str>) {
+        if let Some(file) = self.store.lock().unwrap().files.get_mut(id) {
+            file.owner = Some(tenant.unwrap_or_default().to_string());
+        }
+    }
+
+    pub fn owner(&self, id: &str) -> Option<String> {
+        self.live(id, |file| file.owner.clone()).flatten()
+    }
+
+    // like RedisFileManager::digest
+    pub fn digest(&self, id: &str, max_size: u64) -> Option<(u64, Option<String>)> {
+        self.live(id, |file| {
+            let size = file.content.len() as u64;
+            (
+                size,
+                (size <= max_size).then(|| hex::encode(Sha1::digest(&file.content))),
+            )
+        })
+    }
+}
+
+impl FileManagerTrait for FileManager {
+    async fn save_file(
+        &mut self,
+        file_path: FilePath,
+        base_path: Option<String>,
+        content: Vec<u8>,
+    ) -> Result<(), String> {
+        match self {
+            FileManager::Redis(files) => files.save_file(file_path, base_path, content).await,
+            FileManager::Memory(files) => files.save_file(file_path, base_path, content).await,
+        }
+    }
+
+    async fn get_file(
+        &mut self,
+        file: FilePath,
+        base_path: Option<String>,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            FileManager::Redis(files) => files.get_file(file, base_path).await,
+            FileManager::Memory(files) => files.get_file(file, base_path).await,
+        }
+    }
+
+    async fn save_file_chunked(
+        &mut self,
+        file_path: FilePath,
+        reader: impl Read + Send,
+    ) -> Result<u64, String> {
+        match self {
+            FileManager::Redis(files) => files.save_file_chunked(file_path, reader).await,
+            FileManager::Memory(files) => files.save_file_chunked(file_path, reader).await,
+        }
+    }
+
+    async fn get_file_chunked(
+        &mut self,
+        file: FilePath,
+        writer: impl Write + Send,
+    ) -> Result<u64, String> {
+        match self {
+            FileManager::Redis(files) => files.get_file_chunked(file, writer).await,
+            FileManager::Memory(files) => files.get_file_chunked(file, writer).await,
+        }
+    }
+}
+
+impl FileManager {
+    // the store of the service, memory when the state holds one. settings
+    // only allow the redis store with redis_url set
+    pub fn new(state: &AppState) -> Self {
+        match (&state.memory_files, &state.redis_connection) {
+            (Some(files), _) => FileManager::Memory(files.clone()),
+            (None, Some(connection)) => FileManager::Redis(RedisFileManager::new(
+                connection.clone(),
+                state.encryption.clone(),
+            )),
+            (None, None) => unreachable!("file_store \"redis\" without redis_url"),
+        }
+    }
+
+    pub async fn digest(
+        &mut self,
+        id: &str,
+        max_size: u64,
+    ) -> Result<Option<(u64, Option<String>)>, String> {
+        match self {
+            FileManager::Redis(files) => files.digest(id, max_size).await,
+            FileManager::Memory(files) => Ok(files.digest(id, max_size)),
+        }
+    }
+
+    // records that remote file `id` was returned to `tenant`, so only that
+    // tenant may download it
+    pub async fn set_owner(&mut self, id: &str, tenant: Option<&str>) -> Result<(), String> {
+        match self {
+            FileManager::Redis(files) => files.set_owner(id, tenant).await,
+            FileManager::Memory(files) => {
+                files.set_owner(id, tenant);
+                Ok(())
+            }
+        }
+    }
+
+    // the tenant remote file `id` was returned to, "" for none. none for
+    // files nothing returned
+    pub async fn owner(&mut self, id: &str) -> Result<Option<String>, String> {
+        match self {
+            FileManager::Redis(files) => files.owner(id).await,
+            FileManager::Memory(files) => Ok(files.owner(id)),
+        }
+    }
+
+    // for the result cache, which lives in redis
+    pub fn connection(&self) -> Option<MultiplexedConnection> {
+        match self {
+            FileManager::Redis(files) => Some(files.connection()),
+            FileManager::Memory(_) => None,
+        }
+    }
 }
 
 impl RedisFileManager {
-    pub fn new(connection: MultiplexedConnection) -> Self {
-        RedisFileManager { connection }
+    pub fn new(connection: MultiplexedConnection, encryption: Option<Arc<Encryption>>) -> Self {
+        RedisFileManager {
+            connection,
+            encryption,
+        }
+    }
+
+    fn key(&self, id: &str) -> Result<String, String> {
+        redis_client::file_key(id)
+    }
+
+    fn encryption(&self) -> Result<&Encryption, String> {
+        self.encryption.as_deref().ok_or_else(|| {
+            "remote file is encrypted but no encryption keys are configured".to_string()
+        })
+    }
+
+    // seals whole records as they are read, so the reader's short reads
+    // don't change where records end
+    async fn save_encrypted(
+        &mut self,
+        appender: &mut Appender,
+        encryption: &Encryption,
+        mut reader: impl Read + Send,
+    ) -> Result<u64, String> {
+        let (header, key) = encryption.new_blob()?;
+        appender.append(&mut self.connection, &header).await?;
+
+        let mut written: u64 = 0;
+        for index in 0.. {
+            let mut record = vec![0u8; encryption::RECORD_SIZE];
+            let n = read_full(&mut reader, &mut record)
+                .map_err(|e| format!("Failed to read file for remote save: {}", e))?;
+            record.truncate(n);
+            let last = n < encryption::RECORD_SIZE;
+            let sealed = encryption::seal_record(&key, index, last, record)?;
+            appender.append(&mut self.connection, &sealed).await?;
+            written += n as u64;
+            if last {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    // opens a record at a time, `header` being the start of the file
+    async fn get_encrypted(
+        &mut self,
+        id: &str,
+        layout: &Layout,
+        header: &[u8],
+        mut writer: impl Write + Send,
+    ) -> Result<u64, String> {
+        let (header_len, key) = self.encryption()?.open_blob(header)?;
+        let size = layout.size();
+        let mut offset = header_len as u64;
+        let mut read: u64 = 0;
+        for index in 0.. {
+            if offset >= size {
+                return Err("encrypted remote file is truncated".to_string());
+            }
+            let end = (offset + encryption::SEALED_RECORD_SIZE as u64).min(size);
+            let record = self.read_range(id, layout, offset, end).await?;
+            offset += record.len() as u64;
+            let last = record.len() < encryption::SEALED_RECORD_SIZE;
+            let data = encryption::open_record(&key, index, last, record)?;
+            writer
+                .write_all(&data)
+                .map_err(|e| format!("Failed to write remote file: {}", e))?;
+            read += data.len() as u64;
+            if last {
+                break;
+            }
+        }
+        writer
+            .flush()
+            .map_err(|e| format!("Failed to write remote file: {}", e))?;
+        Ok(read)
+    }
+
+    // the size and sha1 of a remote file as stored when it was saved. larger
+    // files than `max_size` are left without a sha1, and so are encrypted
+    // ones, which would otherwise end up decrypted in the file cache. none
+    // when the file is missing
+    pub async fn digest(
+        &mut self,
+        id: &str,
+        max_size: u64,
+    ) -> Result<Option<(u64, Option<String>)>, String> {
+        let id = self.key(id)?;
+        let stored: Option<String> = self
+            .connection
+            .get(digest_key(&id))
+            .await
+            .map_err(|e| format!("Failed to get remote file digest: {}", e))?;
+        let digest = match stored {
+            Some(stored) => serde_json::from_str::<StoredDigest>(&stored)
+                .map_err(|e| format!("corrupt digest of remote file {}: {}", id, e))?,
+            // saved before digests were stored, only its size is known
+            None => {
+                let exists: bool = self
+                    .connection
+                    .exists(&id)
+                    .await
+                    .map_err(|e| format!("Failed to get remote file: {}", e))?;
+                if !exists {
+                    return Ok(None);
+                }
+                StoredDigest {
+                    size: self.layout(&id).await?.size(),
+                    sha1: None,
+                }
+            }
+        };
+        Ok(Some((
+            digest.size,
+            digest.sha1.filter(|_| digest.size <= max_size),
+        )))
+    }
+
+    async fn save_digest(&mut self, id: &str, digest: &StoredDigest) -> Result<(), String> {
+        self.connection
+            .set_ex::<_, _, ()>(
+                digest_key(id),
+                serde_json::to_string(digest).unwrap(),
+                REMOTE_FILE_TTL,
+            )
+            .await
+            .map_err(|e| format!("Failed to save remote file digest: {}", e))
+    }
+
+    pub fn connection(&self) -> MultiplexedConnection {
+        self.connection.clone()
+    }
+
+    // the owner is kept next to the file and expires with it
+    pub async fn set_owner(&mut self, id: &str, tenant: Option<&str>) -> Result<(), String> {
+        self.connection
+            .set_ex::<_, _, ()>(
+                owner_key(&self.key(id)?),
+                tenant.unwrap_or_default(),
+                REMOTE_FILE_TTL,
+            )
+            .await
+            .map_err(|e| format!("Failed to save remote file owner: {}", e))
+    }
+
+    pub async fn owner(&mut self, id: &str) -> Result<Option<String>, String> {
+        self.connection
+            .get(owner_key(&self.key(id)?))
+            .await
+            .map_err(|e| format!("Failed to get remote file owner: {}", e))
+    }
+
+    // removes the expiry of a remote file
+    pub async fn persist(&mut self, id: &str) -> Result<(), String> {
+        let id = self.key(id)?;
+        let mut keys = vec![id.clone(), owner_key(&id), digest_key(&id)];
+        keys.extend(self.part_keys(&id).await?);
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.persist(key).ignore();
+        }
+        pipe.query_async::<()>(&mut self.connection)
+            .await
+            .map_err(|e| format!("Failed to persist remote file: {}", e))
+    }
+
+    // deletes remote files with their parts, missing ones are skipped
+    pub async fn delete(&mut self, ids: &[String]) -> Result<(), String> {
+        let mut keys = Vec::new();
+        for id in ids {
+            let id = self.key(id)?;
+            keys.extend(self.part_keys(&id).await?);
+            keys.push(owner_key(&id));
+            keys.push(digest_key(&id));
+            keys.push(id);
+        }
+        self.connection
+            .del::<_, ()>(keys)
+            .await
+            .map_err(|e| format!("Failed to delete remote file: {}", e))
+    }
+
+    // the manifest under `id` when the file is stored in parts
+    async fn manifest(&mut self, id: &str) -> Result<Option<Manifest>, String> {
+        let prefix: Vec<u8> = self
+            .connection
+            .getrange(id, 0, MANIFEST_MAGIC.len() as isize - 1)
+            .await
+            .map_err(|e| format!("Failed to get remote file: {}", e))?;
+        if prefix != MANIFEST_MAGIC {
+            return Ok(None);
+        }
+        let manifest: Vec<u8> = self
+            .connection
+            .get(id)
+            .await
+            .map_err(|e| format!("Failed to get remote file: {}", e))?;
+        let manifest = manifest.strip_prefix(MANIFEST_MAGIC).unwrap_or_default();
+        parse_manifest(id, manifest).map(Some)
+    }
+
+    async fn part_keys(&mut self, id: &str) -> Result<Vec<String>, String> {
+        Ok(match self.manifest(id).await? {
+            Some(manifest) => (0..manifest.parts)
+                .map(|index| part_key(id, index))
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    async fn layout(&mut self, id: &str) -> Result<Layout, String> {
+        if let Some(manifest) = self.manifest(id).await? {
+            return Ok(Layout::Parts(manifest));
+        }
+        // STRLEN is 0 for missing keys as well, which get_file fails on too
+        let size: u64 = self
+            .connection
+            .strlen(id)
+            .await
+            .map_err(|e| format!("Failed to get remote file: {}", e))?;
+        if size == 0 {
+            let exists: bool = self
+                .connection
+                .exists(id)
+                .await
+                .map_err(|e| format!("Failed to get remote file: {}", e))?;
+            if !exists {
+                return Err(format!("Failed to get remote file: {} does not exist", id));
+            }
+        }
+        Ok(Layout::Single { size })
+    }
+
+    // bytes `start` up to `end` of the file, fewer when it ends or was
+    // replaced meanwhile
+    async fn read_range(
+        &mut self,
+        id: &str,
+        layout: &Layout,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, String> {
+        let end = end.min(layout.size());
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let failed = |e: redis::RedisError| format!("Failed to get remote file: {}", e);
+        if let Layout::Single { .. } = layout {
+            return self
+                .connection
+                .getrange(id, start as isize, end as isize - 1)
+                .await
+                .map_err(failed);
+        }
+        let mut data = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            let index = offset / PART_SIZE;
+            let part_start = offset % PART_SIZE;
+            let part_end = (PART_SIZE * (index + 1)).min(end) - PART_SIZE * index;
+            let piece: Vec<u8> = self
+                .connection
+                .getrange(
+                    part_key(id, index),
+                    part_start as isize,
+                    part_end as isize - 1,
+                )
+                .await
+                .map_err(failed)?;
+            if piece.is_empty() {
+                break;
+            }
+            offset += piece.len() as u64;
+            data.extend_from_slice(&piece);
+        }
+        Ok(data)
+    }
+}
+
+// a file of the sandbox when `base_path` is its directory
+fn save_local(
+    name: String,
+    executable: bool,
+    base_path: Option<String>,
+    content: Vec<u8>,
+) -> Result<(), String> {
+    let full_path = if let Some(base) = base_path {
+        format!("{}/{}", base, name)
+    } else {
+        name
+    };
+    fs::write(full_path.clone(), content)
+        .map_err(|e| format!("Failed to write local file: {}", e))?;
+
+    if executable {
+        let metadata =
+            fs::metadata(&full_path).map_err(|e| format!("Failed to get file metadata: {}", e))?;
+        let mut permissions = metadata.permissions();
+
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(0o755);
+        fs::set_permissions(&full_path, permissions)
+            .map_err(|e| format!("Failed to set executable permission: {}", e))?;
+    }
+    Ok(())
+}
+
+fn get_local(name: String, base_path: Option<String>) -> Result<Vec<u8>, String> {
+    let full_path = if let Some(base) = base_path {
+        format!("{}/{}", base, name)
+    } else {
+        name
+    };
+    fs::read(full_path).map_err(|e| format!("Failed to read local file: {}", e))
+}
+
+fn parse_manifest(id: &str, manifest: &[u8]) -> Result<Manifest, String> {
+    serde_json::from_slice(manifest).map_err(|e| {
+        format!(
+            "Failed to get remote file: {} has an invalid manifest: {}",
+            id, e
+        )
+    })
+}
+
+// fills `buffer` unless the reader ends first, returning the bytes read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(filled)
 }
@@ -0,0 +1,125 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde_json::{Value, json};
+
+use crate::auth::{self, Credentials, Scope};
+use crate::dead_letters::{self, DeadLetter, Requeue};
+use crate::runner::{self, RunnerInfo};
+use crate::sandboxes::SandboxInfo;
+use crate::types::AppState;
+
+type AdminError = (StatusCode, Json<Value>);
+
+fn unavailable(e: String) -> AdminError {
+    (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": e })))
+}
+
+// admin routes are disabled unless an admin token, jwt or tls clients are
+// configured. a token or client certificate with the admin scope works as
+// well as the admin token
+fn authorize(state: &AppState, credentials: &Credentials) -> Result<(), AdminError> {
+    let error = |status, message: &str| Err((status, Json(json!({ "error": message }))));
+    let token = &state.config.admin_token;
+    let delegated = state.jwt.is_some() || auth::has_tls_clients(state);
+    if token.is_none() && !delegated {
+        return error(StatusCode::NOT_FOUND, "admin api is disabled");
+    }
+    if let (Some(token), Some(given)) = (token, auth::bearer(&credentials.headers))
+        && auth::secrets_match(given, token)
+    {
+        return Ok(());
+    }
+    if !delegated {
+        return error(StatusCode::UNAUTHORIZED, "invalid admin token");
+    }
+    match auth::authenticate(state, credentials, Scope::Admin) {
+        Ok(_) => Ok(()),
+        Err((status, message)) => error(status, &message),
+    }
+}
+
+pub async fn list_sandboxes(
+    State(state): State<AppState>,
+    credentials: Credentials,
+) -> Result<Json<Vec<SandboxInfo>>, AdminError> {
+    authorize(&state, &credentials)?;
+    Ok(Json(state.sandboxes.list()))
+}
+
+// runners registered in redis, from any node sharing it
+pub async fn list_runners(
+    State(state): State<AppState>,
+    credentials: Credentials,
+) -> Result<Json<Vec<RunnerInfo>>, AdminError> {
+    authorize(&state, &credentials)?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    runner::list(&mut connection)
+        .await
+        .map(Json)
+        .map_err(unavailable)
+}
+
+// requests that failed on the host after every retry, newest first
+pub async fn list_dead_letters(
+    State(state): State<AppState>,
+    credentials: Credentials,
+) -> Result<Json<Vec<DeadLetter>>, AdminError> {
+    authorize(&state, &credentials)?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    dead_letters::list(&mut connection)
+        .await
+        .map(Json)
+        .map_err(unavailable)
+}
+
+#[tracing::instrument(skip(state, credentials))]
+pub async fn requeue_dead_letter(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Value>), AdminError> {
+    authorize(&state, &credentials)?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    let ttl = state.config.job_ttl_secs;
+    let job = match dead_letters::requeue(&mut connection, &id, ttl)
+        .await
+        .map_err(unavailable)?
+    {
+        Requeue::Scheduled(job) => job,
+        Requeue::Missing => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": format!("no dead letter {}", id) })),
+            ));
+        }
+        Requeue::Dropped => {
+            let error = format!("dead letter {} kept no request, it was too large", id);
+            return Err((StatusCode::CONFLICT, Json(json!({ "error": error }))));
+        }
+    };
+    tracing::info!(job = job.id, "dead letter requeued by an administrator");
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "id": job.id, "run_at": job.run_at })),
+    ))
+}
+
+#[tracing::instrument(skip(state, credentials))]
+pub async fn kill_sandbox(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    authorize(&state, &credentials)?;
+    if !state.sandboxes.kill(&id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("no sandbox {}", id) })),
+        ));
+    }
+    tracing::warn!("sandbox killed by an administrator");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,170 @@
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+
+use crate::auth::{self, Credentials, Scope};
+use crate::handlers::run::WS_PROTOCOLS;
+use crate::runner;
+use crate::types::{AppState, Mode};
+
+// encodings responses of compressed routes may use
+const ENCODINGS: &[&str] = &["gzip", "br", "zstd"];
+
+// what this node offers, for clients and orchestration to adapt to
+#[derive(Serialize)]
+pub struct Capabilities {
+    version: &'static str,
+    mode: Mode,
+    arch: &'static str,
+    backends: Backends,
+    limits: Limits,
+    rootfs: Vec<String>, // root filesystems and images executions may name
+    profiles: Vec<String>,
+    templates: Vec<String>,
+    languages: Vec<String>,
+    features: Features,
+    compression: Compression,
+    websocket_protocols: Vec<&'static str>, // in the order the server prefers them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    runners: Option<Vec<Runner>>, // on coordinators, the runners requests are sent to
+}
+
+// what a live runner offers, from its last heartbeat
+#[derive(Serialize)]
+struct Runner {
+    id: String,
+    arch: String,
+    capacity: usize,
+    rootfs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Backends {
+    sandbox: &'static str,
+    files: &'static str,
+}
+
+#[derive(Serialize)]
+struct Limits {
+    default_time_limit: u64,
+    default_wall_time_limit: u64,
+    default_memory_limit: u64,
+    max_time_limit: Option<u64>, // none when unlimited
+    max_wall_time_limit: Option<u64>,
+    max_memory_limit: Option<u64>,
+    reject_over_limits: bool, // over a maximum fails instead of clamping
+    max_request_size: u64,
+    max_return_file_size: u64,
+    max_response_size: u64,
+    max_trace_size: u64,
+    min_niceness: i32,
+}
+
+// optional execution fields and the server settings they need
+#[derive(Serialize)]
+struct Features {
+    seccomp_audit: bool,
+    debug: bool,
+    trace: bool,
+    perf: bool,
+    gpu: bool,
+    sandbox_init: bool,
+    landlock: bool,
+    url_transfers: bool,
+    encryption: bool,
+    usage_records: bool,
+}
+
+#[derive(Serialize)]
+struct Compression {
+    encodings: Vec<&'static str>,
+    routes: Vec<String>,
+}
+
+impl Capabilities {
+    fn new(state: &AppState) -> Self {
+        let config = &state.config;
+        let rootfs: BTreeSet<&String> = config.rootfs.keys().chain(config.images.keys()).collect();
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            mode: config.mode,
+            arch: std::env::consts::ARCH,
+            backends: Backends {
+                sandbox: "hakoniwa",
+                files: if state.memory_files.is_some() {
+                    "memory"
+                } else {
+                    "redis"
+                },
+            },
+            limits: Limits {
+                default_time_limit: config.default_time_limit,
+                default_wall_time_limit: config.default_wall_time_limit,
+                default_memory_limit: config.default_memory_limit,
+                max_time_limit: config.max_time_limit,
+                max_wall_time_limit: config.max_wall_time_limit,
+                max_memory_limit: config.max_memory_limit,
+                reject_over_limits: config.reject_over_limits,
+                max_request_size: config.max_request_size,
+                max_return_file_size: config.max_return_file_size,
+                max_response_size: config.max_response_size,
+                max_trace_size: config.max_trace_size,
+                min_niceness: config.min_niceness,
+            },
+            rootfs: rootfs.into_iter().cloned().collect(),
+            profiles: config.profiles.keys().cloned().collect(),
+            templates: config.templates.keys().cloned().collect(),
+            languages: config.languages.keys().cloned().collect(),
+            features: Features {
+                seccomp_audit: config.allow_seccomp_audit,
+                debug: config.allow_debug,
+                trace: config.allow_trace,
+                perf: config.allow_perf,
+                gpu: config.allow_gpu,
+                sandbox_init: config.sandbox_init,
+                landlock: config.landlock,
+                url_transfers: !config.url_allowed_hosts.is_empty(),
+                encryption: config.encryption.is_some(),
+                usage_records: config.usage_sink.is_some(),
+            },
+            compression: Compression {
+                encodings: if config.compressed_routes.is_empty() {
+                    Vec::new()
+                } else {
+                    ENCODINGS.to_vec()
+                },
+                routes: config.compressed_routes.clone(),
+            },
+            websocket_protocols: WS_PROTOCOLS.iter().map(|(name, _)| *name).collect(),
+            runners: None,
+        }
+    }
+}
+
+pub async fn capabilities_endpoint(
+    State(state): State<AppState>,
+    credentials: Credentials,
+) -> Result<Json<Capabilities>, (StatusCode, Json<Value>)> {
+    auth::authenticate(&state, &credentials, Scope::Execute)
+        .map_err(|(status, err)| (status, Json(json!({ "error": err }))))?;
+    let mut capabilities = Capabilities::new(&state);
+    // executions run on the runners, so what they offer is what counts
+    if state.config.mode == Mode::Coordinator {
+        let unavailable = |e| (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": e })));
+        let mut connection = state.redis().map_err(unavailable)?;
+        let runners = runner::list(&mut connection).await.map_err(unavailable)?;
+        capabilities.runners = Some(
+            runners
+                .into_iter()
+                .map(|info| Runner {
+                    id: info.id,
+                    arch: info.arch,
+                    capacity: info.capacity,
+                    rootfs: info.rootfs,
+                })
+                .collect(),
+        );
+    }
+    Ok(Json(capabilities))
+}
@@ -0,0 +1,130 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde_json::{Value, json};
+
+use crate::auth::{self, Credentials, Scope};
+use crate::cron::{self, CronJob, Schedule};
+use crate::jobs;
+use crate::templates;
+use crate::types::{AppState, CronSubmission};
+use crate::utils::gen_random_id;
+
+type CronError = (StatusCode, Json<Value>);
+
+fn cron_error((status, err): (StatusCode, String)) -> CronError {
+    (status, Json(json!({ "error": err })))
+}
+
+fn unavailable(err: String) -> CronError {
+    cron_error((StatusCode::SERVICE_UNAVAILABLE, err))
+}
+
+fn next_run(schedule: &str) -> Result<u64, CronError> {
+    let schedule =
+        Schedule::parse(schedule).map_err(|e| cron_error((StatusCode::BAD_REQUEST, e)))?;
+    schedule.next_after(jobs::unix_now()).ok_or(cron_error((
+        StatusCode::BAD_REQUEST,
+        "cron schedule never fires".to_string(),
+    )))
+}
+
+// recurring jobs of other tenants look the same as missing ones
+async fn find(state: &AppState, credentials: &Credentials, id: &str) -> Result<CronJob, CronError> {
+    let tenant = auth::authenticate(state, credentials, Scope::Execute).map_err(cron_error)?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    match cron::get(&mut connection, id).await.map_err(unavailable)? {
+        Some(cron) if cron.tenant == tenant => Ok(cron),
+        _ => Err(cron_error((
+            StatusCode::NOT_FOUND,
+            format!("no recurring job {}", id),
+        ))),
+    }
+}
+
+#[tracing::instrument(skip(state, credentials, submission))]
+pub async fn create_cron(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Json(submission): Json<CronSubmission>,
+) -> Result<(StatusCode, Json<CronJob>), CronError> {
+    let tenant = auth::authenticate(&state, &credentials, Scope::Execute).map_err(cron_error)?;
+    let request = templates::expand(&state.config, submission.request)
+        .map_err(|e| cron_error((StatusCode::BAD_REQUEST, e)))?;
+    let cron = CronJob {
+        id: gen_random_id(16),
+        tenant,
+        next_run: next_run(&submission.schedule)?,
+        schedule: submission.schedule,
+        request,
+        last_job: None,
+    };
+    let mut connection = state.redis().map_err(unavailable)?;
+    cron::save(&mut connection, &cron)
+        .await
+        .map_err(unavailable)?;
+    tracing::info!(
+        id = cron.id,
+        schedule = cron.schedule,
+        "recurring job created"
+    );
+    Ok((StatusCode::CREATED, Json(cron)))
+}
+
+pub async fn list_crons(
+    State(state): State<AppState>,
+    credentials: Credentials,
+) -> Result<Json<Vec<CronJob>>, CronError> {
+    let tenant = auth::authenticate(&state, &credentials, Scope::Execute).map_err(cron_error)?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    let crons = cron::list(&mut connection).await.map_err(unavailable)?;
+    Ok(Json(
+        crons
+            .into_iter()
+            .filter(|cron| cron.tenant == tenant)
+            .collect(),
+    ))
+}
+
+pub async fn get_cron(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<Json<CronJob>, CronError> {
+    Ok(Json(find(&state, &credentials, &id).await?))
+}
+
+#[tracing::instrument(skip(state, credentials, submission))]
+pub async fn update_cron(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+    Json(submission): Json<CronSubmission>,
+) -> Result<Json<CronJob>, CronError> {
+    let mut cron = find(&state, &credentials, &id).await?;
+    cron.next_run = next_run(&submission.schedule)?;
+    cron.schedule = submission.schedule;
+    cron.request = templates::expand(&state.config, submission.request)
+        .map_err(|e| cron_error((StatusCode::BAD_REQUEST, e)))?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    cron::save(&mut connection, &cron)
+        .await
+        .map_err(unavailable)?;
+    Ok(Json(cron))
+}
+
+#[tracing::instrument(skip(state, credentials))]
+pub async fn delete_cron(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<StatusCode, CronError> {
+    find(&state, &credentials, &id).await?;
+    let mut connection = state.redis().map_err(unavailable)?;
+    cron::delete(&mut connection, &id)
+        .await
+        .map_err(unavailable)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,92 @@
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+};
+use futures_util::stream;
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::sync::mpsc;
+
+use crate::auth::{self, Credentials, Scope};
+use crate::files::{FileManager, FileManagerTrait};
+use crate::types::{AppState, FilePath};
+
+// chunks read from the store ahead of the client
+const STREAM_CHUNKS: usize = 2;
+
+type FileError = (StatusCode, Json<Value>);
+
+fn file_error((status, err): (StatusCode, String)) -> FileError {
+    (status, Json(json!({ "error": err })))
+}
+
+// hands what get_file_chunked writes to the response body, waiting while
+// the client catches up
+struct ChannelWriter(mpsc::SyncSender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// the content of a file a result returned by reference to the caller's
+// tenant. other remote files, and those of other tenants, look missing
+#[tracing::instrument(skip(state, credentials))]
+pub async fn get_file(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, FileError> {
+    let tenant = auth::authenticate(&state, &credentials, Scope::Execute).map_err(file_error)?;
+    let mut file_manager = FileManager::new(&state);
+    let unavailable = |e| file_error((StatusCode::SERVICE_UNAVAILABLE, e));
+    let missing = || file_error((StatusCode::NOT_FOUND, format!("no file {}", id)));
+    let owner = file_manager.owner(&id).await.map_err(unavailable)?;
+    if owner.as_deref() != Some(tenant.as_deref().unwrap_or_default()) {
+        return Err(missing());
+    }
+    if file_manager
+        .digest(&id, 0)
+        .await
+        .map_err(unavailable)?
+        .is_none()
+    {
+        return Err(missing());
+    }
+
+    // streamed as it is read, large files never sit in memory. both ends of
+    // the channel block, so they run off the runtime
+    let (tx, rx) = mpsc::sync_channel(STREAM_CHUNKS);
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let errors = tx.clone();
+        let remote = FilePath::Remote { id };
+        let copied = runtime.block_on(file_manager.get_file_chunked(remote, ChannelWriter(tx)));
+        if let Err(e) = copied {
+            tracing::warn!("failed to stream remote file: {}", e);
+            // ends the body with an error, so the client sees it was cut short
+            let _ = errors.send(Err(io::Error::other(e)));
+        }
+    });
+    let chunks = stream::unfold(rx, |rx| async move {
+        tokio::task::spawn_blocking(move || rx.recv().ok().map(|chunk| (chunk, rx)))
+            .await
+            .ok()
+            .flatten()
+    });
+    Ok((
+        [(CONTENT_TYPE, "application/octet-stream")],
+        Body::from_stream(chunks),
+    ))
+}
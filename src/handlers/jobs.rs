@@ -0,0 +1,149 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use metrics::counter;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+use crate::auth::{self, Credentials, Scope};
+use crate::handlers::run::{check_quota, tenant_label};
+use crate::jobs::{self, Job, JobStatus};
+use crate::templates;
+use crate::types::{AppState, JobSubmission};
+use crate::utils::gen_random_id;
+
+type JobError = (StatusCode, Json<Value>);
+
+// longest a results request is held open, below common proxy timeouts
+const MAX_RESULTS_WAIT_SECS: u64 = 60;
+
+// how often a held results request looks for new ones
+const RESULTS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn job_error((status, err): (StatusCode, String)) -> JobError {
+    (status, Json(json!({ "error": err })))
+}
+
+#[tracing::instrument(skip(state, credentials, submission))]
+pub async fn submit_job(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Json(submission): Json<JobSubmission>,
+) -> Result<(StatusCode, Json<Value>), JobError> {
+    let tenant = auth::authenticate(&state, &credentials, Scope::Execute).map_err(job_error)?;
+    counter!("jobs_submitted_total", "tenant" => tenant_label(tenant.as_deref())).increment(1);
+
+    let request = templates::expand(&state.config, submission.request)
+        .map_err(|e| job_error((StatusCode::BAD_REQUEST, e)))?;
+    if let Some(webhook) = &submission.webhook {
+        state
+            .webhooks
+            .validate(webhook)
+            .map_err(|e| job_error((StatusCode::BAD_REQUEST, e)))?;
+    }
+    let executions = request.execution_count();
+    check_quota(&state, tenant.as_deref(), executions)
+        .await
+        .map_err(job_error)?;
+
+    let job = Job {
+        id: gen_random_id(16),
+        tenant,
+        run_at: submission.run_at.unwrap_or_else(jobs::unix_now),
+        status: JobStatus::Scheduled,
+        request: Some(request),
+        results: Vec::new(),
+        webhook: submission.webhook,
+    };
+    let mut connection = state
+        .redis()
+        .map_err(|e| job_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    jobs::schedule(&mut connection, &job)
+        .await
+        .map_err(|e| job_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    tracing::info!(id = job.id, run_at = job.run_at, "job scheduled");
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "id": job.id, "run_at": job.run_at })),
+    ))
+}
+
+pub async fn get_job(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, JobError> {
+    let job = load_job(&state, &credentials, &id).await?;
+    Ok(Json(json!({
+        "id": job.id,
+        "status": job.status,
+        "run_at": job.run_at,
+        "results": job.results,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct ResultsQuery {
+    #[serde(default)]
+    after: usize, // results the client already has
+    #[serde(default)]
+    wait: Option<String>, // seconds to wait for new results, like "30" or "30s"
+}
+
+// long-polling for clients whose proxies break SSE and websockets: answers
+// once there are results past `after`, the job is done, or `wait` runs out
+pub async fn get_job_results(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+    Query(query): Query<ResultsQuery>,
+) -> Result<Json<Value>, JobError> {
+    let wait = match &query.wait {
+        Some(wait) => wait
+            .strip_suffix('s')
+            .unwrap_or(wait)
+            .parse::<u64>()
+            .map_err(|_| job_error((StatusCode::BAD_REQUEST, format!("invalid wait {}", wait))))?,
+        None => 0,
+    };
+    let deadline = Instant::now() + Duration::from_secs(wait.min(MAX_RESULTS_WAIT_SECS));
+
+    let mut job = load_job(&state, &credentials, &id).await?;
+    while job.results.len() <= query.after
+        && !matches!(job.status, JobStatus::Done)
+        && Instant::now() < deadline
+    {
+        time::sleep(RESULTS_POLL_INTERVAL).await;
+        job = load_job(&state, &credentials, &id).await?;
+    }
+
+    let results = job.results.get(query.after..).unwrap_or_default();
+    Ok(Json(json!({
+        "id": job.id,
+        "status": job.status,
+        "results": results,
+        "next": query.after.max(job.results.len()),
+        "done": matches!(job.status, JobStatus::Done),
+    })))
+}
+
+async fn load_job(state: &AppState, credentials: &Credentials, id: &str) -> Result<Job, JobError> {
+    let tenant = auth::authenticate(state, credentials, Scope::Execute).map_err(job_error)?;
+    let mut connection = state
+        .redis()
+        .map_err(|e| job_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    let job = jobs::get(&mut connection, id)
+        .await
+        .map_err(|e| job_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+
+    // other tenants' jobs look the same as missing ones
+    match job {
+        Some(job) if job.tenant == tenant => Ok(job),
+        _ => Err(job_error((StatusCode::NOT_FOUND, format!("no job {}", id)))),
+    }
+}
@@ -0,0 +1,96 @@
+use axum::{Extension, Json, extract::State, http::StatusCode};
+use metrics::counter;
+use serde_json::{Value, json};
+
+use crate::admission;
+use crate::auth::{self, Credentials, Scope};
+use crate::client_ip::ClientIp;
+use crate::files::FileManager;
+use crate::handlers::run::{check_quota, tenant_label};
+use crate::judge;
+use crate::problems;
+use crate::types::{AppState, JudgeRequest, JudgeResult};
+use crate::usage;
+use crate::utils::gen_random_id;
+use crate::worker::Worker;
+
+#[tracing::instrument(skip(state, credentials, request), fields(problem = %request.problem))]
+pub async fn judge_endpoint(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    credentials: Credentials,
+    Json(request): Json<JudgeRequest>,
+) -> Result<Json<JudgeResult>, (StatusCode, Json<Value>)> {
+    let reject = |(status, err): (StatusCode, String)| {
+        tracing::warn!("rejected judge request: {}", err);
+        (status, Json(json!({ "error": err })))
+    };
+
+    let tenant = auth::authenticate(&state, &credentials, Scope::Execute).map_err(reject)?;
+    counter!("judge_requests_total", "tenant" => tenant_label(tenant.as_deref())).increment(1);
+
+    let mut connection = state
+        .redis()
+        .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    let problem = problems::get(&mut connection, &request.problem)
+        .await
+        .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?
+        .filter(|problem| problem.tenant == tenant)
+        .ok_or_else(|| {
+            reject((
+                StatusCode::NOT_FOUND,
+                format!("no problem {}", request.problem),
+            ))
+        })?;
+
+    // a checker is another execution per testcase
+    let runs_per_test = if problem.checker.is_some() { 2 } else { 1 };
+    let executions = (problem.testcases.len() * runs_per_test) as u64;
+    admission::check(&state).await.map_err(reject)?;
+    check_quota(&state, tenant.as_deref(), executions)
+        .await
+        .map_err(reject)?;
+
+    let id = gen_random_id(10);
+    let registration = state
+        .sandboxes
+        .register(id.clone(), tenant.clone(), Some(client_ip));
+    let mut worker = Worker::new(
+        format!("{}/{}", state.base_code_path, id),
+        Box::new(FileManager::new(&state)),
+        state.config.clone(),
+        registration.sandbox.clone(),
+        state.images.clone(),
+        state.file_cache.clone(),
+        state.transfers.clone(),
+    );
+
+    if let Err(e) = worker.write_files(request.files).await {
+        worker.cleanup().await;
+        return Err(reject((
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("failed to write file: {}", e),
+        )));
+    }
+
+    let labels = request.execution.labels.clone();
+    let result = judge::judge(
+        &state,
+        connection,
+        &mut worker,
+        &problem,
+        request.execution,
+        request.stop_on_failure,
+        tenant.as_deref(),
+    )
+    .await;
+    usage::emit(&state, &id, tenant.as_deref(), &labels, worker.take_usage());
+    worker.cleanup().await;
+    tracing::info!(
+        verdict = ?result.verdict,
+        passed = result.passed,
+        total = result.total,
+        "judging finished"
+    );
+    Ok(Json(result))
+}
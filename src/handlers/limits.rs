@@ -0,0 +1,24 @@
+use axum::{
+    Json,
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+// bodies are read up to the route's DefaultBodyLimit and then dropped, this
+// turns axum's plain text rejection into the json errors clients get elsewhere
+pub async fn payload_too_large(request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE {
+        return response;
+    }
+    tracing::warn!(path, "rejected oversized request body");
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!({ "error": "request body is larger than the server accepts" })),
+    )
+        .into_response()
+}
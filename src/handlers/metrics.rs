@@ -1,10 +1,25 @@
 use axum::extract::State;
-use axum::http::{HeaderMap, HeaderValue, StatusCode, header::CONTENT_TYPE};
-use axum::response::IntoResponse;
+use axum::http::{
+    HeaderMap, HeaderValue, StatusCode, header::AUTHORIZATION, header::CONTENT_TYPE,
+    header::WWW_AUTHENTICATE,
+};
+use axum::response::{IntoResponse, Response};
+use base64::{Engine, engine::general_purpose::STANDARD};
 
+use crate::auth::{bearer, secrets_match};
 use crate::types::AppState;
 
-pub async fn metrics_endpoint(State(state): State<AppState>) -> impl IntoResponse {
+pub async fn metrics_endpoint(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(token) = &state.config.metrics_token
+        && !authorized(&headers, token)
+    {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"metrics\""),
+        );
+        return (StatusCode::UNAUTHORIZED, headers, "invalid metrics token").into_response();
+    }
     state.prometheus_handle.run_upkeep();
     let body = state.prometheus_handle.render();
     let mut headers = HeaderMap::new();
@@ -12,5 +27,29 @@ pub async fn metrics_endpoint(State(state): State<AppState>) -> impl IntoRespons
         CONTENT_TYPE,
         HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
     );
-    (StatusCode::OK, headers, body)
+    (StatusCode::OK, headers, body).into_response()
+}
+
+// the token as a bearer token, or as the password of basic auth with any
+// username, since scrapers support one or the other
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let given = match bearer(headers) {
+        Some(given) => given.to_string(),
+        None => {
+            let Some(basic) = headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Basic "))
+                .and_then(|value| STANDARD.decode(value.trim()).ok())
+            else {
+                return false;
+            };
+            let basic = String::from_utf8_lossy(&basic).to_string();
+            match basic.split_once(':') {
+                Some((_, password)) => password.to_string(),
+                None => return false,
+            }
+        }
+    };
+    secrets_match(&given, token)
 }
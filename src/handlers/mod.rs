@@ -1,2 +1,11 @@
+pub mod admin;
+pub mod capabilities;
+pub mod cron;
+pub mod files;
+pub mod jobs;
+pub mod judge;
+pub mod limits;
 pub mod metrics;
+pub mod problems;
 pub mod run;
+pub mod stats;
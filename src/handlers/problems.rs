@@ -0,0 +1,97 @@
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde_json::{Value, json};
+
+use crate::auth::{self, Credentials, Scope};
+use crate::files::RedisFileManager;
+use crate::problems::{self, Problem};
+use crate::types::AppState;
+use crate::utils::gen_random_id;
+
+type ProblemError = (StatusCode, Json<Value>);
+
+fn problem_error((status, err): (StatusCode, String)) -> ProblemError {
+    (status, Json(json!({ "error": err })))
+}
+
+// problems of other tenants look the same as missing ones
+async fn find(
+    state: &AppState,
+    credentials: &Credentials,
+    id: &str,
+    scope: Scope,
+) -> Result<Problem, ProblemError> {
+    let tenant = auth::authenticate(state, credentials, scope).map_err(problem_error)?;
+    let mut connection = state
+        .redis()
+        .map_err(|e| problem_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    let problem = problems::get(&mut connection, id)
+        .await
+        .map_err(|e| problem_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    match problem {
+        Some(problem) if problem.tenant == tenant => Ok(problem),
+        _ => Err(problem_error((
+            StatusCode::NOT_FOUND,
+            format!("no problem {}", id),
+        ))),
+    }
+}
+
+#[tracing::instrument(skip(state, credentials, body), fields(size = body.len()))]
+pub async fn import_problem(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Problem>), ProblemError> {
+    let tenant =
+        auth::authenticate(&state, &credentials, Scope::FilesWrite).map_err(problem_error)?;
+    let mut connection = state
+        .redis()
+        .map_err(|e| problem_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    let mut file_manager = RedisFileManager::new(connection.clone(), state.encryption.clone());
+    let problem = problems::import(
+        &mut file_manager,
+        &mut connection,
+        gen_random_id(16),
+        tenant,
+        &body,
+        state.config.max_problem_size,
+    )
+    .await
+    .map_err(problem_error)?;
+    tracing::info!(
+        id = problem.id,
+        testcases = problem.testcases.len(),
+        "problem imported"
+    );
+    Ok((StatusCode::CREATED, Json(problem)))
+}
+
+pub async fn get_problem(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<Json<Problem>, ProblemError> {
+    Ok(Json(find(&state, &credentials, &id, Scope::Execute).await?))
+}
+
+#[tracing::instrument(skip(state, credentials))]
+pub async fn delete_problem(
+    State(state): State<AppState>,
+    credentials: Credentials,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ProblemError> {
+    let problem = find(&state, &credentials, &id, Scope::FilesWrite).await?;
+    let mut connection = state
+        .redis()
+        .map_err(|e| problem_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    let mut file_manager = RedisFileManager::new(connection.clone(), state.encryption.clone());
+    problems::delete(&mut connection, &mut file_manager, &problem)
+        .await
+        .map_err(|e| problem_error((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
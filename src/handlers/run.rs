@@ -1,9 +1,10 @@
 use async_stream::try_stream;
 use axum::{
-    Json,
+    Extension, Json,
+    body::Bytes,
     extract::{
         State,
-        ws::{Message, Utf8Bytes},
+        ws::{CloseFrame, Message, Utf8Bytes, close_code},
     },
     response::{
         Sse,
@@ -12,22 +13,46 @@ use axum::{
 };
 use axum::{
     extract::ws::{WebSocket, WebSocketUpgrade},
-    response::Response,
+    http::{HeaderMap, StatusCode, header::SEC_WEBSOCKET_PROTOCOL},
+    response::{IntoResponse, Response},
 };
-use futures_util::Stream;
-use metrics::{counter, gauge, histogram};
-use serde_json::json;
+use futures_util::{FutureExt, Stream};
+use metrics::{Label, counter, gauge, histogram};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::any::Any;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::time::Instant;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::pin;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{self, MissedTickBehavior};
 
 use crate::{
-    files::RedisFileManager,
-    types::{AppState, Execution, ExecutionMessage, ExecutionRequest, ExecutionResult},
+    admission,
+    auth::{self, Credentials, Scope},
+    client_ip::ClientIp,
+    connections::ConnectionSlot,
+    files::FileManager,
+    idempotency::{self, IDEMPOTENCY_KEY_HEADER, Lookup},
+    jobs, quota, runner,
+    sandboxes::{KILLED_ON_DISCONNECT, Sandbox},
+    templates,
+    types::{
+        AppConfig, AppState, CompileStep, Execution, ExecutionError, ExecutionMessage,
+        ExecutionRequest, ExecutionResult, ExecutionSummary, ExecutionTransfer, FilePath,
+        OutcomeError, Progress, ProgressPhase, RunEvent,
+    },
+    usage,
     utils::gen_random_id,
     worker::Worker,
 };
 
+// tmp slot carrying the compile step's artifact to the executions
+const ARTIFACT_TMP_ID: u64 = u64::MAX - 1;
+
 struct GaugeGuard {
     name: &'static str,
 }
@@ -45,20 +70,87 @@ impl Drop for GaugeGuard {
     }
 }
 
-#[tracing::instrument(skip(worker), fields(program = %request.program))]
-async fn execute_execution(
+#[tracing::instrument(skip(worker, state), fields(program = %request.program))]
+pub(crate) async fn execute_execution(
     worker: &mut Worker,
     request: Execution,
-) -> Result<ExecutionResult, String> {
+    state: &AppState,
+    tenant: Option<&str>,
+) -> Result<ExecutionResult, OutcomeError> {
     let _guard = GaugeGuard::new("active_executions");
     tracing::debug!("starting execution");
-    let result = worker.execute(request).await;
+    let mut retries = 0;
+    let mut errors = Vec::new();
+    let result = loop {
+        // each attempt runs in a fresh container over the same working directory.
+        // a panic fails the execution instead of the task running the request
+        let result = match AssertUnwindSafe(worker.execute(request.clone()))
+            .catch_unwind()
+            .await
+        {
+            Ok(result) => result,
+            Err(panic) => {
+                tracing::error!("execution panicked: {}", panic_message(panic.as_ref()));
+                counter!("execution_panics_total", "tenant" => tenant_label(tenant)).increment(1);
+                worker.recover_from_panic().await;
+                Err(ExecutionError {
+                    message: "internal error while running the execution".to_string(),
+                })
+            }
+        };
+        if let Err(e) = &result
+            && worker.failed_on_infrastructure()
+            && retries < state.config.infrastructure_retries
+        {
+            retries += 1;
+            errors.push(e.message.clone());
+            tracing::warn!(
+                retries,
+                "retrying after infrastructure error: {}",
+                e.message
+            );
+            counter!("execution_retries_total", "tenant" => tenant_label(tenant)).increment(1);
+            continue;
+        }
+        break result;
+    };
+
+    let stored_bytes = worker.take_stored_bytes();
+    worker.record_usage(result.as_ref().ok(), stored_bytes);
+    if let Some(tenant) = tenant
+        && quota::applies(&state.config.tenants[tenant])
+    {
+        // settings::validate keeps quotas to nodes with redis
+        if let Ok(mut connection) = state.redis() {
+            let cpu_ms = result.as_ref().map_or(0, |r| r.time_used as u64);
+            quota::record(&mut connection, tenant, cpu_ms, stored_bytes).await;
+        }
+    }
 
+    state.stats.record(result.is_err());
+
+    let labels = metric_labels(&state.config, tenant, &request.labels);
+    let outcome = |outcome: &'static str, status: &'static str| {
+        let mut labels = labels.clone();
+        labels.extend([Label::new("outcome", outcome), Label::new("status", status)]);
+        labels
+    };
     if let Err(e) = &result {
         tracing::error!("error executing code: {}", e.message);
-        counter!("executions_total", "outcome" => "error").increment(1);
+        counter!("executions_total", outcome("error", "none")).increment(1);
 
-        return Err(format!("failed to execute code: {}", e.message));
+        // kept with the request when it is dead-lettered
+        if worker.failed_on_infrastructure() {
+            errors.push(e.message.clone());
+        } else {
+            errors.clear();
+        }
+        return Err(OutcomeError {
+            error: format!("failed to execute code: {}", e.message),
+            id: request.id,
+            diagnostics: worker.take_diagnostics(),
+            errors,
+        });
     }
 
     let result = result.unwrap();
@@ -68,49 +160,152 @@ async fn execute_execution(
         memory_used = result.memory_used,
         "execution finished"
     );
-    counter!("executions_total", "outcome" => "ok").increment(1);
-    histogram!("execution_time_ms").record(result.time_used as f64);
-    histogram!("execution_memory_kb").record(result.memory_used as f64);
+    counter!("executions_total", outcome("ok", result.status.as_str())).increment(1);
+    histogram!("execution_time_ms", labels.clone()).record(result.time_used as f64);
+    histogram!("execution_memory_kb", labels).record(result.memory_used as f64);
 
     Ok(result)
 }
 
 #[tracing::instrument(skip(state, tx), fields(files_count = payload.files.len(), executions_count = payload.executions.len()))]
-async fn execute_code_inner(
+pub(crate) async fn execute_code_inner(
     state: AppState,
     payload: ExecutionRequest,
-    tx: Sender<Result<ExecutionResult, String>>,
+    tx: Sender<RunEvent>,
+    tenant: Option<String>,
+    client_ip: Option<IpAddr>,
 ) {
     let start = Instant::now();
+    let label = tenant_label(tenant.as_deref());
     let _guard = GaugeGuard::new("active_workers");
     tracing::info!("processing execution request");
+    let id = gen_random_id(10);
+    let registration = state
+        .sandboxes
+        .register(id.clone(), tenant.clone(), client_ip);
     let mut worker = Worker::new(
-        format!("{}/{}", state.base_code_path, gen_random_id(10)),
-        Box::new(RedisFileManager::new(state.redis_connection)),
+        format!("{}/{}", state.base_code_path, id),
+        Box::new(FileManager::new(&state)),
+        state.config.clone(),
+        registration.sandbox.clone(),
+        state.images.clone(),
+        state.file_cache.clone(),
+        state.transfers.clone(),
     );
+    let deadline = state.config.max_batch_wall_time;
+    worker.set_deadline(deadline.map(|secs| start + Duration::from_secs(secs)));
 
-    for file in payload.files {
-        if let Err(e) = worker.write_file(file).await {
-            tracing::error!("error writing file: {}", e);
-            counter!("executions_total", "outcome" => "error").increment(1);
-            worker.cleanup().await;
-            histogram!("execution_total_duration_ms").record(start.elapsed().as_millis() as f64);
+    let total = payload.execution_count();
+    if let Err(e) = worker.write_files(payload.files).await {
+        tracing::error!("error writing file: {}", e);
+        counter!(
+            "executions_total",
+            "outcome" => "error",
+            "status" => "none",
+            "tenant" => label.clone()
+        )
+        .increment(1);
+        worker.cleanup().await;
+        histogram!("execution_total_duration_ms", "tenant" => label)
+            .record(start.elapsed().as_millis() as f64);
+
+        let error = format!("failed to write file: {}", e);
+        let _ = tx.send(RunEvent::error(error)).await;
+        return;
+    }
+
+    let progress = async |phase, index, completed| {
+        if payload.progress {
+            let progress = Progress::new(phase, index, completed, total);
+            let _ = tx.send(RunEvent::Progress(progress)).await;
+        }
+    };
+    progress(ProgressPhase::FilesStaged, None, 0).await;
 
-            let _ = tx.send(Err(format!("failed to write file: {}", e))).await;
+    let mut completed = 0;
+    // past the deadline already, the executions below are skipped too
+    if let Some(step) = &payload.compile
+        && !worker.past_deadline()
+    {
+        progress(ProgressPhase::CompileStarted, None, 0).await;
+        let compile = execute_execution(
+            &mut worker,
+            compile_execution(step, payload.labels.clone()),
+            &state,
+            tenant.as_deref(),
+        );
+        let result = unless_disconnected(&tx, &registration.sandbox, compile)
+            .await
+            .map(|result| ExecutionResult {
+                compile: true,
+                ..result
+            });
+        let compiled = result
+            .as_ref()
+            .is_ok_and(|result| result.exit_code == 0 && result.skipped_copy_out.is_empty());
+        let _ = tx
+            .send(RunEvent::outcome(result.map_err(|e| OutcomeError {
+                error: format!("compile step failed: {}", e.error),
+                ..e
+            })))
+            .await;
+        completed += 1;
+        if !compiled {
+            tracing::info!("compile step failed, skipping executions");
+            usage::emit(
+                &state,
+                &id,
+                tenant.as_deref(),
+                &payload.labels,
+                worker.take_usage(),
+            );
+            worker.cleanup().await;
+            histogram!("execution_total_duration_ms", "tenant" => label)
+                .record(start.elapsed().as_millis() as f64);
             return;
         }
     }
 
-    for request in payload.executions {
+    for (index, mut request) in payload.executions.into_iter().enumerate() {
+        if worker.past_deadline() {
+            tracing::info!(index, "batch ran out of wall time, skipping the rest");
+            counter!("batch_wall_time_exceeded_total", "tenant" => label.clone()).increment(1);
+            break;
+        }
+        progress(ProgressPhase::ExecutionStarted, Some(index), completed).await;
         let die_on_error = request.die_on_error;
+        let mut labels = payload.labels.clone();
+        labels.append(&mut request.labels);
+        request.labels = labels;
+        if let Some(step) = &payload.compile {
+            // restored each time, so an execution can't break the artifact for the next
+            request.copy_in.insert(
+                0,
+                ExecutionTransfer {
+                    from: FilePath::Tmp {
+                        id: ARTIFACT_TMP_ID,
+                    },
+                    to: FilePath::Local {
+                        name: step.artifact.clone(),
+                        executable: true,
+                    },
+                    optional: false,
+                    sha256: None,
+                },
+            );
+        }
 
-        let result = execute_execution(&mut worker, request).await;
+        let execution = execute_execution(&mut worker, request, &state, tenant.as_deref());
+        let result = unless_disconnected(&tx, &registration.sandbox, execution).await;
         let exit_code = match &result {
             Ok(res) => res.exit_code,
             Err(_) => 1,
         };
-        if let Ok(res) = result {
-            let _ = tx.send(Ok(res)).await;
+        completed += 1;
+        let killed = result.is_err() && registration.sandbox.is_killed();
+        let _ = tx.send(RunEvent::outcome(result)).await;
+        if killed {
+            break;
         }
 
         if die_on_error && exit_code != 0 {
@@ -118,86 +313,617 @@ async fn execute_code_inner(
         }
     }
 
+    usage::emit(
+        &state,
+        &id,
+        tenant.as_deref(),
+        &payload.labels,
+        worker.take_usage(),
+    );
     worker.cleanup().await;
-    histogram!("execution_total_duration_ms").record(start.elapsed().as_millis() as f64);
+    histogram!("execution_total_duration_ms", "tenant" => label)
+        .record(start.elapsed().as_millis() as f64);
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+// waits for `run`, killing the sandbox if the client goes away meanwhile. the
+// killed execution fails at once and so do the ones after it, so a batch
+// nobody listens to anymore ends early
+async fn unless_disconnected<T>(
+    tx: &Sender<RunEvent>,
+    sandbox: &Sandbox,
+    run: impl Future<Output = T>,
+) -> T {
+    let mut run = pin!(run);
+    tokio::select! {
+        // a client already gone gets nothing started for it
+        biased;
+        _ = tx.closed() => {
+            tracing::info!("client disconnected, killing the sandbox");
+            sandbox.kill(KILLED_ON_DISCONNECT);
+            run.await
+        }
+        output = &mut run => output,
+    }
+}
+
+// the compiler's output is returned, its artifact kept in a tmp slot. a
+// missing artifact is left to the caller to report as a failed compilation
+fn compile_execution(step: &CompileStep, labels: BTreeMap<String, String>) -> Execution {
+    Execution {
+        program: step.program.clone(),
+        args: step.args.clone(),
+        time_limit: step.time_limit,
+        wall_time_limit: step.wall_time_limit,
+        memory_limit: step.memory_limit,
+        copy_out: vec![ExecutionTransfer {
+            from: FilePath::Local {
+                name: step.artifact.clone(),
+                executable: false,
+            },
+            to: FilePath::Tmp {
+                id: ARTIFACT_TMP_ID,
+            },
+            optional: true,
+            sha256: None,
+        }],
+        copy_in: Vec::new(),
+        return_files: vec![
+            FilePath::Stdout { max_size: None },
+            FilePath::Stderr { max_size: None },
+        ],
+        die_on_error: true,
+        autofix: None,
+        niceness: None,
+        seccomp_audit: false,
+        profile: step.profile.clone(),
+        egress_allow: None,
+        gpu: false,
+        score_from: None,
+        rootfs: step.rootfs.clone(),
+        cache: false,
+        debug: false,
+        trace: false,
+        perf: false,
+        shell: false,
+        workdir: None,
+        return_by_reference: false,
+        labels,
+        id: step.id.clone(),
+    }
 }
 
-#[tracing::instrument(skip(state))]
+// checks the caller's tenant, if any, has quota left for `executions` runs
+pub(crate) async fn check_quota(
+    state: &AppState,
+    tenant: Option<&str>,
+    executions: u64,
+) -> Result<(), (StatusCode, String)> {
+    let Some(name) = tenant else {
+        return Ok(());
+    };
+    let limits = &state.config.tenants[name];
+    if !quota::applies(limits) {
+        return Ok(());
+    }
+    let mut connection = state
+        .redis()
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e))?;
+    quota::check(&mut connection, name, limits, executions).await
+}
+
+// the tenant and the allowlisted labels of an execution, for the metrics
+// recorded per execution. labels come from clients, so only the operator's
+// metric_labels become metric labels, to bound their cardinality
+fn metric_labels(
+    config: &AppConfig,
+    tenant: Option<&str>,
+    labels: &BTreeMap<String, String>,
+) -> Vec<Label> {
+    let mut metric_labels = vec![Label::new("tenant", tenant_label(tenant))];
+    metric_labels.extend(
+        config
+            .metric_labels
+            .iter()
+            .map(|name| Label::new(name.clone(), labels.get(name).cloned().unwrap_or_default())),
+    );
+    metric_labels
+}
+
+// tenant names are operator-chosen, so they are safe to use as metric labels
+pub(crate) fn tenant_label(tenant: Option<&str>) -> String {
+    tenant.unwrap_or("anonymous").to_string()
+}
+
+#[tracing::instrument(skip(state, credentials))]
 pub async fn execute_code_endpoint(
     State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    credentials: Credentials,
     Json(payload): Json<ExecutionRequest>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let (tx, mut rx) = mpsc::channel::<Result<ExecutionResult, String>>(100);
-    counter!("requests_total").increment(1);
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<Value>)> {
     tracing::info!("received execution request");
+    let reject = |(status, err): (StatusCode, String)| {
+        tracing::warn!("rejected execution request: {}", err);
+        (status, Json(json!({ "error": err })))
+    };
+
+    let tenant = auth::authenticate(&state, &credentials, Scope::Execute).map_err(|e| {
+        counter!("requests_total", "tenant" => "unauthenticated").increment(1);
+        reject(e)
+    })?;
+    counter!("requests_total", "tenant" => tenant_label(tenant.as_deref())).increment(1);
+
+    let payload = templates::expand(&state.config, payload)
+        .map_err(|e| reject((StatusCode::BAD_REQUEST, e)))?;
+
+    let idempotency_key = match credentials.headers.get(IDEMPOTENCY_KEY_HEADER) {
+        // a detached request's results are already kept, as a job
+        Some(_) if payload.detach => {
+            return Err(reject((
+                StatusCode::BAD_REQUEST,
+                "detached requests take no idempotency key, fetch their job instead".to_string(),
+            )));
+        }
+        Some(value) => {
+            let key = value.to_str().unwrap_or_default();
+            let key = idempotency::redis_key(key, tenant.as_deref())
+                .map_err(|e| reject((StatusCode::BAD_REQUEST, e)))?;
+            Some((key, idempotency::fingerprint(&payload)))
+        }
+        None => None,
+    };
+
+    let payload_executions = payload.execution_count();
+    let (tx, mut rx) = mpsc::channel::<RunEvent>(100);
+    let mut replay = Vec::new();
+    let mut detached_id = None;
+    let mut idempotency_connection = None;
+    if let Some((key, fingerprint)) = &idempotency_key {
+        let mut connection = state
+            .redis()
+            .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+        let lookup = idempotency::lookup(&mut connection, key, fingerprint)
+            .await
+            .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+        match lookup {
+            Lookup::Done(events) => {
+                tracing::info!("replaying stored result for idempotency key");
+                replay = events;
+            }
+            Lookup::Pending => {
+                return Err(reject((
+                    StatusCode::CONFLICT,
+                    "a request with this idempotency key is in progress".to_string(),
+                )));
+            }
+            Lookup::Mismatch => {
+                return Err(reject((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "idempotency key was already used with a different request".to_string(),
+                )));
+            }
+            Lookup::Fresh => {}
+        }
+        idempotency_connection = Some(connection);
+    }
+
+    if replay.is_empty() {
+        admission::check(&state).await.map_err(reject)?;
+        check_quota(&state, tenant.as_deref(), payload.execution_count())
+            .await
+            .map_err(reject)?;
+
+        if let (Some((key, fingerprint)), Some(connection)) =
+            (&idempotency_key, &mut idempotency_connection)
+        {
+            let claimed = idempotency::claim(connection, key, fingerprint)
+                .await
+                .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+            if !claimed {
+                return Err(reject((
+                    StatusCode::CONFLICT,
+                    "a request with this idempotency key is in progress".to_string(),
+                )));
+            }
+        }
+
+        let detached = if payload.detach {
+            let mut connection = state
+                .redis()
+                .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+            let ttl = state.config.job_ttl_secs;
+            let job = jobs::start_detached(&mut connection, tenant.clone(), ttl)
+                .await
+                .map_err(|e| reject((StatusCode::SERVICE_UNAVAILABLE, e)))?;
+            tracing::info!(id = job.id, "running detached request as a job");
+            detached_id = Some(job.id.clone());
+            Some((job, connection))
+        } else {
+            None
+        };
+
+        tokio::spawn(async move {
+            match (detached, idempotency_key.zip(idempotency_connection)) {
+                // the job outlives the client, which gets the events while it's there
+                (Some((job, connection)), _) => {
+                    let client = Some(tx);
+                    jobs::execute(state, connection, job, payload, client, Some(client_ip)).await
+                }
+                (None, None) => {
+                    runner::execute(state, payload, tx, tenant, Some(client_ip), true).await
+                }
+                (None, Some(((key, fingerprint), mut connection))) => {
+                    // keep what the client is sent so a retry can replay it
+                    let ttl = state.config.idempotency_ttl_secs;
+                    let (inner_tx, mut inner_rx) = mpsc::channel(100);
+                    let run = tokio::spawn(runner::execute(
+                        state,
+                        payload,
+                        inner_tx,
+                        tenant,
+                        Some(client_ip),
+                        true,
+                    ));
+                    let heartbeat = {
+                        let mut connection = connection.clone();
+                        let key = key.clone();
+                        tokio::spawn(async move {
+                            let period = Duration::from_secs(idempotency::HEARTBEAT_INTERVAL_SECS);
+                            let mut ticks =
+                                time::interval_at(time::Instant::now() + period, period);
+                            loop {
+                                ticks.tick().await;
+                                idempotency::heartbeat(&mut connection, &key).await;
+                            }
+                        })
+                    };
 
-    tokio::spawn(async move {
-        let _ = execute_code_inner(state, payload, tx).await;
-    });
-
-    Sse::new(try_stream! {
-        loop {
-            match rx.recv().await {
-                Some(data) => {
-                    match data {
-                        Ok(json) => {
-                            yield Event::default().data(serde_json::to_string(&json).unwrap());
-                        },
-                        Err(err) => {
-                            tracing::error!("error executing code: {}", err);
-                            yield Event::default().data(json!({ "error": err }).to_string());
+                    // progress is only for the client watching the run
+                    let mut events = Vec::new();
+                    while let Some(event) = inner_rx.recv().await {
+                        if let RunEvent::Outcome(data) = &event {
+                            events.push(event_data(data));
                         }
+                        let _ = tx.send(event).await;
                     }
-                },
-                None => {
-                    break;
+                    let finished = run.await;
+                    heartbeat.abort();
+                    match finished {
+                        Ok(()) => {
+                            idempotency::complete(&mut connection, &key, &fingerprint, events, ttl)
+                                .await
+                        }
+                        Err(_) => idempotency::release(&mut connection, &key).await,
+                    }
+                }
+            }
+        });
+    } else {
+        drop(tx);
+    }
+
+    let mut summary = ExecutionSummary {
+        executions: payload_executions,
+        ..Default::default()
+    };
+    Ok(Sse::new(try_stream! {
+        if let Some(id) = detached_id {
+            yield Event::default().event("detached").data(json!({ "id": id }).to_string());
+        }
+        for data in replay {
+            // stored payloads keep only the data, errors don't parse as results
+            let outcome = serde_json::from_str::<ExecutionResult>(&data)
+                .map_err(|_| OutcomeError::from(data.clone()));
+            tally(&mut summary, &outcome);
+            yield Event::default().event(event_name(&outcome)).data(data);
+        }
+        while let Some(event) = rx.recv().await {
+            let data = match event {
+                RunEvent::Progress(progress) => {
+                    yield Event::default()
+                        .event("progress")
+                        .data(serde_json::to_string(&progress).unwrap());
+                    continue;
                 }
+                RunEvent::Outcome(data) => *data,
+            };
+            if let Err(err) = &data {
+                tracing::error!("error executing code: {}", err.error);
             }
+            tally(&mut summary, &data);
+            yield Event::default().event(event_name(&data)).data(event_data(&data));
         }
+        // clients can't tell a finished batch from a dropped connection otherwise
+        summary.skipped = summary.executions.saturating_sub(summary.succeeded + summary.failed + summary.errors);
+        yield Event::default().event("done").data(serde_json::to_string(&summary).unwrap());
     })
-    .keep_alive(KeepAlive::default())
+    .keep_alive(KeepAlive::default()))
+}
+
+// SSE payload of one execution outcome
+pub(crate) fn event_data(data: &Result<ExecutionResult, OutcomeError>) -> String {
+    match data {
+        Ok(result) => serde_json::to_string(result).unwrap(),
+        Err(err) => serde_json::to_string(err).unwrap(),
+    }
+}
+
+fn event_name(data: &Result<ExecutionResult, OutcomeError>) -> &'static str {
+    match data {
+        Ok(_) => "result",
+        Err(_) => "error",
+    }
+}
+
+fn tally(summary: &mut ExecutionSummary, data: &Result<ExecutionResult, OutcomeError>) {
+    match data {
+        Ok(result) => {
+            if result.exit_code == 0 {
+                summary.succeeded += 1;
+            } else {
+                summary.failed += 1;
+            }
+            summary.time_used += result.time_used;
+            summary.wall_time_used += result.wall_time_used;
+            summary.files_returned += result.return_files.len();
+        }
+        Err(_) => summary.errors += 1,
+    }
+}
+
+// message format of a websocket connection, named by the subprotocol the
+// client asked for
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum WsProtocol {
+    JsonV1,
+}
+
+// subprotocols the server speaks, connections without one get the first
+pub(crate) const WS_PROTOCOLS: &[(&str, WsProtocol)] = &[("pentagon.v1.json", WsProtocol::JsonV1)];
+
+impl WsProtocol {
+    fn decode(self, msg: &Message) -> Result<ExecutionMessage, String> {
+        match self {
+            WsProtocol::JsonV1 => {
+                let text = msg.to_text().map_err(|e| e.to_string())?;
+                serde_json::from_str(text).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn encode(self, value: &impl Serialize) -> Message {
+        match self {
+            WsProtocol::JsonV1 => {
+                Message::Text(Utf8Bytes::from(serde_json::to_string(value).unwrap()))
+            }
+        }
+    }
+}
+
+// the first subprotocol of the client's list the server speaks, or the
+// unknown ones it asked for
+fn negotiate_protocol(headers: &HeaderMap) -> Result<(&'static str, WsProtocol), Vec<String>> {
+    let requested: Vec<String> = headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if requested.is_empty() {
+        return Ok(WS_PROTOCOLS[0]);
+    }
+    requested
+        .iter()
+        .find_map(|name| {
+            WS_PROTOCOLS
+                .iter()
+                .find(|(known, _)| known == name)
+                .copied()
+        })
+        .ok_or(requested)
+}
+
+// closes a connection asking only for subprotocols the server doesn't
+// speak. browsers drop a handshake that picks none of theirs without saying
+// why, so one is echoed for the close frame to arrive
+fn reject_protocols(ws: WebSocketUpgrade, requested: Vec<String>) -> Response {
+    tracing::warn!(
+        ?requested,
+        "rejected websocket connection with unknown subprotocols"
+    );
+    let supported = WS_PROTOCOLS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    ws.protocols([requested[0].clone()])
+        .on_upgrade(move |mut socket| async move {
+            let _ = socket
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::PROTOCOL,
+                    reason: Utf8Bytes::from(format!("unsupported subprotocol, use {}", supported)),
+                })))
+                .await;
+        })
 }
 
 pub async fn execute_code_ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    headers: HeaderMap,
+    credentials: Credentials,
 ) -> Response {
-    ws.on_upgrade(|ws| handle_socket(ws, state))
+    let tenant = match auth::authenticate(&state, &credentials, Scope::Execute) {
+        Ok(tenant) => tenant,
+        Err((status, err)) => return (status, Json(json!({ "error": err }))).into_response(),
+    };
+    let (name, protocol) = match negotiate_protocol(&headers) {
+        Ok(negotiated) => negotiated,
+        Err(requested) => return reject_protocols(ws, requested),
+    };
+    // clients that asked for none get none back
+    let ws = if headers.contains_key(SEC_WEBSOCKET_PROTOCOL) {
+        ws.protocols([name])
+    } else {
+        ws
+    };
+
+    // the same cap as for http bodies, messages carry inline files too
+    let max_message_size = state.config.max_request_size as usize;
+    let ws = ws.max_message_size(max_message_size);
+
+    // counted per api key, anonymous clients per address
+    let client = match &tenant {
+        Some(tenant) => format!("tenant:{}", tenant),
+        None => format!("ip:{}", client_ip),
+    };
+    let Some(slot) = state.ws_connections.acquire(client) else {
+        tracing::warn!(%client_ip, "rejected websocket connection over the per-client limit");
+        counter!("websocket_connections_rejected_total", "tenant" => tenant_label(tenant.as_deref()))
+            .increment(1);
+        // closing after the upgrade gives browsers a reason, a refused
+        // handshake only shows up as a generic error
+        return ws.on_upgrade(|mut socket| async move {
+            let _ = socket
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: Utf8Bytes::from_static("too many connections"),
+                })))
+                .await;
+        });
+    };
+    ws.on_upgrade(move |ws| handle_socket(ws, state, tenant, client_ip, protocol, slot))
 }
 
-#[tracing::instrument(skip(socket, state))]
-async fn handle_socket(mut socket: WebSocket, state: AppState) {
+#[tracing::instrument(skip(socket, state, _slot))]
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    tenant: Option<String>,
+    client_ip: IpAddr,
+    protocol: WsProtocol,
+    _slot: ConnectionSlot,
+) {
     let _guard = GaugeGuard::new("websocket_connections_active");
     let _worker_guard = GaugeGuard::new("active_workers");
+    let label = tenant_label(tenant.as_deref());
 
     tracing::info!("websocket connection established for code execution");
+    let id = gen_random_id(10);
+    let registration = state
+        .sandboxes
+        .register(id.clone(), tenant.clone(), Some(client_ip));
     let mut worker = Worker::new(
-        format!("{}/{}", state.base_code_path, gen_random_id(10)),
-        Box::new(RedisFileManager::new(state.redis_connection)),
+        format!("{}/{}", state.base_code_path, id),
+        Box::new(FileManager::new(&state)),
+        state.config.clone(),
+        registration.sandbox.clone(),
+        state.images.clone(),
+        state.file_cache.clone(),
+        state.transfers.clone(),
     );
 
-    while let Some(msg) = socket.recv().await {
+    // pings find peers that went away without closing, the idle timeout frees
+    // the sandbox of ones that stay but send nothing
+    let ping_interval = Duration::from_secs(state.config.ws_ping_interval_secs.max(1));
+    let mut ping = time::interval_at(time::Instant::now() + ping_interval, ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let idle_timeout = Duration::from_secs(state.config.ws_idle_timeout_secs);
+    let mut last_request = time::Instant::now();
+    let mut awaiting_pong = false;
+
+    // a killed sandbox ends the connection, which runs the cleanup below
+    loop {
+        let msg = tokio::select! {
+            // messages first, a pong that arrived during a long execution is
+            // read before the next ping checks for it
+            biased;
+            msg = socket.recv() => msg,
+            _ = registration.sandbox.killed() => None,
+            _ = time::sleep_until(last_request + idle_timeout) => {
+                tracing::info!("closing idle websocket connection");
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: Utf8Bytes::from_static("idle timeout"),
+                    })))
+                    .await;
+                None
+            }
+            _ = ping.tick() => {
+                if awaiting_pong {
+                    tracing::info!("websocket peer stopped answering pings");
+                    None
+                } else if socket.send(Message::Ping(Bytes::new())).await.is_err() {
+                    None
+                } else {
+                    awaiting_pong = true;
+                    continue;
+                }
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        match msg {
+            Ok(Message::Pong(_)) => {
+                awaiting_pong = false;
+                continue;
+            }
+            // pings are answered by axum, close frames end the stream after them
+            Ok(Message::Ping(_)) | Ok(Message::Close(_)) => continue,
+            _ => {}
+        }
+
         if let Ok(msg) = msg {
             let start = Instant::now();
             counter!("websocket_messages_received_total").increment(1);
-            let result = serde_json::from_str::<ExecutionMessage>(msg.to_text().unwrap());
+            let result = protocol.decode(&msg);
             if result.is_err() {
                 tracing::error!("invalid execution request: {}", result.err().unwrap());
                 continue;
             }
             let message = result.unwrap();
+
+            let executions = match &message {
+                ExecutionMessage::Single { .. } => 1,
+                ExecutionMessage::Batch { executions, .. } => executions.len() as u64,
+            };
+            let admitted = match admission::check(&state).await {
+                Ok(()) => check_quota(&state, tenant.as_deref(), executions).await,
+                Err(e) => Err(e),
+            };
+            if let Err((_, err)) = admitted {
+                tracing::warn!("rejected execution request: {}", err);
+                let msg = protocol.encode(&json!({ "error": err }));
+                if socket.send(msg).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+
             match message {
                 ExecutionMessage::Single { id, execution } => {
                     tracing::debug!(id = ?id, "processing single execution");
-                    let result = execute_execution(&mut worker, execution).await;
+                    let result =
+                        execute_execution(&mut worker, *execution, &state, tenant.as_deref()).await;
 
                     let msg = match result {
-                        Ok(res) => {
-                            Message::Text(Utf8Bytes::from(serde_json::to_string(&res).unwrap()))
-                        }
-                        Err(err) => {
-                            tracing::error!("error executing code: {}", err);
-                            Message::Text(Utf8Bytes::from(json!({ "error": err }).to_string()))
+                        Ok(res) => protocol.encode(&res),
+                        Err(error) => {
+                            tracing::error!("error executing code: {}", error.error);
+                            protocol.encode(&error)
                         }
                     };
 
@@ -207,21 +933,43 @@ async fn handle_socket(mut socket: WebSocket, state: AppState) {
                     counter!("websocket_messages_sent_total").increment(1);
                 }
 
-                ExecutionMessage::Batch { id, executions } => {
+                ExecutionMessage::Batch {
+                    id,
+                    executions,
+                    progress,
+                } => {
                     tracing::debug!(id = ?id, count = executions.len(), "processing batch execution");
-                    for execution in executions {
-                        let die_on_error = execution.die_on_error.clone();
-                        let result = execute_execution(&mut worker, execution).await;
+                    let total = executions.len() as u64;
+                    let deadline = state.config.max_batch_wall_time;
+                    worker.set_deadline(deadline.map(|secs| start + Duration::from_secs(secs)));
+                    for (index, execution) in executions.into_iter().enumerate() {
+                        if worker.past_deadline() {
+                            tracing::info!(index, "batch ran out of wall time, skipping the rest");
+                            counter!("batch_wall_time_exceeded_total", "tenant" => label.clone())
+                                .increment(1);
+                            break;
+                        }
+                        if progress {
+                            let progress = Progress::new(
+                                ProgressPhase::ExecutionStarted,
+                                Some(index),
+                                index as u64,
+                                total,
+                            );
+                            let msg = protocol.encode(&json!({ "progress": progress }));
+                            if socket.send(msg).await.is_err() {
+                                break;
+                            }
+                            counter!("websocket_messages_sent_total").increment(1);
+                        }
+                        let die_on_error = execution.die_on_error;
+                        let result =
+                            execute_execution(&mut worker, execution, &state, tenant.as_deref())
+                                .await;
 
                         match result {
                             Ok(res) => {
-                                if socket
-                                    .send(Message::Text(Utf8Bytes::from(
-                                        serde_json::to_string(&res).unwrap(),
-                                    )))
-                                    .await
-                                    .is_err()
-                                {
+                                if socket.send(protocol.encode(&res)).await.is_err() {
                                     break;
                                 }
                                 counter!("websocket_messages_sent_total").increment(1);
@@ -229,24 +977,30 @@ async fn handle_socket(mut socket: WebSocket, state: AppState) {
                                     break;
                                 }
                             }
-                            Err(err) => {
-                                tracing::error!("error executing code: {}", err);
-                                if socket
-                                    .send(Message::Text(Utf8Bytes::from(
-                                        json!({ "error": err }).to_string(),
-                                    )))
-                                    .await
-                                    .is_err()
-                                {
+                            Err(error) => {
+                                tracing::error!("error executing code: {}", error.error);
+                                let msg = protocol.encode(&error);
+                                if socket.send(msg).await.is_err() {
                                     break;
                                 }
                                 counter!("websocket_messages_sent_total").increment(1);
                             }
                         }
                     }
+                    worker.set_deadline(None);
                 }
             }
-            histogram!("execution_total_duration_ms").record(start.elapsed().as_millis() as f64);
+            // executions carry their own labels, the connection has none
+            usage::emit(
+                &state,
+                &id,
+                tenant.as_deref(),
+                &BTreeMap::new(),
+                worker.take_usage(),
+            );
+            histogram!("execution_total_duration_ms", "tenant" => label.clone())
+                .record(start.elapsed().as_millis() as f64);
+            last_request = time::Instant::now();
         } else {
             tracing::error!("error receiving websocket message: {}", msg.err().unwrap());
 
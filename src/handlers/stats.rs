@@ -0,0 +1,9 @@
+use axum::{Json, extract::State};
+
+use crate::stats::StatsSnapshot;
+use crate::types::AppState;
+
+pub async fn stats_endpoint(State(state): State<AppState>) -> Json<StatsSnapshot> {
+    let (active_sandboxes, running_executions) = state.sandboxes.counts();
+    Json(state.stats.snapshot(active_sandboxes, running_executions))
+}
@@ -0,0 +1,131 @@
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::redis_client;
+use crate::types::ExecutionRequest;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+// a claim only lives this long unless its run keeps refreshing it, so a node
+// that dies mid-run frees the key quickly instead of after the full ttl
+pub const PENDING_TTL_SECS: u64 = 30;
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 10;
+
+// what is stored under an idempotency key: the request it was first used with
+// and, once that run finished, the SSE payloads it produced
+#[derive(Serialize, Deserialize)]
+struct Record {
+    fingerprint: String,
+    events: Option<Vec<String>>,
+}
+
+pub enum Lookup {
+    Fresh,
+    Pending,
+    Done(Vec<String>),
+    Mismatch, // the key was used with a different request
+}
+
+// keys are scoped per tenant so tenants can't read each other's results
+pub fn redis_key(key: &str, tenant: Option<&str>) -> Result<String, String> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(format!(
+            "idempotency key must be 1 to {} bytes",
+            MAX_KEY_LENGTH
+        ));
+    }
+    Ok(redis_client::key(&format!(
+        "idempotency:{}:{}",
+        tenant.unwrap_or("anonymous"),
+        key
+    )))
+}
+
+pub fn fingerprint(request: &ExecutionRequest) -> String {
+    let body = serde_json::to_vec(request).unwrap_or_default();
+    hex::encode(Sha256::digest(body))
+}
+
+pub async fn lookup(
+    connection: &mut MultiplexedConnection,
+    key: &str,
+    fingerprint: &str,
+) -> Result<Lookup, String> {
+    let stored: Option<String> = connection
+        .get(key)
+        .await
+        .map_err(|e| format!("failed to read idempotency key: {}", e))?;
+    let Some(stored) = stored else {
+        return Ok(Lookup::Fresh);
+    };
+
+    let record: Record =
+        serde_json::from_str(&stored).map_err(|e| format!("corrupt idempotency record: {}", e))?;
+    if record.fingerprint != fingerprint {
+        return Ok(Lookup::Mismatch);
+    }
+    Ok(match record.events {
+        Some(events) => Lookup::Done(events),
+        None => Lookup::Pending,
+    })
+}
+
+// returns false when another request claimed the key first
+pub async fn claim(
+    connection: &mut MultiplexedConnection,
+    key: &str,
+    fingerprint: &str,
+) -> Result<bool, String> {
+    let record = serde_json::to_string(&Record {
+        fingerprint: fingerprint.to_string(),
+        events: None,
+    })
+    .unwrap();
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(record)
+        .arg("NX")
+        .arg("EX")
+        .arg(PENDING_TTL_SECS)
+        .query_async(connection)
+        .await
+        .map_err(|e| format!("failed to claim idempotency key: {}", e))?;
+    Ok(claimed.is_some())
+}
+
+// keeps a claim alive while its run is going
+pub async fn heartbeat(connection: &mut MultiplexedConnection, key: &str) {
+    let refreshed: Result<(), _> = connection.expire(key, PENDING_TTL_SECS as i64).await;
+    if let Err(e) = refreshed {
+        tracing::warn!("failed to refresh idempotency claim: {}", e);
+    }
+}
+
+// frees a claim whose run never finished, so a retry can run it again
+pub async fn release(connection: &mut MultiplexedConnection, key: &str) {
+    let deleted: Result<(), _> = connection.del(key).await;
+    if let Err(e) = deleted {
+        tracing::warn!("failed to release idempotency key: {}", e);
+    }
+}
+
+pub async fn complete(
+    connection: &mut MultiplexedConnection,
+    key: &str,
+    fingerprint: &str,
+    events: Vec<String>,
+    ttl: u64,
+) {
+    let record = serde_json::to_string(&Record {
+        fingerprint: fingerprint.to_string(),
+        events: Some(events),
+    })
+    .unwrap();
+    let stored: Result<(), _> = connection.set_ex(key, record, ttl).await;
+    if let Err(e) = stored {
+        tracing::warn!("failed to store idempotent result: {}", e);
+    }
+}
@@ -0,0 +1,271 @@
+use flate2::read::GzDecoder;
+use metrics::{counter, gauge};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use crate::archive;
+use crate::oci::{Reference, RegistryClient};
+use crate::types::{AppConfig, ImageSource};
+
+// pulled oci images, unpacked under `dir` by digest and evicted least recently
+// used first once they take more than `budget` bytes
+pub struct ImageManager {
+    dir: PathBuf,
+    budget: u64,
+    images: Mutex<HashMap<String, CachedImage>>,
+    pulling: tokio::sync::Mutex<()>, // one pull at a time keeps the budget simple
+}
+
+struct CachedImage {
+    size: u64,
+    last_used: Instant,
+    leases: usize, // running executions using the image, which is never evicted
+}
+
+// keeps an image on disk while an execution runs on it
+pub struct ImageLease {
+    manager: Arc<ImageManager>,
+    digest: String,
+    pub path: PathBuf,
+}
+
+impl Drop for ImageLease {
+    fn drop(&mut self) {
+        let mut images = self.manager.images.lock().unwrap();
+        if let Some(image) = images.get_mut(&self.digest) {
+            image.leases -= 1;
+            image.last_used = Instant::now();
+        }
+    }
+}
+
+impl ImageManager {
+    // picks up images pulled by earlier runs, ordered by when they were last
+    // used. anything else in the directory is a pull that was cut short
+    pub fn new(config: &AppConfig) -> Self {
+        let dir = PathBuf::from(&config.image_dir);
+        let mut images = HashMap::new();
+        if let Ok(entries) = fs::read_dir(&dir) {
+            let now = SystemTime::now();
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let path = entry.path();
+                if !is_digest_hex(&name) {
+                    tracing::info!("removing partial image {}", path.display());
+                    let _ = remove(&path);
+                    continue;
+                }
+                let age = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .unwrap_or_default();
+                let last_used = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                let image = CachedImage {
+                    size: tree_size(&path),
+                    last_used,
+                    leases: 0,
+                };
+                images.insert(format!("sha256:{}", name), image);
+            }
+        }
+        let manager = Self {
+            dir,
+            budget: config.image_disk_budget,
+            images: Mutex::new(images),
+            pulling: tokio::sync::Mutex::new(()),
+        };
+        manager.record_size();
+        manager
+    }
+
+    // the unpacked root filesystem of `source`, pulled first when it isn't cached
+    pub async fn acquire(self: &Arc<Self>, source: &ImageSource) -> Result<ImageLease, String> {
+        let pinned = source
+            .digest
+            .strip_prefix("sha256:")
+            .is_some_and(is_digest_hex);
+        if !pinned {
+            return Err(format!(
+                "image digest {} is not a sha256 digest",
+                source.digest
+            ));
+        }
+        if let Some(lease) = self.lease(&source.digest) {
+            return Ok(lease);
+        }
+        let _pulling = self.pulling.lock().await;
+        // pulled while this request waited for the lock
+        if let Some(lease) = self.lease(&source.digest) {
+            return Ok(lease);
+        }
+
+        let start = Instant::now();
+        let result = self.pull(source).await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        counter!("image_pulls_total", "outcome" => outcome).increment(1);
+        let size = result?;
+        tracing::info!(
+            reference = source.reference,
+            digest = source.digest,
+            size,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "pulled image"
+        );
+
+        self.images.lock().unwrap().insert(
+            source.digest.clone(),
+            CachedImage {
+                size,
+                last_used: Instant::now(),
+                leases: 0,
+            },
+        );
+        let lease = self
+            .lease(&source.digest)
+            .ok_or("pulled image disappeared")?;
+        self.evict();
+        Ok(lease)
+    }
+
+    fn lease(self: &Arc<Self>, digest: &str) -> Option<ImageLease> {
+        let mut images = self.images.lock().unwrap();
+        let image = images.get_mut(digest)?;
+        image.leases += 1;
+        image.last_used = Instant::now();
+        let path = self.image_path(digest);
+        // the directory's mtime carries the lru order across restarts
+        if let Ok(dir) = fs::File::open(&path) {
+            let _ = dir.set_modified(SystemTime::now());
+        }
+        Some(ImageLease {
+            manager: self.clone(),
+            digest: digest.to_string(),
+            path,
+        })
+    }
+
+    async fn pull(&self, source: &ImageSource) -> Result<u64, String> {
+        let path = self.image_path(&source.digest);
+        let partial = path.with_extension("partial");
+        let _ = remove(&partial);
+        fs::create_dir_all(&partial)
+            .map_err(|e| format!("failed to create {}: {}", partial.display(), e))?;
+
+        let result = self.pull_into(source, &partial).await;
+        match result {
+            Ok(size) => {
+                fs::rename(&partial, &path)
+                    .map_err(|e| format!("failed to move image into place: {}", e))?;
+                Ok(size)
+            }
+            Err(e) => {
+                let _ = remove(&partial);
+                let _ = fs::remove_file(path.with_extension("layer"));
+                Err(e)
+            }
+        }
+    }
+
+    async fn pull_into(&self, source: &ImageSource, root: &Path) -> Result<u64, String> {
+        let mut client = RegistryClient::new(Reference::parse(&source.reference)?)?;
+        let layers = client.layers(&source.digest).await?;
+        // layers are downloaded next to the tree, never into it
+        let blob = root.with_extension("layer");
+        let mut size = 0;
+        for layer in layers {
+            let gzip = layer.media_type.ends_with("gzip");
+            if !gzip && !layer.media_type.ends_with("tar") {
+                return Err(format!("unsupported layer type {}", layer.media_type));
+            }
+            client.download_blob(&layer.digest, &blob).await?;
+
+            let root = root.to_path_buf();
+            let remaining = self.budget.saturating_sub(size);
+            let blob_path = blob.clone();
+            let unpacked = tokio::task::spawn_blocking(move || {
+                let file = fs::File::open(&blob_path)
+                    .map_err(|e| format!("failed to open {}: {}", blob_path.display(), e))?;
+                let reader: Box<dyn Read> = if gzip {
+                    Box::new(GzDecoder::new(BufReader::new(file)))
+                } else {
+                    Box::new(BufReader::new(file))
+                };
+                archive::unpack_tar_layer(reader, &root, remaining)
+            })
+            .await
+            .map_err(|e| format!("layer unpacking panicked: {}", e));
+            let _ = fs::remove_file(&blob);
+            size += unpacked??;
+        }
+        Ok(size)
+    }
+
+    // drops unused images, least recently used first, until the cache fits
+    fn evict(&self) {
+        let mut images = self.images.lock().unwrap();
+        let mut total: u64 = images.values().map(|image| image.size).sum();
+        while total > self.budget {
+            let Some(digest) = images
+                .iter()
+                .filter(|(_, image)| image.leases == 0)
+                .min_by_key(|(_, image)| image.last_used)
+                .map(|(digest, _)| digest.clone())
+            else {
+                tracing::warn!(total, "image cache is over budget, all images are in use");
+                break;
+            };
+            let image = images.remove(&digest).unwrap();
+            total -= image.size;
+            tracing::info!(digest, size = image.size, "evicting image");
+            if let Err(e) = remove(&self.image_path(&digest)) {
+                tracing::error!("failed to evict image {}: {}", digest, e);
+            }
+        }
+        gauge!("image_cache_bytes").set(total as f64);
+    }
+
+    fn record_size(&self) {
+        let total: u64 = self.images.lock().unwrap().values().map(|i| i.size).sum();
+        gauge!("image_cache_bytes").set(total as f64);
+    }
+
+    fn image_path(&self, digest: &str) -> PathBuf {
+        self.dir
+            .join(digest.strip_prefix("sha256:").unwrap_or(digest))
+    }
+}
+
+fn is_digest_hex(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn tree_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| tree_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
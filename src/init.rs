@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// first argument that makes the service binary run as a sandbox's init
+pub const ARG: &str = "sandbox-init";
+
+// signals passed on to the program, a pid 1 never gets the default action
+const FORWARDED_SIGNALS: &[libc::c_int] = &[
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+];
+
+const NO_ADDR: *mut libc::c_void = std::ptr::null_mut();
+
+// ends the report the init appends to stderr, after its json and its length
+const REPORT_MAGIC: &[u8; 16] = b"\0pentagon-report";
+
+// the program, 0 once it is gone
+static PROGRAM: AtomicI32 = AtomicI32::new(0);
+
+// what the init writes for the worker when the program is done
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Report {
+    pub exit_code: Option<i32>,   // when the program exited
+    pub signal: Option<i32>,      // when a signal killed it
+    pub reaped: u64,              // other processes reaped, leftovers killed at the end included
+    pub vmpeak: u64,              // in kilobytes, the program's peak address space
+    pub vmrss: u64,               // in kilobytes, the program's resident memory when it exited
+    pub read_bytes: Option<u64>,  // read from storage by every process of the sandbox
+    pub write_bytes: Option<u64>, // sent to storage by every process of the sandbox
+}
+
+// runs as pid 1 of a sandbox: `sandbox-init <program> <args>`. starts the
+// program, forwards signals to it and reaps every process that ends up here,
+// then takes down what the program left behind, appends its report to stderr
+// and exits like the program did
+pub fn run() -> ! {
+    // killing everything at the end is only safe inside the sandbox
+    if std::process::id() != 1 {
+        eprintln!("pentagon init: must run as pid 1 of a sandbox");
+        std::process::exit(127);
+    }
+    let argv: Vec<CString> = std::env::args_os()
+        .skip(2)
+        .filter_map(|arg| CString::new(arg.into_vec()).ok())
+        .collect();
+    if argv.is_empty() {
+        eprintln!("pentagon init: no program to run");
+        std::process::exit(127);
+    }
+
+    let report = match supervise(&argv) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("pentagon init: {}", e);
+            std::process::exit(127);
+        }
+    };
+    if let Err(e) = write_report(&report) {
+        eprintln!("pentagon init: failed to write report: {}", e);
+    }
+    std::process::exit(match (report.exit_code, report.signal) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 127,
+    })
+}
+
+fn supervise(argv: &[CString]) -> Result<Report, String> {
+    let mut pointers: Vec<*const libc::c_char> = argv.iter().map(|arg| arg.as_ptr()).collect();
+    pointers.push(std::ptr::null());
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(format!("failed to fork: {}", io::Error::last_os_error()));
+    }
+    if pid == 0 {
+        // stopped before exec, so the init can watch for the exit of the
+        // program and read its memory usage while it is still there
+        unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, NO_ADDR, 0 as libc::c_long);
+            libc::raise(libc::SIGSTOP);
+            libc::execvp(pointers[0], pointers.as_ptr());
+        }
+        eprintln!(
+            "pentagon init: failed to run {}: {}",
+            argv[0].to_string_lossy(),
+            io::Error::last_os_error()
+        );
+        unsafe { libc::_exit(127) };
+    }
+
+    PROGRAM.store(pid, Ordering::Relaxed);
+    for &signal in FORWARDED_SIGNALS {
+        unsafe {
+            libc::signal(signal, forward as *const () as libc::sighandler_t);
+        }
+    }
+
+    let mut report = Report::default();
+    let mut attached = false;
+    loop {
+        let mut status = 0;
+        let waited = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+        if waited < 0 {
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // nothing left to wait for
+                _ => break,
+            }
+        }
+        if waited != pid {
+            report.reaped += 1;
+            continue;
+        }
+
+        if libc::WIFSTOPPED(status) {
+            let signal = libc::WSTOPSIG(status);
+            let deliver = if status >> 8 == libc::SIGTRAP | (libc::PTRACE_EVENT_EXIT << 8) {
+                (report.vmpeak, report.vmrss) = memory_kb(pid);
+                0
+            } else if signal == libc::SIGSTOP && !attached {
+                attached = true;
+                let options = libc::PTRACE_O_TRACEEXIT | libc::PTRACE_O_EXITKILL;
+                unsafe {
+                    libc::ptrace(
+                        libc::PTRACE_SETOPTIONS,
+                        pid,
+                        NO_ADDR,
+                        options as libc::c_long,
+                    );
+                }
+                0
+            } else if signal == libc::SIGTRAP {
+                // stopped after exec
+                0
+            } else {
+                signal
+            };
+            unsafe {
+                libc::ptrace(libc::PTRACE_CONT, pid, NO_ADDR, deliver as libc::c_long);
+            }
+            continue;
+        }
+
+        if libc::WIFEXITED(status) {
+            report.exit_code = Some(libc::WEXITSTATUS(status));
+        } else {
+            report.signal = Some(libc::WTERMSIG(status));
+        }
+        PROGRAM.store(0, Ordering::Relaxed);
+        // whatever the program left running goes with it, and is reaped
+        // here so its resource usage is accounted for
+        unsafe {
+            libc::kill(-1, libc::SIGKILL);
+        }
+    }
+    // every process ended up reaped here or by a process reaped here, so
+    // the init's own counts hold theirs
+    (report.read_bytes, report.write_bytes) = io_bytes();
+    Ok(report)
+}
+
+// every other process of the sandbox is gone by now, so nothing the program
+// wrote can land after the report and only an init that exits normally
+// leaves one at the very end of stderr
+fn write_report(report: &Report) -> io::Result<()> {
+    let frame = report_frame(report);
+    unsafe {
+        let flags = libc::fcntl(libc::STDERR_FILENO, libc::F_GETFL);
+        if flags >= 0 {
+            libc::fcntl(
+                libc::STDERR_FILENO,
+                libc::F_SETFL,
+                flags & !libc::O_NONBLOCK,
+            );
+        }
+    }
+    let mut stderr = io::stderr().lock();
+    stderr.write_all(&frame)?;
+    stderr.flush()
+}
+
+// the report's json, then its length and the magic
+pub fn report_frame(report: &Report) -> Vec<u8> {
+    let mut frame = serde_json::to_vec(report).unwrap();
+    frame.extend_from_slice(&(frame.len() as u64).to_le_bytes());
+    frame.extend_from_slice(REPORT_MAGIC);
+    frame
+}
+
+// cuts the report an init appended off the end of `stderr`. only trust it
+// when the init exited normally, anything else may be the program's
+pub fn take_report(stderr: &mut Vec<u8>) -> Option<Report> {
+    let trailer = REPORT_MAGIC.len() + 8;
+    let end = stderr.len().checked_sub(trailer)?;
+    if &stderr[end + 8..] != REPORT_MAGIC {
+        return None;
+    }
+    let len = u64::from_le_bytes(stderr[end..end + 8].try_into().unwrap());
+    let start = end.checked_sub(usize::try_from(len).ok()?)?;
+    let report = serde_json::from_slice(&stderr[start..end]).ok()?;
+    stderr.truncate(start);
+    Some(report)
+}
+
+extern "C" fn forward(signal: libc::c_int) {
+    let pid = PROGRAM.load(Ordering::Relaxed);
+    if pid > 0 {
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+// (VmPeak, VmRSS) of `pid`, zero when they can't be read
+fn memory_kb(pid: libc::pid_t) -> (u64, u64) {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap_or(0)
+    };
+    (field("VmPeak:"), field("VmRSS:"))
+}
+
+// (read_bytes, write_bytes) of the init and the children it reaped, none
+// without task io accounting
+fn io_bytes() -> (Option<u64>, Option<u64>) {
+    let io = fs::read_to_string("/proc/self/io").unwrap_or_default();
+    let field = |name: &str| {
+        io.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().parse().ok())
+    };
+    (field("read_bytes:"), field("write_bytes:"))
+}
@@ -0,0 +1,35 @@
+use metrics::counter;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// fails when `data` doesn't hash to the sha256 the client expects for `name`
+pub fn check_bytes(name: &str, expected: &str, data: &[u8]) -> Result<(), String> {
+    compare(name, expected, &hex::encode(Sha256::digest(data)))
+}
+
+// like check_bytes for a file on disk, which is read in pieces
+pub fn check_file(name: &str, expected: &str, path: &Path) -> Result<(), String> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path).map_err(|e| format!("failed to open {}: {}", name, e))?;
+    io::copy(&mut file, &mut hasher).map_err(|e| format!("failed to read {}: {}", name, e))?;
+    compare(name, expected, &hex::encode(hasher.finalize()))
+}
+
+fn compare(name: &str, expected: &str, actual: &str) -> Result<(), String> {
+    if expected.eq_ignore_ascii_case(actual) {
+        return Ok(());
+    }
+    counter!("integrity_check_failures_total").increment(1);
+    tracing::error!(
+        name,
+        expected,
+        actual,
+        "staged file failed its integrity check"
+    );
+    Err(format!(
+        "integrity check failed: {} has sha256 {}, expected {}",
+        name, actual, expected
+    ))
+}
@@ -0,0 +1,416 @@
+use metrics::counter;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time;
+
+use crate::admission;
+use crate::cron;
+use crate::dead_letters::{self, DeadLetter};
+use crate::handlers::run::check_quota;
+use crate::redis_client;
+use crate::runner;
+use crate::types::{AppState, ExecutionRequest, RunEvent};
+use crate::utils::gen_random_id;
+
+pub const INTERRUPTED_ERROR: &str = "job was interrupted by a server restart";
+
+// how often the scheduler looks for due jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// due jobs taken per poll, the rest wait for the next tick
+const BATCH_SIZE: isize = 16;
+
+// instances refresh theirs every poll, one missing it for this long is gone
+// and its unfinished jobs are recovered by the others
+const HEARTBEAT_TTL_SECS: u64 = 30;
+
+// how often an instance looks for jobs of instances that are gone
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(15);
+
+// this process among the instances sharing redis. a restarted process is a
+// new instance, its old self's jobs are recovered once that heartbeat expires
+static INSTANCE: LazyLock<String> = LazyLock::new(|| gen_random_id(16));
+
+// moves a due job from the schedule to the running jobs of ARGV[2], unless
+// another instance got it first
+const CLAIM_SCRIPT: &str = r"
+if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then return 0 end
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
+return 1
+";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Scheduled,
+    Running,
+    Done,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub tenant: Option<String>,
+    pub run_at: u64, // unix seconds
+    pub status: JobStatus,
+    pub request: Option<ExecutionRequest>, // dropped once the job has been picked up
+    pub results: Vec<Value>, // one payload per execution, as /execute would stream them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>, // notified with the finished job
+}
+
+fn job_key(id: &str) -> String {
+    redis_client::key(&format!("job:{}", id))
+}
+
+// ids of jobs waiting to run, scored by the unix time they are due
+fn schedule_key() -> String {
+    redis_client::key("jobs:scheduled")
+}
+
+// ids of claimed jobs that haven't finished -> id of the instance running them
+fn running_key() -> String {
+    redis_client::key("jobs:running")
+}
+
+// pub/sub channel a job's events are published on as they happen, for
+// services that would rather subscribe than poll
+fn events_channel(id: &str) -> String {
+    redis_client::key(&format!("job:{}:events", id))
+}
+
+fn heartbeat_key(instance: &str) -> String {
+    redis_client::key(&format!("instance:{}", instance))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub async fn schedule(connection: &mut MultiplexedConnection, job: &Job) -> Result<(), String> {
+    let record = serde_json::to_string(job).unwrap();
+    redis::pipe()
+        .set(job_key(&job.id), record)
+        .ignore()
+        .zadd(schedule_key(), &job.id, job.run_at)
+        .ignore()
+        .query_async::<()>(connection)
+        .await
+        .map_err(|e| format!("failed to schedule job: {}", e))
+}
+
+pub async fn get(connection: &mut MultiplexedConnection, id: &str) -> Result<Option<Job>, String> {
+    let stored: Option<String> = connection
+        .get(job_key(id))
+        .await
+        .map_err(|e| format!("failed to read job: {}", e))?;
+    stored
+        .map(|stored| {
+            serde_json::from_str(&stored).map_err(|e| format!("corrupt job record: {}", e))
+        })
+        .transpose()
+}
+
+// picked up jobs expire after `ttl` seconds, pending ones are kept until they run
+async fn save(connection: &mut MultiplexedConnection, job: &Job, ttl: Option<u64>) {
+    let record = serde_json::to_string(job).unwrap();
+    let saved: Result<(), _> = match ttl {
+        Some(ttl) => connection.set_ex(job_key(&job.id), record, ttl).await,
+        None => connection.set(job_key(&job.id), record).await,
+    };
+    if let Err(e) = saved {
+        tracing::warn!(id = job.id, "failed to save job: {}", e);
+    }
+}
+
+pub fn start_scheduler(state: AppState) {
+    let instance = INSTANCE.as_str();
+    tokio::spawn(async move {
+        let mut interval = time::interval(POLL_INTERVAL);
+        let mut last_recovery: Option<Instant> = None;
+        loop {
+            interval.tick().await;
+            if let Err(e) = heartbeat(&state, instance).await {
+                tracing::warn!("job scheduler: {}", e);
+            }
+            if last_recovery.is_none_or(|last| last.elapsed() >= RECOVERY_INTERVAL) {
+                last_recovery = Some(Instant::now());
+                if let Err(e) = recover_orphaned_jobs(&state).await {
+                    tracing::warn!("job recovery: {}", e);
+                }
+            }
+            if let Err(e) = cron::enqueue_due(&state).await {
+                tracing::warn!("cron scheduler: {}", e);
+            }
+            if let Err(e) = run_due_jobs(&state, instance).await {
+                tracing::warn!("job scheduler: {}", e);
+            }
+        }
+    });
+}
+
+async fn heartbeat(state: &AppState, instance: &str) -> Result<(), String> {
+    let mut connection = state.redis()?;
+    connection
+        .set_ex::<_, _, ()>(heartbeat_key(instance), unix_now(), HEARTBEAT_TTL_SECS)
+        .await
+        .map_err(|e| format!("failed to refresh heartbeat: {}", e))
+}
+
+// jobs claimed by instances that stopped heartbeating. ones that never
+// started go back on the schedule, ones that were running can't be resumed
+// safely, their executions may have had effects, so they fail
+async fn recover_orphaned_jobs(state: &AppState) -> Result<(), String> {
+    let mut connection = state.redis()?;
+    let running: HashMap<String, String> = connection
+        .hgetall(running_key())
+        .await
+        .map_err(|e| format!("failed to read running jobs: {}", e))?;
+    for (id, instance) in running {
+        let alive: bool = connection
+            .exists(heartbeat_key(&instance))
+            .await
+            .map_err(|e| format!("failed to read heartbeat: {}", e))?;
+        if alive {
+            continue;
+        }
+        // with several instances recovering, whichever removes the id does it
+        let claimed: i64 = connection
+            .hdel(running_key(), &id)
+            .await
+            .map_err(|e| format!("failed to claim orphaned job: {}", e))?;
+        if claimed == 0 {
+            continue;
+        }
+        let Some(mut job) = get(&mut connection, &id).await? else {
+            continue;
+        };
+        match job.status {
+            JobStatus::Scheduled => {
+                tracing::info!(id, instance, "rescheduling job of a stopped instance");
+                schedule(&mut connection, &job).await?;
+            }
+            JobStatus::Running => {
+                tracing::warn!(
+                    id,
+                    instance,
+                    "failing job interrupted by a stopped instance"
+                );
+                let error = json!({ "error": INTERRUPTED_ERROR });
+                job.results.push(error.clone());
+                job.status = JobStatus::Done;
+                save(&mut connection, &job, Some(state.config.job_ttl_secs)).await;
+                publish(&mut connection, &job, "error", error).await;
+                publish(&mut connection, &job, "done", done_event(&job)).await;
+                notify(state, &mut connection, &job).await;
+            }
+            JobStatus::Done => {}
+        }
+    }
+    Ok(())
+}
+
+async fn run_due_jobs(state: &AppState, instance: &str) -> Result<(), String> {
+    let mut connection = state.redis()?;
+    let due: Vec<String> = connection
+        .zrangebyscore_limit(schedule_key(), "-inf", unix_now(), 0, BATCH_SIZE)
+        .await
+        .map_err(|e| format!("failed to read schedule: {}", e))?;
+
+    for id in due {
+        // with several instances sharing redis, whichever removes the id runs it
+        let claimed: i64 = redis::Script::new(CLAIM_SCRIPT)
+            .key(schedule_key())
+            .key(running_key())
+            .arg(&id)
+            .arg(instance)
+            .invoke_async(&mut connection)
+            .await
+            .map_err(|e| format!("failed to claim job: {}", e))?;
+        if claimed == 0 {
+            continue;
+        }
+        match get(&mut connection, &id).await? {
+            Some(job) => {
+                tokio::spawn(run_job(state.clone(), connection.clone(), job));
+            }
+            None => {
+                tracing::warn!(id, "scheduled job has no record");
+                let _: Result<(), _> = connection.hdel(running_key(), &id).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(state, job), fields(id = %job.id))]
+async fn run_job(state: AppState, mut connection: MultiplexedConnection, mut job: Job) {
+    let Some(request) = job.request.take() else {
+        let _: Result<(), _> = connection.hdel(running_key(), &job.id).await;
+        return;
+    };
+    tracing::info!("running scheduled job");
+    job.status = JobStatus::Running;
+    save(&mut connection, &job, Some(state.config.job_ttl_secs)).await;
+    publish(
+        &mut connection,
+        &job,
+        "running",
+        json!({ "status": job.status }),
+    )
+    .await;
+
+    // quotas are charged on the day the job runs, not the day it was submitted
+    let executions = request.execution_count();
+    let admitted = match admission::check(&state).await {
+        Ok(()) => check_quota(&state, job.tenant.as_deref(), executions).await,
+        Err(e) => Err(e),
+    };
+    if let Err((_, err)) = admitted {
+        tracing::warn!("rejected scheduled job: {}", err);
+        let error = json!({ "error": err });
+        job.results.push(error.clone());
+        save(&mut connection, &job, Some(state.config.job_ttl_secs)).await;
+        publish(&mut connection, &job, "error", error).await;
+        finish(&state, &mut connection, job).await;
+    } else {
+        execute(state, connection, job, request, None, None).await;
+    }
+}
+
+// records a detached /execute request as a running job of this instance, so
+// its results outlive the client's connection
+pub async fn start_detached(
+    connection: &mut MultiplexedConnection,
+    tenant: Option<String>,
+    ttl: u64,
+) -> Result<Job, String> {
+    let job = Job {
+        id: gen_random_id(16),
+        tenant,
+        run_at: unix_now(),
+        status: JobStatus::Running,
+        request: None,
+        results: Vec::new(),
+        webhook: None,
+    };
+    let record = serde_json::to_string(&job).unwrap();
+    redis::pipe()
+        .set_ex(job_key(&job.id), record, ttl)
+        .ignore()
+        .hset(running_key(), &job.id, INSTANCE.as_str())
+        .ignore()
+        .query_async::<()>(connection)
+        .await
+        .map_err(|e| format!("failed to save job: {}", e))?;
+    Ok(job)
+}
+
+// runs the request of a running job to the end, saving each outcome as it
+// comes for clients long-polling the results. `client` gets the events too
+// while it is there, its going away doesn't stop the run
+pub async fn execute(
+    state: AppState,
+    mut connection: MultiplexedConnection,
+    mut job: Job,
+    request: ExecutionRequest,
+    client: Option<Sender<RunEvent>>,
+    client_ip: Option<IpAddr>,
+) {
+    let (tx, mut rx) = mpsc::channel(100);
+    // kept for a dead letter, the job record drops its request
+    let retry = request.clone();
+    let mut errors = Vec::new();
+    let run = tokio::spawn(runner::execute(
+        state.clone(),
+        request,
+        tx,
+        job.tenant.clone(),
+        client_ip,
+        false,
+    ));
+    while let Some(event) = rx.recv().await {
+        if let RunEvent::Outcome(data) = &event
+            && let Err(err) = data.as_ref()
+        {
+            errors.extend(err.errors.iter().cloned());
+        }
+        let (name, payload) = match &event {
+            // published for subscribers, but not part of the job's results
+            RunEvent::Progress(progress) => {
+                let progress = serde_json::to_value(progress).unwrap();
+                publish(&mut connection, &job, "progress", progress).await;
+                ("progress", None)
+            }
+            RunEvent::Outcome(data) => match data.as_ref() {
+                Ok(result) => ("result", Some(serde_json::to_value(result).unwrap())),
+                Err(err) => ("error", Some(serde_json::to_value(err).unwrap())),
+            },
+        };
+        if let Some(payload) = payload {
+            job.results.push(payload.clone());
+            save(&mut connection, &job, Some(state.config.job_ttl_secs)).await;
+            publish(&mut connection, &job, name, payload).await;
+        }
+        if let Some(client) = &client {
+            let _ = client.send(event).await;
+        }
+    }
+    let _ = run.await;
+    if !errors.is_empty() {
+        let letter = DeadLetter::new(Some(job.id.clone()), job.tenant.clone(), retry, errors);
+        dead_letters::record(&mut connection, &letter, state.config.job_ttl_secs).await;
+    }
+    finish(&state, &mut connection, job).await;
+}
+
+async fn finish(state: &AppState, connection: &mut MultiplexedConnection, mut job: Job) {
+    job.status = JobStatus::Done;
+    save(connection, &job, Some(state.config.job_ttl_secs)).await;
+    publish(connection, &job, "done", done_event(&job)).await;
+    notify(state, connection, &job).await;
+    if let Err(e) = connection.hdel::<_, _, ()>(running_key(), &job.id).await {
+        tracing::warn!("failed to mark job finished: {}", e);
+    }
+}
+
+// named like the SSE events of /execute, published after the job record is
+// saved so a subscriber reading the record never misses what it announced
+async fn publish(connection: &mut MultiplexedConnection, job: &Job, event: &str, data: Value) {
+    let message = json!({ "event": event, "data": data }).to_string();
+    if let Err(e) = connection
+        .publish::<_, _, ()>(events_channel(&job.id), message)
+        .await
+    {
+        tracing::warn!("failed to publish job event: {}", e);
+    }
+}
+
+fn done_event(job: &Job) -> Value {
+    json!({ "status": job.status, "results": job.results.len() })
+}
+
+// the body is what GET /jobs/{id} answers
+async fn notify(state: &AppState, connection: &mut MultiplexedConnection, job: &Job) {
+    if let Some(url) = &job.webhook {
+        let payload = json!({
+            "id": job.id,
+            "status": job.status,
+            "run_at": job.run_at,
+            "results": job.results,
+        });
+        if let Err(e) = state.webhooks.send(connection, url.clone(), payload).await {
+            tracing::error!(id = job.id, "{}", e);
+            counter!("webhook_deliveries_total", "outcome" => "failed").increment(1);
+        }
+    }
+}
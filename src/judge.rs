@@ -0,0 +1,263 @@
+use redis::aio::MultiplexedConnection;
+
+use crate::files::{FileManagerTrait, RedisFileManager};
+use crate::handlers::run::execute_execution;
+use crate::problems::{Problem, Testcase};
+use crate::types::{
+    AppState, Execution, ExecutionResult, ExecutionTransfer, FilePath, JudgeResult, TestResult,
+    Verdict,
+};
+use crate::worker::Worker;
+
+// where the checker and its inputs are staged in /box, testlib style:
+// checker <input> <output> <answer>
+const CHECKER_NAME: &str = ".checker";
+const CHECKER_INPUT: &str = ".judge_input";
+const CHECKER_OUTPUT: &str = ".judge_output";
+const CHECKER_ANSWER: &str = ".judge_answer";
+
+// tmp buffer holding the program's stdout for the checker
+const OUTPUT_TMP_ID: u64 = u64::MAX;
+
+const SIGXCPU_EXIT_CODE: i32 = 128 + libc::SIGXCPU;
+
+// runs `execution` once per testcase of `problem` in the worker's sandbox,
+// with the input on stdin and the problem's limits. testcases are read
+// through `connection`
+pub async fn judge(
+    state: &AppState,
+    connection: MultiplexedConnection,
+    worker: &mut Worker,
+    problem: &Problem,
+    execution: Execution,
+    stop_on_failure: bool,
+    tenant: Option<&str>,
+) -> JudgeResult {
+    let mut file_manager = RedisFileManager::new(connection, state.encryption.clone());
+    let mut tests = Vec::new();
+    for testcase in &problem.testcases {
+        let result = judge_testcase(
+            state,
+            worker,
+            &mut file_manager,
+            problem,
+            testcase,
+            execution.clone(),
+            tenant,
+        )
+        .await;
+        let failed = result.verdict != Verdict::Accepted;
+        tests.push(result);
+        if failed && stop_on_failure {
+            break;
+        }
+    }
+
+    JudgeResult {
+        verdict: tests
+            .iter()
+            .map(|test| test.verdict)
+            .find(|verdict| *verdict != Verdict::Accepted)
+            .unwrap_or(Verdict::Accepted),
+        passed: tests
+            .iter()
+            .filter(|test| test.verdict == Verdict::Accepted)
+            .count(),
+        total: problem.testcases.len(),
+        time_used: tests.iter().map(|test| test.time_used).max().unwrap_or(0),
+        memory_used: tests.iter().map(|test| test.memory_used).max().unwrap_or(0),
+        score: tests.iter().map(|test| test.score).sum(),
+        max_score: problem
+            .testcases
+            .iter()
+            .map(|testcase| testcase.points)
+            .sum(),
+        tests,
+    }
+}
+
+async fn judge_testcase(
+    state: &AppState,
+    worker: &mut Worker,
+    file_manager: &mut RedisFileManager,
+    problem: &Problem,
+    testcase: &Testcase,
+    mut execution: Execution,
+    tenant: Option<&str>,
+) -> TestResult {
+    execution.time_limit = Some(problem.time_limit);
+    execution.wall_time_limit = Some(problem.wall_time_limit);
+    execution.memory_limit = Some(problem.memory_limit);
+    execution.copy_in.push(ExecutionTransfer {
+        from: FilePath::Remote {
+            id: testcase.input.clone(),
+        },
+        to: FilePath::Stdin {},
+        optional: false,
+        sha256: None,
+    });
+    execution.return_files = vec![FilePath::Stdout { max_size: None }];
+    execution.autofix = Some(false);
+    if problem.checker.is_some() {
+        execution.copy_out = vec![ExecutionTransfer {
+            from: FilePath::Stdout { max_size: None },
+            to: FilePath::Tmp { id: OUTPUT_TMP_ID },
+            optional: false,
+            sha256: None,
+        }];
+    }
+
+    let mut test = TestResult {
+        name: testcase.name.clone(),
+        verdict: Verdict::Error,
+        exit_code: None,
+        time_used: 0,
+        memory_used: 0,
+        score: 0.0,
+        points: testcase.points,
+        message: None,
+    };
+    let result = match execute_execution(worker, execution.clone(), state, tenant).await {
+        Ok(result) => result,
+        Err(err) => {
+            test.message = Some(err.error);
+            return test;
+        }
+    };
+    test.exit_code = Some(result.exit_code);
+    test.time_used = result.time_used;
+    test.memory_used = result.memory_used;
+
+    // the second value is the fraction of the testcase's points earned
+    let (verdict, earned) = if result.time_used >= problem.time_limit as u128 * 1000
+        || result.exit_code == SIGXCPU_EXIT_CODE
+    {
+        (Verdict::TimeLimitExceeded, 0.0)
+    } else if result.exit_code != 0 {
+        (Verdict::RuntimeError, 0.0)
+    } else if let Some(checker) = &problem.checker {
+        match run_checker(state, worker, &execution, checker, testcase, tenant).await {
+            Ok(checker) => checker_verdict(&checker),
+            Err(err) => {
+                test.message = Some(format!("checker failed: {}", err));
+                (Verdict::Error, 0.0)
+            }
+        }
+    } else {
+        match expected_output(file_manager, testcase).await {
+            Ok(expected) if same_tokens(&stdout(&result), &expected) => (Verdict::Accepted, 1.0),
+            Ok(_) => (Verdict::WrongAnswer, 0.0),
+            Err(err) => {
+                test.message = Some(err);
+                (Verdict::Error, 0.0)
+            }
+        }
+    };
+    test.verdict = verdict;
+    test.score = earned * testcase.points;
+    test
+}
+
+// a checker may print the percentage of the testcase's points to award,
+// without one exiting 0 awards them all
+fn checker_verdict(checker: &ExecutionResult) -> (Verdict, f64) {
+    let percent = checker
+        .score
+        .unwrap_or(if checker.exit_code == 0 { 100.0 } else { 0.0 });
+    let earned = (percent / 100.0).clamp(0.0, 1.0);
+    let verdict = if earned >= 1.0 {
+        Verdict::Accepted
+    } else if earned > 0.0 {
+        Verdict::PartiallyAccepted
+    } else {
+        Verdict::WrongAnswer
+    };
+    (verdict, earned)
+}
+
+// the checker runs in the same sandbox as the program, under its limits
+async fn run_checker(
+    state: &AppState,
+    worker: &mut Worker,
+    program: &Execution,
+    checker: &str,
+    testcase: &Testcase,
+    tenant: Option<&str>,
+) -> Result<ExecutionResult, String> {
+    let answer = testcase
+        .output
+        .clone()
+        .ok_or_else(|| format!("testcase {} has no expected output", testcase.name))?;
+    let stage = |from: FilePath, name: &str, executable: bool| ExecutionTransfer {
+        from,
+        to: FilePath::Local {
+            name: name.to_string(),
+            executable,
+        },
+        optional: false,
+        sha256: None,
+    };
+    let execution = Execution {
+        program: format!("{}/{}", state.config.box_path, CHECKER_NAME),
+        args: [CHECKER_INPUT, CHECKER_OUTPUT, CHECKER_ANSWER]
+            .map(String::from)
+            .to_vec(),
+        shell: false,
+        workdir: None,
+        copy_in: vec![
+            stage(
+                FilePath::Remote {
+                    id: checker.to_string(),
+                },
+                CHECKER_NAME,
+                true,
+            ),
+            stage(
+                FilePath::Remote {
+                    id: testcase.input.clone(),
+                },
+                CHECKER_INPUT,
+                false,
+            ),
+            stage(FilePath::Tmp { id: OUTPUT_TMP_ID }, CHECKER_OUTPUT, false),
+            stage(FilePath::Remote { id: answer }, CHECKER_ANSWER, false),
+        ],
+        copy_out: Vec::new(),
+        return_files: Vec::new(),
+        die_on_error: false,
+        score_from: Some(FilePath::Stdout { max_size: None }),
+        ..program.clone()
+    };
+    execute_execution(worker, execution, state, tenant)
+        .await
+        .map_err(|e| e.error)
+}
+
+async fn expected_output(
+    file_manager: &mut RedisFileManager,
+    testcase: &Testcase,
+) -> Result<Vec<u8>, String> {
+    let id = testcase
+        .output
+        .clone()
+        .ok_or_else(|| format!("testcase {} has no expected output", testcase.name))?;
+    file_manager.get_file(FilePath::Remote { id }, None).await
+}
+
+fn stdout(result: &ExecutionResult) -> Vec<u8> {
+    result
+        .return_files
+        .first()
+        .map(|file| file.content.clone())
+        .unwrap_or_default()
+}
+
+// outputs match when they have the same whitespace separated tokens
+fn same_tokens(output: &[u8], expected: &[u8]) -> bool {
+    output
+        .split(u8::is_ascii_whitespace)
+        .filter(|token| !token.is_empty())
+        .eq(expected
+            .split(u8::is_ascii_whitespace)
+            .filter(|token| !token.is_empty()))
+}
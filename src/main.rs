@@ -1,46 +1,211 @@
+mod admission;
+mod archive;
+mod audit;
+mod auth;
+mod bench;
+mod cli;
+mod client_ip;
+mod connections;
+mod cron;
+mod dead_letters;
+mod encryption;
+mod file_cache;
 mod files;
 mod handlers;
+mod idempotency;
+mod images;
+mod init;
+mod integrity;
+mod jobs;
+mod judge;
+#[cfg(any(test, feature = "mock-sandbox"))]
+mod mock;
+mod network;
+mod oci;
+mod perf;
+mod problems;
+mod quota;
+mod redis_client;
+mod result_cache;
+mod runner;
+mod sandboxes;
+mod settings;
+mod signing;
+mod stats;
 mod system_monitor;
+mod systemd;
+mod templates;
+mod tls;
+mod transfers;
 mod types;
+mod usage;
 mod utils;
+mod webhooks;
 mod worker;
 
+#[cfg(test)]
+mod tests;
+
 use crate::{
+    auth::JwtVerifier,
+    client_ip::{ClientIp, Peer, TrustedProxies, client_ip},
+    connections::ConnectionLimiter,
+    encryption::Encryption,
     handlers::{
+        admin::{
+            kill_sandbox, list_dead_letters, list_runners, list_sandboxes, requeue_dead_letter,
+        },
+        capabilities::capabilities_endpoint,
+        cron::{create_cron, delete_cron, get_cron, list_crons, update_cron},
+        files::get_file,
+        jobs::{get_job, get_job_results, submit_job},
+        judge::judge_endpoint,
+        limits::payload_too_large,
         metrics::metrics_endpoint,
+        problems::{delete_problem, get_problem, import_problem},
         run::{execute_code_endpoint, execute_code_ws_handler},
+        stats::stats_endpoint,
     },
-    types::{AppConfig, AppState},
+    file_cache::FileCache,
+    files::MemoryFileManager,
+    images::ImageManager,
+    runner::EventListener,
+    sandboxes::SandboxRegistry,
+    signing::verify_signature,
+    stats::Stats,
+    tls::TlsListener,
+    transfers::UrlTransfers,
+    types::{AppConfig, AppState, FileStore, Mode},
+    usage::UsageMeter,
+    webhooks::WebhookSender,
 };
 
 use axum::{
     Router,
-    routing::{any, get, post},
+    extract::{DefaultBodyLimit, Request},
+    middleware,
+    routing::{MethodRouter, any, delete, get, post},
 };
-use config::Config;
+use clap::Parser;
+use cli::{Cli, Command};
 use dotenvy::dotenv;
 use metrics::{describe_counter, describe_gauge, describe_histogram};
-use metrics_exporter_prometheus::PrometheusBuilder;
-use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tower_http::compression::{
+    CompressionLayer,
+    predicate::{NotForContentType, Predicate, SizeAbove},
+};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
+// how long startup waits for redis before giving up
+const REDIS_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    // sandboxes run this binary as their init, which must not start a runtime
+    if std::env::args().nth(1).as_deref() == Some(init::ARG) {
+        init::run();
+    }
+    let cli = Cli::parse();
+    // taken before the runtime starts threads, as its variables are cleared
+    let activated = systemd::listener().unwrap_or_else(|e| panic!("{}", e));
+    serve(cli, activated);
+}
 
+#[tokio::main]
+async fn serve(cli: Cli, activated: Option<std::net::TcpListener>) {
     dotenv().ok();
-    let settings = Config::builder()
-        .add_source(config::File::with_name("Settings"))
-        .add_source(config::Environment::with_prefix("APP"))
-        .build()
-        .unwrap();
+    tls::install_crypto_provider();
+    // another instance is benchmarked without this one's configuration
+    if let Some(Command::Bench(args)) = &cli.command
+        && let Some(url) = &args.url
+    {
+        std::process::exit(bench::remote(url, args).await);
+    }
+    let app_config = settings::load(&cli).unwrap_or_else(|e| settings::fail(&[e]));
+    let mut problems = settings::validate(&app_config);
+    redis_client::set_key_prefix(&app_config.redis_key_prefix);
+    // an unreachable redis is reported with the rest, without redis_url
+    // there is no connection and what needs one is refused
+    let con = match &app_config.redis_url {
+        None => Ok(None),
+        Some(url) => {
+            let connect = redis_client::connect(&app_config, url);
+            match time::timeout(REDIS_CONNECT_TIMEOUT, connect).await {
+                Ok(connected) => connected.map(Some).map_err(|e| format!("redis: {}", e)),
+                Err(_) => Err(format!(
+                    "redis: no connection within {} seconds",
+                    REDIS_CONNECT_TIMEOUT.as_secs()
+                )),
+            }
+        }
+    };
+    let con = checked(&mut problems, con);
+    // what can still fail on the config is built here, so it's reported too
+    let usage = checked(
+        &mut problems,
+        app_config
+            .usage_sink
+            .as_ref()
+            .map(UsageMeter::new)
+            .transpose(),
+    );
+    let metrics_builder = checked(&mut problems, metrics_builder(&app_config));
+    let webhooks = checked(&mut problems, WebhookSender::new(&app_config));
+    let transfers = checked(&mut problems, UrlTransfers::new(&app_config));
+    let encryption = checked(
+        &mut problems,
+        app_config
+            .encryption
+            .as_ref()
+            .map(Encryption::new)
+            .transpose(),
+    );
+    let server_config = checked(
+        &mut problems,
+        app_config
+            .tls
+            .as_ref()
+            .map(|tls| tls::server_config(&app_config, tls))
+            .transpose(),
+    );
+    let (
+        Some(con),
+        Some(metrics_builder),
+        Some(usage),
+        Some(webhooks),
+        Some(transfers),
+        Some(encryption),
+        Some(server_config),
+        true,
+    ) = (
+        con,
+        metrics_builder,
+        usage,
+        webhooks,
+        transfers,
+        encryption,
+        server_config,
+        problems.is_empty(),
+    )
+    else {
+        settings::fail(&problems);
+    };
+    let app_config = Arc::new(app_config);
 
-    let app_config: AppConfig = settings.try_deserialize().unwrap();
+    let level = app_config.log_level.parse::<tracing::Level>().unwrap();
+    let subscriber = tracing_subscriber::fmt().with_max_level(level);
+    // stdout carries the outcomes of `run` and the report of `bench`
+    if let Some(Command::Run { .. } | Command::Bench(_)) = &cli.command {
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
 
     // Install global Prometheus recorder and keep the handle for rendering metrics.
-    let builder = PrometheusBuilder::new();
-    let handle = builder.install_recorder().unwrap();
+    let handle = metrics_builder.install_recorder().unwrap();
 
     // Optional: describe metrics for documentation.
     describe_counter!("requests_total", "Total number of /execute requests");
@@ -55,12 +220,24 @@ async fn main() {
         "Total execution duration including setup in milliseconds"
     );
     describe_histogram!("execution_memory_kb", "Memory used in kilobytes");
+    describe_histogram!(
+        "file_staging_duration_ms",
+        "Time to stage a request's files in milliseconds"
+    );
+    describe_counter!(
+        "batch_wall_time_exceeded_total",
+        "Total number of batches whose remaining executions were skipped at max_batch_wall_time"
+    );
     describe_gauge!("active_workers", "Number of active workers");
     describe_gauge!("active_executions", "Number of active executions running");
     describe_gauge!(
         "websocket_connections_active",
         "Number of active websocket connections"
     );
+    describe_counter!(
+        "websocket_connections_rejected_total",
+        "Total number of websocket connections refused for exceeding the per-client limit"
+    );
     describe_counter!(
         "websocket_messages_received_total",
         "Total number of websocket messages received"
@@ -69,36 +246,237 @@ async fn main() {
         "websocket_messages_sent_total",
         "Total number of websocket messages sent"
     );
+    describe_counter!(
+        "execution_panics_total",
+        "Total number of executions that failed because the sandbox driver panicked"
+    );
+    describe_counter!(
+        "sandbox_leftover_processes_total",
+        "Total number of sandboxed processes killed after outliving their program"
+    );
+    describe_counter!("judge_requests_total", "Total number of /judge requests");
+    describe_counter!("jobs_submitted_total", "Total number of scheduled jobs submitted");
+    describe_counter!(
+        "webhook_deliveries_total",
+        "Total number of job webhooks delivered or given up on"
+    );
+    describe_counter!(
+        "url_transfers_total",
+        "Total number of url downloads, uploads and git clones, by outcome"
+    );
+    describe_counter!(
+        "integrity_check_failures_total",
+        "Total number of staged files whose sha256 didn't match the request"
+    );
     describe_counter!("files_created_total", "Total number of files created");
+    describe_counter!("image_pulls_total", "Total number of rootfs image pulls");
+    describe_gauge!("image_cache_bytes", "Disk space used by pulled rootfs images");
+    describe_counter!(
+        "file_cache_requests_total",
+        "Total number of remote file lookups in the local file cache"
+    );
+    describe_gauge!("file_cache_bytes", "Disk space used by cached remote files");
+    describe_counter!(
+        "result_cache_requests_total",
+        "Total number of execution result cache lookups"
+    );
+    describe_counter!(
+        "usage_records_failed_total",
+        "Total number of usage records that couldn't be written to the usage sink"
+    );
     describe_gauge!("system_memory_used_bytes", "Used system memory in bytes");
     describe_gauge!("system_memory_total_bytes", "Total system memory in bytes");
     describe_gauge!("system_cpu_usage_percent", "System CPU usage in percent");
     describe_gauge!("system_disk_free_bytes", "Free disk space in bytes");
     describe_gauge!("system_disk_total_bytes", "Total disk space in bytes");
+    describe_gauge!(
+        "sandbox_disk_free_bytes",
+        "Free disk space on the filesystem holding base_code_path in bytes"
+    );
+    describe_gauge!(
+        "admission_queued",
+        "Number of submissions waiting for the host's load to drop"
+    );
+    describe_counter!(
+        "admission_rejections_total",
+        "Total number of submissions turned away because the host is short on resources"
+    );
 
-    system_monitor::start_system_monitor().await;
+    let system = system_monitor::start_system_monitor(&app_config).await;
 
-    let client = redis::Client::open(app_config.redis_url).unwrap();
-    let con = client.get_multiplexed_async_connection().await.unwrap();
-    let app = Router::new()
-        .route("/execute", post(execute_code_endpoint))
+    let jwt = match &app_config.jwt {
+        Some(config) => Some(JwtVerifier::start(config).await),
+        None => None,
+    };
+
+    let state = AppState {
+        redis_connection: con,
+        base_code_path: app_config.base_code_path.clone(),
+        prometheus_handle: handle.clone(),
+        config: app_config.clone(),
+        sandboxes: Arc::new(SandboxRegistry::default()),
+        stats: Arc::new(Stats::new()),
+        images: Arc::new(ImageManager::new(&app_config)),
+        file_cache: Arc::new(FileCache::new(&app_config)),
+        trusted_proxies: Arc::new(
+            TrustedProxies::new(&app_config).unwrap_or_else(|e| settings::fail(&[e])),
+        ),
+        jwt,
+        ws_connections: Arc::new(ConnectionLimiter::new(
+            app_config.ws_max_connections_per_client,
+        )),
+        usage: usage.map(Arc::new),
+        system,
+        webhooks: Arc::new(webhooks),
+        runner_events: Arc::new(EventListener::default()),
+        transfers: Arc::new(transfers),
+        encryption: encryption.map(Arc::new),
+        memory_files: (app_config.file_store == FileStore::Memory)
+            .then(|| MemoryFileManager::new(&app_config)),
+    };
+
+    match &cli.command {
+        Some(Command::Run { request }) => std::process::exit(cli::run(state, request).await),
+        Some(Command::Bench(args)) => std::process::exit(bench::local(state, args).await),
+        _ => {}
+    }
+
+    // jobs and crons are kept in redis
+    if let Some(connection) = &state.redis_connection {
+        jobs::start_scheduler(state.clone());
+        state.webhooks.start(connection.clone());
+        match app_config.mode {
+            Mode::Runner => runner::start(state.clone(), connection.clone()),
+            Mode::Coordinator => state.runner_events.start(state.config.clone()),
+            Mode::Standalone => {}
+        }
+    }
+
+    let app = app(state);
+
+    // a socket from systemd outlives restarts, host and port don't apply to it
+    let listener = match activated {
+        Some(listener) => tokio::net::TcpListener::from_std(listener).unwrap(),
+        None => tokio::net::TcpListener::bind(format!("{}:{}", app_config.host, app_config.port))
+            .await
+            .unwrap(),
+    };
+    let app = app.into_make_service_with_connect_info::<Peer>();
+
+    tracing::info!("listening on {}", listener.local_addr().unwrap());
+    match server_config {
+        // redis is connected by now, so requests can be served from here on
+        None => {
+            systemd::notify("READY=1");
+            axum::serve(listener, app).await.unwrap();
+        }
+        Some(server_config) => {
+            let listener = TlsListener::new(listener, server_config).unwrap();
+            systemd::notify("READY=1");
+            axum::serve(listener, app).await.unwrap();
+        }
+    }
+}
+
+// keeps the value, or the error with the other configuration problems
+fn checked<T>(problems: &mut Vec<String>, built: Result<T, String>) -> Option<T> {
+    built.map_err(|e| problems.push(e)).ok()
+}
+
+// the prometheus recorder with the configured histogram buckets. empty ones
+// are left out, settings::validate reports them already
+fn metrics_builder(config: &AppConfig) -> Result<PrometheusBuilder, String> {
+    let mut builder = PrometheusBuilder::new();
+    for (metric, buckets) in &config.histogram_buckets {
+        if buckets.is_empty() {
+            continue;
+        }
+        builder = builder
+            .set_buckets_for_metric(Matcher::Full(metric.clone()), buckets)
+            .map_err(|e| format!("histogram_buckets.{}: {}", metric, e))?;
+    }
+    Ok(builder)
+}
+
+// the routes of the API and the layers every request passes
+fn app(state: AppState) -> Router {
+    let config = state.config.clone();
+    let compressed = |path: &str, route| compressed(&config, path, route);
+    Router::new()
+        .route("/execute", compressed("/execute", post(execute_code_endpoint)))
         .route("/execute", any(execute_code_ws_handler))
-        .route("/metrics", get(metrics_endpoint))
+        .route("/metrics", compressed("/metrics", get(metrics_endpoint)))
+        .route("/stats", compressed("/stats", get(stats_endpoint)))
+        .route(
+            "/capabilities",
+            compressed("/capabilities", get(capabilities_endpoint)),
+        )
+        .route("/jobs", post(submit_job))
+        .route("/jobs/{id}", compressed("/jobs/{id}", get(get_job)))
+        .route(
+            "/jobs/{id}/results",
+            compressed("/jobs/{id}/results", get(get_job_results)),
+        )
+        .route("/cron", get(list_crons).post(create_cron))
+        .route(
+            "/cron/{id}",
+            get(get_cron).put(update_cron).delete(delete_cron),
+        )
+        .route(
+            "/problems",
+            post(import_problem).layer(DefaultBodyLimit::max(config.max_problem_size as usize)),
+        )
+        .route(
+            "/problems/{id}",
+            compressed("/problems/{id}", get(get_problem).delete(delete_problem)),
+        )
+        .route("/judge", compressed("/judge", post(judge_endpoint)))
+        .route("/files/{id}", compressed("/files/{id}", get(get_file)))
+        .route("/admin/sandboxes", get(list_sandboxes))
+        .route("/admin/sandboxes/{id}", delete(kill_sandbox))
+        .route("/admin/runners", get(list_runners))
+        .route("/admin/dead-letters", get(list_dead_letters))
+        .route("/admin/dead-letters/{id}/requeue", post(requeue_dead_letter))
+        // routes taking uploads set their own, larger limit
+        .layer(DefaultBodyLimit::max(config.max_request_size as usize))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            verify_signature,
+        ))
+        .layer(middleware::from_fn(payload_too_large))
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(tracing::Level::INFO))
+                .make_span_with(|request: &Request| {
+                    let client_ip = request.extensions().get::<ClientIp>().map(|ip| ip.0);
+                    tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        version = ?request.version(),
+                        client_ip = ?client_ip,
+                    )
+                })
                 .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
         )
-        .with_state(AppState {
-            redis_connection: con,
-            base_code_path: app_config.base_code_path.clone(),
-            prometheus_handle: handle.clone(),
-        });
-
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", app_config.port))
-        .await
-        .unwrap();
+        // outside the trace layer, so request spans carry the client's address
+        .layer(middleware::from_fn_with_state(state.clone(), client_ip))
+        .with_state(state)
+}
 
-    tracing::info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+// compresses the route's responses, SSE streams included, for clients that
+// accept gzip, brotli or zstd, when the operator listed the route
+fn compressed(
+    config: &AppConfig,
+    path: &str,
+    route: MethodRouter<AppState>,
+) -> MethodRouter<AppState> {
+    if !config.compressed_routes.iter().any(|route| route == path) {
+        return route;
+    }
+    // unlike the default predicate this keeps event streams, each event is
+    // flushed as it is sent
+    let predicate = SizeAbove::default()
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES);
+    route.layer(CompressionLayer::new().compress_when(predicate))
 }
@@ -0,0 +1,128 @@
+use hakoniwa::{ExitStatus, Output, ProcPidStatus, Rusage};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::sandboxes::Sandbox;
+use crate::types::ResourceUsage;
+
+// how often a sleeping run checks whether its sandbox was killed
+const KILL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// what a program does in place of running, keyed by the program as the
+// execution names it
+static SCRIPTS: LazyLock<Mutex<HashMap<String, Script>>> = LazyLock::new(Default::default);
+
+// the outcome of a faked run. resource usage is whatever it says, so results
+// are the same on every machine
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+    pub signal: Option<i32>, // killed by it instead of exiting
+    pub time_ms: u64,
+    pub memory_kb: u64,
+    pub sleep_ms: u64, // real time the run takes, for tests of deadlines
+    pub files: Vec<(String, Vec<u8>)>, // written to /box, relative to it
+}
+
+// makes every later run of `program` follow `script`
+#[cfg(test)]
+pub fn script(program: &str, script: Script) {
+    SCRIPTS.lock().unwrap().insert(program.to_string(), script);
+}
+
+// stands in for the sandbox. programs without a script echo their arguments,
+// or stdin when they have none, and exit 0. a kill of `sandbox` ends the
+// sleep early with SIGKILL, like it would end the program
+pub fn run(
+    program: &str,
+    args: &[String],
+    stdin: Option<&[u8]>,
+    box_dir: &Path,
+    sandbox: &Sandbox,
+) -> (Output, ResourceUsage) {
+    let mut script = SCRIPTS
+        .lock()
+        .unwrap()
+        .get(program)
+        .cloned()
+        .unwrap_or_else(|| Script {
+            stdout: if args.is_empty() {
+                stdin.unwrap_or_default().to_vec()
+            } else {
+                format!("{}\n", args.join(" ")).into_bytes()
+            },
+            ..Default::default()
+        });
+
+    let until = Instant::now() + Duration::from_millis(script.sleep_ms);
+    while Instant::now() < until {
+        if sandbox.is_killed() {
+            script.signal = Some(libc::SIGKILL);
+            break;
+        }
+        std::thread::sleep(KILL_POLL_INTERVAL.min(until - Instant::now()));
+    }
+    for (name, content) in &script.files {
+        let path = box_dir.join(name);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        if let Err(e) = fs::write(&path, content) {
+            tracing::warn!("mock failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    let (code, exit_code, reason) = match script.signal {
+        Some(signal) => (128 + signal, None, format!("killed by signal {}", signal)),
+        None => (
+            script.exit_code,
+            Some(script.exit_code),
+            format!("exited with code {}", script.exit_code),
+        ),
+    };
+    let time = Duration::from_millis(script.time_ms);
+    let memory_kb = script.memory_kb;
+    let output = Output {
+        status: ExitStatus {
+            code,
+            reason,
+            exit_code,
+            rusage: Some(Rusage {
+                real_time: time,
+                user_time: time,
+                system_time: Duration::ZERO,
+                max_rss: memory_kb as i64,
+            }),
+            proc_pid_smaps_rollup: None,
+            proc_pid_status: Some(ProcPidStatus {
+                name: program.to_string(),
+                vmpeak: memory_kb,
+                vmsize: memory_kb,
+                vmhwm: memory_kb,
+                vmrss: memory_kb,
+                vmdata: memory_kb,
+                vmstk: 0,
+                vmexe: 0,
+                vmlib: 0,
+                vmpte: 0,
+                vmswap: 0,
+                rssanon: memory_kb,
+                rssfile: 0,
+                rssshmem: 0,
+                nonewprivs: 1,
+            }),
+        },
+        stdout: script.stdout,
+        stderr: script.stderr,
+    };
+    let resource = ResourceUsage {
+        max_rss: memory_kb,
+        ..Default::default()
+    };
+    (output, resource)
+}
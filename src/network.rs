@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+// one allowlist entry, either a domain ("*." prefix only valid in operator
+// policies) or an address range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EgressRule {
+    Domain(String),
+    Net(IpNet),
+}
+
+impl EgressRule {
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        if let Ok(net) = entry.parse::<IpNet>() {
+            return Ok(EgressRule::Net(net.trunc()));
+        }
+        if let Ok(addr) = entry.parse::<IpAddr>() {
+            return Ok(EgressRule::Net(IpNet::from(addr)));
+        }
+
+        let domain = entry.trim_end_matches('.').to_ascii_lowercase();
+        let name = domain.strip_prefix("*.").unwrap_or(&domain);
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            return Err(format!("invalid egress rule {}", entry));
+        }
+        Ok(EgressRule::Domain(domain))
+    }
+
+    fn is_wildcard(&self) -> bool {
+        matches!(self, EgressRule::Domain(domain) if domain.starts_with("*."))
+    }
+
+    // whether this policy rule covers `rule`
+    fn allows(&self, rule: &EgressRule) -> bool {
+        match (self, rule) {
+            (EgressRule::Net(policy), EgressRule::Net(net)) => policy.contains(net),
+            (EgressRule::Domain(policy), EgressRule::Domain(domain)) => {
+                match policy.strip_prefix("*.") {
+                    Some(suffix) => domain
+                        .strip_suffix(suffix)
+                        .is_some_and(|rest| rest.ends_with('.')),
+                    None => policy == domain,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+// the rules an execution is filtered with: the requested ones, checked against
+// the policy, or the policy itself. wildcards can't be resolved up front, so
+// they only ever widen what a request may ask for. None means unrestricted
+pub fn allowlist(
+    policy: &[String],
+    requested: Option<&[String]>,
+) -> Result<Option<Vec<EgressRule>>, String> {
+    let policy = policy
+        .iter()
+        .map(|entry| EgressRule::parse(entry))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let Some(requested) = requested else {
+        if policy.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some(
+            policy
+                .into_iter()
+                .filter(|rule| !rule.is_wildcard())
+                .collect(),
+        ));
+    };
+
+    let mut rules = Vec::new();
+    for entry in requested {
+        let rule = EgressRule::parse(entry)?;
+        if rule.is_wildcard() {
+            return Err(format!("egress rule {} must name a single domain", entry));
+        }
+        if !policy.is_empty() && !policy.iter().any(|p| p.allows(&rule)) {
+            return Err(format!(
+                "egress rule {} is not allowed by the profile",
+                entry
+            ));
+        }
+        rules.push(rule);
+    }
+    Ok(Some(rules))
+}
+
+// resolved form of an allowlist: address ranges for the firewall and the
+// addresses each domain was pinned to
+pub struct ResolvedEgress {
+    pub nets: Vec<IpNet>,
+    pub hosts: BTreeMap<String, Vec<IpAddr>>,
+}
+
+// domains are resolved once on the host so the program connects to exactly
+// the addresses the firewall lets through
+pub async fn resolve(rules: &[EgressRule]) -> Result<ResolvedEgress, String> {
+    let mut nets = Vec::new();
+    let mut hosts = BTreeMap::new();
+    for rule in rules {
+        match rule {
+            EgressRule::Net(net) => nets.push(*net),
+            EgressRule::Domain(domain) => {
+                let mut addrs: Vec<IpAddr> = tokio::net::lookup_host((domain.as_str(), 0))
+                    .await
+                    .map_err(|e| format!("failed to resolve egress domain {}: {}", domain, e))?
+                    .map(|addr| addr.ip())
+                    .collect();
+                addrs.sort();
+                addrs.dedup();
+                nets.extend(addrs.iter().map(|addr| IpNet::from(*addr)));
+                hosts.insert(domain.clone(), addrs);
+            }
+        }
+    }
+    Ok(ResolvedEgress { nets, hosts })
+}
+
+impl ResolvedEgress {
+    // /etc/hosts pinning every allowed domain to its resolved addresses
+    pub fn hosts_file(&self) -> String {
+        let mut hosts = String::from("127.0.0.1 localhost\n::1 localhost\n");
+        for (domain, addrs) in &self.hosts {
+            for addr in addrs {
+                hosts.push_str(&format!("{} {}\n", addr, domain));
+            }
+        }
+        hosts
+    }
+}
+
+// nftables ruleset for the sandbox's outbound traffic. with an allowlist
+// everything else is dropped, a connection cap rejects new connections once
+// that many are tracked in the namespace
+pub fn nft_ruleset(allowed: Option<&[IpNet]>, max_connections: Option<u32>) -> String {
+    let policy = if allowed.is_some() { "drop" } else { "accept" };
+    let mut rules = format!(
+        "table inet pentagon {{\n\
+         \tchain output {{\n\
+         \t\ttype filter hook output priority 0; policy {};\n\
+         \t\toif \"lo\" accept\n",
+        policy
+    );
+    if let Some(max) = max_connections {
+        rules.push_str(&format!("\t\tct state new ct count over {} reject\n", max));
+    }
+    rules.push_str("\t\tct state established,related accept\n");
+    for net in allowed.unwrap_or_default() {
+        let family = match net {
+            IpNet::V4(_) => "ip",
+            IpNet::V6(_) => "ip6",
+        };
+        rules.push_str(&format!("\t\t{} daddr {} accept\n", family, net));
+    }
+    rules.push_str("\t}\n}\n");
+    rules
+}
+
+// shell commands capping every non-loopback interface listed in /proc/net/dev
+// (after its two header lines) to `kbit` kbit/s in both directions: a token
+// bucket on egress and a policer on ingress
+pub fn shaping_commands(tc_path: &str, kbit: u64) -> String {
+    // enough burst for 10ms at full rate
+    let burst = (kbit * 1000 / 8 / 100).max(16 * 1024);
+    format!(
+        "{{ read -r _; read -r _\n\
+         while IFS=': ' read -r dev rest; do\n\
+         \t[ \"$dev\" = lo ] && continue\n\
+         \t{tc} qdisc add dev \"$dev\" root tbf rate {kbit}kbit burst {burst} latency 100ms\n\
+         \t{tc} qdisc add dev \"$dev\" handle ffff: ingress\n\
+         \t{tc} filter add dev \"$dev\" parent ffff: protocol all u32 match u32 0 0 \
+         police rate {kbit}kbit burst {burst} drop\n\
+         done; }} < /proc/net/dev\n",
+        tc = tc_path,
+        kbit = kbit,
+        burst = burst
+    )
+}
@@ -0,0 +1,343 @@
+use axum::body::Bytes;
+use axum::http::{Request, StatusCode, Uri, header};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use hyper_util::rt::TokioExecutor;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+// manifests are small json documents, layers are streamed to disk
+const MAX_MANIFEST_SIZE: usize = 4 * 1024 * 1024;
+
+// blobs are usually served from a cdn behind a redirect
+const MAX_REDIRECTS: usize = 5;
+
+// where an image lives, parsed from references like "python:3.12",
+// "docker.io/library/python" or "ghcr.io/org/image:tag"
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+}
+
+impl Reference {
+    pub fn parse(reference: &str) -> Result<Self, String> {
+        // the digest is pinned separately, tags are only informative
+        let reference = reference.split('@').next().unwrap_or_default();
+        let (registry, path) = match reference.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host, path)
+            }
+            _ => (DOCKER_HUB, reference),
+        };
+        let repository = match path.rsplit_once('/') {
+            Some((namespace, name)) => {
+                format!(
+                    "{}/{}",
+                    namespace,
+                    name.split(':').next().unwrap_or_default()
+                )
+            }
+            None => path.split(':').next().unwrap_or_default().to_string(),
+        };
+        if repository.is_empty() || repository.ends_with('/') {
+            return Err(format!("invalid image reference {}", reference));
+        }
+
+        if registry == DOCKER_HUB {
+            let repository = if repository.contains('/') {
+                repository
+            } else {
+                format!("library/{}", repository)
+            };
+            return Ok(Self {
+                registry: DOCKER_HUB_REGISTRY.to_string(),
+                repository,
+            });
+        }
+        Ok(Self {
+            registry: registry.to_string(),
+            repository,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>, // set on image indexes (multi-platform images)
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+pub struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    pub media_type: String,
+    pub digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+// anonymous pulls from a registry speaking the distribution api
+pub struct RegistryClient {
+    client: Client<HttpsConnector<HttpConnector>, Empty<Bytes>>,
+    reference: Reference,
+    token: Option<String>,
+}
+
+impl RegistryClient {
+    pub fn new(reference: Reference) -> Result<Self, String> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|e| format!("failed to load root certificates: {}", e))?
+            .https_only()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            reference,
+            token: None,
+        })
+    }
+
+    // the layers of the image pinned by `digest`, picking the host's platform
+    // from an image index. every manifest is checked against its digest
+    pub async fn layers(&mut self, digest: &str) -> Result<Vec<Descriptor>, String> {
+        let manifest = self.manifest(digest).await?;
+        if manifest.manifests.is_empty() {
+            return Ok(manifest.layers);
+        }
+        let architecture = host_architecture();
+        let platform = manifest
+            .manifests
+            .into_iter()
+            .find(|descriptor| {
+                descriptor.platform.as_ref().is_some_and(|platform| {
+                    platform.os == "linux" && platform.architecture == architecture
+                })
+            })
+            .ok_or_else(|| format!("image has no linux/{} variant", architecture))?;
+        Ok(self.manifest(&platform.digest).await?.layers)
+    }
+
+    async fn manifest(&mut self, digest: &str) -> Result<Manifest, String> {
+        let path = format!("manifests/{}", digest);
+        let body = self.get(&path, Some(MANIFEST_TYPES)).await?;
+        let body = read_limited(body, MAX_MANIFEST_SIZE).await?;
+        verify(digest, &Sha256::digest(&body))?;
+        serde_json::from_slice(&body).map_err(|e| format!("invalid image manifest: {}", e))
+    }
+
+    // streams the blob into `path`, checking it against its digest
+    pub async fn download_blob(&mut self, digest: &str, path: &Path) -> Result<(), String> {
+        let mut body = self.get(&format!("blobs/{}", digest), None).await?;
+        let mut file = std::fs::File::create(path)
+            .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+        let mut hasher = Sha256::new();
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|e| format!("failed to download blob {}: {}", digest, e))?;
+            if let Ok(data) = frame.into_data() {
+                hasher.update(&data);
+                file.write_all(&data)
+                    .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+            }
+        }
+        verify(digest, &hasher.finalize())
+    }
+
+    // fetches a registry path, authenticating once when challenged and
+    // following redirects (without credentials, they lead to other hosts)
+    async fn get(&mut self, path: &str, accept: Option<&str>) -> Result<Incoming, String> {
+        let mut uri = format!(
+            "https://{}/v2/{}/{}",
+            self.reference.registry, self.reference.repository, path
+        );
+        let mut authenticated = self.token.is_some();
+        let mut redirects = 0;
+        loop {
+            let mut request = Request::get(&uri);
+            if let Some(accept) = accept {
+                request = request.header(header::ACCEPT, accept);
+            }
+            if redirects == 0
+                && let Some(token) = &self.token
+            {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let request = request
+                .body(Empty::new())
+                .map_err(|e| format!("invalid registry url {}: {}", uri, e))?;
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| format!("failed to reach {}: {}", self.reference.registry, e))?;
+            let status = response.status();
+
+            if status == StatusCode::UNAUTHORIZED && !authenticated && redirects == 0 {
+                let challenge = response
+                    .headers()
+                    .get(header::WWW_AUTHENTICATE)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or("registry requires authentication without a challenge")?
+                    .to_string();
+                self.token = Some(self.fetch_token(&challenge).await?);
+                authenticated = true;
+                continue;
+            }
+            if status.is_redirection() {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(format!("too many redirects fetching {}", path));
+                }
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or("registry redirect without a location")?;
+                uri = resolve_location(&uri, location)?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(format!(
+                    "registry {} answered {} for {}",
+                    self.reference.registry, status, path
+                ));
+            }
+            return Ok(response.into_body());
+        }
+    }
+
+    // bearer token flow: Bearer realm="...",service="...",scope="..."
+    async fn fetch_token(&self, challenge: &str) -> Result<String, String> {
+        let params = challenge
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| format!("unsupported registry authentication {}", challenge))?;
+        let mut realm = None;
+        let mut query = Vec::new();
+        for param in params.split(',') {
+            let Some((key, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.trim_matches('"');
+            match key {
+                "realm" => realm = Some(value),
+                "service" | "scope" => query.push(format!("{}={}", key, query_escape(value))),
+                _ => {}
+            }
+        }
+        let realm = realm.ok_or("registry challenge without a realm")?;
+        let uri = if query.is_empty() {
+            realm.to_string()
+        } else {
+            format!("{}?{}", realm, query.join("&"))
+        };
+
+        let request = Request::get(&uri)
+            .body(Empty::new())
+            .map_err(|e| format!("invalid token url {}: {}", uri, e))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| format!("failed to reach {}: {}", realm, e))?;
+        if !response.status().is_success() {
+            return Err(format!("token service answered {}", response.status()));
+        }
+        let body = read_limited(response.into_body(), MAX_MANIFEST_SIZE).await?;
+        let token: Token =
+            serde_json::from_slice(&body).map_err(|e| format!("invalid token response: {}", e))?;
+        token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| "token response without a token".to_string())
+    }
+}
+
+async fn read_limited(mut body: Incoming, limit: usize) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| format!("failed to read registry response: {}", e))?;
+        if let Ok(chunk) = frame.into_data() {
+            if data.len() + chunk.len() > limit {
+                return Err("registry response is too large".to_string());
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+    Ok(data)
+}
+
+// only sha256 digests are supported, like every registry in practice
+fn verify(digest: &str, hash: &[u8]) -> Result<(), String> {
+    let expected = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| format!("unsupported digest {}", digest))?;
+    if hex::encode(hash) != expected {
+        return Err(format!("content does not match digest {}", digest));
+    }
+    Ok(())
+}
+
+fn resolve_location(current: &str, location: &str) -> Result<String, String> {
+    if location.starts_with("https://") {
+        return Ok(location.to_string());
+    }
+    let current: Uri = current
+        .parse()
+        .map_err(|e| format!("invalid url {}: {}", current, e))?;
+    match (location.starts_with('/'), current.authority()) {
+        (true, Some(authority)) => Ok(format!("https://{}{}", authority, location)),
+        _ => Err(format!("unsupported redirect to {}", location)),
+    }
+}
+
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b':' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// oci platform names of the architectures runners are built for
+fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        arch => arch,
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+
+use crate::types::PerfCounters;
+
+const PERF_TYPE_HARDWARE: u32 = 0;
+
+// hardware events counted for an execution
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+
+// bits of perf_event_attr's flags
+const FLAG_DISABLED: u64 = 1;
+const FLAG_INHERIT: u64 = 1 << 1;
+const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+const FLAG_ENABLE_ON_EXEC: u64 = 1 << 12;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+// the first version of perf_event_attr, later kernels take it as is
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+// hardware counters of a sandbox, counting user space events of every
+// process it runs from the moment it execs. counters the cpu or the host
+// doesn't provide are left out
+pub struct PerfSession {
+    instructions: Option<File>,
+    cycles: Option<File>,
+    cache_misses: Option<File>,
+}
+
+impl PerfSession {
+    // opens counters on the calling thread that the processes it forks
+    // inherit, disabled until they exec. call it right before spawning, on a
+    // thread that forks nothing else while the session is open. fails when
+    // no counter could be opened, e.g. with perf_event_paranoid above 2 or in
+    // a vm without a pmu
+    pub fn start_on_exec() -> Result<Self, String> {
+        let mut error = None;
+        let mut open = |config| {
+            open_counter(config)
+                .inspect_err(|e| error = Some(e.to_string()))
+                .ok()
+        };
+        let session = Self {
+            instructions: open(PERF_COUNT_HW_INSTRUCTIONS),
+            cycles: open(PERF_COUNT_HW_CPU_CYCLES),
+            cache_misses: open(PERF_COUNT_HW_CACHE_MISSES),
+        };
+        match error {
+            Some(e) if session.instructions.is_none() && session.cycles.is_none() => {
+                Err(format!("failed to open perf counters: {}", e))
+            }
+            _ => Ok(session),
+        }
+    }
+
+    // the counts, call once the container process was waited for so the
+    // counts of its exited children are included
+    pub fn finish(self) -> PerfCounters {
+        PerfCounters {
+            instructions: self.instructions.and_then(read_counter),
+            cycles: self.cycles.and_then(read_counter),
+            cache_misses: self.cache_misses.and_then(read_counter),
+        }
+    }
+}
+
+fn open_counter(config: u64) -> io::Result<File> {
+    let attr = PerfEventAttr {
+        kind: PERF_TYPE_HARDWARE,
+        size: size_of::<PerfEventAttr>() as u32,
+        config,
+        flags: FLAG_DISABLED
+            | FLAG_INHERIT
+            | FLAG_ENABLE_ON_EXEC
+            | FLAG_EXCLUDE_KERNEL
+            | FLAG_EXCLUDE_HV,
+        ..Default::default()
+    };
+    // pid 0 is the calling thread
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            0 as libc::pid_t,
+            -1 as libc::c_int,
+            -1 as libc::c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+}
+
+fn read_counter(mut counter: File) -> Option<u64> {
+    let mut value = [0u8; 8];
+    counter.read_exact(&mut value).ok()?;
+    Some(u64::from_ne_bytes(value))
+}
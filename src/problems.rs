@@ -0,0 +1,192 @@
+use axum::http::StatusCode;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::archive::{self, ArchiveEntry};
+use crate::files::{FileManagerTrait, RedisFileManager};
+use crate::redis_client;
+use crate::types::FilePath;
+
+const MANIFEST_NAME: &str = "problem.json";
+const TESTS_DIR: &str = "tests/";
+
+// limits and checker of a problem package, read from problem.json
+#[derive(Deserialize)]
+struct Manifest {
+    time_limit: u64,      // in seconds, per testcase
+    wall_time_limit: u64, // in seconds, per testcase
+    memory_limit: u64,    // in kilobytes
+    #[serde(default)]
+    checker: Option<String>, // path of the checker inside the archive
+    #[serde(default)]
+    points: BTreeMap<String, f64>, // testcase name -> points, 1 for testcases left out
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Problem {
+    pub id: String,
+    pub tenant: Option<String>,
+    pub time_limit: u64,
+    pub wall_time_limit: u64,
+    pub memory_limit: u64,       // in bytes, like an execution's
+    pub checker: Option<String>, // remote file id of the checker
+    pub testcases: Vec<Testcase>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Testcase {
+    pub name: String,
+    pub input: String,          // remote file id
+    pub output: Option<String>, // remote file id of the expected output
+    #[serde(default = "default_points")]
+    pub points: f64,
+}
+
+fn default_points() -> f64 {
+    1.0
+}
+
+// apart from the files' "problem:" ids, which are remote file ids
+fn problem_key(id: &str) -> String {
+    redis_client::key(&format!("problems:{}", id))
+}
+
+// testcase files are stored under ids derived from the problem, so requests
+// can name them directly as remote files
+fn file_id(problem: &str, path: &str) -> String {
+    format!("problem:{}:{}", problem, path)
+}
+
+// unpacks a problem archive: problem.json with the limits, tests/<name>.in
+// inputs with optional tests/<name>.out (or .ans) expected outputs, and the
+// checker named in the manifest. everything else is ignored
+pub async fn import(
+    file_manager: &mut RedisFileManager,
+    connection: &mut MultiplexedConnection,
+    id: String,
+    tenant: Option<String>,
+    data: &[u8],
+    max_size: u64,
+) -> Result<Problem, (StatusCode, String)> {
+    let invalid = |message: String| (StatusCode::BAD_REQUEST, message);
+    let entries: BTreeMap<String, Vec<u8>> = archive::read_zip(data, max_size)
+        .map_err(invalid)?
+        .into_iter()
+        .map(|ArchiveEntry { name, data, .. }| (name.trim_start_matches("./").to_string(), data))
+        .collect();
+
+    let manifest = entries
+        .get(MANIFEST_NAME)
+        .ok_or_else(|| invalid(format!("archive has no {}", MANIFEST_NAME)))?;
+    let manifest: Manifest = serde_json::from_slice(manifest)
+        .map_err(|e| invalid(format!("invalid {}: {}", MANIFEST_NAME, e)))?;
+
+    let mut stored = Vec::new();
+    if let Some(checker) = &manifest.checker {
+        if !entries.contains_key(checker) {
+            return Err(invalid(format!(
+                "checker {} is not in the archive",
+                checker
+            )));
+        }
+        stored.push(checker.clone());
+    }
+
+    let mut testcases = Vec::new();
+    for path in entries.keys() {
+        let Some(name) = path
+            .strip_prefix(TESTS_DIR)
+            .and_then(|name| name.strip_suffix(".in"))
+        else {
+            continue;
+        };
+        let output = [".out", ".ans"]
+            .iter()
+            .map(|extension| format!("{}{}{}", TESTS_DIR, name, extension))
+            .find(|output| entries.contains_key(output));
+        stored.push(path.clone());
+        stored.extend(output.clone());
+        testcases.push(Testcase {
+            name: name.to_string(),
+            input: file_id(&id, path),
+            output: output.map(|output| file_id(&id, &output)),
+            points: manifest.points.get(name).copied().unwrap_or(1.0),
+        });
+    }
+    if testcases.is_empty() {
+        return Err(invalid(format!(
+            "archive has no {}*.in testcases",
+            TESTS_DIR
+        )));
+    }
+    // numbered tests run in numeric order, 2 before 10
+    testcases.sort_by_key(|testcase| (testcase.name.parse::<u64>().ok(), testcase.name.clone()));
+
+    let unavailable = |e: String| (StatusCode::SERVICE_UNAVAILABLE, e);
+    for path in &stored {
+        let id = file_id(&id, path);
+        file_manager
+            .save_file(
+                FilePath::Remote { id: id.clone() },
+                None,
+                entries[path].clone(),
+            )
+            .await
+            .map_err(unavailable)?;
+        // unlike uploads, problem files stay until the problem is deleted
+        file_manager.persist(&id).await.map_err(unavailable)?;
+    }
+
+    let problem = Problem {
+        checker: manifest.checker.map(|checker| file_id(&id, &checker)),
+        id,
+        tenant,
+        time_limit: manifest.time_limit,
+        wall_time_limit: manifest.wall_time_limit,
+        // manifests count kilobytes like most judges, executions take bytes
+        memory_limit: manifest.memory_limit.saturating_mul(1024),
+        testcases,
+    };
+    let record = serde_json::to_string(&problem).unwrap();
+    let _: () = connection
+        .set(problem_key(&problem.id), record)
+        .await
+        .map_err(|e| unavailable(format!("failed to save problem: {}", e)))?;
+    Ok(problem)
+}
+
+pub async fn get(
+    connection: &mut MultiplexedConnection,
+    id: &str,
+) -> Result<Option<Problem>, String> {
+    let stored: Option<String> = connection
+        .get(problem_key(id))
+        .await
+        .map_err(|e| format!("failed to read problem: {}", e))?;
+    stored
+        .map(|stored| serde_json::from_str(&stored).map_err(|e| format!("corrupt problem: {}", e)))
+        .transpose()
+}
+
+pub async fn delete(
+    connection: &mut MultiplexedConnection,
+    file_manager: &mut RedisFileManager,
+    problem: &Problem,
+) -> Result<(), String> {
+    let mut files = Vec::new();
+    files.extend(problem.checker.clone());
+    for testcase in &problem.testcases {
+        files.push(testcase.input.clone());
+        files.extend(testcase.output.clone());
+    }
+    // files stored in parts take theirs with them
+    file_manager
+        .delete(&files)
+        .await
+        .map_err(|e| format!("failed to delete problem: {}", e))?;
+    connection
+        .del::<_, ()>(problem_key(&problem.id))
+        .await
+        .map_err(|e| format!("failed to delete problem: {}", e))
+}
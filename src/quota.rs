@@ -0,0 +1,93 @@
+use axum::http::StatusCode;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::redis_client;
+use crate::types::Tenant;
+
+// usage counters outlive their day so increments from runs that started
+// before midnight still land somewhere
+const USAGE_TTL: i64 = 60 * 60 * 24 * 2;
+
+// counters reset every UTC day
+fn usage_key(tenant: &str, counter: &str) -> String {
+    let day = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / (60 * 60 * 24))
+        .unwrap_or_default();
+    redis_client::key(&format!("quota:{}:{}:{}", tenant, day, counter))
+}
+
+// tenants without a daily limit have nothing to count
+pub fn applies(tenant: &Tenant) -> bool {
+    tenant.daily_executions.is_some()
+        || tenant.daily_cpu_seconds.is_some()
+        || tenant.daily_bytes_stored.is_some()
+}
+
+// rejects a submission of `executions` runs once any daily quota is used up.
+// cpu time and storage are only known afterwards, so a run can overshoot them
+pub async fn check(
+    connection: &mut MultiplexedConnection,
+    name: &str,
+    tenant: &Tenant,
+    executions: u64,
+) -> Result<(), (StatusCode, String)> {
+    let keys = [
+        usage_key(name, "executions"),
+        usage_key(name, "cpu_ms"),
+        usage_key(name, "bytes_stored"),
+    ];
+    let used: Vec<Option<u64>> = connection.mget(&keys).await.map_err(|e| {
+        tracing::error!("failed to read quota usage: {}", e);
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "failed to check quota".to_string(),
+        )
+    })?;
+    let [executions_used, cpu_ms_used, bytes_used] = [0, 1, 2].map(|i| used[i].unwrap_or(0));
+
+    let exceeded = |message: String| Err((StatusCode::TOO_MANY_REQUESTS, message));
+    if let Some(limit) = tenant.daily_executions
+        && executions_used + executions > limit
+    {
+        return exceeded(format!(
+            "quota exceeded: {} of {} daily executions used, {} requested",
+            executions_used, limit, executions
+        ));
+    }
+    if let Some(limit) = tenant.daily_cpu_seconds
+        && cpu_ms_used >= limit * 1000
+    {
+        return exceeded(format!("quota exceeded: {} daily cpu seconds used", limit));
+    }
+    if let Some(limit) = tenant.daily_bytes_stored
+        && bytes_used >= limit
+    {
+        return exceeded(format!("quota exceeded: {} daily bytes stored used", limit));
+    }
+    Ok(())
+}
+
+pub async fn record(
+    connection: &mut MultiplexedConnection,
+    name: &str,
+    cpu_ms: u64,
+    bytes_stored: u64,
+) {
+    let mut pipe = redis::pipe();
+    for (counter, amount) in [
+        ("executions", 1),
+        ("cpu_ms", cpu_ms),
+        ("bytes_stored", bytes_stored),
+    ] {
+        let key = usage_key(name, counter);
+        pipe.incr(&key, amount)
+            .ignore()
+            .expire(&key, USAGE_TTL)
+            .ignore();
+    }
+    if let Err(e) = pipe.query_async::<()>(connection).await {
+        tracing::warn!("failed to record quota usage: {}", e);
+    }
+}
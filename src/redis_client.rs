@@ -0,0 +1,130 @@
+use redis::aio::{MultiplexedConnection, PubSub};
+use redis::{Client, ClientTlsConfig, ConnectionAddr, IntoConnectionInfo, TlsCertificates};
+use std::fs;
+use std::sync::OnceLock;
+
+use crate::types::AppConfig;
+
+// connects to `url`, the redis_url, over tls for rediss:// urls, logging in
+// with the configured acl user. the connection is shared by remote files,
+// problems, jobs and runner queues alike
+pub async fn connect(config: &AppConfig, url: &str) -> Result<MultiplexedConnection, String> {
+    client(config, url)?
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| format!("failed to connect to redis: {}", e))
+}
+
+// a subscriber connection to `url`, set up like `connect`'s
+pub async fn subscribe(config: &AppConfig, url: &str) -> Result<PubSub, String> {
+    client(config, url)?
+        .get_async_pubsub()
+        .await
+        .map_err(|e| format!("failed to connect to redis: {}", e))
+}
+
+fn client(config: &AppConfig, url: &str) -> Result<Client, String> {
+    let mut info = url
+        .into_connection_info()
+        .map_err(|e| format!("invalid redis_url: {}", e))?;
+    // credentials in the url are kept unless configured separately, so the
+    // password can come from the environment instead of the url
+    if config.redis_username.is_some() {
+        info.redis.username = config.redis_username.clone();
+    }
+    if config.redis_password.is_some() {
+        info.redis.password = config.redis_password.clone();
+    }
+
+    let tls = matches!(info.addr, ConnectionAddr::TcpTls { .. });
+    let custom_tls = config.redis_ca_cert.is_some()
+        || config.redis_client_cert.is_some()
+        || config.redis_client_key.is_some();
+    if custom_tls && !tls {
+        return Err("redis tls certificates need a rediss:// redis_url".to_string());
+    }
+    let client = if custom_tls {
+        let client_tls = match (&config.redis_client_cert, &config.redis_client_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read(cert)?,
+                client_key: read(key)?,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    "redis_client_cert and redis_client_key must be set together".to_string(),
+                );
+            }
+        };
+        // without a ca the system roots are used
+        let root_cert = config.redis_ca_cert.as_deref().map(read).transpose()?;
+        Client::build_with_tls(
+            info,
+            TlsCertificates {
+                client_tls,
+                root_cert,
+            },
+        )
+    } else {
+        Client::open(info)
+    }
+    .map_err(|e| format!("invalid redis configuration: {}", e))?;
+    Ok(client)
+}
+
+// put in front of every key, so deployments sharing an instance never touch
+// each other's keys. set once at startup from redis_key_prefix
+static KEY_PREFIX: OnceLock<String> = OnceLock::new();
+
+// the service's own keys start with one of these, so remote file ids can't
+const NAMESPACES: &[&str] = &[
+    "cron",
+    "dead",
+    "idempotency",
+    "instance",
+    "job",
+    "jobs",
+    "problems",
+    "quota",
+    "result",
+    "runner",
+    "runners",
+    "signature",
+    "webhook",
+    "webhooks",
+];
+
+// keys kept next to a remote file's, "<file key>:<suffix>"
+const FILE_SUFFIXES: &[&str] = &["owner", "digest"];
+
+pub fn set_key_prefix(prefix: &str) {
+    let _ = KEY_PREFIX.set(prefix.to_string());
+}
+
+// every key the service uses is made here, `name` under the prefix
+pub fn key(name: &str) -> String {
+    let prefix = KEY_PREFIX.get().map_or("", String::as_str);
+    format!("{}:{}", prefix, name)
+}
+
+// the key of remote file `id`, which can't name one of the service's own
+// keys or what is kept next to another file: its owner, its digest or the
+// parts ":part:<n>" of a large one
+pub fn file_key(id: &str) -> Result<String, String> {
+    let namespace = id.split(':').next().unwrap_or_default();
+    let suffix: Vec<&str> = id.rsplitn(3, ':').collect();
+    let reserved = NAMESPACES.contains(&namespace)
+        || match suffix.as_slice() {
+            [index, "part", _] => !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()),
+            [last, ..] if suffix.len() > 1 => FILE_SUFFIXES.contains(last),
+            _ => false,
+        };
+    if reserved {
+        return Err(format!("remote file id {} is reserved", id));
+    }
+    Ok(key(id))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))
+}
@@ -0,0 +1,124 @@
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::redis_client;
+use crate::types::{Execution, ExecutionResult};
+
+// bumped whenever what goes into a key or the stored result changes
+const KEY_VERSION: &[u8] = b"v2";
+
+// what a run depends on: the execution as requested minus its staging, the
+// tenant it runs for, the limits and root filesystem it ends up with, its
+// stdin and everything in /box, which holds the program and the files it
+// reads, as box_digest hashed it
+pub fn key(
+    execution: &Execution,
+    tenant: Option<&str>,
+    limits: [u64; 3],
+    rootfs: &str,
+    stdin: Option<&[u8]>,
+    box_digest: &[u8],
+) -> io::Result<String> {
+    let spec = Execution {
+        copy_in: Vec::new(),
+        cache: false,
+        labels: BTreeMap::new(),
+        id: None,
+        ..execution.clone()
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_VERSION);
+    field(&mut hasher, &serde_json::to_vec(&spec)?);
+    // results are never shared between tenants
+    optional(&mut hasher, tenant.map(str::as_bytes));
+    for limit in limits {
+        hasher.update(limit.to_le_bytes());
+    }
+    field(&mut hasher, rootfs.as_bytes());
+    optional(&mut hasher, stdin);
+    field(&mut hasher, box_digest);
+    Ok(redis_client::key(&format!(
+        "result:{}",
+        hex::encode(hasher.finalize())
+    )))
+}
+
+// the names, modes and contents of everything under `box_dir`. a run that
+// changes it can't be cached, since a hit doesn't replay the writes later
+// executions over the same /box would read
+pub fn box_digest(box_dir: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    hash_tree(&mut hasher, box_dir, box_dir)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+// length prefixed so neighbouring fields can't run into each other
+fn field(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update((data.len() as u64).to_le_bytes());
+    hasher.update(data);
+}
+
+fn optional(hasher: &mut Sha256, data: Option<&[u8]>) {
+    match data {
+        Some(data) => {
+            hasher.update(b"1");
+            field(hasher, data);
+        }
+        None => hasher.update(b"0"),
+    }
+}
+
+fn hash_tree(hasher: &mut Sha256, root: &Path, dir: &Path) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        field(hasher, relative.as_os_str().as_bytes());
+        hasher.update(metadata.permissions().mode().to_le_bytes());
+        if metadata.is_dir() {
+            hash_tree(hasher, root, &path)?;
+        } else if metadata.is_symlink() {
+            field(hasher, fs::read_link(&path)?.as_os_str().as_bytes());
+        } else if metadata.is_file() {
+            hasher.update(metadata.len().to_le_bytes());
+            io::copy(&mut fs::File::open(&path)?, hasher)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn get(
+    connection: &mut MultiplexedConnection,
+    key: &str,
+) -> Result<Option<ExecutionResult>, String> {
+    let stored: Option<String> = connection
+        .get(key)
+        .await
+        .map_err(|e| format!("failed to read cached result: {}", e))?;
+    stored
+        .map(|stored| {
+            serde_json::from_str(&stored).map_err(|e| format!("corrupt cached result: {}", e))
+        })
+        .transpose()
+}
+
+pub async fn put(
+    connection: &mut MultiplexedConnection,
+    key: &str,
+    result: &ExecutionResult,
+    ttl: u64,
+) -> Result<(), String> {
+    let record = serde_json::to_string(result).unwrap();
+    connection
+        .set_ex(key, record, ttl)
+        .await
+        .map_err(|e| format!("failed to cache result: {}", e))
+}
@@ -0,0 +1,707 @@
+use futures_util::StreamExt;
+use redis::aio::{MultiplexedConnection, PubSubSink};
+use redis::{AsyncCommands, Direction};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{self, Instant};
+
+use crate::admission;
+use crate::dead_letters::{self, DeadLetter};
+use crate::handlers::run::execute_code_inner;
+use crate::redis_client;
+use crate::types::{
+    AppConfig, AppState, ExecutionRequest, ExecutionResult, Mode, OutcomeError, Progress, RunEvent,
+};
+use crate::utils::gen_random_id;
+
+// runners refresh their registration this often, one missing it for
+// HEARTBEAT_TTL_SECS is gone and gets no more requests
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const HEARTBEAT_TTL_SECS: u64 = 15;
+
+// runners publish on a request's event list name whenever they push to it.
+// coordinators look at the list this often without a notification too, in
+// case one was lost while their subscription reconnected
+const EVENT_WAIT: Duration = Duration::from_secs(1);
+
+// idle runners wait this long for a request before looking again
+const CLAIM_TIMEOUT_SECS: f64 = 5.0;
+
+// a runner slot whose redis connection failed reconnects after this
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// how often a runner checks whether the coordinator cancelled a request
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// events read at once
+const EVENT_BATCH_SIZE: usize = 64;
+
+// event lists of requests whose coordinator went away are left to expire
+const EVENTS_TTL_SECS: i64 = 60 * 60;
+
+// ids of registered runners, some of which may have stopped heartbeating
+fn runners_key() -> String {
+    redis_client::key("runners")
+}
+
+fn events_key(id: &str) -> String {
+    redis_client::key(&format!("runner:events:{}", id))
+}
+
+// set when the client of a request went away, so its runner kills it
+fn cancel_key(id: &str) -> String {
+    redis_client::key(&format!("runner:cancel:{}", id))
+}
+
+fn runner_key(id: &str) -> String {
+    redis_client::key(&format!("runner:{}", id))
+}
+
+// requests routed to a runner, pushed on the left and taken from the right
+fn queue_key(runner: &str) -> String {
+    redis_client::key(&format!("runner:queue:{}", runner))
+}
+
+// requests a runner took and hasn't finished, moved here atomically as they
+// are taken so a runner that dies leaves them behind to be requeued
+fn processing_key(runner: &str) -> String {
+    redis_client::key(&format!("runner:processing:{}", runner))
+}
+
+// takes task ARGV[1] off KEYS[1], the lists of a dead runner, and reports
+// ARGV[3..] to its coordinator through KEYS[2]. with KEYS[3] the task is
+// queued there again. nothing happens when another node moved it first
+const REQUEUE_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then return 0 end
+for i = 3, #ARGV do redis.call('RPUSH', KEYS[2], ARGV[i]) end
+redis.call('EXPIRE', KEYS[2], ARGV[2])
+redis.call('PUBLISH', KEYS[2], 1)
+if KEYS[3] then redis.call('LPUSH', KEYS[3], ARGV[1]) end
+return 1
+";
+
+// what a runner announces about itself with every heartbeat
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunnerInfo {
+    pub id: String,
+    pub capacity: usize,     // requests it works on at once
+    pub busy: usize,         // of those, taken at its last heartbeat
+    pub rootfs: Vec<String>, // root filesystems and images executions can ask for
+    pub arch: String,
+    #[serde(default)]
+    pub queued: usize, // requests routed to it and not yet taken, filled in when listed
+}
+
+// a request handed from a coordinator to whichever runner claims it
+#[derive(Serialize, Deserialize)]
+struct Task {
+    id: String,
+    tenant: Option<String>,
+    client_ip: Option<IpAddr>,
+    request: ExecutionRequest,
+}
+
+// what a runner reports back, in order, ending with done
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Event {
+    Result(Box<ExecutionResult>),
+    Error(OutcomeError),
+    Progress(Progress),
+    Requeued, // the runner died, another one starts the request over
+    Done,
+}
+
+// wakes a coordinator's requests when their runners report events, through
+// one subscriber connection for all of them
+#[derive(Default)]
+pub struct EventListener {
+    subscriptions: Mutex<Subscriptions>,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    waiting: HashMap<String, Arc<Notify>>, // event list name -> its request's
+    sink: Option<PubSubSink>,              // none until subscribed and while reconnecting
+}
+
+// a request's subscription to its events, dropped when it is done
+struct Watch {
+    listener: Arc<EventListener>,
+    channel: String,
+    notify: Arc<Notify>,
+}
+
+impl EventListener {
+    // subscribes to the events of the requests this node dispatches, again
+    // whenever the connection is lost
+    pub fn start(self: &Arc<Self>, config: Arc<AppConfig>) {
+        let listener = self.clone();
+        tokio::spawn(async move {
+            let url = config.redis_url.as_deref().unwrap_or_default();
+            loop {
+                let (sink, mut messages) = match redis_client::subscribe(&config, url).await {
+                    Ok(pubsub) => pubsub.split(),
+                    Err(e) => {
+                        tracing::warn!("runner events: {}", e);
+                        time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+                // requests watched from now on subscribe themselves
+                let channels: Vec<String> = {
+                    let mut subscriptions = listener.subscriptions.lock().unwrap();
+                    subscriptions.sink = Some(sink.clone());
+                    subscriptions.waiting.keys().cloned().collect()
+                };
+                if !channels.is_empty()
+                    && let Err(e) = sink.clone().subscribe(channels).await
+                {
+                    tracing::warn!("failed to subscribe to runner events: {}", e);
+                }
+                // events may have come in while there was no subscription
+                for notify in listener.subscriptions.lock().unwrap().waiting.values() {
+                    notify.notify_one();
+                }
+                while let Some(message) = messages.next().await {
+                    let subscriptions = listener.subscriptions.lock().unwrap();
+                    if let Some(notify) = subscriptions.waiting.get(message.get_channel_name()) {
+                        notify.notify_one();
+                    }
+                }
+                tracing::warn!("lost the runner events subscription, reconnecting");
+                listener.subscriptions.lock().unwrap().sink = None;
+                time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+
+    async fn watch(self: &Arc<Self>, id: &str) -> Watch {
+        let channel = events_key(id);
+        let notify = Arc::new(Notify::new());
+        let sink = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            subscriptions
+                .waiting
+                .insert(channel.clone(), notify.clone());
+            subscriptions.sink.clone()
+        };
+        if let Some(mut sink) = sink
+            && let Err(e) = sink.subscribe(&channel).await
+        {
+            tracing::warn!("failed to subscribe to runner events: {}", e);
+        }
+        Watch {
+            listener: self.clone(),
+            channel,
+            notify,
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut subscriptions = self.listener.subscriptions.lock().unwrap();
+        subscriptions.waiting.remove(&self.channel);
+        if let Some(mut sink) = subscriptions.sink.clone() {
+            let channel = std::mem::take(&mut self.channel);
+            tokio::spawn(async move {
+                let _: Result<(), _> = sink.unsubscribe(channel).await;
+            });
+        }
+    }
+}
+
+// runs the request here, or on a runner when this node is a coordinator.
+// either way every outcome is sent to `tx`, which is closed at the end. a
+// coordinator dead-letters requests the host failed with `dead_letter`,
+// jobs leave it unset and record their own
+pub async fn execute(
+    state: AppState,
+    payload: ExecutionRequest,
+    tx: Sender<RunEvent>,
+    tenant: Option<String>,
+    client_ip: Option<IpAddr>,
+    dead_letter: bool,
+) {
+    match state.config.mode {
+        Mode::Coordinator => dispatch(state, payload, tx, tenant, client_ip, dead_letter).await,
+        Mode::Standalone | Mode::Runner => {
+            execute_code_inner(state, payload, tx, tenant, client_ip).await
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn dispatch(
+    state: AppState,
+    payload: ExecutionRequest,
+    tx: Sender<RunEvent>,
+    tenant: Option<String>,
+    client_ip: Option<IpAddr>,
+    dead_letter: bool,
+) {
+    let mut connection = match state.redis() {
+        Ok(connection) => connection,
+        Err(err) => {
+            let _ = tx.send(RunEvent::error(err)).await;
+            return;
+        }
+    };
+    let runner = match route(&mut connection, &payload).await {
+        Ok(runner) => runner,
+        Err(err) => {
+            tracing::warn!("failed to route request: {}", err);
+            let _ = tx.send(RunEvent::error(err)).await;
+            return;
+        }
+    };
+    let queue = queue_key(&runner);
+    let id = gen_random_id(16);
+    // subscribed before the runner can report anything
+    let watch = state.runner_events.watch(&id).await;
+    let task = serde_json::to_string(&Task {
+        id: id.clone(),
+        tenant,
+        client_ip,
+        request: payload,
+    })
+    .unwrap();
+    if let Err(e) = connection.lpush::<_, _, ()>(&queue, &task).await {
+        tracing::error!("failed to queue request for a runner: {}", e);
+        let _ = tx
+            .send(RunEvent::error("failed to queue request".to_string()))
+            .await;
+        return;
+    }
+    tracing::info!(id, runner, "queued request for a runner");
+
+    // the wait covers the time in the queue too, a request no runner picks up
+    // fails like one whose runner stopped
+    let timeout = Duration::from_secs(state.config.runner_timeout_secs);
+    let mut last_event = Instant::now();
+    // a requeued request runs from the start again, so the outcomes the
+    // client already has are skipped the second time
+    let (mut delivered, mut replayed) = (0, 0);
+    let mut errors = Vec::new();
+    loop {
+        if tx.is_closed() {
+            cancel(&mut connection, &queue, &task, &id).await;
+            return;
+        }
+        let events: Option<Vec<String>> = match redis::cmd("LPOP")
+            .arg(events_key(&id))
+            .arg(EVENT_BATCH_SIZE)
+            .query_async(&mut connection)
+            .await
+        {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::warn!("failed to read runner events: {}", e);
+                None
+            }
+        };
+        let events = events.unwrap_or_default();
+        if events.is_empty() {
+            if last_event.elapsed() < timeout {
+                tokio::select! {
+                    _ = watch.notify.notified() => {}
+                    _ = time::sleep(EVENT_WAIT) => {}
+                    _ = tx.closed() => {}
+                }
+                continue;
+            }
+            // still queued means no runner will ever report on it
+            let _: Result<i64, _> = connection.lrem(&queue, 1, &task).await;
+            tracing::warn!(id, "gave up waiting for a runner");
+            let _ = tx
+                .send(RunEvent::error(
+                    "no runner finished the request in time".to_string(),
+                ))
+                .await;
+            return;
+        }
+        last_event = Instant::now();
+        for event in events {
+            let data = match serde_json::from_str(&event) {
+                Ok(Event::Result(result)) => RunEvent::outcome(Ok(*result)),
+                Ok(Event::Error(err)) => RunEvent::outcome(Err(err)),
+                Ok(Event::Progress(progress)) => RunEvent::Progress(progress),
+                Ok(Event::Requeued) => {
+                    tracing::warn!(id, "runner stopped, the request was requeued");
+                    replayed = delivered;
+                    continue;
+                }
+                Ok(Event::Done) => {
+                    if dead_letter
+                        && !errors.is_empty()
+                        && let Ok(task) = serde_json::from_str::<Task>(&task)
+                    {
+                        let letter = DeadLetter::new(None, task.tenant, task.request, errors);
+                        dead_letters::record(&mut connection, &letter, state.config.job_ttl_secs)
+                            .await;
+                    }
+                    return;
+                }
+                Err(e) => RunEvent::error(format!("invalid runner event: {}", e)),
+            };
+            if let RunEvent::Outcome(outcome) = &data {
+                if replayed > 0 {
+                    replayed -= 1;
+                    continue;
+                }
+                delivered += 1;
+                if let Err(err) = outcome.as_ref() {
+                    errors.extend(err.errors.iter().cloned());
+                }
+            }
+            if tx.send(data).await.is_err() {
+                cancel(&mut connection, &queue, &task, &id).await;
+                return;
+            }
+        }
+    }
+}
+
+// the client went away: the request is taken back when it is still queued,
+// and killed by its runner when it is already running
+async fn cancel(connection: &mut MultiplexedConnection, queue: &str, task: &str, id: &str) {
+    tracing::info!(id, "client disconnected, cancelling the request");
+    let _: Result<i64, _> = connection.lrem(queue, 1, task).await;
+    let cancelled: Result<(), _> = connection
+        .set_ex(cancel_key(id), 1, EVENTS_TTL_SECS as u64)
+        .await;
+    if let Err(e) = cancelled {
+        tracing::warn!(id, "failed to cancel the request: {}", e);
+    }
+}
+
+// the live runner that has every root filesystem the request asks for and
+// the most room left, counting what is already queued for it
+async fn route(
+    connection: &mut MultiplexedConnection,
+    request: &ExecutionRequest,
+) -> Result<String, String> {
+    let runners = list(connection).await?;
+    pick(&runners, request)
+}
+
+fn pick(runners: &[RunnerInfo], request: &ExecutionRequest) -> Result<String, String> {
+    let needed: BTreeSet<&String> = request
+        .executions
+        .iter()
+        .filter_map(|execution| execution.rootfs.as_ref())
+        .chain(
+            request
+                .compile
+                .iter()
+                .filter_map(|step| step.rootfs.as_ref()),
+        )
+        .collect();
+    if runners.is_empty() {
+        return Err("no runners are available".to_string());
+    }
+    runners
+        .iter()
+        .filter(|runner| needed.iter().all(|name| runner.rootfs.contains(name)))
+        .max_by_key(|runner| runner.capacity as i64 - (runner.busy + runner.queued) as i64)
+        .map(|runner| runner.id.clone())
+        .ok_or_else(|| {
+            let needed: Vec<_> = needed.into_iter().cloned().collect();
+            format!("no runner has rootfs {}", needed.join(", "))
+        })
+}
+
+// runners whose heartbeat is current. the requests of the ones that stopped
+// go to live runners, and the stopped runners are forgotten
+pub async fn list(connection: &mut MultiplexedConnection) -> Result<Vec<RunnerInfo>, String> {
+    let ids: Vec<String> = connection
+        .smembers(runners_key())
+        .await
+        .map_err(|e| format!("failed to list runners: {}", e))?;
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut pipe = redis::pipe();
+    for id in &ids {
+        pipe.get(runner_key(id)).llen(queue_key(id));
+    }
+    let replies: Vec<(Option<String>, usize)> = pipe
+        .query_async(connection)
+        .await
+        .map_err(|e| format!("failed to read runners: {}", e))?;
+
+    let mut runners = Vec::new();
+    let mut stopped = Vec::new();
+    for (id, (info, queued)) in ids.into_iter().zip(replies) {
+        match info.and_then(|info| serde_json::from_str::<RunnerInfo>(&info).ok()) {
+            Some(info) => runners.push(RunnerInfo { queued, ..info }),
+            None => stopped.push(id),
+        }
+    }
+    for id in stopped {
+        match requeue(connection, &id, &mut runners).await {
+            Ok(()) => {
+                tracing::info!(runner = id, "forgetting runner that stopped heartbeating");
+                let _: Result<(), _> = redis::pipe()
+                    .srem(runners_key(), &id)
+                    .del(queue_key(&id))
+                    .del(processing_key(&id))
+                    .query_async(connection)
+                    .await;
+            }
+            // tried again the next time runners are listed
+            Err(e) => tracing::warn!(runner = id, "failed to requeue requests: {}", e),
+        }
+    }
+    Ok(runners)
+}
+
+// hands the requests a stopped runner had taken or was yet to take to the
+// live runners, or fails them when none can run them
+async fn requeue(
+    connection: &mut MultiplexedConnection,
+    stopped: &str,
+    runners: &mut [RunnerInfo],
+) -> Result<(), String> {
+    for list in [processing_key(stopped), queue_key(stopped)] {
+        let tasks: Vec<String> = connection
+            .lrange(&list, 0, -1)
+            .await
+            .map_err(|e| format!("failed to read requests: {}", e))?;
+        for task in tasks {
+            let Ok(parsed) = serde_json::from_str::<Task>(&task) else {
+                let _: Result<i64, _> = connection.lrem(&list, 1, &task).await;
+                continue;
+            };
+            let moving = redis::Script::new(REQUEUE_SCRIPT);
+            let mut script = moving.prepare_invoke();
+            script
+                .key(&list)
+                .key(events_key(&parsed.id))
+                .arg(&task)
+                .arg(EVENTS_TTL_SECS);
+            let target = pick(runners, &parsed.request);
+            match &target {
+                Ok(runner) => {
+                    script
+                        .key(queue_key(runner))
+                        .arg(serde_json::to_string(&Event::Requeued).unwrap());
+                }
+                Err(err) => {
+                    let err = OutcomeError::from(format!("runner stopped: {}", err));
+                    script
+                        .arg(serde_json::to_string(&Event::Error(err)).unwrap())
+                        .arg(serde_json::to_string(&Event::Done).unwrap());
+                }
+            }
+            let moved: i64 = script
+                .invoke_async(connection)
+                .await
+                .map_err(|e| format!("failed to requeue request: {}", e))?;
+            if moved == 1
+                && let Ok(runner) = target
+            {
+                tracing::info!(
+                    id = parsed.id,
+                    runner,
+                    "requeued request of a stopped runner"
+                );
+                if let Some(info) = runners.iter_mut().find(|info| info.id == runner) {
+                    info.queued += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// registers this node and keeps its registration current
+async fn heartbeat(
+    connection: &mut MultiplexedConnection,
+    info: &RunnerInfo,
+) -> Result<(), String> {
+    redis::pipe()
+        .set_ex(
+            runner_key(&info.id),
+            serde_json::to_string(info).unwrap(),
+            HEARTBEAT_TTL_SECS,
+        )
+        .ignore()
+        .sadd(runners_key(), &info.id)
+        .ignore()
+        .query_async::<()>(connection)
+        .await
+        .map_err(|e| format!("failed to register runner: {}", e))
+}
+
+// registers the node and claims requests routed to it while it has free
+// slots, running them like /execute would and reporting their events back
+// through `connection`
+pub fn start(state: AppState, connection: MultiplexedConnection) {
+    let id = gen_random_id(16);
+    let slots = state.config.runner_concurrency.max(1);
+    let busy = Arc::new(AtomicUsize::new(0));
+    tracing::info!(id, slots, "running requests queued by coordinators");
+
+    let mut rootfs: Vec<String> = state.config.rootfs.keys().cloned().collect();
+    rootfs.extend(state.config.images.keys().cloned());
+    let (mut heartbeats, heartbeat_id, heartbeat_busy) =
+        (connection.clone(), id.clone(), busy.clone());
+    tokio::spawn(async move {
+        let mut interval = time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let info = RunnerInfo {
+                id: heartbeat_id.clone(),
+                capacity: slots,
+                busy: heartbeat_busy.load(Ordering::Relaxed),
+                rootfs: rootfs.clone(),
+                arch: std::env::consts::ARCH.to_string(),
+                queued: 0,
+            };
+            if let Err(e) = heartbeat(&mut heartbeats, &info).await {
+                tracing::warn!("runner heartbeat: {}", e);
+            }
+            // runners requeue the requests of stopped ones, whether or not
+            // a coordinator routes anything meanwhile
+            if let Err(e) = list(&mut heartbeats).await {
+                tracing::warn!("runner heartbeat: {}", e);
+            }
+        }
+    });
+
+    let (queue, processing) = (queue_key(&id), processing_key(&id));
+    for _ in 0..slots {
+        let (state, mut connection, busy) = (state.clone(), connection.clone(), busy.clone());
+        let (queue, processing) = (queue.clone(), processing.clone());
+        tokio::spawn(async move {
+            // waiting for a request blocks the connection, so every slot
+            // has its own
+            let mut claims = None;
+            loop {
+                // a busy host leaves requests to the other runners until
+                // its readings say otherwise
+                let refreshed = state.system.refreshed();
+                if admission::host_busy(&state) {
+                    refreshed.await;
+                    continue;
+                }
+                let claiming = match &mut claims {
+                    Some(connection) => connection,
+                    None => {
+                        let url = state.config.redis_url.as_deref().unwrap_or_default();
+                        match redis_client::connect(&state.config, url).await {
+                            Ok(connection) => claims.insert(connection),
+                            Err(e) => {
+                                tracing::warn!("runner slot: {}", e);
+                                time::sleep(RECONNECT_DELAY).await;
+                                continue;
+                            }
+                        }
+                    }
+                };
+                let claimed: Result<Option<String>, _> = claiming
+                    .blmove(
+                        &queue,
+                        &processing,
+                        Direction::Right,
+                        Direction::Left,
+                        CLAIM_TIMEOUT_SECS,
+                    )
+                    .await;
+                let task = match claimed {
+                    Ok(Some(task)) => task,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!("failed to claim a queued request: {}", e);
+                        claims = None;
+                        time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+                busy.fetch_add(1, Ordering::Relaxed);
+                match serde_json::from_str::<Task>(&task) {
+                    Ok(parsed) => run_task(&state, connection.clone(), parsed).await,
+                    Err(e) => tracing::error!("dropped invalid queued request: {}", e),
+                }
+                // done and reported, it isn't requeued should this runner stop
+                let acked: Result<i64, _> = connection.lrem(&processing, 1, &task).await;
+                if let Err(e) = acked {
+                    tracing::warn!("failed to acknowledge a finished request: {}", e);
+                }
+                busy.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+#[tracing::instrument(skip_all, fields(id = %task.id))]
+async fn run_task(state: &AppState, mut connection: MultiplexedConnection, task: Task) {
+    let mut watcher = connection.clone();
+    let cancelled = cancel_key(&task.id);
+    // the client may have gone while the request waited, or was requeued
+    if watcher.exists(&cancelled).await.unwrap_or(false) {
+        tracing::info!("skipping cancelled request");
+        return;
+    }
+    tracing::info!("running queued request");
+    let key = events_key(&task.id);
+    let (tx, mut rx) = mpsc::channel(100);
+    let run = tokio::spawn(execute_code_inner(
+        state.clone(),
+        task.request,
+        tx,
+        task.tenant,
+        task.client_ip,
+    ));
+    let mut report = async |event: Event| {
+        let event = serde_json::to_string(&event).unwrap();
+        let pushed: Result<(), _> = redis::pipe()
+            .rpush(&key, event)
+            .ignore()
+            .expire(&key, EVENTS_TTL_SECS)
+            .ignore()
+            .publish(&key, 1)
+            .ignore()
+            .query_async(&mut connection)
+            .await;
+        if let Err(e) = pushed {
+            tracing::error!("failed to report event to the coordinator: {}", e);
+        }
+    };
+    let mut checks = time::interval(CANCEL_POLL_INTERVAL);
+    loop {
+        let data = tokio::select! {
+            data = rx.recv() => data,
+            _ = checks.tick() => {
+                // closing the channel kills the sandbox, like a disconnect would
+                if watcher.exists(&cancelled).await.unwrap_or(false) {
+                    tracing::info!("coordinator cancelled the request");
+                    break;
+                }
+                continue;
+            }
+        };
+        let Some(data) = data else {
+            break;
+        };
+        report(match data {
+            RunEvent::Outcome(data) => match *data {
+                Ok(result) => Event::Result(Box::new(result)),
+                Err(err) => Event::Error(err),
+            },
+            RunEvent::Progress(progress) => Event::Progress(progress),
+        })
+        .await;
+    }
+    drop(rx);
+    let _ = run.await;
+    report(Event::Done).await;
+}
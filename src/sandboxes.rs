@@ -0,0 +1,291 @@
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// how long processes left in a sandbox's pid namespace get to die, and how
+// often it is checked meanwhile
+const NAMESPACE_KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const NAMESPACE_KILL_INTERVAL: Duration = Duration::from_millis(10);
+
+// why a sandbox was killed, the error of the executions it cut short
+pub const KILLED_BY_ADMIN: &str = "sandbox was killed by an administrator";
+pub const KILLED_ON_DISCONNECT: &str = "sandbox was killed after the client disconnected";
+
+// live sandboxes by id, so admins can inspect and kill wedged runs
+#[derive(Default)]
+pub struct SandboxRegistry {
+    sandboxes: Mutex<HashMap<String, Arc<Sandbox>>>,
+}
+
+pub struct Sandbox {
+    pub id: String,
+    tenant: Option<String>,
+    client_ip: Option<IpAddr>,
+    created: Instant,
+    running: Mutex<Option<Running>>,
+    killed: OnceLock<&'static str>, // why, set by the first kill
+    kill_notify: Notify,
+}
+
+struct Running {
+    program: String,
+    pid: u32,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+pub struct SandboxInfo {
+    pub id: String,
+    pub tenant: Option<String>,
+    pub client_ip: Option<IpAddr>, // none for scheduled jobs
+    pub age_ms: u128,
+    pub program: Option<String>,  // running program, none while idle
+    pub elapsed_ms: Option<u128>, // time since the running program started
+    pub memory_kb: Option<u64>,   // resident memory of the running program
+}
+
+// removes the sandbox from the registry when the request owning it ends
+pub struct Registration {
+    registry: Arc<SandboxRegistry>,
+    pub sandbox: Arc<Sandbox>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry
+            .sandboxes
+            .lock()
+            .unwrap()
+            .remove(&self.sandbox.id);
+    }
+}
+
+impl SandboxRegistry {
+    pub fn register(
+        self: &Arc<Self>,
+        id: String,
+        tenant: Option<String>,
+        client_ip: Option<IpAddr>,
+    ) -> Registration {
+        let sandbox = Arc::new(Sandbox {
+            id: id.clone(),
+            tenant,
+            client_ip,
+            created: Instant::now(),
+            running: Mutex::new(None),
+            killed: OnceLock::new(),
+            kill_notify: Notify::new(),
+        });
+        self.sandboxes.lock().unwrap().insert(id, sandbox.clone());
+        Registration {
+            registry: self.clone(),
+            sandbox,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SandboxInfo> {
+        let mut sandboxes: Vec<_> = self
+            .sandboxes
+            .lock()
+            .unwrap()
+            .values()
+            .map(|sandbox| sandbox.info())
+            .collect();
+        sandboxes.sort_by_key(|sandbox| std::cmp::Reverse(sandbox.age_ms));
+        sandboxes
+    }
+
+    // (live sandboxes, sandboxes running a program)
+    pub fn counts(&self) -> (usize, usize) {
+        let sandboxes = self.sandboxes.lock().unwrap();
+        let running = sandboxes
+            .values()
+            .filter(|sandbox| sandbox.running.lock().unwrap().is_some())
+            .count();
+        (sandboxes.len(), running)
+    }
+
+    // returns false if there is no such sandbox
+    pub fn kill(&self, id: &str) -> bool {
+        let sandbox = self.sandboxes.lock().unwrap().get(id).cloned();
+        match sandbox {
+            Some(sandbox) => {
+                sandbox.kill(KILLED_BY_ADMIN);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Sandbox {
+    // `pid` is the container process returned by spawn
+    pub fn set_running(&self, program: &str, pid: u32) {
+        *self.running.lock().unwrap() = Some(Running {
+            program: program.to_string(),
+            pid,
+            started: Instant::now(),
+        });
+        // a kill that raced the spawn has nothing to signal yet
+        if self.is_killed() {
+            self.signal();
+        }
+    }
+
+    pub fn clear_running(&self) {
+        *self.running.lock().unwrap() = None;
+    }
+
+    // kills the running program of an execution that was abandoned midway and
+    // reaps its container process, which is a child of the service
+    pub fn reap(&self) {
+        let Some(running) = self.running.lock().unwrap().take() else {
+            return;
+        };
+        let namespace = pid_namespace(running.pid);
+        if let Some(pid) = namespace_init(running.pid) {
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+        unsafe {
+            libc::kill(running.pid as libc::pid_t, libc::SIGKILL);
+            libc::waitpid(running.pid as libc::pid_t, std::ptr::null_mut(), 0);
+        }
+        if let Some(namespace) = namespace
+            && let Err(e) = kill_namespace(&namespace)
+        {
+            tracing::error!(sandbox = self.id, "{}", e);
+        }
+    }
+
+    // whose request the sandbox runs, none without tenants
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.get().is_some()
+    }
+
+    pub fn kill_reason(&self) -> Option<&'static str> {
+        self.killed.get().copied()
+    }
+
+    // resolves once the sandbox has been killed
+    pub async fn killed(&self) {
+        loop {
+            let notified = self.kill_notify.notified();
+            if self.is_killed() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // kills the running program and fails every later execution with `reason`
+    pub fn kill(&self, reason: &'static str) {
+        let _ = self.killed.set(reason);
+        self.kill_notify.notify_waiters();
+        self.signal();
+    }
+
+    // killing pid 1 of the sandbox's pid namespace takes every process in it down
+    fn signal(&self) {
+        if let Some(running) = &*self.running.lock().unwrap() {
+            let pid = namespace_init(running.pid).unwrap_or(running.pid);
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+
+    fn info(&self) -> SandboxInfo {
+        let running = self.running.lock().unwrap();
+        SandboxInfo {
+            id: self.id.clone(),
+            tenant: self.tenant.clone(),
+            client_ip: self.client_ip,
+            age_ms: self.created.elapsed().as_millis(),
+            program: running.as_ref().map(|r| r.program.clone()),
+            elapsed_ms: running.as_ref().map(|r| r.started.elapsed().as_millis()),
+            memory_kb: running
+                .as_ref()
+                .and_then(|r| namespace_init(r.pid))
+                .and_then(resident_memory_kb),
+        }
+    }
+}
+
+// the container process forks the program as pid 1 of the new pid namespace
+fn namespace_init(pid: u32) -> Option<u32> {
+    fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid))
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+// the pid namespace the container process `pid` runs the program in
+pub fn pid_namespace(pid: u32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{}/ns/pid_for_children", pid)).ok()
+}
+
+// kills whatever is still running in `namespace` and waits for it to go,
+// returning how many processes were left. the kernel takes the namespace
+// down with its pid 1, so anything found here outlived the program
+pub fn kill_namespace(namespace: &Path) -> Result<usize, String> {
+    let deadline = Instant::now() + NAMESPACE_KILL_TIMEOUT;
+    let mut left = BTreeSet::new();
+    loop {
+        let pids = namespace_pids(namespace);
+        if pids.is_empty() {
+            return Ok(left.len());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "{} processes of the sandbox survived being killed",
+                pids.len()
+            ));
+        }
+        for &pid in &pids {
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+        left.extend(pids);
+        std::thread::sleep(NAMESPACE_KILL_INTERVAL);
+    }
+}
+
+// host pids of the processes in `namespace`
+fn namespace_pids(namespace: &Path) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| {
+            fs::read_link(format!("/proc/{}/ns/pid", pid)).is_ok_and(|ns| ns == namespace)
+        })
+        .collect()
+}
+
+fn resident_memory_kb(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
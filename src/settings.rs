@@ -0,0 +1,220 @@
+use config::{Config, ConfigError};
+use std::fs;
+use std::path::Path;
+
+use crate::cli::Cli;
+use crate::client_ip::TrustedProxies;
+use crate::quota;
+use crate::types::{AppConfig, FileStore, Mode, UsageSink};
+use crate::worker;
+
+// reads the settings file, the APP_ environment and the command line flags.
+// serde stops at the first missing or mistyped field, so only that one is
+// reported
+pub fn load(cli: &Cli) -> Result<AppConfig, String> {
+    build(cli)
+        .and_then(|settings| settings.try_deserialize())
+        .map_err(|e| e.to_string())
+}
+
+fn build(cli: &Cli) -> Result<Config, ConfigError> {
+    Config::builder()
+        .add_source(config::File::with_name(&cli.config))
+        .add_source(config::Environment::with_prefix("APP"))
+        .set_override_option("port", cli.port)?
+        .set_override_option("host", cli.bind.clone())?
+        .set_override_option("log_level", cli.log_level.clone())?
+        .set_override_option("mode", cli.mode())?
+        .build()
+}
+
+// everything wrong with `config` that shows without serving a request, so
+// a broken config is fixed in one go instead of one panic at a time
+pub fn validate(config: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.log_level.parse::<tracing::Level>().is_err() {
+        problems.push(format!(
+            "log_level: {:?} is not one of error, warn, info, debug or trace",
+            config.log_level
+        ));
+    }
+    if config.redis_url.is_some() {
+        // remote file ids would otherwise name any key in the instance
+        if config.redis_key_prefix.is_empty() {
+            problems.push("redis_key_prefix: must not be empty".to_string());
+        }
+    } else {
+        if config.file_store == FileStore::Redis {
+            problems.push("redis_url: must be set unless file_store is \"memory\"".to_string());
+        }
+        if config.mode != Mode::Standalone {
+            problems.push(
+                "mode: coordinators and runners share their queues through redis_url, which is unset"
+                    .to_string(),
+            );
+        }
+        // replayed signatures are refused by what redis remembers of them
+        if !config.signing_keys.is_empty() {
+            problems.push("signing_keys: need redis_url, which is unset".to_string());
+        }
+        for (name, tenant) in &config.tenants {
+            if quota::applies(tenant) {
+                problems.push(format!(
+                    "tenants.{}: quotas are counted in redis_url, which is unset",
+                    name
+                ));
+            }
+        }
+        if let Some(UsageSink::Redis { .. }) = config.usage_sink {
+            problems.push("usage_sink: the redis stream needs redis_url, which is unset".to_string());
+        }
+    }
+    // each node would see only its own files
+    if config.file_store == FileStore::Memory && config.mode != Mode::Standalone {
+        problems.push(
+            "file_store: \"memory\" isn't shared between nodes, coordinators and runners need \"redis\""
+                .to_string(),
+        );
+    }
+    // sandboxes are created below it, so it is created up front
+    if let Err(e) = fs::create_dir_all(&config.base_code_path) {
+        problems.push(format!(
+            "base_code_path: can't create {}: {}",
+            config.base_code_path, e
+        ));
+    }
+
+    let limits = [
+        (
+            "time_limit",
+            config.default_time_limit,
+            config.max_time_limit,
+        ),
+        (
+            "wall_time_limit",
+            config.default_wall_time_limit,
+            config.max_wall_time_limit,
+        ),
+        (
+            "memory_limit",
+            config.default_memory_limit,
+            config.max_memory_limit,
+        ),
+    ];
+    for (name, default, max) in limits {
+        if default == 0 {
+            problems.push(format!("default_{}: must be above 0", name));
+        }
+        if let Some(max) = max
+            && default > max
+        {
+            problems.push(format!(
+                "default_{}: {} is above max_{} {}",
+                name, default, name, max
+            ));
+        }
+    }
+    if config.max_batch_wall_time == Some(0) {
+        problems.push("max_batch_wall_time: must be above 0".to_string());
+    }
+    if !(-20..=19).contains(&config.min_niceness) {
+        problems.push(format!(
+            "min_niceness: {} is outside -20 to 19",
+            config.min_niceness
+        ));
+    }
+    for (name, value) in [
+        ("max_request_size", config.max_request_size),
+        ("max_return_file_size", config.max_return_file_size),
+        ("max_response_size", config.max_response_size),
+        ("memory_file_store_size", config.memory_file_store_size),
+        ("staging_concurrency", config.staging_concurrency as u64),
+        ("runner_concurrency", config.runner_concurrency as u64),
+    ] {
+        if value == 0 {
+            problems.push(format!("{}: must be above 0", name));
+        }
+    }
+
+    for (metric, buckets) in &config.histogram_buckets {
+        if buckets.is_empty() || !buckets.is_sorted_by(|a, b| a < b) {
+            problems.push(format!(
+                "histogram_buckets.{}: must be non-empty and increasing",
+                metric
+            ));
+        }
+    }
+
+    for name in &config.metric_labels {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with("__");
+        if !valid {
+            problems.push(format!(
+                "metric_labels: {:?} is not a prometheus label name",
+                name
+            ));
+        } else if ["tenant", "outcome", "status"].contains(&name.as_str()) {
+            problems.push(format!(
+                "metric_labels: {:?} is taken by the execution metrics",
+                name
+            ));
+        }
+    }
+
+    for dev in &config.dev_devices {
+        if !worker::ALLOWED_DEVICES.contains(&dev.as_str()) {
+            problems.push(format!(
+                "dev_devices: {:?} is not one of {}",
+                dev,
+                worker::ALLOWED_DEVICES.join(", ")
+            ));
+        }
+    }
+    if let Err(e) = TrustedProxies::new(config) {
+        problems.push(format!("trusted_proxies: {}", e));
+    }
+    if let Err(e) = worker::check_box_path(&config.box_path) {
+        problems.push(format!("box_path: {:?} {}", config.box_path, e));
+    }
+    if let Err(e) = worker::landlock_ruleset(&config.box_path, &[&config.landlock_paths]) {
+        problems.push(format!("landlock_paths: {}", e));
+    }
+    for (name, profile) in &config.profiles {
+        if let Err(e) = worker::landlock_ruleset(&config.box_path, &[&profile.landlock_paths]) {
+            problems.push(format!("profiles.{}.landlock_paths: {}", name, e));
+        }
+    }
+    for (name, path) in &config.rootfs {
+        if !Path::new(path).is_dir() {
+            problems.push(format!("rootfs.{}: {} is not a directory", name, path));
+        }
+    }
+
+    if let Some(tls) = &config.tls {
+        let files = [
+            ("cert", Some(&tls.cert)),
+            ("key", Some(&tls.key)),
+            ("client_ca", tls.client_ca.as_ref()),
+        ];
+        for (name, path) in files {
+            if let Some(path) = path
+                && let Err(e) = fs::metadata(path)
+            {
+                problems.push(format!("tls.{}: can't read {}: {}", name, path, e));
+            }
+        }
+    }
+
+    problems
+}
+
+// prints every problem and exits, before logging is set up
+pub fn fail(problems: &[String]) -> ! {
+    eprintln!("pentagon: invalid configuration");
+    for problem in problems {
+        eprintln!("  - {}", problem);
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,286 @@
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode, header::CONTENT_LENGTH},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::{StreamExt, stream};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::redis_client;
+use crate::types::{AppConfig, AppState};
+use crate::utils::gen_random_id;
+
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+// prometheus can't sign its scrapes
+const UNSIGNED_PATHS: &[&str] = &["/metrics"];
+
+// unverified bodies are hashed as they arrive and only this much of one is
+// held in memory, the rest waits on disk until the signature checks out
+const UNVERIFIED_MEMORY_LIMIT: usize = 64 * 1024;
+
+// size of each read when a spooled body is passed on
+const SPOOL_CHUNK_SIZE: usize = 64 * 1024;
+
+// the parts of "keyid=<id>, timestamp=<unix seconds>, signature=<hex>"
+struct Signature {
+    key_id: String,
+    timestamp: u64,
+    signature: Vec<u8>,
+}
+
+fn parse(header: &str) -> Option<Signature> {
+    let (mut key_id, mut timestamp, mut signature) = (None, None, None);
+    for pair in header.split(',') {
+        let (name, value) = pair.trim().split_once('=')?;
+        match name {
+            "keyid" => key_id = Some(value.to_string()),
+            "timestamp" => timestamp = value.parse().ok(),
+            "signature" => signature = hex::decode(value).ok(),
+            _ => {}
+        }
+    }
+    Some(Signature {
+        key_id: key_id?,
+        timestamp: timestamp?,
+        signature: signature?,
+    })
+}
+
+// hmac-sha256 over "<timestamp>\n<method>\n<path and query>\n<body>", so a
+// signed body can't be replayed against another route either. this is all
+// of it but the body, which is hashed as it arrives
+fn message_head(timestamp: u64, request: &Request) -> Vec<u8> {
+    let target = request
+        .uri()
+        .path_and_query()
+        .map(|target| target.as_str())
+        .unwrap_or("/");
+    format!("{}\n{}\n{}\n", timestamp, request.method(), target).into_bytes()
+}
+
+// the limit of the route itself, so an unverified request is never read
+// further than what it is headed for accepts
+fn body_limit(config: &AppConfig, request: &Request) -> u64 {
+    if request.method() == Method::POST && request.uri().path() == "/problems" {
+        config.max_problem_size
+    } else {
+        config.max_request_size
+    }
+}
+
+// an unverified body as it was received
+struct Spooled {
+    head: Vec<u8>,
+    rest: Option<fs::File>, // past UNVERIFIED_MEMORY_LIMIT, already unlinked
+}
+
+enum SpoolError {
+    TooLarge,
+    Read(String),   // the client's side
+    Failed(String), // the server's
+}
+
+// reads `body` into memory and, past UNVERIFIED_MEMORY_LIMIT, a file in
+// `dir`, feeding every chunk to `mac`
+async fn spool(
+    body: Body,
+    limit: u64,
+    dir: &str,
+    mac: &mut Hmac<Sha256>,
+) -> Result<Spooled, SpoolError> {
+    let mut spooled = Spooled {
+        head: Vec::new(),
+        rest: None,
+    };
+    let mut received: u64 = 0;
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| SpoolError::Read(format!("failed to read body: {}", e)))?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(SpoolError::TooLarge);
+        }
+        mac.update(&chunk);
+        if spooled.rest.is_none() && spooled.head.len() + chunk.len() <= UNVERIFIED_MEMORY_LIMIT {
+            spooled.head.extend_from_slice(&chunk);
+            continue;
+        }
+        if spooled.rest.is_none() {
+            spooled.rest = Some(spool_file(dir).await.map_err(SpoolError::Failed)?);
+        }
+        if let Some(file) = &mut spooled.rest {
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| SpoolError::Failed(format!("failed to spool body: {}", e)))?;
+        }
+    }
+    Ok(spooled)
+}
+
+// unlinked right away, so it goes with the last handle
+async fn spool_file(dir: &str) -> Result<fs::File, String> {
+    let path = Path::new(dir).join(format!(".signed-{}", gen_random_id(16)));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await
+        .map_err(|e| format!("failed to spool body: {}", e))?;
+    fs::remove_file(&path)
+        .await
+        .map_err(|e| format!("failed to spool body: {}", e))?;
+    Ok(file)
+}
+
+// the body again, read back from where it was spooled
+async fn unspool(spooled: Spooled) -> Result<Body, String> {
+    let head = Bytes::from(spooled.head);
+    let Some(mut file) = spooled.rest else {
+        return Ok(Body::from(head));
+    };
+    file.seek(SeekFrom::Start(0))
+        .await
+        .map_err(|e| format!("failed to read spooled body: {}", e))?;
+    let rest = stream::unfold(file, |mut file| async move {
+        let mut buffer = vec![0; SPOOL_CHUNK_SIZE];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    Ok(Body::from_stream(
+        stream::once(async { Ok(head) }).chain(rest),
+    ))
+}
+
+// when signing keys are configured every request has to carry a signature
+// made with one of them less than signature_max_age_secs ago, and each
+// signature is accepted once
+pub async fn verify_signature(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config;
+    if config.signing_keys.is_empty() || UNSIGNED_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let reject = |status: StatusCode, message: &str| {
+        tracing::warn!("rejected request signature: {}", message);
+        (status, Json(json!({ "error": message }))).into_response()
+    };
+
+    let Some(signature) = signature_header(request.headers()).and_then(parse) else {
+        return reject(StatusCode::UNAUTHORIZED, "missing or malformed signature");
+    };
+    let Some(secret) = config.signing_keys.get(&signature.key_id) else {
+        return reject(StatusCode::UNAUTHORIZED, "unknown signing key");
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if now.abs_diff(signature.timestamp) > config.signature_max_age_secs {
+        return reject(StatusCode::UNAUTHORIZED, "signature timestamp is too old");
+    }
+
+    // anyone can name a key and a fresh timestamp, so until the signature
+    // is checked the body is held to the route's limit and mostly on disk
+    let limit = body_limit(config, &request);
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let too_large = || {
+        reject(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "request body is larger than the server accepts",
+        )
+    };
+    if declared.is_some_and(|declared| declared > limit) {
+        return too_large();
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(&message_head(signature.timestamp, &request));
+    let (parts, body) = request.into_parts();
+    let spooled = match spool(body, limit, &state.base_code_path, &mut mac).await {
+        Ok(spooled) => spooled,
+        Err(SpoolError::TooLarge) => return too_large(),
+        Err(SpoolError::Read(e)) => {
+            tracing::warn!("{}", e);
+            return reject(StatusCode::BAD_REQUEST, "failed to read request body");
+        }
+        Err(SpoolError::Failed(e)) => {
+            tracing::error!("{}", e);
+            return reject(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "failed to read request body",
+            );
+        }
+    };
+    if mac.verify_slice(&signature.signature).is_err() {
+        return reject(StatusCode::UNAUTHORIZED, "invalid signature");
+    }
+    let body = match unspool(spooled).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return reject(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "failed to read request body",
+            );
+        }
+    };
+    let request = Request::from_parts(parts, body);
+
+    // outside the window the timestamp check rejects it anyway
+    let mut connection = match state.redis() {
+        Ok(connection) => connection,
+        Err(e) => return reject(StatusCode::SERVICE_UNAVAILABLE, &e),
+    };
+    let claimed: Result<Option<String>, _> = redis::cmd("SET")
+        .arg(redis_client::key(&format!(
+            "signature:{}",
+            hex::encode(&signature.signature)
+        )))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(config.signature_max_age_secs * 2 + 1)
+        .query_async(&mut connection)
+        .await;
+    match claimed {
+        Ok(Some(_)) => next.run(request).await,
+        Ok(None) => reject(StatusCode::UNAUTHORIZED, "signature was already used"),
+        Err(e) => {
+            tracing::error!("failed to record request signature: {}", e);
+            reject(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "failed to verify signature",
+            )
+        }
+    }
+}
+
+fn signature_header(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
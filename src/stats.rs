@@ -0,0 +1,81 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// window the recent error rate is computed over
+const RECENT_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+// in-process execution counters behind /stats
+pub struct Stats {
+    started: Instant,
+    executions: AtomicU64,
+    errors: AtomicU64,
+    recent: Mutex<VecDeque<(Instant, bool)>>, // (finished at, failed)
+}
+
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub executions_total: u64,
+    pub execution_errors_total: u64,
+    pub active_sandboxes: usize,
+    pub running_executions: usize,
+    pub recent_window_secs: u64,
+    pub recent_executions: usize,
+    pub recent_error_rate: f64, // failed share of recent executions, 0 when there were none
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            executions: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, failed: bool) {
+        self.executions.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back((now, failed));
+        prune(&mut recent, now);
+    }
+
+    pub fn snapshot(&self, active_sandboxes: usize, running_executions: usize) -> StatsSnapshot {
+        let mut recent = self.recent.lock().unwrap();
+        prune(&mut recent, Instant::now());
+        let failed = recent.iter().filter(|(_, failed)| *failed).count();
+        let recent_error_rate = if recent.is_empty() {
+            0.0
+        } else {
+            failed as f64 / recent.len() as f64
+        };
+
+        StatsSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            executions_total: self.executions.load(Ordering::Relaxed),
+            execution_errors_total: self.errors.load(Ordering::Relaxed),
+            active_sandboxes,
+            running_executions,
+            recent_window_secs: RECENT_WINDOW.as_secs(),
+            recent_executions: recent.len(),
+            recent_error_rate,
+        }
+    }
+}
+
+fn prune(recent: &mut VecDeque<(Instant, bool)>, now: Instant) {
+    while let Some((at, _)) = recent.front()
+        && now.duration_since(*at) > RECENT_WINDOW
+    {
+        recent.pop_front();
+    }
+}
@@ -1,9 +1,44 @@
 use metrics::gauge;
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use sysinfo::{Disks, System};
+use tokio::sync::{Notify, futures::Notified};
 use tokio::time;
 
-pub async fn start_system_monitor() {
+use crate::types::AppConfig;
+
+// the latest readings, for admission control. none until the first refresh
+#[derive(Default)]
+pub struct SystemStatus {
+    readings: Mutex<Option<Readings>>,
+    refreshed: Notify,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Readings {
+    pub cpu_usage_percent: f32,
+    pub load_percent: f64, // one minute load average per cpu, 100 is one runnable task per cpu
+    pub sandbox_disk_free_bytes: Option<u64>, // on the filesystem holding base_code_path
+}
+
+impl SystemStatus {
+    pub fn readings(&self) -> Option<Readings> {
+        *self.readings.lock().unwrap()
+    }
+
+    // resolves at the next refresh after it is created, so it is taken
+    // before looking at the readings to not miss one
+    pub fn refreshed(&self) -> Notified<'_> {
+        self.refreshed.notified()
+    }
+}
+
+pub async fn start_system_monitor(config: &AppConfig) -> Arc<SystemStatus> {
+    let status = Arc::new(SystemStatus::default());
+    let readings = status.clone();
+    let base_code_path = config.base_code_path.clone();
+
     // Describe metrics
 
     tokio::spawn(async move {
@@ -36,6 +71,37 @@ pub async fn start_system_monitor() {
             }
             gauge!("system_disk_free_bytes").set(total_free as f64);
             gauge!("system_disk_total_bytes").set(total_space as f64);
+
+            // sandboxes only write below base_code_path, whatever the other disks have
+            let sandbox_disk_free_bytes = available_space(&base_code_path);
+            if let Some(free) = sandbox_disk_free_bytes {
+                gauge!("sandbox_disk_free_bytes").set(free as f64);
+            }
+
+            let cpus = system.cpus().len().max(1);
+            let load_percent = System::load_average().one / cpus as f64 * 100.0;
+
+            *readings.readings.lock().unwrap() = Some(Readings {
+                cpu_usage_percent: global_cpu,
+                load_percent,
+                sandbox_disk_free_bytes,
+            });
+            readings.refreshed.notify_waiters();
         }
     });
+    status
+}
+
+// bytes unprivileged users can still write on the filesystem holding `path`
+fn available_space(path: &str) -> Option<u64> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        tracing::warn!(
+            "failed to read free space of base_code_path: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
 }
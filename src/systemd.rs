@@ -0,0 +1,93 @@
+use std::env;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::OnceLock;
+
+// first file descriptor systemd passes, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+// NOTIFY_SOCKET, taken out of the environment by listener
+static NOTIFY_SOCKET: OnceLock<Option<String>> = OnceLock::new();
+
+// the listening socket systemd passed when the service is socket activated,
+// none when it wasn't. the variables are cleared so sandboxes don't see them,
+// NOTIFY_SOCKET with them, which is kept for notify
+pub fn listener() -> Result<Option<TcpListener>, String> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let _ = NOTIFY_SOCKET.set(env::var("NOTIFY_SOCKET").ok());
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        env::remove_var("NOTIFY_SOCKET");
+    }
+    // meant for another process when the pid isn't ours
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(None);
+    }
+    match fds.parse::<u32>() {
+        Ok(1) => {}
+        Ok(0) => return Ok(None),
+        _ => return Err(format!("expected one socket from systemd, got {}", fds)),
+    }
+
+    let fd = LISTEN_FDS_START;
+    let stream = socket_option(fd, libc::SO_TYPE) == Some(libc::SOCK_STREAM);
+    // a unix stream socket would pass as a tcp listener otherwise
+    let inet = matches!(
+        socket_option(fd, libc::SO_DOMAIN),
+        Some(libc::AF_INET | libc::AF_INET6)
+    );
+    if !stream || !inet {
+        return Err(
+            "the socket from systemd must be a tcp stream socket, i.e. ListenStream= with an address or port"
+                .into(),
+        );
+    }
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("failed to set up the socket from systemd: {}", e))?;
+    Ok(Some(listener))
+}
+
+fn socket_option(fd: RawFd, name: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let found = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0;
+    found.then_some(value)
+}
+
+// tells the service manager about a state change, e.g. READY=1, when the
+// service runs with Type=notify. does nothing otherwise
+pub fn notify(state: &str) {
+    let Some(Some(path)) = NOTIFY_SOCKET.get() else {
+        return;
+    };
+    // a leading @ names a socket in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(path),
+    };
+    let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(e) = sent {
+        tracing::warn!("failed to notify systemd: {}", e);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+
+use crate::types::{AppConfig, ExecutionRequest, File, Language};
+
+// the language a request asks for when the server should pick it
+const AUTO_LANGUAGE: &str = "auto";
+
+// fills in a request that names a template: the template's executions and
+// compile step with `{param}` placeholders in program and args replaced, and
+// its files with the request's files added on top (a file of the same name
+// replaces the template's)
+pub fn expand(config: &AppConfig, request: ExecutionRequest) -> Result<ExecutionRequest, String> {
+    let request = apply_language(config, request)?;
+    let Some(name) = &request.template else {
+        return Ok(request);
+    };
+    let template = config
+        .templates
+        .get(name)
+        .ok_or_else(|| format!("unknown template {}", name))?;
+    if !request.executions.is_empty() || request.compile.is_some() {
+        return Err(
+            "a request using a template can't have executions or a compile step".to_string(),
+        );
+    }
+
+    let substitute = |value: &str| {
+        request
+            .params
+            .iter()
+            .fold(value.to_string(), |value, (param, replacement)| {
+                value.replace(&format!("{{{}}}", param), replacement)
+            })
+    };
+    let mut executions = template.executions.clone();
+    for execution in &mut executions {
+        execution.program = substitute(&execution.program);
+        for arg in &mut execution.args {
+            *arg = substitute(arg);
+        }
+    }
+    let mut compile = template.compile.clone();
+    if let Some(compile) = &mut compile {
+        compile.program = substitute(&compile.program);
+        compile.artifact = substitute(&compile.artifact);
+        for arg in &mut compile.args {
+            *arg = substitute(arg);
+        }
+    }
+
+    let mut files: Vec<File> = template
+        .files
+        .iter()
+        .filter(|file| {
+            !request
+                .files
+                .iter()
+                .any(|own| file_name(own) == file_name(file))
+        })
+        .cloned()
+        .collect();
+    files.extend(request.files);
+
+    Ok(ExecutionRequest {
+        executions,
+        files,
+        template: None,
+        params: BTreeMap::new(),
+        language: None,
+        compile,
+        progress: request.progress,
+        labels: request.labels,
+        detach: request.detach,
+    })
+}
+
+// turns a request naming a language into one using that language's template,
+// with the submitted source file as its {file} and {stem} params
+fn apply_language(
+    config: &AppConfig,
+    mut request: ExecutionRequest,
+) -> Result<ExecutionRequest, String> {
+    let Some(language) = request.language.take() else {
+        return Ok(request);
+    };
+    if request.template.is_some() {
+        return Err("a request can't name both a language and a template".to_string());
+    }
+
+    let (preset, file) = if language == AUTO_LANGUAGE {
+        request
+            .files
+            .iter()
+            .find_map(|file| detect(config, file).map(|preset| (preset, file_name(file))))
+            .ok_or("could not detect the language of the submitted files")?
+    } else {
+        let preset = config
+            .languages
+            .get(&language)
+            .ok_or_else(|| format!("unknown language {}", language))?;
+        let file = request
+            .files
+            .iter()
+            .map(file_name)
+            .find(|name| has_extension(preset, name))
+            .or_else(|| request.files.first().map(file_name))
+            .ok_or("a request naming a language needs a source file")?;
+        (preset, file)
+    };
+
+    let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+    let params = [("file", file.to_string()), ("stem", stem.to_string())];
+    request.template = Some(preset.template.clone());
+    request
+        .params
+        .extend(params.map(|(name, value)| (name.to_string(), value)));
+    Ok(request)
+}
+
+// matches the file name's extension first, then the shebang of inline files
+fn detect<'a>(config: &'a AppConfig, file: &File) -> Option<&'a Language> {
+    let languages = config.languages.values();
+    if let Some(language) = languages
+        .clone()
+        .find(|language| has_extension(language, file_name(file)))
+    {
+        return Some(language);
+    }
+    let File::Local { content, .. } = file else {
+        return None;
+    };
+    let interpreter = shebang_interpreter(content)?;
+    languages
+        .into_iter()
+        .find(|language| language.interpreters.contains(&interpreter))
+}
+
+fn has_extension(language: &Language, name: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, extension)| language.extensions.iter().any(|e| e == extension))
+}
+
+// "#!/usr/bin/python3" and "#!/usr/bin/env python3" both name python3
+fn shebang_interpreter(content: &[u8]) -> Option<String> {
+    let line = content.strip_prefix(b"#!")?.split(|b| *b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+    let program = words.next()?.rsplit('/').next()?;
+    let interpreter = if program == "env" {
+        words.find(|word| !word.starts_with('-'))?
+    } else {
+        program
+    };
+    Some(interpreter.to_string())
+}
+
+fn file_name(file: &File) -> &str {
+    match file {
+        File::Local { name, .. } | File::Remote { name, .. } | File::Archive { name, .. } => name,
+    }
+}
@@ -0,0 +1,161 @@
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::{Value, json};
+
+use super::TestApp;
+use crate::files::FileManagerTrait;
+use crate::redis_client;
+use crate::types::FilePath;
+
+#[tokio::test]
+async fn capabilities_name_the_backends() {
+    let app = TestApp::new("").await;
+    let (status, body) = app.get("/capabilities").await;
+    assert_eq!(status, StatusCode::OK);
+    let capabilities: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(capabilities["backends"]["files"], "memory");
+    assert_eq!(capabilities["mode"], "standalone");
+    assert!(capabilities.get("runners").is_none());
+}
+
+#[tokio::test]
+async fn tenants_need_an_api_key() {
+    let app = TestApp::new("[tenants.acme]\napi_key = \"secret\"\n").await;
+    let execute = |key: Option<&str>| {
+        let mut request = Request::post("/execute").header("content-type", "application/json");
+        if let Some(key) = key {
+            request = request.header("x-api-key", key);
+        }
+        let body = json!({ "executions": [{ "program": "/bin/true" }] });
+        request.body(Body::from(body.to_string())).unwrap()
+    };
+
+    let (status, body) = app.send(execute(None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"], "missing api key or token");
+    let (status, _) = app.send(execute(Some("wrong"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // without daily limits the tenant's usage isn't counted in redis
+    let (status, _) = app.send(execute(Some("secret"))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn missing_files_are_not_found() {
+    let app = TestApp::new("").await;
+    let (status, _) = app.get("/files/missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn detached_requests_need_redis() {
+    let app = TestApp::new("").await;
+    let request = Request::post("/execute")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "detach": true, "executions": [{ "program": "/bin/true" }] }).to_string(),
+        ))
+        .unwrap();
+    let (status, body) = app.send(request).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert!(
+        body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("this needs redis")
+    );
+}
+
+#[tokio::test]
+async fn unsigned_bodies_are_held_to_the_route_limit() {
+    let app = TestApp::new("max_request_size = 1024\n[signing_keys]\nci = \"secret\"\n").await;
+    let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let request = Request::post("/execute")
+        .header(
+            "x-signature",
+            format!("keyid=ci, timestamp={}, signature=00", now),
+        )
+        .body(Body::from(vec![b' '; 2048]))
+        .unwrap();
+    let (status, _) = app.send(request).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn file_ids_cant_alias_other_keys() {
+    for id in [
+        "job:1",
+        "runners",
+        "input:part:0",
+        "input:owner",
+        "input:digest",
+    ] {
+        assert!(redis_client::file_key(id).is_err(), "{} is reserved", id);
+    }
+    for id in [
+        "input",
+        "problem:p:tests/1.in",
+        "input:part:x",
+        "part:0",
+        "owner",
+    ] {
+        assert!(redis_client::file_key(id).is_ok(), "{} is a file id", id);
+    }
+}
+
+#[tokio::test]
+async fn webhooks_only_reach_allowed_public_hosts() {
+    let app = TestApp::new(
+        "webhook_allowed_hosts = [\"hooks.example.com\", \"*.internal\", \"10.0.0.1\"]",
+    )
+    .await;
+    let webhooks = &app.state.webhooks;
+    assert!(webhooks.validate("https://hooks.example.com/done").is_ok());
+    for url in [
+        "ftp://hooks.example.com/done",
+        "https://example.com/done",
+        "http://10.0.0.1/done",
+        "http://127.0.0.1/done",
+    ] {
+        assert!(webhooks.validate(url).is_err(), "{} is refused", url);
+    }
+    let disabled = TestApp::new("").await;
+    assert!(
+        disabled
+            .state
+            .webhooks
+            .validate("https://hooks.example.com/done")
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn memory_files_are_held_to_the_store_size() {
+    let app = TestApp::new("memory_file_store_size = 8").await;
+    let mut files = app.state.memory_files.clone().unwrap();
+    let remote = |id: &str| FilePath::Remote { id: id.to_string() };
+    assert!(files.save_file(remote("a"), None, vec![0; 6]).await.is_ok());
+    assert!(
+        files
+            .save_file(remote("b"), None, vec![0; 6])
+            .await
+            .is_err()
+    );
+    // replacing a file frees what it held
+    assert!(files.save_file(remote("a"), None, vec![0; 8]).await.is_ok());
+}
+
+#[tokio::test]
+async fn dead_letters_are_for_admins() {
+    let app = TestApp::new("admin_token = \"secret\"").await;
+    let (status, _) = app.get("/admin/dead-letters").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let request = Request::get("/admin/dead-letters")
+        .header("authorization", "Bearer secret")
+        .body(Body::empty())
+        .unwrap();
+    let (status, _) = app.send(request).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,226 @@
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::archive::{read_archive, read_zip, unpack_tar_layer};
+use crate::utils::gen_random_id;
+
+struct ZipFile<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    deflate: bool,
+    size: u32, // declared uncompressed size
+}
+
+impl<'a> ZipFile<'a> {
+    fn new(name: &'a str, data: &'a [u8]) -> Self {
+        Self {
+            name,
+            data,
+            deflate: false,
+            size: data.len() as u32,
+        }
+    }
+}
+
+fn zip(files: &[ZipFile]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for file in files {
+        let (method, raw) = if file.deflate {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(file.data).unwrap();
+            (8u16, encoder.finish().unwrap())
+        } else {
+            (0u16, file.data.to_vec())
+        };
+        let crc = crc32fast::hash(file.data);
+        let offset = archive.len() as u32;
+
+        archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        archive.extend_from_slice(&[20, 0, 0, 0]); // version, flags
+        archive.extend_from_slice(&method.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]); // time and date
+        archive.extend_from_slice(&crc.to_le_bytes());
+        archive.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&file.size.to_le_bytes());
+        archive.extend_from_slice(&(file.name.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&[0, 0]); // extra length
+        archive.extend_from_slice(file.name.as_bytes());
+        archive.extend_from_slice(&raw);
+
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&[20, 3, 20, 0, 0, 0]); // made on unix, version, flags
+        directory.extend_from_slice(&method.to_le_bytes());
+        directory.extend_from_slice(&[0; 4]);
+        directory.extend_from_slice(&crc.to_le_bytes());
+        directory.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        directory.extend_from_slice(&file.size.to_le_bytes());
+        directory.extend_from_slice(&(file.name.len() as u16).to_le_bytes());
+        directory.extend_from_slice(&[0; 8]); // extra and comment length, disk, internal
+        directory.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(file.name.as_bytes());
+    }
+    let directory_offset = archive.len() as u32;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]); // disks
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&directory_offset.to_le_bytes());
+    archive.extend_from_slice(&[0, 0]); // comment length
+    archive
+}
+
+// a ustar header, checksummed, followed by `data` padded to whole blocks
+fn tar_entry(archive: &mut Vec<u8>, name: &str, kind: u8, link: &str, data: &[u8]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(512), 0);
+}
+
+fn tar(entries: &[(&str, u8, &str, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for (name, kind, link, data) in entries {
+        tar_entry(&mut archive, name, *kind, link, data);
+    }
+    archive.extend_from_slice(&[0; 1024]);
+    archive
+}
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pentagon-archive-{}", gen_random_id(10)));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn zip_entries_are_read() {
+    let archive = zip(&[
+        ZipFile::new("main.c", b"int main() {}"),
+        ZipFile {
+            deflate: true,
+            ..ZipFile::new("data/input.txt", b"hello hello hello hello")
+        },
+    ]);
+    let entries = read_archive(&archive, 1024).unwrap();
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["main.c", "data/input.txt"]);
+    assert_eq!(entries[1].data, b"hello hello hello hello");
+}
+
+#[test]
+fn escaping_names_are_refused() {
+    for name in ["../evil", "a/../../evil", "/etc/passwd"] {
+        let err = read_zip(&zip(&[ZipFile::new(name, b"x")]), 1024)
+            .err()
+            .unwrap();
+        assert_eq!(err, format!("zip entry {} escapes the archive", name));
+    }
+    let err = read_archive(&tar(&[("../evil", b'0', "", b"x")]), 1024)
+        .err()
+        .unwrap();
+    assert_eq!(err, "tar entry ../evil escapes the archive");
+    // tar strips the leading slash, so absolute names land inside
+    let entries = read_archive(&tar(&[("/etc/passwd", b'0', "", b"x")]), 1024).unwrap();
+    assert_eq!(entries[0].name, "etc/passwd");
+}
+
+#[test]
+fn deflate_bombs_stop_at_max_size() {
+    let zeros = vec![0u8; 1024 * 1024];
+    // declared honestly, the size alone is refused
+    let bomb = ZipFile {
+        deflate: true,
+        ..ZipFile::new("bomb", &zeros)
+    };
+    let err = read_zip(&zip(&[bomb]), 64 * 1024).err().unwrap();
+    assert_eq!(err, "zip archive expands to more than 65536 bytes");
+    // declared small, inflating stops right past the declared size
+    let lying = ZipFile {
+        deflate: true,
+        size: 16,
+        ..ZipFile::new("bomb", &zeros)
+    };
+    let err = read_zip(&zip(&[lying]), 64 * 1024).err().unwrap();
+    assert_eq!(err, "zip entry bomb is corrupt");
+}
+
+#[test]
+fn corrupt_zip_directories_are_errors() {
+    let archive = zip(&[ZipFile::new("a", b"contents")]);
+    let end = archive.len() - 22;
+
+    // cut off inside the central directory
+    let mut truncated = archive[..end - 10].to_vec();
+    truncated.extend_from_slice(&archive[end..]);
+    assert!(read_zip(&truncated, 1024).is_err());
+
+    // more entries than there are
+    let mut overcounted = archive.clone();
+    overcounted[end + 10..end + 12].copy_from_slice(&2u16.to_le_bytes());
+    assert!(read_zip(&overcounted, 1024).is_err());
+
+    // a directory offset past the end
+    let mut misplaced = archive.clone();
+    misplaced[end + 16..end + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        read_zip(&misplaced, 1024).err().unwrap(),
+        "truncated zip archive"
+    );
+
+    // a local header offset pointing elsewhere, the last field before the name
+    let mut wrong_header = archive.clone();
+    let local_offset = end - "a".len() - 4;
+    wrong_header[local_offset..local_offset + 4].copy_from_slice(&1u32.to_le_bytes());
+    assert_eq!(
+        read_zip(&wrong_header, 1024).err().unwrap(),
+        "corrupt zip entry a"
+    );
+
+    assert_eq!(
+        read_zip(b"PK not really", 1024).err().unwrap(),
+        "not a zip archive"
+    );
+}
+
+#[test]
+fn layer_symlinks_resolve_inside_the_root() {
+    let dir = temp_dir();
+    let (root, outside) = (dir.join("root"), dir.join("outside"));
+    fs::create_dir_all(&root).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+
+    let layer = tar(&[
+        ("up", b'2', "../outside", b""),
+        ("abs", b'2', &format!("{}", outside.display()), b""),
+        ("up/escaped", b'0', "", b"x"),
+        ("abs/escaped", b'0', "", b"y"),
+        ("hard", b'1', "../outside/secret", b""),
+    ]);
+    fs::write(outside.join("secret"), b"secret").unwrap();
+    let result = unpack_tar_layer(layer.as_slice(), &root, 1024);
+
+    // the links point wherever they like, but are followed as if root were /
+    assert_eq!(fs::read(root.join("outside/escaped")).unwrap(), b"x");
+    let inside = root.join(outside.strip_prefix("/").unwrap());
+    assert_eq!(fs::read(inside.join("escaped")).unwrap(), b"y");
+    assert!(!outside.join("escaped").exists());
+    // the hard link target doesn't exist inside the root
+    assert!(result.unwrap_err().starts_with("failed to link hard"));
+    fs::remove_dir_all(&dir).unwrap();
+}
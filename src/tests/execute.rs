@@ -0,0 +1,414 @@
+use serde_json::{Value, json};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use super::TestApp;
+use crate::files::FileManagerTrait;
+use crate::handlers::run::execute_code_inner;
+use crate::init::{self, Report};
+use crate::mock::{self, Script};
+use crate::types::FilePath;
+
+// the text of the result's return file `index`
+fn returned(result: &Value, index: usize) -> String {
+    let content: Vec<u8> = serde_json::from_value(result["return_files"][index]["content"].clone())
+        .unwrap_or_default();
+    String::from_utf8(content).unwrap()
+}
+
+fn names(events: &[(String, Value)]) -> Vec<&str> {
+    events.iter().map(|(name, _)| name.as_str()).collect()
+}
+
+#[tokio::test]
+async fn returns_stdout() {
+    let app = TestApp::new("").await;
+    let events = app
+        .execute(json!({
+            "executions": [{
+                "program": "/bin/echo",
+                "args": ["hello", "world"],
+                "return_files": [{ "type": "stdout" }],
+            }],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["result", "done"]);
+    let result = &events[0].1;
+    assert_eq!(result["exit_code"], 0);
+    assert_eq!(result["status"], "success");
+    assert_eq!(returned(result, 0), "hello world\n");
+    assert_eq!(events[1].1["succeeded"], 1);
+}
+
+#[tokio::test]
+async fn reports_usage_of_the_script() {
+    let app = TestApp::new("").await;
+    mock::script(
+        "/usage",
+        Script {
+            exit_code: 3,
+            time_ms: 250,
+            memory_kb: 2048,
+            ..Default::default()
+        },
+    );
+    let events = app
+        .execute(json!({ "executions": [{ "program": "/usage" }] }))
+        .await;
+
+    let result = &events[0].1;
+    assert_eq!(result["exit_code"], 3);
+    assert_eq!(result["status"], "nonzero_exit");
+    assert_eq!(result["time_used"], 250);
+    assert_eq!(result["memory_used"], 2048);
+    assert_eq!(result["resource"]["max_rss"], 2048);
+    assert_eq!(events[1].1["failed"], 1);
+}
+
+#[tokio::test]
+async fn sigxcpu_is_a_time_limit() {
+    let app = TestApp::new("").await;
+    mock::script(
+        "/spin",
+        Script {
+            signal: Some(libc::SIGXCPU),
+            time_ms: 1000,
+            ..Default::default()
+        },
+    );
+    let events = app
+        .execute(json!({ "executions": [{ "program": "/spin", "time_limit": 1 }] }))
+        .await;
+
+    assert_eq!(events[0].1["status"], "time_limit_exceeded");
+}
+
+#[tokio::test]
+async fn die_on_error_skips_the_rest() {
+    let app = TestApp::new("").await;
+    mock::script(
+        "/fail",
+        Script {
+            exit_code: 1,
+            ..Default::default()
+        },
+    );
+    let events = app
+        .execute(json!({
+            "executions": [
+                { "program": "/fail", "die_on_error": true },
+                { "program": "/bin/echo", "args": ["never"] },
+            ],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["result", "done"]);
+    let summary = &events[1].1;
+    assert_eq!(summary["executions"], 2);
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(summary["skipped"], 1);
+}
+
+#[tokio::test]
+async fn failed_compile_skips_the_executions() {
+    let app = TestApp::new("").await;
+    mock::script(
+        "/cc",
+        Script {
+            exit_code: 1,
+            stderr: b"syntax error".to_vec(),
+            ..Default::default()
+        },
+    );
+    let events = app
+        .execute(json!({
+            "compile": { "program": "/cc", "artifact": "a.out" },
+            "executions": [{ "program": "./a.out" }],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["result", "done"]);
+    assert_eq!(events[0].1["compile"], true);
+    assert_eq!(events[1].1["skipped"], 1);
+}
+
+#[tokio::test]
+async fn files_returned_by_reference_can_be_fetched() {
+    let app = TestApp::new("").await;
+    mock::script(
+        "/write",
+        Script {
+            files: vec![("out.txt".to_string(), b"artifact".to_vec())],
+            ..Default::default()
+        },
+    );
+    let events = app
+        .execute(json!({
+            "executions": [{
+                "program": "/write",
+                "return_files": [{ "type": "local", "name": "out.txt", "executable": false }],
+                "return_by_reference": true,
+            }],
+        }))
+        .await;
+
+    let file = &events[0].1["return_files"][0];
+    assert_eq!(file["remote"]["size"], 8);
+    let id = file["remote"]["id"].as_str().unwrap();
+    let (status, body) = app.get(&format!("/files/{}", id)).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"artifact");
+
+    // files nothing returned can't be fetched
+    let mut files = app.state.memory_files.clone().unwrap();
+    let staged = FilePath::Remote {
+        id: "staged".to_string(),
+    };
+    files
+        .save_file(staged, None, b"input".to_vec())
+        .await
+        .unwrap();
+    let (status, _) = app.get("/files/staged").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn remote_files_are_staged_from_memory() {
+    let app = TestApp::new("").await;
+    let mut files = app.state.memory_files.clone().unwrap();
+    let remote = FilePath::Remote {
+        id: "input".to_string(),
+    };
+    files
+        .save_file(remote, None, b"42\n".to_vec())
+        .await
+        .unwrap();
+    let events = app
+        .execute(json!({
+            "files": [{ "type": "remote", "name": "in.txt", "id": "input" }],
+            "executions": [{
+                "program": "/bin/true",
+                "return_files": [{ "type": "local", "name": "in.txt", "executable": false }],
+            }],
+        }))
+        .await;
+
+    assert_eq!(events[0].0, "result");
+    assert_eq!(returned(&events[0].1, 0), "42\n");
+}
+
+#[tokio::test]
+async fn missing_remote_files_are_errors() {
+    let app = TestApp::new("").await;
+    let events = app
+        .execute(json!({
+            "files": [{ "type": "remote", "name": "in.txt", "id": "missing" }],
+            "executions": [{ "program": "/bin/true" }],
+        }))
+        .await;
+
+    assert_eq!(events[0].0, "error");
+    assert_eq!(events.last().unwrap().1["errors"], 1);
+}
+
+#[tokio::test]
+async fn labels_are_echoed_in_results() {
+    let app = TestApp::new("").await;
+    let events = app
+        .execute(json!({
+            "labels": { "submission": "s-1", "stage": "request" },
+            "executions": [
+                { "program": "/bin/true", "labels": { "stage": "first" } },
+                { "program": "/bin/true" },
+            ],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["result", "result", "done"]);
+    assert_eq!(
+        events[0].1["labels"],
+        json!({ "submission": "s-1", "stage": "first" })
+    );
+    assert_eq!(
+        events[1].1["labels"],
+        json!({ "submission": "s-1", "stage": "request" })
+    );
+}
+
+#[tokio::test]
+async fn ids_are_echoed_in_results() {
+    let app = TestApp::new("").await;
+    let events = app
+        .execute(json!({
+            "executions": [
+                { "program": "/bin/true", "id": "first" },
+                { "program": "/bin/true" },
+            ],
+        }))
+        .await;
+
+    assert_eq!(events[0].1["id"], "first");
+    assert!(events[1].1.get("id").is_none());
+}
+
+#[tokio::test]
+async fn failed_executions_get_an_error_event() {
+    let app = TestApp::new("").await;
+    let events = app
+        .execute(json!({
+            "executions": [
+                {
+                    "program": "/bin/true",
+                    "id": "broken",
+                    "copy_in": [{
+                        "from": { "type": "remote", "id": "missing" },
+                        "to": { "type": "stdin" },
+                    }],
+                },
+                { "program": "/bin/true" },
+            ],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["error", "result", "done"]);
+    assert_eq!(events[0].1["id"], "broken");
+    assert_eq!(events[2].1["errors"], 1);
+}
+
+#[tokio::test]
+async fn bad_local_copies_are_errors() {
+    let app = TestApp::new("").await;
+    let local = |name: &str| json!({ "type": "local", "name": name, "executable": false });
+    let events = app
+        .execute(json!({
+            "executions": [
+                {
+                    "program": "/bin/true",
+                    "copy_in": [{ "from": local("never-written"), "to": local("copy") }],
+                },
+                {
+                    "program": "/bin/true",
+                    "copy_in": [{
+                        "from": { "type": "data", "content": [104, 105] },
+                        "to": local("missing/dir/copy"),
+                    }],
+                },
+            ],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["error", "error", "done"]);
+    let error = |index: usize| events[index].1["error"].as_str().unwrap();
+    assert!(error(0).contains("failed to open file never-written"));
+    assert!(error(1).contains("failed to write file missing/dir/copy"));
+}
+
+#[tokio::test]
+async fn missing_returned_files_are_errors() {
+    let app = TestApp::new("").await;
+    let events = app
+        .execute(json!({
+            "executions": [{
+                "program": "/bin/true",
+                "return_files": [
+                    { "type": "tmp", "id": 7 },
+                    { "type": "remote", "id": "missing" },
+                ],
+            }],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["error", "done"]);
+    assert!(events[0].1["error"].as_str().unwrap().contains("missing"));
+}
+
+#[tokio::test]
+async fn disconnected_clients_stop_the_batch() {
+    let app = TestApp::new("").await;
+    let request = serde_json::from_value(json!({
+        "executions": [{ "program": "/bin/true" }, { "program": "/bin/true" }],
+    }))
+    .unwrap();
+    let (tx, rx) = mpsc::channel(100);
+    drop(rx);
+    execute_code_inner(app.state.clone(), request, tx, None, None).await;
+
+    // the first execution is killed as it starts, the second never runs
+    let stats = app.state.stats.snapshot(0, 0);
+    assert_eq!(stats.executions_total, 1);
+    assert_eq!(stats.execution_errors_total, 1);
+}
+
+#[tokio::test]
+async fn disconnecting_mid_run_kills_the_program() {
+    let app = TestApp::new("").await;
+    mock::script(
+        "/hang",
+        Script {
+            sleep_ms: 30_000,
+            ..Default::default()
+        },
+    );
+    let request = serde_json::from_value(json!({
+        "executions": [{ "program": "/hang" }, { "program": "/bin/true" }],
+    }))
+    .unwrap();
+    let (tx, rx) = mpsc::channel(100);
+    let run = tokio::spawn(execute_code_inner(
+        app.state.clone(),
+        request,
+        tx,
+        None,
+        None,
+    ));
+    time::sleep(Duration::from_millis(200)).await;
+    drop(rx);
+
+    time::timeout(Duration::from_secs(5), run)
+        .await
+        .expect("the program outlived its client")
+        .unwrap();
+    let stats = app.state.stats.snapshot(0, 0);
+    assert_eq!(stats.executions_total, 1);
+    assert_eq!(stats.execution_errors_total, 1);
+}
+
+#[tokio::test]
+async fn batches_stop_at_their_wall_time() {
+    let app = TestApp::new("max_batch_wall_time = 1\n").await;
+    mock::script(
+        "/slow",
+        Script {
+            sleep_ms: 1100,
+            ..Default::default()
+        },
+    );
+    let events = app
+        .execute(json!({
+            "executions": [{ "program": "/slow" }, { "program": "/bin/true" }],
+        }))
+        .await;
+
+    assert_eq!(names(&events), ["result", "done"]);
+    assert_eq!(events[1].1["skipped"], 1);
+}
+
+#[test]
+fn init_reports_are_cut_off_the_end_of_stderr() {
+    let report = Report {
+        exit_code: Some(0),
+        reaped: 2,
+        ..Default::default()
+    };
+    let mut stderr = b"warning\n".to_vec();
+    stderr.extend(init::report_frame(&report));
+    assert_eq!(init::take_report(&mut stderr).unwrap().reaped, 2);
+    assert_eq!(stderr, b"warning\n");
+
+    // a frame the program wrote earlier is just output
+    stderr.extend(init::report_frame(&report));
+    stderr.extend(b"more\n");
+    assert!(init::take_report(&mut stderr).is_none());
+}
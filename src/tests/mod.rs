@@ -0,0 +1,144 @@
+// handler tests against the mock sandbox and in-memory files, so they need
+// neither root nor redis, and tests of the archive readers
+
+mod api;
+mod archive;
+mod execute;
+
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde_json::Value;
+use std::fs;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+use crate::client_ip::{Peer, TrustedProxies};
+use crate::connections::ConnectionLimiter;
+use crate::file_cache::FileCache;
+use crate::files::MemoryFileManager;
+use crate::images::ImageManager;
+use crate::runner::EventListener;
+use crate::sandboxes::SandboxRegistry;
+use crate::stats::Stats;
+use crate::transfers::UrlTransfers;
+use crate::types::{AppConfig, AppState};
+use crate::utils::gen_random_id;
+use crate::webhooks::WebhookSender;
+use crate::{app, system_monitor, tls};
+
+// an instance of the service without redis, with the settings in `extra`
+// on top of the required ones. its sandboxes live in a directory of their own
+pub struct TestApp {
+    pub state: AppState,
+    router: Router,
+    base_code_path: String,
+}
+
+impl TestApp {
+    pub async fn new(extra: &str) -> Self {
+        tls::install_crypto_provider();
+        let base_code_path = std::env::temp_dir()
+            .join(format!("pentagon-test-{}", gen_random_id(10)))
+            .display()
+            .to_string();
+        fs::create_dir_all(&base_code_path).unwrap();
+        let settings = format!(
+            "file_store = \"memory\"\n\
+             port = 0\n\
+             base_code_path = \"{}\"\n\
+             {}",
+            base_code_path, extra
+        );
+        let config: AppConfig = config::Config::builder()
+            .add_source(config::File::from_str(&settings, config::FileFormat::Toml))
+            .build()
+            .and_then(|settings| settings.try_deserialize())
+            .unwrap();
+        let config = Arc::new(config);
+
+        let state = AppState {
+            redis_connection: None,
+            base_code_path: base_code_path.clone(),
+            prometheus_handle: PrometheusBuilder::new().build_recorder().handle(),
+            config: config.clone(),
+            sandboxes: Arc::new(SandboxRegistry::default()),
+            stats: Arc::new(Stats::new()),
+            images: Arc::new(ImageManager::new(&config)),
+            file_cache: Arc::new(FileCache::new(&config)),
+            trusted_proxies: Arc::new(TrustedProxies::new(&config).unwrap()),
+            jwt: None,
+            ws_connections: Arc::new(ConnectionLimiter::new(config.ws_max_connections_per_client)),
+            usage: None,
+            system: system_monitor::start_system_monitor(&config).await,
+            webhooks: Arc::new(WebhookSender::new(&config).unwrap()),
+            runner_events: Arc::new(EventListener::default()),
+            transfers: Arc::new(UrlTransfers::new(&config).unwrap()),
+            encryption: None,
+            memory_files: Some(MemoryFileManager::new(&config)),
+        };
+        Self {
+            router: app(state.clone()),
+            state,
+            base_code_path,
+        }
+    }
+
+    // sends the request from a client on localhost, returning the status and body
+    pub async fn send(&self, mut request: Request<Body>) -> (StatusCode, Vec<u8>) {
+        request.extensions_mut().insert(ConnectInfo(Peer {
+            addr: "127.0.0.1:40000".parse().unwrap(),
+            client_certificate: None,
+        }));
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    pub async fn get(&self, uri: &str) -> (StatusCode, Vec<u8>) {
+        self.send(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+    }
+
+    // posts `body` to /execute and returns its events by name, the done event
+    // included
+    pub async fn execute(&self, body: Value) -> Vec<(String, Value)> {
+        let request = Request::post("/execute")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let (status, body) = self.send(request).await;
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        events(&String::from_utf8(body).unwrap())
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.base_code_path);
+    }
+}
+
+// the events of an SSE stream with their json data
+fn events(stream: &str) -> Vec<(String, Value)> {
+    stream
+        .split("\n\n")
+        .filter_map(|event| {
+            let mut name = String::from("message");
+            let mut data = String::new();
+            for line in event.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    name = value.trim().to_string();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data.push_str(value.trim_start());
+                }
+            }
+            (!data.is_empty()).then(|| (name, serde_json::from_str(&data).unwrap()))
+        })
+        .collect()
+}
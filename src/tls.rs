@@ -0,0 +1,178 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::client_ip::Peer;
+use crate::types::{AppConfig, TlsConfig};
+
+// a client that connects and never finishes the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// handshakes finished but not yet picked up by the server
+const ACCEPT_BACKLOG: usize = 128;
+
+// the redis, webhook, transfer and jwks clients build their tls
+// configuration with the process-wide provider, which rustls can't pick on
+// its own with both ring and aws-lc compiled in
+pub fn install_crypto_provider() {
+    if CryptoProvider::get_default().is_none() {
+        let _ = ring::default_provider().install_default();
+    }
+}
+
+pub fn server_config(app_config: &AppConfig, config: &TlsConfig) -> Result<ServerConfig, String> {
+    for (common_name, client) in &config.clients {
+        if let Some(tenant) = &client.tenant
+            && !app_config.tenants.contains_key(tenant)
+        {
+            return Err(format!(
+                "tls client {} names unknown tenant {}",
+                common_name, tenant
+            ));
+        }
+    }
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read certificate {}: {}", config.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| format!("failed to read private key {}: {}", config.key, e))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("invalid tls configuration: {}", e))?;
+    let builder = match &config.client_ca {
+        None => builder.with_no_client_auth(),
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca)
+                .map_err(|e| format!("failed to read client ca {}: {}", client_ca, e))?
+            {
+                let cert = cert.map_err(|e| format!("invalid client ca {}: {}", client_ca, e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("invalid client ca {}: {}", client_ca, e))?;
+            }
+            let mut verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if !config.require_client_certificate {
+                verifier = verifier.allow_unauthenticated();
+            }
+            let verifier = verifier
+                .build()
+                .map_err(|e| format!("invalid client ca {}: {}", client_ca, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+// accepts tls connections, handshaking each in its own task so a slow client
+// doesn't hold up the ones behind it
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, Peer)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: ServerConfig) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let (tx, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // running out of file descriptors, mostly
+                        tracing::error!("failed to accept connection: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let stream = match tokio::time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        acceptor.accept(stream),
+                    )
+                    .await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            tracing::debug!(%addr, "tls handshake failed: {}", e);
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!(%addr, "tls handshake timed out");
+                            return;
+                        }
+                    };
+                    let peer = Peer {
+                        addr,
+                        client_certificate: client_common_name(&stream),
+                    };
+                    let _ = tx.send((stream, peer)).await;
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            accepted,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = Peer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // the sender lives as long as the accept task, which never returns
+        self.accepted.recv().await.unwrap()
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(Peer {
+            addr: self.local_addr,
+            client_certificate: None,
+        })
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+// the common name of the client certificate, which rustls has verified
+// against the client ca by the end of the handshake
+fn client_common_name(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let (_, connection) = stream.get_ref();
+    let certificate = connection.peer_certificates()?.first()?;
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    let common_name = certificate
+        .subject()
+        .iter_common_name()
+        .next()?
+        .as_str()
+        .ok()?
+        .to_string();
+    Some(common_name)
+}
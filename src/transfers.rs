@@ -0,0 +1,355 @@
+use axum::body::Bytes;
+use axum::http::{Request, Uri, header::CONTENT_LENGTH};
+use futures_util::stream;
+use http_body_util::{
+    BodyExt, Empty, Full, LengthLimitError, Limited, StreamBody, combinators::BoxBody,
+};
+use hyper::body::{Frame, Incoming};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use hyper_util::rt::TokioExecutor;
+use metrics::counter;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::time::Instant;
+
+use crate::types::AppConfig;
+
+// bytes read from disk per body frame of an upload
+const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+// how often a running clone's size is checked
+const CLONE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// bytes of git's error output kept for the execution error
+const MAX_GIT_ERROR_SIZE: u64 = 4096;
+
+// moves files between sandboxes and urls clients hand in, like presigned
+// object storage urls, without going through redis
+pub struct UrlTransfers {
+    client: Client<HttpsConnector<HttpConnector>, BoxBody<Bytes, io::Error>>,
+    allowed_schemes: Vec<String>,
+    allowed_hosts: Vec<String>,
+    max_download_size: u64,
+    timeout: Duration,
+    git_path: String,
+    git_max_size: u64,
+    git_timeout: Duration,
+}
+
+impl UrlTransfers {
+    pub fn new(config: &AppConfig) -> Result<Self, String> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|e| format!("failed to load root certificates: {}", e))?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            allowed_schemes: config.url_allowed_schemes.clone(),
+            allowed_hosts: config.url_allowed_hosts.clone(),
+            max_download_size: config.url_max_download_size,
+            timeout: Duration::from_secs(config.url_transfer_timeout_secs),
+            git_path: config.git_path.clone(),
+            git_max_size: config.git_max_size,
+            git_timeout: Duration::from_secs(config.git_timeout_secs),
+        })
+    }
+
+    // urls have to use an allowed scheme and point at an allowed host, so
+    // clients can't make the server reach into the network it runs in
+    fn check(&self, url: &str) -> Result<Uri, String> {
+        if self.allowed_hosts.is_empty() {
+            return Err("url transfers are disabled on this server".to_string());
+        }
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|e| format!("invalid url: {}", e))?;
+        let scheme = uri.scheme_str().unwrap_or_default();
+        if !self.allowed_schemes.iter().any(|allowed| allowed == scheme) {
+            return Err(format!("scheme {:?} is not allowed", scheme));
+        }
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        if !self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| host_matches(allowed, &host))
+        {
+            return Err(format!("host {} is not allowed", host));
+        }
+        Ok(uri)
+    }
+
+    // PUTs `data` to `url`
+    pub async fn upload_bytes(&self, url: &str, data: &[u8]) -> Result<u64, String> {
+        let body = Full::new(Bytes::copy_from_slice(data))
+            .map_err(|never| match never {})
+            .boxed();
+        self.upload(url, body, data.len() as u64).await
+    }
+
+    // PUTs `file` to `url` as it is read, so large artifacts are never held
+    // in memory
+    pub async fn upload_file(&self, url: &str, file: std::fs::File) -> Result<u64, String> {
+        let size = file
+            .metadata()
+            .map_err(|e| format!("failed to read file size: {}", e))?
+            .len();
+        let file = tokio::fs::File::from_std(file);
+        let chunks = stream::unfold(file, |mut file| async move {
+            let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(Frame::data(Bytes::from(buffer))), file))
+                }
+                Err(e) => Some((Err(e), file)),
+            }
+        });
+        // presigned urls need the length up front, object stores reject
+        // chunked uploads
+        self.upload(url, BoxBody::new(StreamBody::new(chunks)), size)
+            .await
+    }
+
+    async fn upload(
+        &self,
+        url: &str,
+        body: BoxBody<Bytes, io::Error>,
+        size: u64,
+    ) -> Result<u64, String> {
+        let uploaded = self.put(url, body, size).await;
+        count("upload", &uploaded);
+        uploaded.map(|_| size)
+    }
+
+    async fn put(
+        &self,
+        url: &str,
+        body: BoxBody<Bytes, io::Error>,
+        size: u64,
+    ) -> Result<(), String> {
+        let uri = self.check(url)?;
+        let request = Request::put(uri)
+            .header(CONTENT_LENGTH, size)
+            .body(body)
+            .map_err(|e| format!("invalid request: {}", e))?;
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| "upload timed out".to_string())?
+            .map_err(|e| format!("failed to upload: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("upload answered {}", response.status()));
+        }
+        Ok(())
+    }
+
+    // GETs `url` into memory, failing once it grows past url_max_download_size
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>, String> {
+        let downloaded = tokio::time::timeout(self.timeout, async {
+            let body = self.get(url).await?;
+            body.collect()
+                .await
+                .map(|collected| collected.to_bytes().to_vec())
+                .map_err(|e| self.read_error(e))
+        })
+        .await
+        .unwrap_or_else(|_| Err("download timed out".to_string()));
+        count("download", &downloaded);
+        downloaded
+    }
+
+    // GETs `url` into the file at `path` as it arrives, so large inputs are
+    // never held in memory. returns the bytes written
+    pub async fn download_to(&self, url: &str, path: &Path) -> Result<u64, String> {
+        let downloaded = tokio::time::timeout(self.timeout, async {
+            let mut body = self.get(url).await?;
+            let mut file = tokio::fs::File::create(path)
+                .await
+                .map_err(|e| format!("failed to create file: {}", e))?;
+            let mut written = 0;
+            while let Some(frame) = body.frame().await {
+                let frame = frame.map_err(|e| self.read_error(e))?;
+                if let Ok(data) = frame.into_data() {
+                    file.write_all(&data)
+                        .await
+                        .map_err(|e| format!("failed to write file: {}", e))?;
+                    written += data.len() as u64;
+                }
+            }
+            file.flush()
+                .await
+                .map_err(|e| format!("failed to write file: {}", e))?;
+            Ok(written)
+        })
+        .await
+        .unwrap_or_else(|_| Err("download timed out".to_string()));
+        count("download", &downloaded);
+        downloaded
+    }
+
+    // the response body, cut off past url_max_download_size. a larger
+    // announced length fails before anything is read
+    async fn get(&self, url: &str) -> Result<Limited<Incoming>, String> {
+        let uri = self.check(url)?;
+        let body = Empty::new().map_err(|never| match never {}).boxed();
+        let request = Request::get(uri)
+            .body(body)
+            .map_err(|e| format!("invalid request: {}", e))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| format!("failed to download: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("download answered {}", response.status()));
+        }
+        let length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if length.is_some_and(|length| length > self.max_download_size) {
+            return Err(self.too_large());
+        }
+        Ok(Limited::new(
+            response.into_body(),
+            self.max_download_size as usize,
+        ))
+    }
+
+    fn read_error(&self, e: Box<dyn std::error::Error + Send + Sync>) -> String {
+        if e.is::<LengthLimitError>() {
+            self.too_large()
+        } else {
+            format!("failed to download: {}", e)
+        }
+    }
+
+    fn too_large(&self) -> String {
+        format!("download is larger than {} bytes", self.max_download_size)
+    }
+
+    // shallow-clones `rev` (a branch or tag, the default branch when None)
+    // of the repository at `url` into `dest`, which must not exist yet. the
+    // clone is killed once it grows past git_max_size or runs past
+    // git_timeout_secs
+    pub async fn clone_repository(
+        &self,
+        url: &str,
+        rev: Option<&str>,
+        dest: &Path,
+    ) -> Result<(), String> {
+        let cloned = self.clone_into(url, rev, dest).await;
+        count("clone", &cloned);
+        cloned
+    }
+
+    async fn clone_into(&self, url: &str, rev: Option<&str>, dest: &Path) -> Result<(), String> {
+        self.check(url)?;
+        let mut command = Command::new(&self.git_path);
+        // only the checked url is fetched: no other transports, redirects,
+        // credential prompts, or configuration and hooks of the host
+        command
+            .args(["-c", "protocol.allow=never"])
+            .args(["-c", "protocol.http.allow=always"])
+            .args(["-c", "protocol.https.allow=always"])
+            .args(["-c", "http.followRedirects=false"])
+            .args(["-c", "core.hooksPath=/dev/null"])
+            .args([
+                "clone",
+                "--quiet",
+                "--depth",
+                "1",
+                "--single-branch",
+                "--no-tags",
+            ]);
+        if let Some(rev) = rev {
+            command.arg("--branch").arg(rev);
+        }
+        let mut child = command
+            .arg("--")
+            .arg(url)
+            .arg(dest)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to run git: {}", e))?;
+
+        let deadline = Instant::now() + self.git_timeout;
+        let status = loop {
+            tokio::select! {
+                status = child.wait() => break status.map_err(|e| format!("failed to run git: {}", e))?,
+                _ = tokio::time::sleep(CLONE_CHECK_INTERVAL) => {
+                    if Instant::now() >= deadline {
+                        return Err("clone timed out".to_string());
+                    }
+                    let dir = dest.to_path_buf();
+                    let size = tokio::task::spawn_blocking(move || dir_size(&dir))
+                        .await
+                        .unwrap_or_default();
+                    if size > self.git_max_size {
+                        return Err(format!(
+                            "repository is larger than {} bytes",
+                            self.git_max_size
+                        ));
+                    }
+                }
+            }
+        };
+        if !status.success() {
+            let mut error = String::new();
+            if let Some(stderr) = child.stderr.take() {
+                let _ = stderr
+                    .take(MAX_GIT_ERROR_SIZE)
+                    .read_to_string(&mut error)
+                    .await;
+            }
+            return Err(format!("failed to clone {}: {}", url, error.trim()));
+        }
+        Ok(())
+    }
+}
+
+// bytes of the regular files under `path`, symlinks aren't followed
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn count<T>(direction: &'static str, result: &Result<T, String>) {
+    let outcome = if result.is_ok() { "ok" } else { "failed" };
+    counter!("url_transfers_total", "direction" => direction, "outcome" => outcome).increment(1);
+}
+
+// "*.example.com" allows every subdomain of example.com, other entries a
+// single host
+pub fn host_matches(allowed: &str, host: &str) -> bool {
+    let allowed = allowed.to_ascii_lowercase();
+    match allowed.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => allowed == host,
+    }
+}
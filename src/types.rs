@@ -1,30 +1,648 @@
 use metrics_exporter_prometheus::PrometheusHandle;
 use redis::aio::MultiplexedConnection;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+use crate::auth::JwtVerifier;
+use crate::client_ip::TrustedProxies;
+use crate::connections::ConnectionLimiter;
+use crate::encryption::Encryption;
+use crate::file_cache::FileCache;
+use crate::files::MemoryFileManager;
+use crate::images::ImageManager;
+use crate::runner::EventListener;
+use crate::sandboxes::SandboxRegistry;
+use crate::stats::Stats;
+use crate::system_monitor::SystemStatus;
+use crate::transfers::UrlTransfers;
+use crate::usage::UsageMeter;
+use crate::webhooks::WebhookSender;
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct AppConfig {
-    pub redis_url: String,
+    #[serde(default)]
+    pub redis_url: Option<String>, // unset runs without redis, which needs file_store = "memory"
+    #[serde(default = "default_log_level")]
+    pub log_level: String, // error, warn, info, debug or trace
+    #[serde(default)]
+    pub redis_key_prefix: String, // namespaces every redis key, so deployments can share an instance
+    #[serde(default)]
+    pub file_store: FileStore, // where remote files are kept
+    #[serde(default = "default_memory_file_store_size")]
+    pub memory_file_store_size: u64, // in bytes, memory files past it are refused until others expire
+    #[serde(default)]
+    pub redis_username: Option<String>, // acl user, overrides one in redis_url
+    #[serde(default)]
+    pub redis_password: Option<String>, // overrides one in redis_url
+    #[serde(default)]
+    pub redis_ca_cert: Option<String>, // pem bundle the server certificate of a rediss:// url is verified against, unset uses the system roots
+    #[serde(default)]
+    pub redis_client_cert: Option<String>, // pem certificate chain for redis servers requiring mutual tls
+    #[serde(default)]
+    pub redis_client_key: Option<String>, // pem private key of redis_client_cert
     pub base_code_path: String,
     pub port: u16,
+    #[serde(default = "default_time_limit")]
+    pub default_time_limit: u64, // in seconds, for executions that don't set one
+    #[serde(default = "default_wall_time_limit")]
+    pub default_wall_time_limit: u64, // in seconds
+    #[serde(default = "default_memory_limit")]
+    pub default_memory_limit: u64, // in bytes, like an execution's memory_limit
+    #[serde(default)]
+    pub max_time_limit: Option<u64>, // in seconds, higher requested limits are clamped to it
+    #[serde(default)]
+    pub max_wall_time_limit: Option<u64>, // in seconds
+    #[serde(default)]
+    pub max_memory_limit: Option<u64>, // in bytes
+    #[serde(default)]
+    pub reject_over_limits: bool, // fail executions asking for more than a maximum instead of clamping
+    #[serde(default)]
+    pub max_batch_wall_time: Option<u64>, // in seconds, for all executions of a request together, the rest are skipped
+    #[serde(default = "default_max_return_file_size")]
+    pub max_return_file_size: u64, // in bytes, per returned file
+    #[serde(default = "default_max_response_size")]
+    pub max_response_size: u64, // in bytes, all returned files of one result
+    #[serde(default)]
+    pub min_niceness: i32, // highest priority (lowest nice value) clients may request
+    #[serde(default)]
+    pub allow_seccomp_audit: bool, // let executions request seccomp audit mode
+    #[serde(default)]
+    pub allow_debug: bool, // let executions request sandbox diagnostics in their errors
+    #[serde(default)]
+    pub sandbox_init: bool, // run programs under a reaping init, this binary bind-mounted as pid 1
+    #[serde(default)]
+    pub allow_perf: bool, // let executions request hardware performance counters
+    #[serde(default)]
+    pub allow_trace: bool, // let executions request a syscall trace of the program
+    #[serde(default = "default_strace_path")]
+    pub strace_path: String, // strace binary as seen inside the sandbox, used for traces
+    #[serde(default = "default_max_trace_size")]
+    pub max_trace_size: u64, // in bytes, a returned syscall trace is cut off past this
+    #[serde(default = "default_true")]
+    pub landlock: bool, // restrict filesystem access with landlock
+    #[serde(default = "default_landlock_paths")]
+    pub landlock_paths: BTreeMap<String, String>, // path -> access ("r", "w", "x" combined)
+    #[serde(default = "default_true")]
+    pub no_new_privs: bool, // set no_new_privs on sandboxed processes
+    #[serde(default)]
+    pub drop_capabilities: bool, // clear bounding, inheritable and ambient capabilities via setpriv
+    #[serde(default = "default_setpriv_path")]
+    pub setpriv_path: String, // setpriv binary as seen inside the sandbox
+    #[serde(default)]
+    pub uid_mappings: Vec<IdMapping>, // user namespace uid ranges, empty maps the service user to itself
+    #[serde(default)]
+    pub gid_mappings: Vec<IdMapping>, // user namespace gid ranges, empty maps the service group to itself
+    #[serde(default = "default_dev_devices")]
+    pub dev_devices: Vec<String>, // host devices bind-mounted into a private /dev, empty leaves /dev out
+    #[serde(default)]
+    pub proc_read_only: bool, // mount the sandbox /proc read-only
+    #[serde(default)]
+    pub env_passthrough: Vec<String>, // host environment variables programs are spawned with, over the default PATH
+    #[serde(default)]
+    pub dns_nameservers: Vec<String>, // nameservers in the generated resolv.conf of networked sandboxes
+    #[serde(default)]
+    pub dns_search: Vec<String>, // search domains in the generated resolv.conf
+    #[serde(default = "default_shell_path")]
+    pub shell_path: String, // posix shell as seen inside the sandbox
+    #[serde(default = "default_box_path")]
+    pub box_path: String, // where the sandbox directory is mounted inside the sandbox
+    #[serde(default = "default_nft_path")]
+    pub nft_path: String, // nft binary as seen inside the sandbox, used for egress filtering
+    #[serde(default = "default_tc_path")]
+    pub tc_path: String, // tc binary as seen inside the sandbox, used for bandwidth limits
+    #[serde(default)]
+    pub allow_gpu: bool, // let executions request gpu passthrough
+    #[serde(default = "default_gpu_devices")]
+    pub gpu_devices: Vec<String>, // host device patterns bind-mounted for gpu executions
+    #[serde(default)]
+    pub gpu_library_paths: Vec<String>, // driver libraries bind-mounted read-only for gpu executions
+    #[serde(default = "default_hostname")]
+    pub hostname: String, // sandbox hostname, "{id}" is replaced with the sandbox id
+    #[serde(default)]
+    pub rootfs: BTreeMap<String, String>, // name -> host directory executions can use as their root filesystem
+    #[serde(default)]
+    pub images: BTreeMap<String, ImageSource>, // name -> oci image pulled on first use, for the same purpose
+    #[serde(default = "default_image_dir")]
+    pub image_dir: String, // where pulled images are unpacked
+    #[serde(default = "default_image_disk_budget")]
+    pub image_disk_budget: u64, // in bytes, least recently used images are evicted beyond it
+    #[serde(default)]
+    pub profiles: BTreeMap<String, SandboxProfile>, // named per-execution sandbox overrides
+    #[serde(default)]
+    pub templates: BTreeMap<String, ExecutionTemplate>, // named requests clients can submit with params
+    #[serde(default)]
+    pub languages: BTreeMap<String, Language>, // presets for requests that only submit a source file
+    #[serde(default)]
+    pub tenants: BTreeMap<String, Tenant>, // api key holders, empty leaves the service open
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size: u64, // in bytes, json request bodies including inline files
+    #[serde(default = "default_max_archive_size")]
+    pub max_archive_size: u64, // in bytes, unpacked size of an archive input file
+    #[serde(default = "default_max_archive_entries")]
+    pub max_archive_entries: usize, // files an archive input file may hold
+    #[serde(default)]
+    pub file_cache_dir: Option<String>, // where remote files are cached across requests, unset disables the cache
+    #[serde(default = "default_file_cache_budget")]
+    pub file_cache_budget: u64, // in bytes, least recently used files are evicted beyond it
+    #[serde(default = "default_file_cache_max_file_size")]
+    pub file_cache_max_file_size: u64, // in bytes, larger remote files are never cached
+    #[serde(default = "default_result_cache_ttl_secs")]
+    pub result_cache_ttl_secs: u64, // how long results of executions with cache set are reused
+    #[serde(default = "default_staging_concurrency")]
+    pub staging_concurrency: usize, // remote files fetched at once while staging a request
+    #[serde(default = "default_max_problem_size")]
+    pub max_problem_size: u64, // in bytes, a problem archive both compressed and unpacked
+    #[serde(default = "default_infrastructure_retries")]
+    pub infrastructure_retries: u32, // extra attempts after spawn, wait or storage failures
+    #[serde(default = "default_job_ttl_secs")]
+    pub job_ttl_secs: u64, // how long finished jobs, their results and dead letters are kept
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub idempotency_ttl_secs: u64, // how long results are kept for Idempotency-Key retries
+    #[serde(default = "default_ws_ping_interval_secs")]
+    pub ws_ping_interval_secs: u64, // websocket pings, a peer missing one pong is dropped
+    #[serde(default = "default_ws_idle_timeout_secs")]
+    pub ws_idle_timeout_secs: u64, // websocket connections without a request this long are closed
+    #[serde(default = "default_ws_max_connections_per_client")]
+    pub ws_max_connections_per_client: usize, // open websocket connections per api key, or per address without one
+    #[serde(default)]
+    pub admin_token: Option<String>, // bearer token for /admin routes, unset disables them
+    #[serde(default = "default_host")]
+    pub host: String, // address to listen on
+    #[serde(default = "default_compressed_routes")]
+    pub compressed_routes: Vec<String>, // routes whose responses are compressed for clients accepting it
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>, // accept bearer tokens of an identity provider besides api keys
+    #[serde(default)]
+    pub signing_keys: BTreeMap<String, String>, // key id -> hmac secret, requests must be signed with one when set
+    #[serde(default = "default_signature_max_age_secs")]
+    pub signature_max_age_secs: u64, // how far a signature's timestamp may be from the server's clock
+    #[serde(default)]
+    pub tls: Option<TlsConfig>, // serve https instead of http
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>, // addresses or cidr ranges whose forwarding headers name the client
+    #[serde(default)]
+    pub min_free_disk_bytes: Option<u64>, // new work is rejected while base_code_path's filesystem has less free
+    #[serde(default)]
+    pub load_shedding: Option<LoadShedding>, // hold back or reject new work while the host is busy
+    #[serde(default)]
+    pub mode: Mode, // standalone runs requests itself, coordinators hand /execute requests and jobs to runners
+    #[serde(default = "default_runner_concurrency")]
+    pub runner_concurrency: usize, // requests a runner works on at once
+    #[serde(default = "default_runner_timeout_secs")]
+    pub runner_timeout_secs: u64, // coordinators give up on a request after this long without an event from a runner
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32, // deliveries of a job's webhook before it is given up
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub webhook_timeout_secs: u64, // per delivery attempt
+    #[serde(default)]
+    pub webhook_allowed_hosts: Vec<String>, // hosts webhooks may point at, "*.example.com" for subdomains, empty disables them
+    #[serde(default)]
+    pub url_allowed_hosts: Vec<String>, // hosts `url` files may point at, "*.example.com" for subdomains, empty disables them
+    #[serde(default = "default_url_allowed_schemes")]
+    pub url_allowed_schemes: Vec<String>,
+    #[serde(default = "default_url_max_download_size")]
+    pub url_max_download_size: u64, // largest file copy_in fetches from a `url`
+    #[serde(default = "default_url_transfer_timeout_secs")]
+    pub url_transfer_timeout_secs: u64, // per upload to or download from a `url`
+    #[serde(default = "default_git_path")]
+    pub git_path: String, // git binary on the host, used for `git` copy_in sources
+    #[serde(default = "default_git_max_size")]
+    pub git_max_size: u64, // clones growing past this many bytes are stopped
+    #[serde(default = "default_git_timeout_secs")]
+    pub git_timeout_secs: u64,
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>, // encrypt remote files written to redis, unset stores them as they are
+    #[serde(default)]
+    pub usage_sink: Option<UsageSink>, // where per-batch usage records are written for billing, unset disables them
+    #[serde(default)]
+    pub histogram_buckets: BTreeMap<String, Vec<f64>>, // metric name -> bucket upper bounds, others are exported as summaries
+    #[serde(default)]
+    pub metric_labels: Vec<String>, // execution labels added to the per-execution metrics, empty when an execution lacks one
+    #[serde(default)]
+    pub metrics_token: Option<String>, // required by /metrics as a bearer token or basic auth password, unset leaves it open
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Tenant {
+    pub api_key: String, // sent in the x-api-key header
+    #[serde(default)]
+    pub daily_cpu_seconds: Option<u64>,
+    #[serde(default)]
+    pub daily_executions: Option<u64>,
+    #[serde(default)]
+    pub daily_bytes_stored: Option<u64>, // bytes written to remote storage by copy_out
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct JwtConfig {
+    #[serde(default)]
+    pub secret: Option<String>, // shared secret of HS256/384/512 signed tokens
+    #[serde(default)]
+    pub jwks_url: Option<String>, // key set of the identity provider, for asymmetrically signed tokens
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    #[serde(default)]
+    pub issuer: Option<String>, // required iss claim
+    #[serde(default)]
+    pub audience: Option<String>, // required aud claim
+    #[serde(default = "default_tenant_claim")]
+    pub tenant_claim: String, // claim naming the tenant whose quotas apply
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert: String, // pem certificate chain
+    pub key: String,  // pem private key
+    #[serde(default)]
+    pub client_ca: Option<String>, // pem bundle client certificates are verified against, unset disables mutual tls
+    #[serde(default = "default_true")]
+    pub require_client_certificate: bool, // refuse clients without one, else they fall back to api keys and tokens
+    #[serde(default)]
+    pub clients: BTreeMap<String, TlsClient>, // certificate common name -> what it may do, empty authorizes none by certificate
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    pub key_id: String, // key new files are encrypted with, the others only decrypt older files
+    pub keys: BTreeMap<String, EncryptionKey>,
+}
+
+// a 32 byte master key, base64 encoded
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct EncryptionKey {
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub command: Option<String>, // prints the key instead, e.g. a kms decrypt of a wrapped key, run once at startup
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Standalone,
+    Coordinator, // serves the api, queues /execute requests and jobs in redis for runners
+    Runner,      // serves the api too, and runs requests queued by coordinators
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStore {
+    #[default]
+    Redis,
+    Memory, // in this process, lost when it exits. files expire like redis ones and are capped by memory_file_store_size
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct LoadShedding {
+    #[serde(default)]
+    pub max_cpu_percent: Option<u32>, // host cpu usage over all cores
+    #[serde(default)]
+    pub max_load_percent: Option<u32>, // one minute load average per cpu, 100 is one runnable task per cpu
+    #[serde(default)]
+    pub queue_timeout_secs: u64, // how long new work waits for the host to calm down, 0 rejects it right away
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct TlsClient {
+    #[serde(default)]
+    pub tenant: Option<String>, // tenant whose quotas apply
+    #[serde(default = "default_client_scopes")]
+    pub scopes: Vec<String>, // same names as jwt scopes
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum UsageSink {
+    Redis {
+        stream: String,
+        #[serde(default = "default_usage_stream_max_len")]
+        max_len: u64, // older records are trimmed beyond it, approximately
+    },
+    File {
+        path: String, // json lines, appended to
+    },
+    Http {
+        url: String, // each record is POSTed as json
+    },
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct IdMapping {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32,
+}
+
+impl IdMapping {
+    pub fn as_tuple(&self) -> (u32, u32, u32) {
+        (self.container_id, self.host_id, self.size)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct ImageSource {
+    pub reference: String, // e.g. "docker.io/library/python:3.12-slim"
+    pub digest: String,    // "sha256:...", of the manifest or multi-platform index
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct SandboxProfile {
+    #[serde(default)]
+    pub landlock_paths: BTreeMap<String, String>, // added to (or overriding) the global rules
+    #[serde(default)]
+    pub keep_capabilities: Vec<String>, // capabilities kept when drop_capabilities is on, e.g. "net_bind_service"
+    #[serde(default)]
+    pub allow_new_privs: bool, // exempt this profile from no_new_privs
+    #[serde(default)]
+    pub network: bool, // give executions network access through pasta
+    #[serde(default)]
+    pub egress_policy: Vec<String>, // domains ("*.example.com" allowed) and CIDRs executions may reach, empty is unrestricted
+    #[serde(default)]
+    pub bandwidth_kbit: Option<u64>, // per-direction rate limit of networked executions
+    #[serde(default)]
+    pub max_connections: Option<u32>, // cap on tracked connections in the sandbox network namespace
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_setpriv_path() -> String {
+    "/usr/bin/setpriv".to_string()
+}
+
+fn default_strace_path() -> String {
+    "/usr/bin/strace".to_string()
+}
+
+fn default_max_trace_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_shell_path() -> String {
+    "/bin/sh".to_string()
+}
+
+fn default_box_path() -> String {
+    "/box".to_string()
+}
+
+fn default_nft_path() -> String {
+    "/usr/sbin/nft".to_string()
+}
+
+fn default_tc_path() -> String {
+    "/usr/sbin/tc".to_string()
+}
+
+fn default_gpu_devices() -> Vec<String> {
+    vec!["/dev/nvidia*".to_string()]
+}
+
+fn default_hostname() -> String {
+    "sandbox".to_string()
+}
+
+fn default_dev_devices() -> Vec<String> {
+    ["null", "zero", "urandom", "tty"]
+        .iter()
+        .map(|dev| dev.to_string())
+        .collect()
+}
+
+fn default_landlock_paths() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("/bin".to_string(), "rx".to_string()),
+        ("/lib".to_string(), "rx".to_string()),
+        ("/usr".to_string(), "rx".to_string()),
+    ])
+}
+
+fn default_time_limit() -> u64 {
+    5
+}
+
+fn default_wall_time_limit() -> u64 {
+    10
+}
+
+fn default_memory_limit() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_image_dir() -> String {
+    "/var/lib/pentagon/images".to_string()
+}
+
+fn default_image_disk_budget() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_max_request_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_max_archive_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_max_archive_entries() -> usize {
+    10000
+}
+
+fn default_max_problem_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_memory_file_store_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_file_cache_budget() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_file_cache_max_file_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_result_cache_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_staging_concurrency() -> usize {
+    16
+}
+
+fn default_infrastructure_retries() -> u32 {
+    2
+}
+
+fn default_job_ttl_secs() -> u64 {
+    60 * 60 * 24 * 7
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    60 * 60 * 24
+}
+
+fn default_compressed_routes() -> Vec<String> {
+    ["/execute", "/jobs/{id}", "/jobs/{id}/results", "/judge", "/metrics"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_ws_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_signature_max_age_secs() -> u64 {
+    300
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    8
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    10
+}
+
+fn default_url_allowed_schemes() -> Vec<String> {
+    vec!["https".to_string()]
+}
+
+fn default_url_max_download_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_url_transfer_timeout_secs() -> u64 {
+    300
+}
+
+fn default_git_path() -> String {
+    "git".to_string()
+}
+
+fn default_git_max_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_git_timeout_secs() -> u64 {
+    120
+}
+
+fn default_runner_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn default_runner_timeout_secs() -> u64 {
+    600
+}
+
+fn default_usage_stream_max_len() -> u64 {
+    1_000_000
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_client_scopes() -> Vec<String> {
+    vec!["execute".to_string(), "files:write".to_string()]
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_tenant_claim() -> String {
+    "tenant".to_string()
+}
+
+fn default_ws_max_connections_per_client() -> usize {
+    16
+}
+
+fn default_ws_idle_timeout_secs() -> u64 {
+    5 * 60
+}
+
+fn default_max_return_file_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_response_size() -> u64 {
+    256 * 1024 * 1024
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub redis_connection: MultiplexedConnection,
+    pub redis_connection: Option<MultiplexedConnection>, // none without redis_url
     pub base_code_path: String,
     pub prometheus_handle: PrometheusHandle,
+    pub config: Arc<AppConfig>,
+    pub sandboxes: Arc<SandboxRegistry>,
+    pub stats: Arc<Stats>,
+    pub images: Arc<ImageManager>,
+    pub file_cache: Arc<FileCache>,
+    pub trusted_proxies: Arc<TrustedProxies>,
+    pub jwt: Option<Arc<JwtVerifier>>, // set when jwt is configured
+    pub ws_connections: Arc<ConnectionLimiter>,
+    pub usage: Option<Arc<UsageMeter>>, // set when usage_sink is configured
+    pub system: Arc<SystemStatus>,
+    pub webhooks: Arc<WebhookSender>,
+    pub runner_events: Arc<EventListener>, // started on coordinators
+    pub transfers: Arc<UrlTransfers>,
+    pub encryption: Option<Arc<Encryption>>, // set when encryption is configured
+    pub memory_files: Option<MemoryFileManager>, // holds remote files instead of redis when set
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl AppState {
+    // the connection for what needs redis, which fails without redis_url
+    pub fn redis(&self) -> Result<MultiplexedConnection, String> {
+        self.redis_connection
+            .clone()
+            .ok_or_else(|| "this needs redis, which is not configured (see redis_url)".to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum File {
-    Local { name: String, content: Vec<u8> },
-    Remote { name: String, id: String },
+    Local {
+        name: String,
+        content: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>, // hex digest the content is checked against
+    },
+    Remote {
+        name: String,
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>, // checked once the file is fetched
+    },
+    Archive {
+        #[serde(default)]
+        name: String, // directory in /box to extract into, empty for /box itself
+        #[serde(default)]
+        content: Option<Vec<u8>>, // zip, tar.gz or tar bytes
+        #[serde(default)]
+        id: Option<String>, // remote file holding the archive, instead of content
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>, // of the archive itself, not what it holds
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum FilePath {
@@ -39,40 +657,253 @@ pub enum FilePath {
     },
     Stdin {},
     Tmp { id: u64 },
+    Dir { name: String }, // a directory in /box, moved as a tar archive
+    Url { url: String },  // url on an allowed host, like a presigned object storage url
+    Git {
+        url: String,         // repository on an allowed host
+        rev: Option<String>, // branch or tag, the default branch when unset
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ExecutionTransfer {
     pub from: FilePath,
     pub to: FilePath,
+    #[serde(default)]
+    pub optional: bool, // skip instead of failing when the source is missing (copy_out only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>, // hex digest the source is checked against (copy_in only)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Execution {
-    pub program: String,                  // path to executable
+    pub program: String,                  // path to executable, or a shell script with shell
+    #[serde(default)]
     pub args: Vec<String>,                // command line arguments
-    pub time_limit: u64,                  // in seconds
-    pub wall_time_limit: u64,             // in seconds
-    pub memory_limit: u64,                // in kilobytes
+    #[serde(default)]
+    pub shell: bool,                      // run program as a script of shell_path, args appended quoted
+    #[serde(default)]
+    pub workdir: Option<String>,          // directory of the sandbox the program runs in, relative to box_path
+    #[serde(default)]
+    pub time_limit: Option<u64>,          // in seconds, default_time_limit when unset
+    #[serde(default)]
+    pub wall_time_limit: Option<u64>,     // in seconds, default_wall_time_limit when unset
+    #[serde(default)]
+    pub memory_limit: Option<u64>,        // in bytes of address space, default_memory_limit when unset
+    #[serde(default)]
     pub copy_out: Vec<ExecutionTransfer>, // list of file names to copy out
+    #[serde(default)]
     pub copy_in: Vec<ExecutionTransfer>,  // list of files to copy in
+    #[serde(default)]
     pub return_files: Vec<FilePath>,      // list of files to return
+    #[serde(default)]
     pub die_on_error: bool,               // whether to stop execution on first error
-    pub autofix: Option<bool>             // whether to fix stdout/stderr truncation automatically, i.e add new line if not present, trim trailing spaces
+    #[serde(default)]
+    pub autofix: Option<bool>,            // whether to fix stdout/stderr truncation automatically, i.e add new line if not present, trim trailing spaces
+    #[serde(default)]
+    pub niceness: Option<i32>,            // scheduling priority, clamped to [min_niceness, 19]
+    #[serde(default)]
+    pub seccomp_audit: bool,              // log banned syscalls instead of denying them and report them
+    #[serde(default)]
+    pub profile: Option<String>,          // name of a sandbox profile from the server config
+    #[serde(default)]
+    pub egress_allow: Option<Vec<String>>, // domains and CIDRs the program may reach, within the profile's egress_policy
+    #[serde(default)]
+    pub gpu: bool, // bind-mount the server's gpu devices and driver libraries
+    #[serde(default)]
+    pub score_from: Option<FilePath>, // stdout or a local file whose first token is reported as the score
+    #[serde(default)]
+    pub rootfs: Option<String>, // name of a root filesystem from the server config, the host's / when unset
+    #[serde(default)]
+    pub cache: bool, // reuse the result of an earlier run with the same inputs, never with copy_out
+    #[serde(default)]
+    pub debug: bool, // errors carry the sandbox's diagnostics, needs allow_debug
+    #[serde(default)]
+    pub trace: bool, // run the program under strace and return the trace, needs allow_trace
+    #[serde(default)]
+    pub perf: bool, // count instructions, cycles and cache misses, needs allow_perf
+    #[serde(default)]
+    pub return_by_reference: bool, // store return_files other than stdout and stderr as remote files and return references
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>, // echoed in the result, over the request's labels of the same name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // the client's name for the execution, echoed in its result or error
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ExecutionFile {
     pub name: String,
     pub content: Vec<u8>,
+    #[serde(default)]
+    pub truncated: bool, // content was cut to the server's return size limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<RemoteFile>, // where the file was stored instead, content is empty then
+}
+
+// a returned file stored as a remote file, for clients to fetch when they need it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteFile {
+    pub id: String,
+    pub size: u64,
+    pub sha256: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ExecutionResult {
     pub exit_code: i32,
+    #[serde(default)]
+    pub status: ExitStatus, // how the program ended, exit_code alone can't tell a limit from a crash
+    #[serde(default)]
+    pub reaped_processes: Option<u64>, // processes the sandbox init reaped besides the program, none without it
+    #[serde(default)]
+    pub limit_exceeded: Option<LimitExceeded>, // the limit behind a limit status and the usage against it
     pub time_used: u128,                  // in milliseconds
+    #[serde(default)]
+    pub wall_time_used: u128,             // in milliseconds, from spawn to exit
     pub memory_used: u64,                 // in kilobytes
+    #[serde(default)]
+    pub bytes_read: Option<u64>, // read from storage by the program and its children, needs sandbox_init
+    #[serde(default)]
+    pub bytes_written: Option<u64>, // written to storage by the program and its children, needs sandbox_init
+    #[serde(default)]
+    pub resource: Option<ResourceUsage>, // getrusage counts of the program and its children
     pub return_files: Vec<ExecutionFile>, // list of returned files
+    pub skipped_copy_out: Vec<String>,    // optional copy_out sources that were missing
+    pub syscall_violations: Option<Vec<SyscallViolation>>, // banned syscalls attempted, audit mode only
+    pub score: Option<f64>, // read from score_from, e.g. a checker's partial credit
+    #[serde(default)]
+    pub clamped_limits: Vec<String>, // requested limits lowered to the server's maxima
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compile: bool, // result of the request's compile step rather than a run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool, // reused from an earlier run with the same inputs
+    #[serde(default)]
+    pub perf: Option<PerfCounters>, // hardware counters, perf mode only
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>, // the execution's labels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // the execution's id
+}
+
+// in milliseconds for the time limits, kilobytes for the memory limit. the
+// memory limit counts as exceeded a little below it, so used may be lower
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub limit: u128,
+    pub used: u128,
+}
+
+// user space events of the program and its children, null when the cpu
+// or the host doesn't count them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PerfCounters {
+    pub instructions: Option<u64>,
+    pub cycles: Option<u64>,
+    pub cache_misses: Option<u64>,
+}
+
+// getrusage(2) of the processes of a sandbox, summed over all of them but
+// max_rss, which is the largest one's
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResourceUsage {
+    pub max_rss: u64,                      // in kilobytes
+    pub minor_page_faults: u64,            // served without reading from storage
+    pub major_page_faults: u64,            // needed reading from storage
+    pub voluntary_context_switches: u64,   // gave up the cpu, e.g. to wait for input
+    pub involuntary_context_switches: u64, // preempted
+    pub block_reads: u64,                  // read from storage, in 512 byte blocks
+    pub block_writes: u64,                 // written to storage, in 512 byte blocks
+}
+
+// sent as the last SSE event of a request
+#[derive(Serialize, Debug, Default)]
+pub struct ExecutionSummary {
+    pub executions: u64,        // requested, the compile step included
+    pub succeeded: u64,         // exited 0
+    pub failed: u64,            // exited non-zero
+    pub errors: u64,            // never produced a result
+    pub skipped: u64,           // never ran, after die_on_error or a failed compile step
+    pub time_used: u128,        // in milliseconds, summed over all results
+    pub wall_time_used: u128,   // in milliseconds, summed over all results
+    pub files_returned: usize,  // return_files over all results
+}
+
+// where a request that asked for progress is, sent before each step
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Progress {
+    pub phase: ProgressPhase,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>, // of the execution that started, in the request's executions
+    pub completed: u64, // executions finished, the compile step included
+    pub total: u64,     // executions requested, the compile step included
+    pub percent: u64,   // completed of total, rounded down
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    FilesStaged,
+    CompileStarted,
+    ExecutionStarted,
+}
+
+impl Progress {
+    pub fn new(phase: ProgressPhase, index: Option<usize>, completed: u64, total: u64) -> Self {
+        Self {
+            phase,
+            index,
+            completed,
+            total,
+            percent: (completed * 100).checked_div(total).unwrap_or(100),
+        }
+    }
+}
+
+// what a running request reports, in order
+#[derive(Debug)]
+pub enum RunEvent {
+    Progress(Progress),
+    Outcome(Box<Result<ExecutionResult, OutcomeError>>), // a result, or an error in place of one
+}
+
+impl RunEvent {
+    pub fn outcome(data: Result<ExecutionResult, OutcomeError>) -> Self {
+        RunEvent::Outcome(Box::new(data))
+    }
+
+    // an error that belongs to no execution in particular
+    pub fn error(message: String) -> Self {
+        Self::outcome(Err(OutcomeError::from(message)))
+    }
+}
+
+// an error event, with the id of the execution it took the place of
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutcomeError {
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<serde_json::Value>, // the sandbox as it failed, for debug executions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>, // of every attempt, when the host failed it after all its retries
+}
+
+impl From<String> for OutcomeError {
+    fn from(error: String) -> Self {
+        Self {
+            error,
+            id: None,
+            diagnostics: None,
+            errors: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyscallViolation {
+    pub syscall: String,
+    pub count: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -80,10 +911,159 @@ pub struct ExecutionError {
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExecutionRequest {
+    #[serde(default)]
     pub executions: Vec<Execution>,
+    #[serde(default)]
     pub files: Vec<File>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>, // name of a server-side template supplying the executions
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>, // values for the template's "{param}" placeholders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>, // language preset to run the submitted file with, "auto" detects it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compile: Option<CompileStep>, // runs before the executions, which all get its artifact
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub progress: bool, // report progress events between the results
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>, // the client's own, e.g. its submission id, given to every execution
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detach: bool, // keep running when the client disconnects, with the results kept as a job
+}
+
+impl ExecutionRequest {
+    // what the request is charged against the tenant's quota
+    pub fn execution_count(&self) -> u64 {
+        self.executions.len() as u64 + self.compile.is_some() as u64
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompileStep {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub time_limit: Option<u64>,      // in seconds, server defaults apply like for executions
+    #[serde(default)]
+    pub wall_time_limit: Option<u64>, // in seconds
+    #[serde(default)]
+    pub memory_limit: Option<u64>,    // in bytes
+    pub artifact: String, // file the compiler writes in /box, restored before every execution
+    #[serde(default)]
+    pub profile: Option<String>, // name of a sandbox profile from the server config
+    #[serde(default)]
+    pub rootfs: Option<String>, // name of a root filesystem from the server config
+    #[serde(default)]
+    pub id: Option<String>, // echoed in the compile result or error, like an execution's
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Language {
+    pub template: String, // run with the source file in the {file} and {stem} params
+    #[serde(default)]
+    pub extensions: Vec<String>, // file extensions without the dot, e.g. "py"
+    #[serde(default)]
+    pub interpreters: Vec<String>, // shebang interpreters, e.g. "python3"
+}
+
+// a request stored in the server config, see ExecutionRequest::template
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct ExecutionTemplate {
+    pub executions: Vec<Execution>,
+    #[serde(default)]
+    pub files: Vec<File>,
+    #[serde(default)]
+    pub compile: Option<CompileStep>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobSubmission {
+    #[serde(default)]
+    pub run_at: Option<u64>, // unix seconds, runs as soon as possible when absent
+    #[serde(default)]
+    pub webhook: Option<String>, // url the finished job is POSTed to
+    #[serde(flatten)]
+    pub request: ExecutionRequest,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JudgeRequest {
+    pub problem: String, // id of an imported problem
+    #[serde(default)]
+    pub files: Vec<File>, // written to /box once, before the first testcase
+    pub execution: Execution, // run per testcase with its input on stdin, limits come from the problem
+    #[serde(default)]
+    pub stop_on_failure: bool, // skip the remaining testcases after the first one that doesn't pass
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitStatus {
+    #[default]
+    Success, // exited 0
+    NonzeroExit,
+    Signaled,              // killed by a signal none of the limits sent, e.g. a segfault
+    TimeLimitExceeded,     // ran out of cpu time
+    WallTimeLimitExceeded, // killed at the wall time limit with cpu time to spare
+    MemoryLimitExceeded,   // failed near the memory limit, which makes allocations fail
+}
+
+impl ExitStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "success",
+            ExitStatus::NonzeroExit => "nonzero_exit",
+            ExitStatus::Signaled => "signaled",
+            ExitStatus::TimeLimitExceeded => "time_limit_exceeded",
+            ExitStatus::WallTimeLimitExceeded => "wall_time_limit_exceeded",
+            ExitStatus::MemoryLimitExceeded => "memory_limit_exceeded",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Accepted,
+    PartiallyAccepted, // the checker awarded part of the testcase's points
+    WrongAnswer,
+    TimeLimitExceeded,
+    RuntimeError,
+    Error, // the testcase could not be judged
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub verdict: Verdict,
+    pub exit_code: Option<i32>,
+    pub time_used: u128,         // in milliseconds
+    pub memory_used: u64,        // in kilobytes
+    pub score: f64,              // points earned, out of the testcase's points
+    pub points: f64,
+    pub message: Option<String>, // why the testcase could not be judged
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JudgeResult {
+    pub verdict: Verdict, // of the first testcase that didn't pass, accepted otherwise
+    pub passed: usize,
+    pub total: usize,
+    pub time_used: u128,  // slowest testcase, in milliseconds
+    pub memory_used: u64, // largest testcase, in kilobytes
+    pub score: f64,       // points earned over all testcases
+    pub max_score: f64,   // points of all testcases, including skipped ones
+    pub tests: Vec<TestResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CronSubmission {
+    pub schedule: String, // five field cron expression in UTC, or @hourly, @daily, ...
+    #[serde(flatten)]
+    pub request: ExecutionRequest,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -93,9 +1073,11 @@ pub enum ExecutionMessage {
     Batch {
         id: String,
         executions: Vec<Execution>,
+        #[serde(default)]
+        progress: bool, // report progress messages between the results
     },
     Single {
         id: String,
-        execution: Execution,
+        execution: Box<Execution>,
     },
 }
@@ -0,0 +1,171 @@
+use axum::body::Bytes;
+use axum::http::{Request, header::CONTENT_TYPE};
+use http_body_util::Full;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use hyper_util::rt::TokioExecutor;
+use metrics::counter;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+use crate::types::{AppState, ExecutionResult, UsageSink};
+
+// what a batch consumed, summed over its executions
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Usage {
+    pub executions: u64,
+    pub cpu_ms: u64,
+    pub wall_ms: u64,
+    pub memory_kb_seconds: f64, // peak memory of each execution times its wall time
+    pub bytes_stored: u64,      // written to remote storage by copy_out
+    pub bytes_transferred: u64, // returned file contents sent back to the client
+}
+
+impl Usage {
+    // executions that never produced a result still count, like for quotas
+    pub fn add(&mut self, result: Option<&ExecutionResult>, bytes_stored: u64) {
+        self.executions += 1;
+        self.bytes_stored += bytes_stored;
+        let Some(result) = result else {
+            return;
+        };
+        self.cpu_ms += result.time_used as u64;
+        self.wall_ms += result.wall_time_used as u64;
+        self.memory_kb_seconds += result.memory_used as f64 * result.wall_time_used as f64 / 1000.0;
+        self.bytes_transferred += result
+            .return_files
+            .iter()
+            .map(|file| file.content.len() as u64)
+            .sum::<u64>();
+    }
+}
+
+#[derive(Serialize)]
+struct UsageRecord<'a> {
+    batch: &'a str, // sandbox id of the request or websocket connection
+    tenant: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: &'a BTreeMap<String, String>, // of the request, none for websocket batches
+    timestamp: u64, // unix seconds the batch finished
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+// writes a record per finished batch to the configured sink, for billing
+pub struct UsageMeter {
+    sink: UsageSink,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl UsageMeter {
+    pub fn new(sink: &UsageSink) -> Result<Self, String> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|e| format!("failed to load root certificates: {}", e))?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            sink: sink.clone(),
+            client: Client::builder(TokioExecutor::new()).build(connector),
+        })
+    }
+
+    async fn write(&self, state: &AppState, record: &UsageRecord<'_>) -> Result<(), String> {
+        match &self.sink {
+            UsageSink::Redis { stream, max_len } => {
+                // one stream field per value, so consumers don't have to parse json
+                let Ok(Value::Object(fields)) = serde_json::to_value(record) else {
+                    unreachable!()
+                };
+                let mut command = redis::cmd("XADD");
+                command
+                    .arg(stream)
+                    .arg("MAXLEN")
+                    .arg("~")
+                    .arg(max_len)
+                    .arg("*");
+                for (name, value) in fields {
+                    match value {
+                        Value::Null => continue,
+                        Value::String(value) => command.arg(name).arg(value),
+                        value => command.arg(name).arg(value.to_string()),
+                    };
+                }
+                let mut connection = state.redis()?;
+                command
+                    .query_async::<String>(&mut connection)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| format!("failed to add to {}: {}", stream, e))
+            }
+            UsageSink::File { path } => {
+                // a single write per line, appends of concurrent batches don't interleave
+                let mut line = serde_json::to_vec(record).unwrap();
+                line.push(b'\n');
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|e| format!("failed to open {}: {}", path, e))?;
+                file.write_all(&line)
+                    .await
+                    .map_err(|e| format!("failed to write {}: {}", path, e))
+            }
+            UsageSink::Http { url } => {
+                let request = Request::post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Full::new(Bytes::from(serde_json::to_vec(record).unwrap())))
+                    .map_err(|e| format!("invalid usage url {}: {}", url, e))?;
+                let response = self
+                    .client
+                    .request(request)
+                    .await
+                    .map_err(|e| format!("failed to reach {}: {}", url, e))?;
+                if !response.status().is_success() {
+                    return Err(format!("{} answered {}", url, response.status()));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// records `usage` of the batch in the background, batches that ran nothing
+// aren't recorded
+pub fn emit(
+    state: &AppState,
+    batch: &str,
+    tenant: Option<&str>,
+    labels: &BTreeMap<String, String>,
+    usage: Usage,
+) {
+    let Some(meter) = state.usage.clone() else {
+        return;
+    };
+    if usage.executions == 0 {
+        return;
+    }
+    let (state, batch, tenant) = (state.clone(), batch.to_string(), tenant.map(str::to_string));
+    let labels = labels.clone();
+    tokio::spawn(async move {
+        let record = UsageRecord {
+            batch: &batch,
+            tenant: tenant.as_deref(),
+            labels: &labels,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            usage,
+        };
+        if let Err(e) = meter.write(&state, &record).await {
+            tracing::error!(batch, "failed to record usage: {}", e);
+            counter!("usage_records_failed_total").increment(1);
+        }
+    });
+}
@@ -13,6 +13,17 @@ pub fn gen_random_id(length: u32) -> String {
     id
 }
 
+pub fn is_glob_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+pub fn truncate(data: &[u8], max_size: Option<u64>) -> &[u8] {
+    match max_size {
+        Some(size) if data.len() > size as usize => &data[..size as usize],
+        _ => data,
+    }
+}
+
 pub fn autofix(input: Vec<u8>) -> Vec<u8> {
     if input.is_empty() {
         return Vec::new();
@@ -0,0 +1,359 @@
+use axum::body::Bytes;
+use axum::http::{Request, StatusCode, Uri, header::CONTENT_TYPE};
+use http_body_util::Full;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::{HttpConnector, dns::Name};
+use hyper_util::rt::TokioExecutor;
+use metrics::counter;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+use crate::redis_client;
+use crate::transfers::host_matches;
+use crate::types::AppConfig;
+use crate::utils::gen_random_id;
+
+// the wait before the second attempt, doubled for every attempt after it
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// how often deliveries that are due are looked for
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// due deliveries taken per poll, the rest wait for the next tick
+const BATCH_SIZE: isize = 16;
+
+// a claimed delivery is due again this long after its attempt should have
+// timed out, so one whose instance died mid-attempt is picked up again
+const CLAIM_MARGIN: Duration = Duration::from_secs(60);
+
+// records of deliveries outlive the longest wait between attempts
+const DELIVERY_TTL_SECS: u64 = 60 * 60 * 24;
+
+// pushes a due delivery's time past ARGV[3] unless another instance
+// already claimed it
+const CLAIM_SCRIPT: &str = r"
+local due = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not due or tonumber(due) > tonumber(ARGV[2]) then return 0 end
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+return 1
+";
+
+// a notification waiting for its next attempt
+#[derive(Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    payload: Value,
+    attempts: u32, // made so far
+}
+
+// ids of pending deliveries, scored by the unix milliseconds they are due
+fn schedule_key() -> String {
+    redis_client::key("webhooks:scheduled")
+}
+
+fn delivery_key(id: &str) -> String {
+    redis_client::key(&format!("webhook:{}", id))
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// addresses a webhook may reach: none of loopback, private, link-local,
+// shared, multicast or otherwise special ranges, so receivers can't be used
+// to reach into the network the server runs in
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)) // shared address space
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || (first & 0xfe00) == 0xfc00 // unique local
+                    || (first & 0xffc0) == 0xfe80) // link-local
+            }
+        },
+    }
+}
+
+// resolves like the default resolver but fails for names with any
+// address that isn't public, so a name can't be pointed inside later
+#[derive(Clone)]
+struct PublicResolver;
+
+impl tower::Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} resolves to {}, which is not public", name, address.ip()),
+                ));
+            }
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+// posts completion notifications. pending deliveries are kept in redis and
+// retried by whichever instance finds them due, so a restart loses none
+pub struct WebhookSender {
+    client: Client<HttpsConnector<HttpConnector<PublicResolver>>, Full<Bytes>>,
+    allowed_hosts: Vec<String>,
+    max_attempts: u32,
+    timeout: Duration,
+}
+
+impl WebhookSender {
+    pub fn new(config: &AppConfig) -> Result<Self, String> {
+        let mut http = HttpConnector::new_with_resolver(PublicResolver);
+        http.enforce_http(false);
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|e| format!("failed to load root certificates: {}", e))?
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http);
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            allowed_hosts: config.webhook_allowed_hosts.clone(),
+            max_attempts: config.webhook_max_attempts.max(1),
+            timeout: Duration::from_secs(config.webhook_timeout_secs),
+        })
+    }
+
+    // http or https urls of an allowed host only. names are checked for
+    // public addresses when they are resolved, addresses here
+    pub fn validate(&self, url: &str) -> Result<(), String> {
+        if self.allowed_hosts.is_empty() {
+            return Err("webhooks are disabled on this server".to_string());
+        }
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|e| format!("invalid webhook {}: {}", url, e))?;
+        let host = match uri.scheme_str() {
+            Some("http") | Some("https") if uri.host().is_some() => {
+                uri.host().unwrap_or_default().to_ascii_lowercase()
+            }
+            _ => return Err(format!("webhook {} must be an http or https url", url)),
+        };
+        if !self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| host_matches(allowed, &host))
+        {
+            return Err(format!("webhook host {} is not allowed", host));
+        }
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>()
+            && !is_public(ip)
+        {
+            return Err(format!("webhook address {} is not public", ip));
+        }
+        Ok(())
+    }
+
+    // stores a delivery of `payload` to `url`, due right away
+    pub async fn send(
+        &self,
+        connection: &mut MultiplexedConnection,
+        url: String,
+        payload: Value,
+    ) -> Result<(), String> {
+        let id = gen_random_id(16);
+        let delivery = Delivery {
+            url,
+            payload,
+            attempts: 0,
+        };
+        redis::pipe()
+            .atomic()
+            .set_ex(
+                delivery_key(&id),
+                serde_json::to_string(&delivery).unwrap(),
+                DELIVERY_TTL_SECS,
+            )
+            .zadd(schedule_key(), &id, unix_millis())
+            .query_async::<()>(connection)
+            .await
+            .map_err(|e| format!("failed to queue webhook: {}", e))
+    }
+
+    // delivers due webhooks in the background
+    pub fn start(self: &Arc<Self>, connection: MultiplexedConnection) {
+        let sender = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = sender.deliver_due(connection.clone()).await {
+                    tracing::warn!("webhook delivery: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn deliver_due(
+        self: &Arc<Self>,
+        mut connection: MultiplexedConnection,
+    ) -> Result<(), String> {
+        let now = unix_millis();
+        let due: Vec<String> = connection
+            .zrangebyscore_limit(schedule_key(), "-inf", now, 0, BATCH_SIZE)
+            .await
+            .map_err(|e| format!("failed to read pending webhooks: {}", e))?;
+        let lease = now + (self.timeout + CLAIM_MARGIN).as_millis() as u64;
+        for id in due {
+            let claimed: i64 = redis::Script::new(CLAIM_SCRIPT)
+                .key(schedule_key())
+                .arg(&id)
+                .arg(now)
+                .arg(lease)
+                .invoke_async(&mut connection)
+                .await
+                .map_err(|e| format!("failed to claim webhook: {}", e))?;
+            if claimed == 1 {
+                tokio::spawn(self.clone().deliver(connection.clone(), id));
+            }
+        }
+        Ok(())
+    }
+
+    // makes the next attempt of delivery `id`, then drops it once it is
+    // accepted, a response says retrying won't help, or the attempts run
+    // out. waits between attempts grow exponentially with full jitter, so
+    // receivers coming back from an outage aren't hit by every sender at once
+    async fn deliver(self: Arc<Self>, mut connection: MultiplexedConnection, id: String) {
+        let stored: Result<Option<String>, _> = connection.get(delivery_key(&id)).await;
+        let mut delivery = match stored {
+            Ok(Some(stored)) => match serde_json::from_str::<Delivery>(&stored) {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    tracing::error!(id, "dropping corrupt webhook delivery: {}", e);
+                    self.forget(&mut connection, &id).await;
+                    return;
+                }
+            },
+            Ok(None) => {
+                self.forget(&mut connection, &id).await;
+                return;
+            }
+            Err(e) => {
+                // the claim runs out and another attempt picks it up
+                tracing::warn!(id, "failed to read webhook delivery: {}", e);
+                return;
+            }
+        };
+        delivery.attempts += 1;
+        let (url, attempt) = (delivery.url.clone(), delivery.attempts);
+        let body = Bytes::from(serde_json::to_vec(&delivery.payload).unwrap());
+        let (error, retryable) = match self.attempt(&url, body).await {
+            Ok(()) => {
+                tracing::debug!(url, attempt, "delivered webhook");
+                counter!("webhook_deliveries_total", "outcome" => "delivered").increment(1);
+                self.forget(&mut connection, &id).await;
+                return;
+            }
+            Err(failure) => failure,
+        };
+        if !retryable || attempt >= self.max_attempts {
+            tracing::error!(url, attempt, "giving up on webhook: {}", error);
+            counter!("webhook_deliveries_total", "outcome" => "failed").increment(1);
+            self.forget(&mut connection, &id).await;
+            return;
+        }
+        tracing::warn!(url, attempt, "webhook delivery failed: {}", error);
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_BACKOFF)
+            .mul_f64(fastrand::f64());
+        let due = unix_millis() + backoff.as_millis() as u64;
+        let rescheduled = redis::pipe()
+            .atomic()
+            .set_ex(
+                delivery_key(&id),
+                serde_json::to_string(&delivery).unwrap(),
+                DELIVERY_TTL_SECS,
+            )
+            .zadd(schedule_key(), &id, due)
+            .query_async::<()>(&mut connection)
+            .await;
+        if let Err(e) = rescheduled {
+            tracing::warn!(id, "failed to reschedule webhook: {}", e);
+        }
+    }
+
+    async fn forget(&self, connection: &mut MultiplexedConnection, id: &str) {
+        let forgotten = redis::pipe()
+            .atomic()
+            .del(delivery_key(id))
+            .zrem(schedule_key(), id)
+            .query_async::<()>(connection)
+            .await;
+        if let Err(e) = forgotten {
+            tracing::warn!(id, "failed to remove webhook delivery: {}", e);
+        }
+    }
+
+    // the error and whether another attempt could succeed
+    async fn attempt(&self, url: &str, body: Bytes) -> Result<(), (String, bool)> {
+        // the allowed hosts may have changed since the delivery was queued
+        self.validate(url).map_err(|e| (e, false))?;
+        let request = Request::post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(body))
+            .map_err(|e| (format!("invalid request: {}", e), false))?;
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| ("timed out".to_string(), true))?
+            .map_err(|e| (format!("failed to reach receiver: {}", e), true))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        // other client errors would get the same answer every time
+        let retryable = status.is_server_error()
+            || status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS;
+        Err((format!("receiver answered {}", status), retryable))
+    }
+}
@@ -1,35 +1,162 @@
-use crate::files::{FileManagerTrait, RedisFileManager};
-use crate::utils::autofix;
+use crate::archive;
+use crate::audit::{SyscallAudit, open_kmsg};
+use crate::file_cache::FileCache;
+use crate::files::{FileManager, FileManagerTrait};
+use crate::images::ImageManager;
+use crate::init;
+use crate::integrity;
+#[cfg(any(test, feature = "mock-sandbox"))]
+use crate::mock;
+use crate::network;
+use crate::perf::PerfSession;
+use crate::result_cache;
+use crate::sandboxes::{self, Sandbox};
+use crate::transfers::UrlTransfers;
+use crate::usage::Usage;
+use crate::utils::{autofix, gen_random_id, is_glob_pattern, truncate};
 use std::os::unix::fs::PermissionsExt;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::{Read, Write};
+use std::sync::Arc;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use futures_util::{StreamExt, stream};
 
 use hakoniwa::landlock::*;
 use hakoniwa::seccomp::{Action, Filter};
-use hakoniwa::{Container, Namespace, Rlimit, Runctl, Stdio};
+use hakoniwa::{
+    Child, Command, Container, MountOptions, Namespace, Pasta, Rlimit, Runctl, Stdio,
+};
 
 use metrics::{counter, histogram};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::time::Instant;
+use tracing::Span;
+use tracing::field::Empty;
 
-use crate::types::{Execution, ExecutionError, ExecutionFile, ExecutionResult, File, FilePath};
+use crate::types::{
+    AppConfig, Execution, ExecutionError, ExecutionFile, ExecutionResult, ExitStatus, File,
+    FilePath, IdMapping, LimitExceeded, RemoteFile, ResourceUsage, SyscallViolation,
+};
 
 pub struct Worker {
     container: Container,
     path: String,
     temp_files: HashMap<u64, Vec<u8>>,
-    file_manager: Box<RedisFileManager>,
+    file_manager: Box<FileManager>,
+    config: Arc<AppConfig>,
+    stored_bytes: u64,
+    usage: Usage,
+    sandbox: Arc<Sandbox>,
+    images: Arc<ImageManager>,
+    file_cache: Arc<FileCache>,
+    transfers: Arc<UrlTransfers>,
+    infrastructure_failure: bool, // the last execute failed because of the host, not the program
+    diagnostics: Option<Value>,   // what a debug execution's last execute error carries
+    cleaned_up: bool,             // cleanup ran, dropping has nothing left to remove
+    deadline: Option<Instant>,    // end of the batch's max_batch_wall_time, which caps wall time limits
+}
+
+const BANNED_SYSCALLS: &[&str] = &["mount", "umount", "poweroff", "reboot"];
+
+// banned unless the execution's profile enables networking
+const NETWORK_SYSCALLS: &[&str] = &["socket", "bind", "connect", "listen", "sendto", "recvfrom"];
+
+// devices operators may expose in the sandbox /dev
+pub(crate) const ALLOWED_DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+// where the network setup script and its nftables ruleset are mounted inside
+// the sandbox, the script applies them and then execs the program
+const NETWORK_SETUP_PATH: &str = "/run/pentagon/network.sh";
+const NETWORK_RULES_PATH: &str = "/run/pentagon/network.nft";
+
+// where the service binary is mounted to run as the sandbox's init, and the
+// report it leaves for the worker
+const INIT_PATH: &str = "/run/pentagon/init";
+
+// where strace writes the trace of a traced execution inside the sandbox
+const TRACE_PATH: &str = "/run/pentagon/strace.log";
+
+// upper bound on files a single glob pattern may expand to
+const MAX_GLOB_MATCHES: usize = 1000;
+
+// lowest scheduling priority a process can run at
+const MAX_NICENESS: i32 = 19;
+
+// bytes of a score file that are looked at
+const MAX_SCORE_SIZE: u64 = 64;
+
+// percent of the memory limit a failed program's peak address space may
+// stay under and still count as having hit it
+const MEMORY_LIMIT_MARGIN: u64 = 5;
+
+// in-memory outputs above this size are streamed to remote storage in chunks
+const STREAM_THRESHOLD: usize = 4 * 1024 * 1024;
+
+// the environment programs are spawned with, before env_passthrough
+const SPAWN_ENV: &[(&str, &str)] = &[("PATH", "/bin")];
+
+// what a debug execution's errors carry about the sandbox, for failures the
+// message alone doesn't explain
+#[derive(Serialize)]
+struct Diagnostics {
+    program: String, // as spawned, after wrappers like setpriv
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    rootfs: String,
+    profile: Option<String>,
+    reason: Option<String>, // the container's exit status, once it exited
+    seccomp_hits: Option<Vec<SyscallViolation>>, // none when /dev/kmsg can't be read
+}
+
+// a phase of an execution, traced as a child span of execute with how long
+// it took and the bytes it moved, so slow executions can be attributed to
+// file staging or to the program itself
+struct Phase {
+    span: Span,
+    start: Instant,
 }
 
-const BANNED_SYSCALLS: &[&str] = &[
-    "mount", "umount", "poweroff", "reboot", "socket", "bind", "connect", "listen", "sendto",
-    "recvfrom",
-];
+impl Phase {
+    // `span` declares the bytes and duration_ms fields it gets
+    fn start(span: Span) -> Self {
+        Self {
+            span,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&self, field: &str, value: usize) {
+        self.span.record(field, value);
+    }
+
+    // phases left early by an error close without their fields
+    fn finish(self, bytes: Option<u64>) {
+        let duration_ms = self.start.elapsed().as_millis() as u64;
+        self.span.record("duration_ms", duration_ms);
+        if let Some(bytes) = bytes {
+            self.span.record("bytes", bytes);
+        }
+        tracing::debug!(parent: &self.span, duration_ms, bytes, "finished execution phase");
+    }
+}
 
 impl Worker {
-    #[tracing::instrument(skip(file_manager))]
-    pub fn new(code_path: String, file_manager: Box<RedisFileManager>) -> Self {
+    #[tracing::instrument(skip(file_manager, config, sandbox, images, file_cache, transfers))]
+    pub fn new(
+        code_path: String,
+        file_manager: Box<FileManager>,
+        config: Arc<AppConfig>,
+        sandbox: Arc<Sandbox>,
+        images: Arc<ImageManager>,
+        file_cache: Arc<FileCache>,
+        transfers: Arc<UrlTransfers>,
+    ) -> Self {
         tracing::debug!("creating new worker");
         fs::create_dir_all(&code_path).expect("Failed to create code directory");
         let mut container = Container::new();
@@ -40,25 +167,32 @@ impl Worker {
             .unshare(Namespace::Uts)
             .unshare(Namespace::Network);
 
-        let mut ruleset = Ruleset::default();
+        // keep the host's name out of the uts namespace
+        let sandbox_id = Path::new(&code_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        container.hostname(&config.hostname.replace("{id}", &sandbox_id));
 
-        ruleset.restrict(Resource::FS, CompatMode::Enforce);
-        ruleset.add_fs_rule("/bin", FsAccess::R | FsAccess::X);
-        ruleset.add_fs_rule("/lib", FsAccess::R | FsAccess::X);
-        ruleset.add_fs_rule("/usr", FsAccess::R | FsAccess::X);
-        ruleset.add_fs_rule("/box", FsAccess::R | FsAccess::W | FsAccess::X);
-
-        container.landlock_ruleset(ruleset);
+        // multi-instance hosts give each deployment disjoint ranges
+        if !config.uid_mappings.is_empty() {
+            let uidmaps: Vec<_> = config.uid_mappings.iter().map(IdMapping::as_tuple).collect();
+            container.uidmaps(&uidmaps);
+        }
+        if !config.gid_mappings.is_empty() {
+            let gidmaps: Vec<_> = config.gid_mappings.iter().map(IdMapping::as_tuple).collect();
+            container.gidmaps(&gidmaps);
+        }
 
-        let mut filter = Filter::new(Action::Allow);
+        if config.landlock {
+            let ruleset = landlock_ruleset(&config.box_path, &[&config.landlock_paths])
+                .expect("invalid landlock rule");
+            container.landlock_ruleset(ruleset);
+        }
 
-        BANNED_SYSCALLS.iter().for_each(|syscall| {
-            filter.add_rule(Action::Errno(libc::SIGSYS), syscall);
-        });
-        container.seccomp_filter(filter);
+        container.seccomp_filter(seccomp_filter(Action::Errno(libc::SIGSYS), false));
 
-        container.rootfs("/").expect("unable to mount root fs");
-        container.bindmount_rw(&code_path, "/box");
+        // filesystems are mounted per execution, which may pick its own rootfs
 
         container.runctl(Runctl::GetProcPidStatus);
         container.runctl(Runctl::GetProcPidSmapsRollup);
@@ -68,31 +202,468 @@ impl Worker {
             path: code_path.to_string(),
             temp_files: HashMap::new(),
             file_manager,
+            config,
+            stored_bytes: 0,
+            usage: Usage::default(),
+            sandbox,
+            images,
+            file_cache,
+            transfers,
+            infrastructure_failure: false,
+            diagnostics: None,
+            cleaned_up: false,
+            deadline: None,
         }
     }
 
+    // starts a batch's max_batch_wall_time, none ends it
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    // the batch has no wall time left, its remaining executions are skipped
+    pub fn past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // bytes written to remote storage since the last call
+    pub fn take_stored_bytes(&mut self) -> u64 {
+        std::mem::take(&mut self.stored_bytes)
+    }
+
+    pub fn record_usage(&mut self, result: Option<&ExecutionResult>, bytes_stored: u64) {
+        self.usage.add(result, bytes_stored);
+    }
+
+    // after execute panicked: the program it left running is killed so the
+    // next execution starts from a clean sandbox
+    pub async fn recover_from_panic(&mut self) {
+        self.infrastructure_failure = false;
+        let sandbox = self.sandbox.clone();
+        let _ = tokio::task::spawn_blocking(move || sandbox.reap()).await;
+    }
+
+    // what the executions since the last call consumed
+    pub fn take_usage(&mut self) -> Usage {
+        std::mem::take(&mut self.usage)
+    }
+
+    // whether the last execute error came from the host (spawning, waiting,
+    // storage) rather than the program, so running it again may succeed
+    pub fn failed_on_infrastructure(&self) -> bool {
+        self.infrastructure_failure
+    }
+
+    fn infrastructure_error(&mut self, message: String) -> ExecutionError {
+        self.infrastructure_failure = true;
+        ExecutionError { message }
+    }
+
+    // the diagnostics of the last execute error, when it ran in debug mode
+    pub fn take_diagnostics(&mut self) -> Option<Value> {
+        self.diagnostics.take()
+    }
+
+    fn with_diagnostics(
+        &mut self,
+        error: ExecutionError,
+        diagnostics: Option<Diagnostics>,
+    ) -> ExecutionError {
+        self.diagnostics = diagnostics.and_then(|d| serde_json::to_value(d).ok());
+        error
+    }
+
+    // files bind-mounted into the sandbox live next to its directory so the
+    // program can't modify them
+    fn write_sidecar(&self, name: &str, contents: &str) -> Result<String, ExecutionError> {
+        let path = format!("{}.{}", self.path, name);
+        fs::write(&path, contents).map_err(|e| ExecutionError {
+            message: format!("failed to write {}: {}", name, e),
+        })?;
+        Ok(path)
+    }
+
     fn store_temp_file(&mut self, id: u64, data: Vec<u8>) {
         self.temp_files.insert(id, data);
     }
 
-    #[tracing::instrument(skip(self, file))]
-    pub async fn write_file(&mut self, file: File) -> Result<(), String> {
+    // where a local `name` is on the host. names are relative to /box and may
+    // not leave it, neither with ".." nor through symlinks the program created
+    fn box_path(&self, name: &str) -> Result<PathBuf, ExecutionError> {
+        let escapes = || ExecutionError {
+            message: format!("path {:?} escapes the sandbox", name),
+        };
+        if name.is_empty() || name.contains('\0') || !archive::is_relative(name) {
+            return Err(escapes());
+        }
+        let root = fs::canonicalize(&self.path).map_err(|e| ExecutionError {
+            message: format!("failed to resolve sandbox path: {}", e),
+        })?;
+        let path = root.join(name);
+
+        // the deepest part that exists decides where the path really points,
+        // anything below it is created inside
+        let mut existing = path.as_path();
+        while fs::symlink_metadata(existing).is_err() {
+            existing = existing.parent().ok_or_else(escapes)?;
+        }
+        match fs::canonicalize(existing) {
+            Ok(resolved) if resolved.starts_with(&root) => Ok(path),
+            _ => Err(escapes()),
+        }
+    }
+
+    fn open_box_file(&self, name: &str) -> Result<fs::File, ExecutionError> {
+        let full_path = self.box_path(name)?;
+        fs::File::open(&full_path).map_err(|e| ExecutionError {
+            message: format!("failed to open file {}: {}", name, e),
+        })
+    }
+
+    // writes `data` to `name` in the sandbox, adding the executable bits when asked
+    fn write_box_file(
+        &self,
+        name: &str,
+        data: &[u8],
+        executable: bool,
+    ) -> Result<(), ExecutionError> {
+        let full_path = self.box_path(name)?;
+        fs::write(&full_path, data).map_err(|e| ExecutionError {
+            message: format!("failed to write file {}: {}", name, e),
+        })?;
+        counter!("files_created_total").increment(1);
+        if executable {
+            make_executable(&full_path, name)?;
+        }
+        Ok(())
+    }
+
+    // reads at most `limit + 1` bytes so callers can tell whether the file was cut short
+    fn read_box_file_limited(&self, name: &str, limit: u64) -> Result<Vec<u8>, ExecutionError> {
+        let f = self.open_box_file(name)?;
+        let mut buffer = Vec::new();
+        f.take(limit.saturating_add(1))
+            .read_to_end(&mut buffer)
+            .map_err(|e| ExecutionError {
+                message: format!("failed to read file {}: {}", name, e),
+            })?;
+        Ok(buffer)
+    }
+
+    // expands a glob pattern inside the sandbox directory, returning regular
+    // files as paths relative to it
+    fn expand_glob(&self, pattern: &str) -> Result<Vec<String>, ExecutionError> {
+        if pattern.starts_with('/') || pattern.split('/').any(|part| part == "..") {
+            return Err(ExecutionError {
+                message: format!("glob pattern {} escapes the sandbox", pattern),
+            });
+        }
+
+        let root = fs::canonicalize(&self.path).map_err(|e| ExecutionError {
+            message: format!("failed to resolve sandbox path: {}", e),
+        })?;
+        let full_pattern = format!(
+            "{}/{}",
+            glob::Pattern::escape(&root.to_string_lossy()),
+            pattern
+        );
+        let entries = glob::glob(&full_pattern).map_err(|e| ExecutionError {
+            message: format!("invalid glob pattern {}: {}", pattern, e),
+        })?;
+
+        let mut matches = Vec::new();
+        for entry in entries.flatten() {
+            // symlinks may point anywhere, only follow the ones staying in the sandbox
+            let Ok(resolved) = fs::canonicalize(&entry) else {
+                continue;
+            };
+            if !resolved.starts_with(&root) || !resolved.is_file() {
+                continue;
+            }
+            let Ok(relative) = entry.strip_prefix(&root) else {
+                continue;
+            };
+
+            if matches.len() >= MAX_GLOB_MATCHES {
+                return Err(ExecutionError {
+                    message: format!(
+                        "glob pattern {} matched more than {} files",
+                        pattern, MAX_GLOB_MATCHES
+                    ),
+                });
+            }
+            matches.push(relative.to_string_lossy().to_string());
+        }
+
+        Ok(matches)
+    }
+
+    // stores a returned file as a remote file of its own, so the result
+    // carries a reference to it instead of the content
+    async fn return_reference(
+        &mut self,
+        name: String,
+        source: CopySource<'_>,
+    ) -> Result<ExecutionFile, ExecutionError> {
+        let hash_failed = |e: io::Error| ExecutionError {
+            message: format!("failed to hash returned file {}: {}", name, e),
+        };
+        let sha256 = match &source {
+            CopySource::Memory(data) => Sha256::digest(data),
+            CopySource::Disk(file) => {
+                let mut reader = file;
+                let mut hasher = Sha256::new();
+                io::copy(&mut reader, &mut hasher).map_err(hash_failed)?;
+                reader.seek(SeekFrom::Start(0)).map_err(hash_failed)?;
+                hasher.finalize()
+            }
+        };
+        let id = format!("return:{}", gen_random_id(20));
+        let size = self
+            .copy_out_to(FilePath::Remote { id: id.clone() }, source)
+            .await?;
+        self.file_manager
+            .set_owner(&id, self.sandbox.tenant())
+            .await
+            .map_err(|e| self.infrastructure_error(e))?;
+        Ok(ExecutionFile {
+            name,
+            content: Vec::new(),
+            truncated: false,
+            remote: Some(RemoteFile {
+                id,
+                size,
+                sha256: hex::encode(sha256),
+            }),
+        })
+    }
+
+    async fn copy_out_to(
+        &mut self,
+        to: FilePath,
+        source: CopySource<'_>,
+    ) -> Result<u64, ExecutionError> {
+        let copied = match to {
+            FilePath::Tmp { id } => {
+                let data = source.into_bytes()?;
+                let size = data.len() as u64;
+                self.store_temp_file(id, data);
+                size
+            }
+            FilePath::Remote { id } => {
+                // stream in chunks instead of buffering large artifacts in memory
+                let written = match source {
+                    CopySource::Memory(data) if data.len() <= STREAM_THRESHOLD => self
+                        .file_manager
+                        .save_file(FilePath::Remote { id }, None, data.to_vec())
+                        .await
+                        .map(|_| data.len() as u64),
+                    CopySource::Memory(data) => {
+                        self.file_manager
+                            .save_file_chunked(FilePath::Remote { id }, data)
+                            .await
+                    }
+                    CopySource::Disk(file) => {
+                        self.file_manager
+                            .save_file_chunked(FilePath::Remote { id }, file)
+                            .await
+                    }
+                }
+                .map_err(|e| self.infrastructure_error(e))?;
+                tracing::debug!(bytes = written, "streamed copy_out to remote storage");
+                self.stored_bytes += written;
+                written
+            }
+
+            // straight to the client's object storage, it never passes through redis
+            FilePath::Url { url } => {
+                let uploaded = match source {
+                    CopySource::Memory(data) => self.transfers.upload_bytes(&url, data).await,
+                    CopySource::Disk(file) => self.transfers.upload_file(&url, file).await,
+                }
+                .map_err(|message| ExecutionError { message })?;
+                tracing::debug!(bytes = uploaded, "uploaded copy_out to url");
+                uploaded
+            }
+
+            FilePath::Dir { name } => {
+                let data = source.into_bytes()?;
+                self.unpack_into_box(&name, &data)
+                    .map_err(|message| ExecutionError { message })?;
+                data.len() as u64
+            }
+
+            FilePath::Local { name, executable } => {
+                let data = source.into_bytes()?;
+                self.write_box_file(&name, &data, executable)?;
+                data.len() as u64
+            }
+
+            _ => {
+                return Err(ExecutionError {
+                    message: "Unsupported file path for copy_out".to_string(),
+                });
+            }
+        };
+
+        Ok(copied)
+    }
+
+    // extracts a zip or tar archive into the directory `name` of /box,
+    // returning how many files it held
+    fn unpack_into_box(&self, name: &str, data: &[u8]) -> Result<usize, String> {
+        let dir = match name {
+            "" | "." => PathBuf::from(&self.path),
+            name => self.box_path(name).map_err(|e| e.message)?,
+        };
+        let entries = archive::read_archive(data, self.config.max_archive_size)?;
+        if entries.len() > self.config.max_archive_entries {
+            return Err(format!(
+                "archive has more than {} files",
+                self.config.max_archive_entries
+            ));
+        }
+
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        for entry in &entries {
+            // earlier executions may have left symlinks along the way
+            let relative = Path::new(name).join(&entry.name);
+            let path = self
+                .box_path(&relative.to_string_lossy())
+                .map_err(|e| e.message)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            fs::write(&path, &entry.data).map_err(|e| e.to_string())?;
+            if entry.executable {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(entries.len())
+    }
+
+    // clones the repository next to the sandbox and moves its files into the
+    // directory `name` of /box once it is complete, so a clone that fails
+    // halfway leaves nothing behind
+    async fn clone_into_box(
+        &self,
+        url: &str,
+        rev: Option<&str>,
+        name: &str,
+    ) -> Result<(), ExecutionError> {
+        let dir = match name {
+            "" | "." => PathBuf::from(&self.path),
+            name => self.box_path(name)?,
+        };
+        let staging = PathBuf::from(format!("{}.staging", self.path));
+        let clone = staging.join("git");
+        fs::create_dir_all(&staging).map_err(|e| ExecutionError {
+            message: format!("failed to create staging directory: {}", e),
+        })?;
+        let _ = fs::remove_dir_all(&clone);
+
+        let cloned = self
+            .transfers
+            .clone_repository(url, rev, &clone)
+            .await
+            .and_then(|()| move_entries(&clone, &dir));
+        let _ = fs::remove_dir_all(&staging);
+        cloned.map_err(|message| ExecutionError { message })?;
+        tracing::debug!(url, "cloned copy_in repository");
+        Ok(())
+    }
+
+    // a directory of /box as a tar archive
+    fn pack_box_dir(&self, name: &str) -> Result<Vec<u8>, ExecutionError> {
+        let dir = self.box_path(name)?;
+        if !fs::symlink_metadata(&dir).is_ok_and(|m| m.is_dir()) {
+            return Err(ExecutionError {
+                message: format!("{} is not a directory in /box", name),
+            });
+        }
+        archive::write_tar(&dir, self.config.max_archive_size)
+            .map_err(|message| ExecutionError { message })
+    }
+
+    // stages the request's files in /box. remote ones are fetched a few at a
+    // time, streamed to disk next to the sandbox and moved in request order
+    // as they arrive, so later files win
+    #[tracing::instrument(skip(self, files), fields(files = files.len()))]
+    pub async fn write_files(&mut self, files: Vec<File>) -> Result<(), String> {
+        let start = Instant::now();
+        let staging = PathBuf::from(format!("{}.staging", self.path));
+        fs::create_dir_all(&staging).map_err(|e| e.to_string())?;
+
+        let file_manager = &*self.file_manager;
+        let file_cache = &*self.file_cache;
+        let retries = self.config.infrastructure_retries;
+        let mut fetched = stream::iter(files.into_iter().enumerate())
+            .map(|(index, file)| {
+                let mut file_manager = file_manager.clone();
+                let path = staging.join(index.to_string());
+                async move { fetch_file(&mut file_manager, file_cache, file, path, retries).await }
+            })
+            .buffered(self.config.staging_concurrency.max(1));
+        let mut result = Ok(());
+        while let Some(file) = fetched.next().await {
+            result = file.and_then(|file| self.store_file(file));
+            if result.is_err() {
+                break;
+            }
+        }
+        drop(fetched);
+
+        let _ = fs::remove_dir_all(&staging);
+        histogram!("file_staging_duration_ms").record(start.elapsed().as_millis() as f64);
+        result
+    }
+
+    fn store_file(&self, file: StagedFile) -> Result<(), String> {
+        let file = match file {
+            StagedFile::Downloaded { name, path } => {
+                let full_path = self.box_path(&name).map_err(|e| e.message)?;
+                fs::rename(&path, &full_path).map_err(|e| e.to_string())?;
+                counter!("files_created_total").increment(1);
+                return Ok(());
+            }
+            StagedFile::Inline(file) => file,
+        };
         match file {
-            File::Local { name, content } => {
-                let full_path = format!("{}/{}", self.path, name);
+            File::Local {
+                name,
+                content,
+                sha256,
+            } => {
+                if let Some(expected) = &sha256 {
+                    integrity::check_bytes(&name, expected, &content)?;
+                }
+                let full_path = self.box_path(&name).map_err(|e| e.message)?;
                 let mut file = fs::File::create(&full_path).map_err(|e| e.to_string())?;
                 file.write_all(&content).map_err(|e| e.to_string())?;
             }
 
-            File::Remote { id, name } => {
-                let data = self
-                    .file_manager
-                    .get_file(FilePath::Remote { id }, None)
-                    .await?;
+            File::Archive {
+                name,
+                content: Some(content),
+                id: None,
+                sha256,
+            } => {
+                if let Some(expected) = &sha256 {
+                    integrity::check_bytes(&name, expected, &content)?;
+                }
+                let count = self.unpack_into_box(&name, &content)?;
+                counter!("files_created_total").increment(count as u64);
+                return Ok(());
+            }
 
-                let full_path = format!("{}/{}", self.path, name);
-                let mut file = fs::File::create(&full_path).map_err(|e| e.to_string())?;
-                file.write_all(&data).map_err(|e| e.to_string())?;
+            File::Archive { .. } => {
+                return Err("an archive needs either content or id".to_string());
+            }
+
+            // downloaded beforehand
+            File::Remote { name, .. } => {
+                return Err(format!("remote file {} was not fetched", name));
             }
         }
 
@@ -105,39 +676,185 @@ impl Worker {
         &mut self,
         execution: Execution,
     ) -> Result<ExecutionResult, ExecutionError> {
+        let config = self.config.clone();
+        self.infrastructure_failure = false;
+        self.diagnostics = None;
+        if let Some(reason) = self.sandbox.kill_reason() {
+            return Err(ExecutionError {
+                message: reason.to_string(),
+            });
+        }
+        if execution.seccomp_audit && !config.allow_seccomp_audit {
+            return Err(ExecutionError {
+                message: "seccomp audit mode is disabled on this server".to_string(),
+            });
+        }
+        if execution.debug && !config.allow_debug {
+            return Err(ExecutionError {
+                message: "debug mode is disabled on this server".to_string(),
+            });
+        }
+        if execution.perf && !config.allow_perf {
+            return Err(ExecutionError {
+                message: "performance counters are disabled on this server".to_string(),
+            });
+        }
+        if execution.trace && !config.allow_trace {
+            return Err(ExecutionError {
+                message: "syscall tracing is disabled on this server".to_string(),
+            });
+        }
+
+        let profile = match &execution.profile {
+            Some(name) => Some(config.profiles.get(name).ok_or_else(|| ExecutionError {
+                message: format!("unknown sandbox profile {}", name),
+            })?),
+            None => None,
+        };
+
+        // limits the request left out come from the config, all are capped by it
+        let mut clamped_limits = Vec::new();
+        let mut limit = |name: &str, value: Option<u64>, default: u64, max: Option<u64>| {
+            clamp_limit(name, value.unwrap_or(default), max, &config, &mut clamped_limits)
+        };
+        let time_limit = limit(
+            "time_limit",
+            execution.time_limit,
+            config.default_time_limit,
+            config.max_time_limit,
+        )?;
+        let wall_time_limit = limit(
+            "wall_time_limit",
+            execution.wall_time_limit,
+            config.default_wall_time_limit,
+            config.max_wall_time_limit,
+        )?;
+        let memory_limit = limit(
+            "memory_limit",
+            execution.memory_limit,
+            config.default_memory_limit,
+            config.max_memory_limit,
+        )?;
+        // the program gets no more than what the batch has left, in whole seconds
+        let wall_time_limit = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                wall_time_limit.min(left.as_millis().div_ceil(1000).max(1) as u64)
+            }
+            None => wall_time_limit,
+        };
+
+        // copy_out has effects a cached result can't replay
+        let cacheable =
+            (execution.cache && execution.copy_out.is_empty()).then(|| execution.clone());
+
         // initalization
         let mut stdin: Option<Vec<u8>> = None;
 
         // copy files
+        let copy_in = Phase::start(tracing::info_span!(
+            "copy_in",
+            files = execution.copy_in.len(),
+            bytes = Empty,
+            duration_ms = Empty
+        ));
+        let mut copied_in: u64 = 0;
         for file in execution.copy_in {
+            // remote files go straight to disk, they may not fit in memory
+            if let (FilePath::Remote { id }, FilePath::Local { name, executable }) =
+                (&file.from, &file.to)
+            {
+                let full_path = self.box_path(name)?;
+                let fetched =
+                    download(&mut self.file_manager, &self.file_cache, id, &full_path).await;
+                if let Err(e) = fetched {
+                    return Err(self.infrastructure_error(e));
+                }
+                copied_in += fs::metadata(&full_path).map_or(0, |metadata| metadata.len());
+                if let Some(expected) = &file.sha256 {
+                    integrity::check_file(id, expected, &full_path)
+                        .map_err(|message| ExecutionError { message })?;
+                }
+                if *executable {
+                    fs::set_permissions(&full_path, fs::Permissions::from_mode(0o755))
+                        .map_err(|e| ExecutionError {
+                            message: format!("failed to make {} executable: {}", name, e),
+                        })?;
+                }
+                counter!("files_created_total").increment(1);
+                continue;
+            }
+            // so do fetched ones
+            if let (FilePath::Url { url }, FilePath::Local { name, executable }) =
+                (&file.from, &file.to)
+            {
+                let full_path = self.box_path(name)?;
+                let size = self
+                    .transfers
+                    .download_to(url, &full_path)
+                    .await
+                    .map_err(|message| ExecutionError { message })?;
+                tracing::debug!(bytes = size, "fetched copy_in from url");
+                copied_in += size;
+                if let Some(expected) = &file.sha256 {
+                    integrity::check_file(url, expected, &full_path)
+                        .map_err(|message| ExecutionError { message })?;
+                }
+                if *executable {
+                    fs::set_permissions(&full_path, fs::Permissions::from_mode(0o755))
+                        .map_err(|e| ExecutionError {
+                            message: format!("failed to make {} executable: {}", name, e),
+                        })?;
+                }
+                counter!("files_created_total").increment(1);
+                continue;
+            }
+
+            if let FilePath::Git { url, rev } = &file.from {
+                let FilePath::Dir { name } = &file.to else {
+                    return Err(ExecutionError {
+                        message: "git sources can only be copied to a dir".to_string(),
+                    });
+                };
+                if file.sha256.is_some() {
+                    return Err(ExecutionError {
+                        message: "git sources can't be checked with sha256".to_string(),
+                    });
+                }
+                self.clone_into_box(url, rev.as_deref(), name).await?;
+                continue;
+            }
+
+            let expected = file
+                .sha256
+                .as_ref()
+                .map(|expected| (source_name(&file.from), expected));
             let data = match file.from {
                 FilePath::Local { name, executable } => {
-                    let mut f = fs::File::open(&name).map_err(|e| e.to_string()).unwrap();
                     let mut buffer = Vec::new();
-                    f.read_to_end(&mut buffer)
-                        .map_err(|e| e.to_string())
-                        .unwrap();
-
-                    // if executable is true, set the executable bit
+                    self.open_box_file(&name)?
+                        .read_to_end(&mut buffer)
+                        .map_err(|e| ExecutionError {
+                            message: format!("failed to read file {}: {}", name, e),
+                        })?;
                     if executable {
-                        let mut perms = fs::metadata(&name)
-                            .map_err(|e| e.to_string())
-                            .unwrap()
-                            .permissions();
-                        perms.set_mode(perms.mode() | 0o111); // set executable bits
-                        fs::set_permissions(&name, perms)
-                            .map_err(|e| e.to_string())
-                            .unwrap();
+                        make_executable(&self.box_path(&name)?, &name)?;
                     }
                     buffer
                 }
 
                 FilePath::Data { content } => content,
+                FilePath::Dir { name } => self.pack_box_dir(&name)?,
                 FilePath::Remote { id } => self
                     .file_manager
                     .get_file(FilePath::Remote { id }, None)
                     .await
-                    .unwrap(),
+                    .map_err(|e| self.infrastructure_error(e))?,
+                FilePath::Url { url } => self
+                    .transfers
+                    .download(&url)
+                    .await
+                    .map_err(|message| ExecutionError { message })?,
                 FilePath::Tmp { id } => {
                     if !self.temp_files.contains_key(&id) {
                         Vec::new()
@@ -153,31 +870,24 @@ impl Worker {
                 }
             };
 
+            if let Some((name, expected)) = expected {
+                integrity::check_bytes(&name, expected, &data)
+                    .map_err(|message| ExecutionError { message })?;
+            }
+            copied_in += data.len() as u64;
+
             match file.to {
                 FilePath::Local { name, executable } => {
-                    let full_path = format!("{}/{}", self.path, name);
-                    tracing::debug!("copying to {}", full_path);
-                    let mut f = fs::File::create(&full_path)
-                        .map_err(|e| e.to_string())
-                        .unwrap();
-
-                    // if executable is true, set the executable bit
-                    if executable {
-                        let mut perms = fs::metadata(&full_path)
-                            .map_err(|e| e.to_string())
-                            .unwrap()
-                            .permissions();
-                        perms.set_mode(perms.mode() | 0o111); // set executable bits
-                        fs::set_permissions(&full_path, perms)
-                            .map_err(|e| e.to_string())
-                            .unwrap();
-                    }
-                    f.write_all(&data).map_err(|e| e.to_string()).unwrap();
-                    counter!("files_created_total").increment(1);
+                    tracing::debug!("copying to {}", name);
+                    self.write_box_file(&name, &data, executable)?;
                 }
                 FilePath::Tmp { id } => {
                     self.store_temp_file(id, data);
                 }
+                FilePath::Dir { name } => {
+                    self.unpack_into_box(&name, &data)
+                        .map_err(|message| ExecutionError { message })?;
+                }
 
                 FilePath::Stdin {} => {
                     stdin = Some(data);
@@ -190,82 +900,474 @@ impl Worker {
             }
         }
 
+        copy_in.finish(Some(copied_in));
+
         // prepare execution
-        self.container
-            .setrlimit(Rlimit::Cpu, execution.time_limit, execution.time_limit);
+        self.container.setrlimit(Rlimit::Cpu, time_limit, time_limit);
+        self.container.setrlimit(Rlimit::As, memory_limit, memory_limit);
+        self.container.setrlimit(Rlimit::Stack, memory_limit, memory_limit);
 
-        self.container.setrlimit(
-            Rlimit::As,
-            execution.memory_limit as u64,
-            execution.memory_limit as u64,
-        );
+        // per-execution settings go on a copy so they never leak into later runs
+        let mut container = self.container.clone();
+        // the lease keeps a pulled image from being evicted until the run ends
+        let (rootfs, _image_lease) = match &execution.rootfs {
+            None => ("/".to_string(), None),
+            Some(name) => match (config.rootfs.get(name), config.images.get(name)) {
+                (Some(dir), _) => (dir.clone(), None),
+                (None, Some(image)) => {
+                    let lease = self.images.acquire(image).await.map_err(|e| {
+                        self.infrastructure_error(format!("failed to pull image {}: {}", name, e))
+                    })?;
+                    (lease.path.to_string_lossy().to_string(), Some(lease))
+                }
+                (None, None) => {
+                    return Err(ExecutionError {
+                        message: format!("unknown rootfs {}", name),
+                    });
+                }
+            },
+        };
 
-        self.container.setrlimit(
-            Rlimit::Stack,
-            execution.memory_limit as u64,
-            execution.memory_limit as u64,
-        );
+        let limits = [time_limit, wall_time_limit, memory_limit];
+        let cache_key = cacheable.and_then(|spec| {
+            let tenant = self.sandbox.tenant();
+            result_cache::box_digest(Path::new(&self.path))
+                .and_then(|digest| {
+                    let stdin = stdin.as_deref();
+                    let key = result_cache::key(&spec, tenant, limits, &rootfs, stdin, &digest)?;
+                    Ok((key, digest))
+                })
+                .inspect_err(|e| tracing::warn!("failed to hash execution inputs: {}", e))
+                .ok()
+        });
+        if let Some((key, _)) = &cache_key
+            && let Some(mut connection) = self.file_manager.connection()
+        {
+            match result_cache::get(&mut connection, key).await {
+                Ok(Some(result)) => {
+                    counter!("result_cache_requests_total", "outcome" => "hit").increment(1);
+                    return Ok(ExecutionResult {
+                        clamped_limits,
+                        cached: true,
+                        labels: execution.labels,
+                        id: execution.id,
+                        ..result
+                    });
+                }
+                Ok(None) => {
+                    counter!("result_cache_requests_total", "outcome" => "miss").increment(1);
+                }
+                Err(e) => tracing::warn!("{}", e),
+            }
+        }
+
+        let workdir = match &execution.workdir {
+            Some(workdir) => {
+                let dir = self.box_path(workdir)?;
+                if !fs::symlink_metadata(&dir).is_ok_and(|m| m.is_dir()) {
+                    return Err(ExecutionError {
+                        message: format!("workdir {} is not a directory in the sandbox", workdir),
+                    });
+                }
+                Path::new(&config.box_path)
+                    .join(workdir)
+                    .to_string_lossy()
+                    .into_owned()
+            }
+            None => config.box_path.clone(),
+        };
+
+        mount_filesystems(&mut container, &rootfs, &self.path, &config)?;
+        let network = profile.is_some_and(|p| p.network);
+        let egress = match profile {
+            Some(profile) if profile.network => {
+                network::allowlist(&profile.egress_policy, execution.egress_allow.as_deref())
+                    .map_err(|e| ExecutionError { message: e })?
+            }
+            _ if execution.egress_allow.is_some() => {
+                return Err(ExecutionError {
+                    message: "egress_allow requires a networked profile".to_string(),
+                });
+            }
+            _ => None,
+        };
+        let networked_profile = profile.filter(|p| p.network);
+        let max_connections = networked_profile.and_then(|p| p.max_connections);
+        let bandwidth_kbit = networked_profile.and_then(|p| p.bandwidth_kbit);
+
+        // landlock rules for files mounted for this execution only
+        let mut extra_paths = BTreeMap::new();
+        if network {
+            let resolv_conf = self.write_sidecar("resolv.conf", &resolv_conf_contents(&config)?)?;
+            let target = etc_file_target("/etc/resolv.conf");
+            container.network(Pasta::default());
+            container.bindmount_ro(&resolv_conf, &target);
+            extra_paths.insert(target, "r".to_string());
+        }
+
+        let network_setup =
+            egress.is_some() || max_connections.is_some() || bandwidth_kbit.is_some();
+        if network_setup {
+            // the setup runs inside the sandbox, so the program must lose
+            // net_admin before it starts or it could undo it
+            let keeps_net_admin = profile.is_some_and(|p| {
+                p.keep_capabilities.iter().any(|cap| {
+                    let cap = cap.to_ascii_lowercase();
+                    cap.strip_prefix("cap_").unwrap_or(&cap) == "net_admin"
+                })
+            });
+            if !config.drop_capabilities || keeps_net_admin {
+                return Err(ExecutionError {
+                    message: "network policies require drop_capabilities without net_admin"
+                        .to_string(),
+                });
+            }
+
+            let mut script = String::from("set -e\n");
+
+            let allowed = match &egress {
+                Some(rules) => {
+                    let resolved = network::resolve(rules)
+                        .await
+                        .map_err(|e| ExecutionError { message: e })?;
+                    let hosts = self.write_sidecar("hosts", &resolved.hosts_file())?;
+                    let target = etc_file_target("/etc/hosts");
+                    container.bindmount_ro(&hosts, &target);
+                    extra_paths.insert(target, "r".to_string());
+                    Some(resolved.nets)
+                }
+                None => None,
+            };
+
+            if allowed.is_some() || max_connections.is_some() {
+                let ruleset = network::nft_ruleset(allowed.as_deref(), max_connections);
+                let ruleset = self.write_sidecar("network.nft", &ruleset)?;
+                container.bindmount_ro(&ruleset, NETWORK_RULES_PATH);
+                extra_paths.insert(NETWORK_RULES_PATH.to_string(), "r".to_string());
+                extra_paths.insert(config.nft_path.clone(), "rx".to_string());
+                script.push_str(&format!("{} -f {}\n", config.nft_path, NETWORK_RULES_PATH));
+            }
+
+            if let Some(kbit) = bandwidth_kbit {
+                extra_paths.insert(config.tc_path.clone(), "rx".to_string());
+                script.push_str(&network::shaping_commands(&config.tc_path, kbit));
+            }
+
+            script.push_str("exec \"$@\"\n");
+            let script = self.write_sidecar("network.sh", &script)?;
+            container.bindmount_ro(&script, NETWORK_SETUP_PATH);
+            extra_paths.insert(NETWORK_SETUP_PATH.to_string(), "r".to_string());
+            extra_paths.insert(config.shell_path.clone(), "rx".to_string());
+        }
+
+        if execution.shell {
+            extra_paths.insert(config.shell_path.clone(), "rx".to_string());
+        }
+
+        // strace writes the trace to a file next to the sandbox, out of /box
+        // and of the program's way
+        let trace = if execution.trace {
+            let trace = self.write_sidecar("strace.log", "")?;
+            container.bindmount_rw(&trace, TRACE_PATH);
+            extra_paths.insert(TRACE_PATH.to_string(), "rw".to_string());
+            extra_paths.insert(config.strace_path.clone(), "rx".to_string());
+            Some(trace)
+        } else {
+            None
+        };
+
+        if config.sandbox_init {
+            let binary = std::env::current_exe().map_err(|e| ExecutionError {
+                message: format!("failed to find the service binary for the init: {}", e),
+            })?;
+            container.bindmount_ro(&binary.to_string_lossy(), INIT_PATH);
+            extra_paths.insert(INIT_PATH.to_string(), "rx".to_string());
+        }
+
+        if execution.gpu {
+            if !config.allow_gpu {
+                return Err(ExecutionError {
+                    message: "gpu passthrough is disabled on this server".to_string(),
+                });
+            }
+            for path in gpu_devices(&config.gpu_devices)? {
+                container.bindmount_rw(&path, &path);
+                extra_paths.insert(path, "rw".to_string());
+            }
+            for path in &config.gpu_library_paths {
+                container.bindmount_ro(path, path);
+                extra_paths.insert(path.clone(), "rx".to_string());
+            }
+        }
+
+        if config.landlock {
+            let profile_paths = profile
+                .map(|p| &p.landlock_paths)
+                .filter(|paths| !paths.is_empty());
+            if profile_paths.is_some() || !extra_paths.is_empty() {
+                let mut rules = vec![&config.landlock_paths];
+                rules.extend(profile_paths);
+                rules.push(&extra_paths);
+                let ruleset = landlock_ruleset(&config.box_path, &rules)
+                    .map_err(|e| ExecutionError { message: e })?;
+                container.landlock_ruleset(ruleset);
+            }
+        }
+
+        if execution.seccomp_audit {
+            // banned syscalls are allowed but logged so every attempt gets recorded
+            container.seccomp_filter(seccomp_filter(Action::Log, network));
+        } else if network {
+            container.seccomp_filter(seccomp_filter(Action::Errno(libc::SIGSYS), network));
+        }
+
+        let no_new_privs = config.no_new_privs && !profile.is_some_and(|p| p.allow_new_privs);
+        if !no_new_privs {
+            container.runctl(Runctl::AllowNewPrivs);
+        }
 
-        let mut cmd = self.container.command(&execution.program);
-        cmd.current_dir("/box")
-            .args(execution.args)
-            .env("PATH", "/bin")
+        // nothing is spawned when the mock is compiled in, see mock.rs
+        #[cfg(any(test, feature = "mock-sandbox"))]
+        let mocked = {
+            let (program, args) = (execution.program.clone(), execution.args.clone());
+            let (stdin, sandbox) = (stdin.clone(), self.sandbox.clone());
+            let box_dir = PathBuf::from(&self.path);
+            Some(move || mock::run(&program, &args, stdin.as_deref(), &box_dir, &sandbox))
+        };
+        #[cfg(not(any(test, feature = "mock-sandbox")))]
+        let mocked: Option<fn() -> (hakoniwa::Output, ResourceUsage)> = None;
+
+        let (program, args) = if execution.shell {
+            let script = std::iter::once(execution.program.clone())
+                .chain(execution.args.iter().map(|arg| shell_quote(arg)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            (config.shell_path.clone(), vec!["-c".to_string(), script])
+        } else {
+            (execution.program.clone(), execution.args)
+        };
+        let (program, args) = if trace.is_some() {
+            let mut wrapped = ["-f", "-o", TRACE_PATH, "--"].map(String::from).to_vec();
+            wrapped.push(program);
+            wrapped.extend(args);
+            (config.strace_path.clone(), wrapped)
+        } else {
+            (program, args)
+        };
+        let (program, args) = if config.drop_capabilities {
+            let keep = profile.map_or(&[][..], |p| p.keep_capabilities.as_slice());
+            let mut wrapped = setpriv_args(keep, no_new_privs);
+            wrapped.push(program);
+            wrapped.extend(args);
+            (config.setpriv_path.clone(), wrapped)
+        } else {
+            (program, args)
+        };
+        let (program, args) = if network_setup {
+            let mut wrapped = vec![NETWORK_SETUP_PATH.to_string(), program];
+            wrapped.extend(args);
+            (config.shell_path.clone(), wrapped)
+        } else {
+            (program, args)
+        };
+        let (program, args) = if config.sandbox_init {
+            let mut wrapped = vec![init::ARG.to_string(), program];
+            wrapped.extend(args);
+            (INIT_PATH.to_string(), wrapped)
+        } else {
+            (program, args)
+        };
+
+        let env = spawn_env(&config.env_passthrough);
+        let mut diagnostics = execution.debug.then(|| Diagnostics {
+            program: program.clone(),
+            args: args.clone(),
+            env: env.clone(),
+            rootfs: rootfs.clone(),
+            profile: execution.profile.clone(),
+            reason: None,
+            seccomp_hits: None,
+        });
+
+        let mut cmd = container.command(&program);
+        for (key, value) in &env {
+            cmd.env(key, value);
+        }
+        cmd.current_dir(&workdir)
+            .args(args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped());
 
-        cmd.wait_timeout(execution.wall_time_limit);
+        cmd.wait_timeout(wall_time_limit);
+
+        let niceness = execution
+            .niceness
+            .map(|n| n.clamp(self.config.min_niceness, MAX_NICENESS));
+
+        let kmsg = if execution.seccomp_audit {
+            Some(open_kmsg().map_err(|e| ExecutionError { message: e })?)
+        } else if execution.debug {
+            // seccomp hits are a nice to have for diagnostics
+            open_kmsg()
+                .inspect_err(|e| tracing::warn!("no seccomp hits for debug mode: {}", e))
+                .ok()
+        } else {
+            None
+        };
 
         // run
 
         let wall_start = Instant::now();
-        let mut proc = match cmd.spawn() {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(ExecutionError {
-                    message: format!("Failed to spawn process: {}", e),
-                });
+        let (output, rusage, audit, perf) = match mocked {
+            Some(mock) => {
+                let (output, rusage) =
+                    tokio::task::spawn_blocking(mock).await.expect("mock panicked");
+                (Ok(output), Some(rusage), None, None)
             }
-        };
+            None => {
+                let spawn = Phase::start(tracing::info_span!("spawn", duration_ms = Empty));
+                let (spawned, perf) = spawn_sandbox(&mut cmd, niceness, execution.perf);
+                spawn.finish(None);
+                let mut proc = match spawned {
+                    Ok(p) => p,
+                    Err(e) => {
+                        let error =
+                            self.infrastructure_error(format!("Failed to spawn process: {}", e));
+                        return Err(self.with_diagnostics(error, diagnostics));
+                    }
+                };
+
+                self.sandbox.set_running(&execution.program, proc.id());
+                let namespace = sandboxes::pid_namespace(proc.id());
 
-        if let Some(stdin) = stdin {
-            if let Some(mut proc_stdin) = proc.stdin.take() {
-                std::thread::spawn(move || {
-                    if let Err(_) = proc_stdin.write_all(&stdin) {
-                        // return RunOutput::error("Failed to write to stdin".to_string(), None, None);
-                        tracing::warn!("failed to write to stdin, process could be dead");
+                let audit = kmsg.and_then(|kmsg| match SyscallAudit::start(kmsg, proc.id()) {
+                    Ok(audit) => Some(audit),
+                    Err(e) => {
+                        tracing::warn!("failed to start syscall audit: {}", e);
+                        None
                     }
-                    drop(proc_stdin);
-                });
-            } else {
-                return Err(ExecutionError {
-                    message: "Failed to open stdin of process".to_string(),
                 });
+
+                if let Some(stdin) = stdin {
+                    if let Some(mut proc_stdin) = proc.stdin.take() {
+                        let write = Phase::start(tracing::info_span!(
+                            "stdin_write",
+                            bytes = Empty,
+                            duration_ms = Empty
+                        ));
+                        std::thread::spawn(move || {
+                            if proc_stdin.write_all(&stdin).is_err() {
+                                // return RunOutput::error("Failed to write to stdin".to_string(), None, None);
+                                tracing::warn!("failed to write to stdin, process could be dead");
+                            }
+                            drop(proc_stdin);
+                            write.finish(Some(stdin.len() as u64));
+                        });
+                    } else {
+                        return Err(ExecutionError {
+                            message: "Failed to open stdin of process".to_string(),
+                        });
+                    }
+                }
+
+                let wait = Phase::start(tracing::info_span!(
+                    "wait",
+                    bytes = Empty,
+                    duration_ms = Empty
+                ));
+                // on a thread of its own, so a disconnect or a kill is noticed
+                // while the program runs
+                let (output, rusage) =
+                    tokio::task::spawn_blocking(move || wait_with_usage(&mut proc))
+                        .await
+                        .expect("wait thread panicked");
+                // what the program wrote to stdout and stderr
+                wait.finish(
+                    output
+                        .as_ref()
+                        .ok()
+                        .map(|output| (output.stdout.len() + output.stderr.len()) as u64),
+                );
+                self.sandbox.clear_running();
+                // nothing the program started may keep running into the next
+                // execution or hold on to /box while it is removed
+                if let Some(namespace) = namespace {
+                    // it polls until the processes are gone
+                    let killed =
+                        tokio::task::spawn_blocking(move || sandboxes::kill_namespace(&namespace))
+                            .await
+                            .unwrap_or_else(|e| Err(format!("namespace kill panicked: {}", e)));
+                    match killed {
+                        Ok(0) => {}
+                        Ok(left) => {
+                            tracing::warn!(left, "killed processes that outlived the program");
+                            counter!("sandbox_leftover_processes_total").increment(left as u64);
+                        }
+                        Err(e) => return Err(self.infrastructure_error(e)),
+                    }
+                }
+                (output, rusage, audit, perf)
             }
+        };
+        if let Some(reason) = self.sandbox.kill_reason() {
+            return Err(ExecutionError {
+                message: reason.to_string(),
+            });
         }
-
-        let output = match proc.wait_with_output() {
+        let mut output = match output {
             Ok(o) => o,
             Err(e) => {
-                return Err(ExecutionError {
-                    message: format!("Failed to wait for process output: {}", e),
-                });
+                let error = self.infrastructure_error(format!(
+                    "Failed to wait for process output: {}",
+                    e
+                ));
+                return Err(self.with_diagnostics(error, diagnostics));
             }
         };
 
-        let wall_ms = wall_start.elapsed().as_secs_f64() * 1000.0;
-        histogram!("execution_wall_time_ms").record(wall_ms);
+        let perf = perf.map(PerfSession::finish);
+        let mut syscall_violations = audit.map(SyscallAudit::finish);
+        if let Some(diagnostics) = &mut diagnostics {
+            diagnostics.reason = Some(output.status.reason.clone());
+            diagnostics.seccomp_hits = syscall_violations.clone();
+        }
+        // debug mode watches for hits too, results only report them in audit mode
+        if !execution.seccomp_audit {
+            syscall_violations = None;
+        }
+
+        let wall_time_used = wall_start.elapsed().as_millis();
+        histogram!("execution_wall_time_ms").record(wall_start.elapsed().as_secs_f64() * 1000.0);
 
-        let output_status = output.status.clone();
+        let mut output_status = output.status.clone();
+
+        // the init's report ends its stderr, which the program could forge,
+        // so it only counts when the init itself exited normally. none when it
+        // was killed before it could write it, e.g. at the wall time limit
+        let init_report = if config.sandbox_init && output.status.exit_code.is_some() {
+            init::take_report(&mut output.stderr)
+        } else {
+            None
+        };
+        // the init exits with 128 + the signal that killed the program
+        if init_report
+            .as_ref()
+            .is_some_and(|report| report.signal.is_some())
+        {
+            output_status.exit_code = None;
+        }
 
+        // hakoniwa only leaves rusage out when the container itself failed
         let resource = match output.status.rusage {
-            Some(r) => Some(r),
+            Some(r) => r,
             None => {
                 tracing::warn!("failed to get resource usage: {}", output_status.reason);
-                // return Err(ExecutionError {
-                //     message: "failed to get resource usage".to_string(),
-                // });
-                None
+                let error = self.infrastructure_error(format!(
+                    "failed to get resource usage: {}",
+                    output_status.reason
+                ));
+                return Err(self.with_diagnostics(error, diagnostics));
             }
         };
 
@@ -286,67 +1388,107 @@ impl Worker {
             output.stdout.clone()
         };
 
+        let score = match &execution.score_from {
+            Some(FilePath::Stdout { .. }) => parse_score(&stdout),
+            Some(FilePath::Local { name, .. }) => self
+                .read_box_file_limited(name, MAX_SCORE_SIZE)
+                .ok()
+                .and_then(|data| parse_score(&data)),
+            Some(_) => {
+                return Err(ExecutionError {
+                    message: "score_from must be stdout or a local file".to_string(),
+                });
+            }
+            None => None,
+        };
+
+        let mut skipped_copy_out: Vec<String> = Vec::new();
+        let copy_out = Phase::start(tracing::info_span!(
+            "copy_out",
+            files = execution.copy_out.len(),
+            bytes = Empty,
+            duration_ms = Empty
+        ));
+        let mut copied_out: u64 = 0;
         if output.status.exit_code.unwrap_or(0) == 0 {
             // only copy out files when process is successful
             for file in execution.copy_out {
-                let data = match file.from {
-                    FilePath::Stdout { max_size } => {
-                        match max_size {
-                            Some(size) => {
-                                if stdout.len() > size as usize {
-                                    stdout[..size as usize].to_vec()
-                                } else {
-                                    stdout.clone()
+                if let FilePath::Local { name, .. } = &file.from
+                    && is_glob_pattern(name)
+                {
+                    let matches = self.expand_glob(name)?;
+                    if matches.is_empty() && file.optional {
+                        skipped_copy_out.push(name.clone());
+                    }
+                    for relative in matches {
+                        let source = CopySource::Disk(self.open_box_file(&relative)?);
+                        let to = match &file.to {
+                            FilePath::Local { name, executable } => {
+                                let target = format!("{}/{}", name, relative);
+                                if let Some(parent) = self.box_path(&target)?.parent() {
+                                    fs::create_dir_all(parent).map_err(|e| ExecutionError {
+                                        message: format!(
+                                            "failed to create directory for {}: {}",
+                                            target, e
+                                        ),
+                                    })?;
                                 }
-                            }
-                            None => stdout.clone()
-                        }
-                    },
-                    FilePath::Stderr { max_size } => {
-                        match max_size {
-                            Some(size) => {
-                                if output.stderr.len() > size as usize {
-                                    output.stderr[..size as usize].to_vec()
-                                } else {
-                                    output.stderr.clone()
+                                FilePath::Local {
+                                    name: target,
+                                    executable: *executable,
                                 }
                             }
-                            None => output.stderr.clone()
-                        }
-                    },
+                            FilePath::Remote { id } => FilePath::Remote {
+                                id: format!("{}/{}", id, relative),
+                            },
+                            _ => {
+                                return Err(ExecutionError {
+                                    message: "Unsupported file path for glob copy_out".to_string(),
+                                });
+                            }
+                        };
+                        copied_out += self.copy_out_to(to, source).await?;
+                    }
+                    continue;
+                }
+
+                let directory;
+                let source = match file.from {
+                    FilePath::Stdout { max_size } => CopySource::Memory(truncate(&stdout, max_size)),
+                    FilePath::Dir { name } => {
+                        directory = self.pack_box_dir(&name)?;
+                        CopySource::Memory(&directory)
+                    }
+                    FilePath::Stderr { max_size } => {
+                        CopySource::Memory(truncate(&output.stderr, max_size))
+                    }
                     FilePath::Local { name, executable } => {
-                        let full_path = format!("{}/{}", self.path, name);
-                        let f = fs::File::open(&full_path);
-                        let mut buffer = Vec::new();
-                        match f {
-                            Ok(mut file) => {
-                                file.read_to_end(&mut buffer)
-                                    .map_err(|e| e.to_string())
-                                    .unwrap();
-
-                                // if executable is true, set the executable bit
+                        let full_path = self.box_path(&name)?;
+                        match fs::File::open(&full_path) {
+                            Ok(file) => {
                                 if executable {
-                                    let mut perms = fs::metadata(&full_path)
-                                        .map_err(|e| e.to_string())
-                                        .unwrap()
-                                        .permissions();
-                                    perms.set_mode(perms.mode() | 0o111); // set executable bits
-                                    fs::set_permissions(&full_path, perms)
-                                        .map_err(|e| e.to_string())
-                                        .unwrap();
+                                    make_executable(&full_path, &name)?;
                                 }
-                                buffer
+                                CopySource::Disk(file)
                             }
                             Err(e) => {
+                                if file.optional {
+                                    tracing::debug!(
+                                        "skipping missing optional copy_out file {}",
+                                        name
+                                    );
+                                    skipped_copy_out.push(name);
+                                    continue;
+                                }
                                 if executable {
                                     return Err(ExecutionError {
                                         message: format!(
                                             "failed to open file {} for copy_out: {}",
-                                            full_path, e
+                                            name, e
                                         ),
                                     });
                                 }
-                                Vec::new()
+                                CopySource::Memory(&[])
                             }
                         }
                     }
@@ -357,74 +1499,67 @@ impl Worker {
                     }
                 };
 
-                match file.to {
-                    FilePath::Tmp { id } => {
-                        self.store_temp_file(id, data);
-                    }
-                    FilePath::Remote { id } => {
-                        self.file_manager
-                            .save_file(FilePath::Remote { id }, None, data)
-                            .await
-                            .unwrap();
-                    }
-
-                    FilePath::Local { name, executable } => {
-                        let mut f = fs::File::create(&name).map_err(|e| e.to_string()).unwrap();
-                        f.write_all(&data).map_err(|e| e.to_string()).unwrap();
-                        counter!("files_created_total").increment(1);
-
-                        // if executable is true, set the executable bit
-                        if executable {
-                            let mut perms = fs::metadata(&name)
-                                .map_err(|e| e.to_string())
-                                .unwrap()
-                                .permissions();
-                            perms.set_mode(perms.mode() | 0o111); // set executable bits
-                            fs::set_permissions(&name, perms)
-                                .map_err(|e| e.to_string())
-                                .unwrap();
-                        }
-                    }
-
-                    _ => {
-                        return Err(ExecutionError {
-                            message: "Unsupported file path for copy_out".to_string(),
-                        });
-                    }
-                }
+                copied_out += self.copy_out_to(file.to, source).await?;
             }
         }
 
+        copy_out.finish(Some(copied_out));
+
+        // server-side caps so a single result can't exhaust memory
+        let return_phase = Phase::start(tracing::info_span!(
+            "return_files",
+            files = Empty,
+            bytes = Empty,
+            duration_ms = Empty
+        ));
+        let max_file_size = self.config.max_return_file_size;
+        let mut budget = self.config.max_response_size;
         let mut return_files: Vec<ExecutionFile> = Vec::new();
+        let by_reference = execution.return_by_reference;
         for file in execution.return_files {
             match file {
                 // match all possible file paths
+                FilePath::Local { name, .. } if is_glob_pattern(&name) => {
+                    for relative in self.expand_glob(&name)? {
+                        if by_reference {
+                            let source = CopySource::Disk(self.open_box_file(&relative)?);
+                            return_files.push(self.return_reference(relative, source).await?);
+                            continue;
+                        }
+                        let content =
+                            self.read_box_file_limited(&relative, max_file_size.min(budget))?;
+                        return_files.push(limit_return_file(
+                            relative,
+                            content,
+                            max_file_size,
+                            &mut budget,
+                        ));
+                    }
+                }
+
                 FilePath::Local { name, executable } => {
-                    let full_path = format!("{}/{}", self.path, name);
-                    let mut f = fs::File::open(&full_path)
-                        .map_err(|e| e.to_string())
-                        .unwrap();
-                    let mut buffer = Vec::new();
-                    f.read_to_end(&mut buffer)
-                        .map_err(|e| e.to_string())
-                        .unwrap();
+                    let full_path = self.box_path(&name)?;
+                    let buffer = if by_reference {
+                        Vec::new()
+                    } else {
+                        self.read_box_file_limited(&name, max_file_size.min(budget))?
+                    };
 
-                    // if executable is true, set the executable bit
                     if executable {
-                        let mut perms = fs::metadata(&full_path)
-                            .map_err(|e| e.to_string())
-                            .unwrap()
-                            .permissions();
-                        perms.set_mode(perms.mode() | 0o111); // set executable bits
-                        fs::set_permissions(&full_path, perms)
-                            .map_err(|e| e.to_string())
-                            .unwrap();
+                        make_executable(&full_path, &name)?;
                     }
 
-                    return_files.push(ExecutionFile {
+                    if by_reference {
+                        let source = CopySource::Disk(self.open_box_file(&name)?);
+                        return_files.push(self.return_reference(name, source).await?);
+                        continue;
+                    }
+                    return_files.push(limit_return_file(
                         name,
-                        content: buffer,
-                    });
+                        buffer,
+                        max_file_size,
+                        &mut budget,
+                    ));
                 }
 
                 FilePath::Remote { id } => {
@@ -432,70 +1567,71 @@ impl Worker {
                         .file_manager
                         .get_file(FilePath::Remote { id: id.clone() }, None)
                         .await
-                        .unwrap();
+                        .map_err(|e| self.infrastructure_error(e))?;
 
-                    return_files.push(ExecutionFile {
-                        name: format!("remote_{}", id),
-                        content: data,
-                    });
+                    let name = format!("remote_{}", id);
+                    if by_reference {
+                        let source = CopySource::Memory(&data);
+                        return_files.push(self.return_reference(name, source).await?);
+                        continue;
+                    }
+                    return_files.push(limit_return_file(
+                        name,
+                        data,
+                        max_file_size,
+                        &mut budget,
+                    ));
                 }
 
-                FilePath::Stderr {
-                    max_size,
-                } => {
-                    match max_size {
-                        Some(size) => {
-                            if output.stderr.len() > size as usize {
-                                return_files.push(ExecutionFile {
-                                    name: "stderr".to_string(),
-                                    content: output.stderr[..size as usize].to_vec(),
-                                });
-                            } else {
-                                return_files.push(ExecutionFile {
-                                    name: "stderr".to_string(),
-                                    content: output.stderr.clone(),
-                                });
-                            }
-                        }
-                        None => {
-                            return_files.push(ExecutionFile {
-                                name: "stderr".to_string(),
-                                content: output.stderr.clone(),
-                            });
-                        }
-                    }
+                FilePath::Stderr { max_size } => {
+                    return_files.push(limit_return_file(
+                        "stderr".to_string(),
+                        truncate(&output.stderr, max_size).to_vec(),
+                        max_file_size,
+                        &mut budget,
+                    ));
                 }
 
                 FilePath::Stdout { max_size } => {
-                    match max_size {
-                        Some(size) => {
-                            if stdout.len() > size as usize {
-                                return_files.push(ExecutionFile {
-                                    name: "stdout".to_string(),
-                                    content: stdout[..size as usize].to_vec(),
-                                });
-                            } else {
-                                return_files.push(ExecutionFile {
-                                    name: "stdout".to_string(),
-                                    content: stdout.clone(),
-                                });
-                            }
-                        }
-                        None => {
-                            return_files.push(ExecutionFile {
-                                name: "stdout".to_string(),
-                                content: stdout.clone(),
-                            });
-                        }
+                    return_files.push(limit_return_file(
+                        "stdout".to_string(),
+                        truncate(&stdout, max_size).to_vec(),
+                        max_file_size,
+                        &mut budget,
+                    ));
+                }
+
+                FilePath::Dir { name } => {
+                    let data = self.pack_box_dir(&name)?;
+                    let name = format!("{}.tar", name.trim_end_matches('/'));
+                    if by_reference {
+                        let source = CopySource::Memory(&data);
+                        return_files.push(self.return_reference(name, source).await?);
+                        continue;
                     }
+                    return_files.push(limit_return_file(
+                        name,
+                        data,
+                        max_file_size,
+                        &mut budget,
+                    ));
                 }
 
                 FilePath::Tmp { id } => {
-                    let data = self.temp_files.remove(&id).unwrap();
-                    return_files.push(ExecutionFile {
-                        name: format!("tmp_{}", id),
-                        content: data,
-                    });
+                    // empty when nothing was stored under it, like in copy_in
+                    let data = self.temp_files.remove(&id).unwrap_or_default();
+                    let name = format!("tmp_{}", id);
+                    if by_reference {
+                        let source = CopySource::Memory(&data);
+                        return_files.push(self.return_reference(name, source).await?);
+                        continue;
+                    }
+                    return_files.push(limit_return_file(
+                        name,
+                        data,
+                        max_file_size,
+                        &mut budget,
+                    ));
                 }
 
                 _ => {
@@ -505,27 +1641,676 @@ impl Worker {
                 }
             }
         }
+        if let Some(trace) = &trace {
+            let max_trace_size = max_file_size.min(self.config.max_trace_size);
+            let mut content = Vec::new();
+            fs::File::open(trace)
+                .and_then(|f| {
+                    f.take(max_trace_size.min(budget).saturating_add(1))
+                        .read_to_end(&mut content)
+                })
+                .map_err(|e| ExecutionError {
+                    message: format!("failed to read syscall trace: {}", e),
+                })?;
+            return_files.push(limit_return_file(
+                "strace".to_string(),
+                content,
+                max_trace_size,
+                &mut budget,
+            ));
+        }
+        return_phase.record("files", return_files.len());
+        return_phase.finish(Some(
+            return_files
+                .iter()
+                .map(|file| file.content.len() as u64)
+                .sum(),
+        ));
 
-        let memory_used = match proc_resource {
-            Some(res) => res.vmrss as u64,
-            None => 0,
-        };
-        let time_used = match resource {
-            Some(res) => res.user_time.as_millis() + res.system_time.as_millis(),
-            None => 0,
+        // with an init, the container's own status is the init's
+        let (peak_memory_kb, memory_used) = match (&init_report, &proc_resource) {
+            (Some(report), _) => (report.vmpeak, report.vmrss),
+            (None, Some(res)) if !config.sandbox_init => (res.vmpeak, res.vmrss),
+            (None, _) => (0, 0),
         };
+        let time_used = resource.user_time.as_millis() + resource.system_time.as_millis();
+        let (status, limit_exceeded) = exit_status(
+            &output_status,
+            time_used,
+            wall_time_used,
+            peak_memory_kb,
+            limits,
+        );
 
-        Ok(ExecutionResult {
+        let result = ExecutionResult {
             exit_code: output.status.code,
+            status,
+            reaped_processes: init_report.as_ref().map(|report| report.reaped),
+            limit_exceeded,
             time_used,
+            wall_time_used,
             memory_used,
+            bytes_read: init_report.as_ref().and_then(|report| report.read_bytes),
+            bytes_written: init_report.as_ref().and_then(|report| report.write_bytes),
+            resource: rusage,
             return_files,
-        })
+            skipped_copy_out,
+            syscall_violations,
+            score,
+            clamped_limits,
+            compile: false,
+            cached: false,
+            perf,
+            labels: execution.labels,
+            id: execution.id,
+        };
+        // a run that changed /box is left uncached, later executions read
+        // what it wrote
+        if let Some((key, digest)) = cache_key
+            && result_cache::box_digest(Path::new(&self.path)).is_ok_and(|after| after == digest)
+            && let Some(mut connection) = self.file_manager.connection()
+        {
+            let ttl = config.result_cache_ttl_secs;
+            if let Err(e) = result_cache::put(&mut connection, &key, &result, ttl).await {
+                tracing::warn!("{}", e);
+            }
+        }
+        Ok(result)
     }
 
     #[tracing::instrument(skip(self))]
     pub async fn cleanup(&mut self) {
         tracing::debug!("cleaning up worker");
-        let _ = fs::remove_dir_all(&self.path);
+        self.cleaned_up = true;
+        let path = self.path.clone();
+        let _ = tokio::task::spawn_blocking(move || remove_sandbox_files(&path)).await;
+    }
+}
+
+// a worker dropped without cleanup, on an early return, a panic or an
+// aborted task, still kills what it left running and removes its files
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let sandbox = self.sandbox.clone();
+        let path = (!self.cleaned_up).then(|| std::mem::take(&mut self.path));
+        if let Some(path) = &path {
+            tracing::debug!(path, "cleaning up dropped worker");
+        }
+        // reaping waits for the sandbox's processes to go, off the runtime
+        let release = move || {
+            sandbox.reap();
+            if let Some(path) = path {
+                remove_sandbox_files(&path);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(release);
+            }
+            Err(_) => release(),
+        }
+    }
+}
+
+// the sandbox directory and everything kept next to it
+fn remove_sandbox_files(path: &str) {
+    let _ = fs::remove_dir_all(path);
+    let _ = fs::remove_dir_all(format!("{}.staging", path));
+    for name in [
+        "resolv.conf",
+        "hosts",
+        "network.nft",
+        "network.sh",
+        "strace.log",
+        "init.json",
+    ] {
+        let _ = fs::remove_file(format!("{}.{}", path, name));
+    }
+}
+
+// how a copy_in source is named in errors
+fn source_name(path: &FilePath) -> String {
+    match path {
+        FilePath::Local { name, .. } | FilePath::Dir { name } => name.clone(),
+        FilePath::Remote { id } => id.clone(),
+        FilePath::Url { url } | FilePath::Git { url, .. } => url.clone(),
+        FilePath::Tmp { id } => format!("tmp {}", id),
+        _ => "copy_in source".to_string(),
+    }
+}
+
+// moves what is in `from` into `to`, creating it. names already in `to`
+// are left alone and fail the move
+// adds the executable bits to what `path` already allows
+fn make_executable(path: &Path, name: &str) -> Result<(), ExecutionError> {
+    let failed = |e: std::io::Error| ExecutionError {
+        message: format!("failed to make {} executable: {}", name, e),
+    };
+    let mut permissions = fs::metadata(path).map_err(failed)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(path, permissions).map_err(failed)
+}
+
+fn move_entries(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|e| format!("failed to create {}: {}", to.display(), e))?;
+    let entries = fs::read_dir(from).map_err(|e| e.to_string())?;
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let target = to.join(entry.file_name());
+        if fs::symlink_metadata(&target).is_ok() {
+            return Err(format!(
+                "{} already exists",
+                entry.file_name().to_string_lossy()
+            ));
+        }
+        fs::rename(entry.path(), &target).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// copies remote file `id` to `path`, from the local file cache when it holds
+// the same content, filling the cache otherwise
+async fn download(
+    file_manager: &mut FileManager,
+    file_cache: &FileCache,
+    id: &str,
+    path: &Path,
+) -> Result<(), String> {
+    let digest = match file_cache.max_file_size() {
+        Some(max_size) => match file_manager.digest(id, max_size).await? {
+            Some((size, Some(digest))) => Some((size, digest)),
+            Some((_, None)) => None,
+            None => return Err(format!("Failed to get remote file: {} does not exist", id)),
+        },
+        None => None,
+    };
+    if let Some((size, digest)) = &digest
+        && file_cache.copy_to(digest, *size, path)?
+    {
+        return Ok(());
+    }
+
+    let mut target = fs::File::create(path)
+        .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+    let remote = FilePath::Remote { id: id.to_string() };
+    file_manager.get_file_chunked(remote, &mut target).await?;
+    if let Some((_, digest)) = digest {
+        file_cache.insert(&digest, path);
+    }
+    Ok(())
+}
+
+// a request file ready to be written to /box
+enum StagedFile {
+    Inline(File),                               // content is in memory
+    Downloaded { name: String, path: PathBuf }, // streamed to `path`
+}
+
+// resolves a file's remote content, retrying storage errors like other
+// infrastructure failures. plain files are streamed to `path`, archives are
+// read whole since they are unpacked from memory
+async fn fetch_file(
+    file_manager: &mut FileManager,
+    file_cache: &FileCache,
+    file: File,
+    path: PathBuf,
+    retries: u32,
+) -> Result<StagedFile, String> {
+    let mut attempt = 0;
+    let staged = loop {
+        let result = match &file {
+            File::Remote { id, name, .. } => download(file_manager, file_cache, id, &path)
+                .await
+                .map(|_| StagedFile::Downloaded {
+                    name: name.clone(),
+                    path: path.clone(),
+                }),
+            File::Archive {
+                name,
+                content: None,
+                id: Some(id),
+                sha256,
+            } => file_manager
+                .get_file(FilePath::Remote { id: id.clone() }, None)
+                .await
+                .map(|data| {
+                    StagedFile::Inline(File::Archive {
+                        name: name.clone(),
+                        content: Some(data),
+                        id: None,
+                        sha256: sha256.clone(),
+                    })
+                }),
+            _ => return Ok(StagedFile::Inline(file)),
+        };
+        match result {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                tracing::warn!(retries = attempt, "retrying file fetch: {}", e);
+            }
+            result => break result?,
+        }
+    };
+    // a mismatch is not retried, the stored content is what's wrong
+    if let File::Remote {
+        name,
+        sha256: Some(expected),
+        ..
+    } = &file
+    {
+        integrity::check_file(name, expected, &path)?;
+    }
+    Ok(staged)
+}
+
+// classifies how the program ended and which limit it hit. a limit counts as
+// hit when its signal arrived or the usage reached it: SIGXCPU or the cpu time
+// for the time limit, SIGKILL after the wall time for the wall time limit. the
+// memory limit is RLIMIT_AS, which fails allocations instead of killing, so a
+// failure with the peak address space within MEMORY_LIMIT_MARGIN of it counts
+fn exit_status(
+    status: &hakoniwa::ExitStatus,
+    time_used: u128,
+    wall_time_used: u128,
+    peak_memory_kb: u64,
+    [time_limit, wall_time_limit, memory_limit]: [u64; 3],
+) -> (ExitStatus, Option<LimitExceeded>) {
+    if status.code == 0 {
+        return (ExitStatus::Success, None);
+    }
+    let signal = match status.exit_code {
+        Some(_) => None,
+        None => Some(status.code - 128),
+    };
+    let (time_limit, wall_time_limit) = (time_limit as u128 * 1000, wall_time_limit as u128 * 1000);
+    if signal == Some(libc::SIGXCPU) || time_used >= time_limit {
+        let exceeded = LimitExceeded {
+            limit: time_limit,
+            used: time_used,
+        };
+        return (ExitStatus::TimeLimitExceeded, Some(exceeded));
+    }
+    if signal == Some(libc::SIGKILL) && wall_time_used >= wall_time_limit {
+        let exceeded = LimitExceeded {
+            limit: wall_time_limit,
+            used: wall_time_used,
+        };
+        return (ExitStatus::WallTimeLimitExceeded, Some(exceeded));
+    }
+    if peak_memory_kb * 1024 >= memory_limit / 100 * (100 - MEMORY_LIMIT_MARGIN) {
+        let exceeded = LimitExceeded {
+            limit: (memory_limit / 1024) as u128,
+            used: peak_memory_kb as u128,
+        };
+        return (ExitStatus::MemoryLimitExceeded, Some(exceeded));
+    }
+    match signal {
+        Some(_) => (ExitStatus::Signaled, None),
+        None => (ExitStatus::NonzeroExit, None),
+    }
+}
+
+// values above the server's maximum are lowered to it, or refused when the
+// server is set to reject them
+fn clamp_limit(
+    name: &str,
+    value: u64,
+    max: Option<u64>,
+    config: &AppConfig,
+    clamped: &mut Vec<String>,
+) -> Result<u64, ExecutionError> {
+    let Some(max) = max.filter(|max| value > *max) else {
+        return Ok(value);
+    };
+    if config.reject_over_limits {
+        return Err(ExecutionError {
+            message: format!("{} {} is above the server maximum of {}", name, value, max),
+        });
+    }
+    clamped.push(name.to_string());
+    Ok(max)
+}
+
+// mounts the root filesystem first, so the mounts on top of it replace its
+// directories of the same name
+fn mount_filesystems(
+    container: &mut Container,
+    rootfs: &str,
+    code_path: &str,
+    config: &AppConfig,
+) -> Result<(), ExecutionError> {
+    container.rootfs(rootfs).map_err(|e| ExecutionError {
+        message: format!("unable to mount root fs {}: {}", rootfs, e),
+    })?;
+    container.bindmount_rw(code_path, &config.box_path);
+
+    if !config.dev_devices.is_empty() {
+        container.tmpfsmount("/dev");
+        for dev in &config.dev_devices {
+            let path = format!("/dev/{}", dev);
+            container.bindmount_rw(&path, &path);
+        }
+        container.symlink("/proc/self/fd", "/dev/fd");
+        for (fd, name) in ["stdin", "stdout", "stderr"].iter().enumerate() {
+            container.symlink(&format!("/proc/self/fd/{}", fd), &format!("/dev/{}", name));
+        }
+    }
+
+    if config.proc_read_only {
+        // replaces the procfs mount Container::new sets up
+        container.mount(
+            "proc",
+            "/proc",
+            "proc",
+            MountOptions::NOSUID | MountOptions::NODEV | MountOptions::NOEXEC | MountOptions::RDONLY,
+        );
+    }
+    Ok(())
+}
+
+// the sandbox directory is mounted over the root filesystem, so it can't
+// be the root or hide the directories the sandbox sets up itself
+pub(crate) fn check_box_path(path: &str) -> Result<(), String> {
+    let relative = path.strip_prefix('/').ok_or("must be absolute")?;
+    if relative.is_empty() || !archive::is_relative(relative) {
+        return Err("must name a directory below /".to_string());
+    }
+    if ["proc", "dev", "run"]
+        .iter()
+        .any(|dir| Path::new(relative).starts_with(dir))
+    {
+        return Err("can't be in /proc, /dev or /run".to_string());
+    }
+    Ok(())
+}
+
+// later rule sets override earlier ones for the same path, `box_path` is
+// always writable
+pub(crate) fn landlock_ruleset(
+    box_path: &str,
+    rules: &[&BTreeMap<String, String>],
+) -> Result<Ruleset, String> {
+    let mut ruleset = Ruleset::default();
+    ruleset.restrict(Resource::FS, CompatMode::Enforce);
+
+    for (path, access) in rules.iter().flat_map(|rules| rules.iter()) {
+        let access = access
+            .parse::<FsAccess>()
+            .map_err(|e| format!("invalid landlock access for {}: {}", path, e))?;
+        ruleset.add_fs_rule(path, access);
+    }
+    ruleset.add_fs_rule(box_path, FsAccess::R | FsAccess::W | FsAccess::X);
+
+    Ok(ruleset)
+}
+
+// SPAWN_ENV with the host's values of the `passthrough` variables it has,
+// which replace the defaults
+fn spawn_env(passthrough: &[String]) -> BTreeMap<String, String> {
+    let mut env: BTreeMap<String, String> = SPAWN_ENV
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    for name in passthrough {
+        if let Some(value) = std::env::var_os(name) {
+            env.insert(name.clone(), value.to_string_lossy().into_owned());
+        }
+    }
+    env
+}
+
+// `arg` as a single word of a posix shell command line
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// setpriv arguments clearing every capability set except `keep`, ending in "--"
+// so the program and its arguments follow
+fn setpriv_args(keep: &[String], no_new_privs: bool) -> Vec<String> {
+    let caps = std::iter::once("-all".to_string())
+        .chain(keep.iter().map(|cap| format!("+{}", cap)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut args = vec![
+        format!("--bounding-set={}", caps),
+        format!("--inh-caps={}", caps),
+        format!("--ambient-caps={}", caps),
+    ];
+    if no_new_privs {
+        args.push("--no-new-privs".to_string());
+    }
+    args.push("--".to_string());
+    args
+}
+
+fn seccomp_filter(banned_action: Action, network: bool) -> Filter {
+    let mut filter = Filter::new(Action::Allow);
+    BANNED_SYSCALLS.iter().for_each(|syscall| {
+        filter.add_rule(banned_action, syscall);
+    });
+    if !network {
+        NETWORK_SYSCALLS.iter().for_each(|syscall| {
+            filter.add_rule(banned_action, syscall);
+        });
+    }
+    filter
+}
+
+// host device nodes matching the configured patterns, e.g. /dev/nvidia*
+fn gpu_devices(patterns: &[String]) -> Result<Vec<String>, ExecutionError> {
+    let mut devices = Vec::new();
+    for pattern in patterns {
+        let entries = glob::glob(pattern).map_err(|e| ExecutionError {
+            message: format!("invalid gpu device pattern {}: {}", pattern, e),
+        })?;
+        devices.extend(
+            entries
+                .filter_map(Result::ok)
+                .map(|path| path.to_string_lossy().to_string()),
+        );
+    }
+    if devices.is_empty() {
+        return Err(ExecutionError {
+            message: "no gpu devices found on this server".to_string(),
+        });
+    }
+    Ok(devices)
+}
+
+// the first whitespace separated token, if it is a number
+fn parse_score(data: &[u8]) -> Option<f64> {
+    let token = data
+        .split(u8::is_ascii_whitespace)
+        .find(|token| !token.is_empty())?;
+    std::str::from_utf8(token)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|score| score.is_finite())
+}
+
+fn resolv_conf_contents(config: &AppConfig) -> Result<String, ExecutionError> {
+    let mut contents = String::new();
+    for nameserver in &config.dns_nameservers {
+        if nameserver.parse::<IpAddr>().is_err() {
+            return Err(ExecutionError {
+                message: format!("invalid dns nameserver {}", nameserver),
+            });
+        }
+        contents.push_str(&format!("nameserver {}\n", nameserver));
+    }
+    if !config.dns_search.is_empty() {
+        contents.push_str(&format!("search {}\n", config.dns_search.join(" ")));
+    }
+    Ok(contents)
+}
+
+// the host's /etc is bind-mounted as is, so when one of its files is a symlink
+// (e.g. resolv.conf with systemd-resolved) a generated replacement has to go
+// where the link points
+fn etc_file_target(path: &str) -> String {
+    fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+// niceness and perf counters are per thread on linux and inherited on fork,
+// so a sandbox needing either is spawned from a short-lived thread that set
+// them up first, leaving the runtime's threads untouched
+fn spawn_sandbox(
+    cmd: &mut Command,
+    niceness: Option<i32>,
+    perf: bool,
+) -> (hakoniwa::Result<Child>, Option<PerfSession>) {
+    if niceness.is_none() && !perf {
+        return (cmd.spawn(), None);
+    }
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                if let Some(niceness) = niceness
+                    && unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, niceness) } != 0
+                {
+                    tracing::warn!(
+                        "failed to set niceness {}: {}",
+                        niceness,
+                        std::io::Error::last_os_error()
+                    );
+                }
+                // opened before the fork, so the program is counted from its exec on
+                let perf = perf
+                    .then(|| {
+                        PerfSession::start_on_exec()
+                            .inspect_err(|e| tracing::warn!("no perf counters: {}", e))
+                            .ok()
+                    })
+                    .flatten();
+                (cmd.spawn(), perf)
+            })
+            .join()
+            .expect("spawn thread panicked")
+    })
+}
+
+// wait_with_output that also takes the getrusage counts of the container
+// process, and so of everything in the sandbox it reaped, before reaping it.
+// they come from the kernel, nothing in the sandbox can change them
+fn wait_with_usage(
+    proc: &mut Child,
+) -> (hakoniwa::Result<hakoniwa::Output>, Option<ResourceUsage>) {
+    drop(proc.stdin.take());
+    let (stdout, stderr) = std::thread::scope(|scope| {
+        let read = |pipe: Option<std::io::PipeReader>| {
+            scope.spawn(move || {
+                let mut data = Vec::new();
+                if let Some(mut pipe) = pipe
+                    && let Err(e) = pipe.read_to_end(&mut data)
+                {
+                    tracing::warn!("failed to read the output of the program: {}", e);
+                }
+                data
+            })
+        };
+        let (stdout, stderr) = (read(proc.stdout.take()), read(proc.stderr.take()));
+        (
+            stdout.join().expect("stdout thread panicked"),
+            stderr.join().expect("stderr thread panicked"),
+        )
+    });
+    let resource = exited_usage(proc.id() as libc::pid_t);
+    let output = proc.wait().map(|status| hakoniwa::Output {
+        status,
+        stdout,
+        stderr,
+    });
+    (output, resource)
+}
+
+// getrusage of `pid` and its reaped children once it exited, leaving it to
+// be reaped. none when waitid fails
+fn exited_usage(pid: libc::pid_t) -> Option<ResourceUsage> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // the libc wrapper has no rusage argument
+        let waited = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                pid,
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED | libc::WNOWAIT,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if waited == 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINTR) {
+            tracing::warn!("failed to get resource usage: {}", e);
+            return None;
+        }
+    }
+    Some(ResourceUsage {
+        max_rss: usage.ru_maxrss as u64,
+        minor_page_faults: usage.ru_minflt as u64,
+        major_page_faults: usage.ru_majflt as u64,
+        voluntary_context_switches: usage.ru_nvcsw as u64,
+        involuntary_context_switches: usage.ru_nivcsw as u64,
+        block_reads: usage.ru_inblock as u64,
+        block_writes: usage.ru_oublock as u64,
+    })
+}
+
+// where copy_out data comes from; files stay open so they can be streamed
+enum CopySource<'a> {
+    Memory(&'a [u8]),
+    Disk(fs::File),
+}
+
+impl CopySource<'_> {
+    fn into_bytes(self) -> Result<Vec<u8>, ExecutionError> {
+        match self {
+            CopySource::Memory(data) => Ok(data.to_vec()),
+            CopySource::Disk(mut file) => {
+                let mut buffer = Vec::new();
+                file.read_to_end(&mut buffer).map_err(|e| ExecutionError {
+                    message: format!("failed to read file for copy_out: {}", e),
+                })?;
+                Ok(buffer)
+            }
+        }
+    }
+}
+
+// caps a returned file to the per-file limit and what is left of the response budget
+fn limit_return_file(
+    name: String,
+    mut content: Vec<u8>,
+    max_file_size: u64,
+    budget: &mut u64,
+) -> ExecutionFile {
+    let limit = max_file_size.min(*budget);
+    let truncated = content.len() as u64 > limit;
+    if truncated {
+        tracing::warn!(name = %name, size = content.len(), limit, "truncating returned file");
+        content.truncate(limit as usize);
+    }
+    *budget -= content.len() as u64;
+
+    ExecutionFile {
+        name,
+        content,
+        truncated,
+        remote: None,
     }
 }